@@ -0,0 +1,235 @@
+//! Applying a plan, by committing each hunk that has a target as a
+//! fixup of it.
+
+use anyhow::{anyhow, Result};
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::{owned, plan};
+
+/// Commits each hunk of the plan that has a target on top of HEAD, in
+/// the order of `Plan::hunks_to_apply()`, as a `fixup!` commit of its
+/// target (see `Plan::target_locator()`) made by `signature`, and moves
+/// HEAD to the last one.
+///
+/// Returns the fixups, in the same order. The index is left as it is,
+/// so the hunks that were absorbed are no longer staged, and the others
+/// still are.
+pub fn commit_fixups(
+    repo: &git2::Repository,
+    plan: &plan::Plan,
+    signature: &git2::Signature,
+) -> Result<Vec<git2::Oid>> {
+    let head = repo.head()?.peel_to_commit()?;
+    let committed = commit_fixups_with(repo, plan, &head, &HashMap::new(), |fixup| {
+        let message = format!("fixup! {}", fixup.locator);
+        Ok(repo.commit(
+            None,
+            signature,
+            signature,
+            &message,
+            fixup.tree,
+            &[fixup.parent],
+        )?)
+    })?;
+    if let Some(&last) = committed.last() {
+        repo.head()?.set_target(last, "absorb: fixups")?;
+    }
+    Ok(committed)
+}
+
+/// A fixup that `commit_fixups_with()` is about to commit.
+pub struct Fixup<'a, 'repo> {
+    pub assignment: &'a plan::HunkAssignment<'repo>,
+    /// The target of the assignment.
+    pub target: &'a git2::Commit<'repo>,
+    /// The hunk of the assignment, adjusted by `Plan::hunks_to_apply()`.
+    pub hunk: &'a owned::Hunk,
+    /// How the message of the fixup refers to its target.
+    pub locator: &'a str,
+    /// The tree of the fixup, with the hunk applied.
+    pub tree: &'a git2::Tree<'repo>,
+    /// The fixup before it, or the commit they are committed on top of.
+    pub parent: &'a git2::Commit<'repo>,
+}
+
+/// Like `commit_fixups()`, on top of `head`, with `commit` making each
+/// fixup commit and returning its id. HEAD isn't moved.
+///
+/// The hunks whose target is in `existing` are already in the fixup it
+/// maps to (eg: one from an earlier run, amended with them), which is
+/// returned for them instead.
+pub fn commit_fixups_with<'repo>(
+    repo: &'repo git2::Repository,
+    plan: &plan::Plan<'repo>,
+    head: &git2::Commit<'repo>,
+    existing: &HashMap<git2::Oid, git2::Oid>,
+    mut commit: impl FnMut(Fixup<'_, 'repo>) -> Result<git2::Oid>,
+) -> Result<Vec<git2::Oid>> {
+    let mut head = head.clone();
+    let mut tree = head.tree()?;
+    let mut committed = Vec::new();
+    for (assignment, hunk) in plan.hunks_to_apply() {
+        // unwrap() is safe here, as hunks_to_apply() only returns hunks
+        // that have a target
+        let target = assignment.target.as_ref().unwrap();
+        if let Some(&fixup) = existing.get(&target.id()) {
+            committed.push(fixup);
+            continue;
+        }
+        tree = apply_assignment(repo, &tree, assignment, &hunk)?;
+        let fixup = commit(Fixup {
+            assignment,
+            target,
+            hunk: &hunk,
+            locator: &plan.target_locator(target),
+            tree: &tree,
+            parent: &head,
+        })?;
+        head = repo.find_commit(fixup)?;
+        committed.push(fixup);
+    }
+    Ok(committed)
+}
+
+/// Applies the hunk of an assignment, which has been adjusted by
+/// `Plan::hunks_to_apply()`, to the tree.
+pub fn apply_assignment<'repo>(
+    repo: &'repo git2::Repository,
+    base: &git2::Tree,
+    assignment: &plan::HunkAssignment,
+    hunk: &owned::Hunk,
+) -> Result<git2::Tree<'repo>> {
+    match assignment.file_change {
+        None => apply_hunk_to_tree(repo, base, hunk, &assignment.path),
+        Some(file_change) => {
+            apply_file_change_to_tree(repo, base, hunk, &assignment.path, file_change)
+        }
+    }
+}
+
+/// Creates, deletes, replaces or changes the mode of the file at `path`.
+/// A file is created or deleted from the hunk that adds or removes all of
+/// its lines.
+fn apply_file_change_to_tree<'repo>(
+    repo: &'repo git2::Repository,
+    base: &git2::Tree,
+    hunk: &owned::Hunk,
+    path: &[u8],
+    file_change: plan::FileChange,
+) -> Result<git2::Tree<'repo>> {
+    let mut update = git2::build::TreeUpdateBuilder::new();
+    match file_change {
+        plan::FileChange::Added(mode) => {
+            let mut blobwriter = repo.blob_writer(None)?;
+            for line in &*hunk.added.lines {
+                blobwriter.write_all(line)?;
+            }
+            update.upsert(path, blobwriter.commit()?, mode);
+        }
+        plan::FileChange::Deleted => {
+            update.remove(path);
+        }
+        plan::FileChange::Mode(mode) => {
+            update.upsert(path, entry_id(repo, base, path)?, mode);
+        }
+        plan::FileChange::Replaced(id, mode) => {
+            update.upsert(path, id, mode);
+        }
+    }
+    Ok(repo.find_tree(update.create_updated(repo, base)?)?)
+}
+
+/// Returns the id of the entry at `path` in the tree.
+fn entry_id(repo: &git2::Repository, tree: &git2::Tree, path: &[u8]) -> Result<git2::Oid> {
+    let mut tree = repo.find_tree(tree.id())?;
+    let mut names = path.split(|&x| x == b'/').peekable();
+    while let Some(name) = names.next() {
+        let id = tree
+            .get_name_bytes(name)
+            .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?
+            .id();
+        if names.peek().is_none() {
+            return Ok(id);
+        }
+        tree = repo.find_tree(id)?;
+    }
+    Err(anyhow!("couldn't find tree entry in tree for path"))
+}
+
+/// Applies the hunk to the file at `path` in the tree.
+fn apply_hunk_to_tree<'repo>(
+    repo: &'repo git2::Repository,
+    base: &git2::Tree,
+    hunk: &owned::Hunk,
+    path: &[u8],
+) -> Result<git2::Tree<'repo>> {
+    let mut treebuilder = repo.treebuilder(Some(base))?;
+
+    // recurse into nested tree if applicable
+    if let Some(slash) = path.iter().position(|&x| x == b'/') {
+        let (first, rest) = path.split_at(slash);
+        let rest = &rest[1..];
+
+        let (subtree, submode) = {
+            let entry = treebuilder
+                .get(first)?
+                .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?;
+            (repo.find_tree(entry.id())?, entry.filemode())
+        };
+        // TODO: loop instead of recursing to avoid potential stack overflow
+        let result_subtree = apply_hunk_to_tree(repo, &subtree, hunk, rest)?;
+
+        treebuilder.insert(first, result_subtree.id(), submode)?;
+        return Ok(repo.find_tree(treebuilder.write()?)?);
+    }
+
+    let (blob, mode) = {
+        let entry = treebuilder
+            .get(path)?
+            .ok_or_else(|| anyhow!("couldn't find blob entry in tree for path"))?;
+        (repo.find_blob(entry.id())?, entry.filemode())
+    };
+
+    // TODO: convert path to OsStr and pass it during blob_writer
+    // creation, to get gitattributes handling (note that converting
+    // &[u8] to &std::path::Path is only possible on unixy platforms)
+    let mut blobwriter = repo.blob_writer(None)?;
+    let old_content = blob.content();
+    let (old_start, _, _, _) = hunk.anchors();
+
+    // first, write the lines from the old content that are above the
+    // hunk
+    let old_content = {
+        let (pre, post) = split_lines_after(old_content, old_start);
+        blobwriter.write_all(pre)?;
+        post
+    };
+    // next, write the added side of the hunk
+    for line in &*hunk.added.lines {
+        blobwriter.write_all(line)?;
+    }
+    // if this hunk removed lines from the old content, those must be
+    // skipped
+    let (_, old_content) = split_lines_after(old_content, hunk.removed.lines.len());
+    // finally, write the remaining lines of the old content
+    blobwriter.write_all(old_content)?;
+
+    treebuilder.insert(path, blobwriter.commit()?, mode)?;
+    Ok(repo.find_tree(treebuilder.write()?)?)
+}
+
+/// Return slices for lines [1..n] and [n+1; ...]
+fn split_lines_after(content: &[u8], n: usize) -> (&[u8], &[u8]) {
+    let split_index = if n > 0 {
+        memchr::Memchr::new(b'\n', content)
+            .fuse() // TODO: is fuse necessary here?
+            .nth(n - 1) // the position of '\n' ending the `n`-th line
+            .map(|x| x + 1)
+            .unwrap_or_else(|| content.len())
+    } else {
+        0
+    };
+    content.split_at(split_index)
+}
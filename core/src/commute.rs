@@ -0,0 +1,579 @@
+use crate::owned;
+
+/// Tests if all elements of the iterator are equal to each other.
+///
+/// An empty iterator returns `true`.
+///
+/// `uniform()` is short-circuiting. It will stop processing as soon
+/// as it finds two pairwise inequal elements.
+fn uniform<I, E>(iter: I) -> bool
+where
+    I: IntoIterator<Item = E>,
+    E: Eq,
+{
+    let mut iter = iter.into_iter();
+    match iter.next() {
+        Some(first) => iter.all(|e| e == first),
+        None => true,
+    }
+}
+
+pub fn commute(first: &owned::Hunk, second: &owned::Hunk) -> Option<(owned::Hunk, owned::Hunk)> {
+    let (_, _, first_upper, first_lower) = first.anchors();
+    let (second_upper, second_lower, _, _) = second.anchors();
+
+    // represent hunks in content order rather than application order
+    let (first_above, above, below) = {
+        if first_lower <= second_upper {
+            (true, first, second)
+        } else if second_lower <= first_upper {
+            (false, second, first)
+        } else {
+            // if both hunks are exclusively adding or removing, and
+            // both hunks are composed entirely of the same line being
+            // repeated, then they commute no matter what their
+            // offsets are, because they can be interleaved in any
+            // order without changing the final result
+            if (first.added.lines.is_empty()
+                && second.added.lines.is_empty()
+                && uniform(first.removed.lines.iter().chain(&*second.removed.lines)))
+                || (first.removed.lines.is_empty()
+                    && second.removed.lines.is_empty()
+                    && uniform(first.added.lines.iter().chain(&*second.added.lines)))
+            {
+                // TODO: removed/added start positions probably need to be
+                // tweaked here
+                return Some((second.clone(), first.clone()));
+            }
+            // these hunks overlap and cannot be interleaved, so they
+            // do not commute
+            return None;
+        }
+    };
+
+    let above = above.clone();
+    let mut below = below.clone();
+    let above_change_offset = (above.added.lines.len() as i64 - above.removed.lines.len() as i64)
+        * if first_above { -1 } else { 1 };
+    below.added.start = (below.added.start as i64 + above_change_offset) as usize;
+    below.removed.start = (below.removed.start as i64 + above_change_offset) as usize;
+
+    Some(if first_above {
+        (below, above)
+    } else {
+        (above, below)
+    })
+}
+
+/// Like `commute()`, but also lets two hunks that replace the same lines
+/// commute, as long as they change disjoint parts of each line.
+pub fn commute_intra_line(
+    first: &owned::Hunk,
+    second: &owned::Hunk,
+) -> Option<(owned::Hunk, owned::Hunk)> {
+    commute(first, second).or_else(|| commute_within_lines(first, second))
+}
+
+fn commute_within_lines(
+    first: &owned::Hunk,
+    second: &owned::Hunk,
+) -> Option<(owned::Hunk, owned::Hunk)> {
+    // the hunks must both be one-for-one replacements, and the second
+    // must replace exactly the lines that the first added
+    if !first.is_replacement()
+        || !second.is_replacement()
+        || first.added.start != second.removed.start
+        || first.added.lines.len() != second.removed.lines.len()
+        || first.added.lines.is_empty()
+    {
+        return None;
+    }
+
+    let mut middle_lines = Vec::with_capacity(first.removed.lines.len());
+    for ((before, between), after) in first
+        .removed
+        .lines
+        .iter()
+        .zip(&*first.added.lines)
+        .zip(&*second.added.lines)
+    {
+        middle_lines.push(commute_line(before, between, after)?.into());
+    }
+    let middle_lines = std::sync::Arc::new(middle_lines);
+
+    // the second hunk's change now comes first, and the first hunk's
+    // change is made on top of it, with each hunk keeping its offset
+    let second_offset = second.added.start as i64 - second.removed.start as i64;
+    let commuted_second = owned::Hunk {
+        removed: first.removed.clone(),
+        added: owned::Block {
+            start: (first.removed.start as i64 + second_offset) as usize,
+            lines: middle_lines.clone(),
+            trailing_newline: first.removed.trailing_newline,
+        },
+    };
+    let commuted_first = owned::Hunk {
+        removed: owned::Block {
+            start: first.removed.start,
+            lines: middle_lines,
+            trailing_newline: first.removed.trailing_newline,
+        },
+        added: owned::Block {
+            lines: second.added.lines.clone(),
+            trailing_newline: second.added.trailing_newline,
+            ..first.added.clone()
+        },
+    };
+    Some((commuted_second, commuted_first))
+}
+
+/// Given a line that was changed from `before` to `between` and then
+/// from `between` to `after`, returns what `before` looks like with only
+/// the second change applied, if the two changes don't touch each other.
+fn commute_line(before: &[u8], between: &[u8], after: &[u8]) -> Option<Vec<u8>> {
+    if before == between {
+        return Some(after.to_vec());
+    }
+    if between == after {
+        return Some(before.to_vec());
+    }
+
+    // the parts of `between` that were changed by the first and second
+    // change are `between[first_start..between.len() - first_suffix]`
+    // and `between[second_start..between.len() - second_suffix]`
+    let (first_start, first_suffix) = changed_range(before, between);
+    let (second_start, second_suffix) = changed_range(between, after);
+    let first_end = between.len() - first_suffix;
+    let second_end = between.len() - second_suffix;
+
+    let mut ret = Vec::with_capacity(before.len() + after.len() - between.len());
+    if first_end < second_start {
+        // the first change precedes the second, so the second change
+        // must be moved by however much the first changed the length
+        let start = second_start + before.len() - between.len();
+        ret.extend_from_slice(&before[..start]);
+        ret.extend_from_slice(&after[second_start..after.len() - second_suffix]);
+        ret.extend_from_slice(&before[before.len() - second_suffix..]);
+    } else if second_end < first_start {
+        ret.extend_from_slice(&after[..after.len() - second_suffix]);
+        ret.extend_from_slice(&before[second_end..]);
+    } else {
+        return None;
+    }
+    Some(ret)
+}
+
+/// Returns the length of the common prefix of the two slices, and the
+/// length of their common suffix after that prefix.
+fn changed_range(old: &[u8], new: &[u8]) -> (usize, usize) {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    (prefix, suffix)
+}
+
+/// Commutes the hunk so that it comes before the patch. If it does not
+/// commute with the patch, returns the hunk of the patch that it
+/// conflicts with.
+///
+/// With `intra_line`, hunks that replace the same lines may still
+/// commute, see `commute_intra_line()`.
+pub fn commute_diff_before<'a, I>(
+    after: &owned::Hunk,
+    before: I,
+    intra_line: bool,
+) -> Result<owned::Hunk, &'a owned::Hunk>
+where
+    I: IntoIterator<Item = &'a owned::Hunk>,
+    <I as IntoIterator>::IntoIter: DoubleEndedIterator,
+{
+    before
+        .into_iter()
+        // the patch's hunks must be iterated in reverse application
+        // order (last applied to first applied), which also happens
+        // to be reverse line order (bottom to top), which also
+        // happens to be reverse of the order they're stored
+        .rev()
+        .try_fold(after.clone(), |after, next| {
+            if intra_line {
+                commute_intra_line(next, &after)
+            } else {
+                commute(next, &after)
+            }
+            .map(|(commuted_after, _)| commuted_after)
+            .ok_or(next)
+        })
+}
+
+/// If the hunk only adds lines, returns the index of the hunk of the
+/// same patch that only removes those very lines, so that together they
+/// move a block of lines elsewhere in the file.
+///
+/// Blocks of blank lines are not considered moved, since any two of
+/// them look the same.
+pub fn moved_from(hunk: &owned::Hunk, patch: &[owned::Hunk]) -> Option<usize> {
+    if !hunk.removed.lines.is_empty()
+        || hunk
+            .added
+            .lines
+            .iter()
+            .all(|line| line.iter().all(u8::is_ascii_whitespace))
+    {
+        return None;
+    }
+    patch.iter().position(|other| {
+        other.added.lines.is_empty()
+            && other.removed.lines == hunk.added.lines
+            && other.removed.trailing_newline == hunk.added.trailing_newline
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_commute() {
+        // example init: <<EOF
+        // foo
+        // EOF
+        let hunk1 = owned::Hunk {
+            added: owned::Block {
+                start: 2,
+                lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+        };
+        // after hunk1: <<EOF
+        // foo
+        // bar
+        // EOF
+        let hunk2 = owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 0,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+        };
+        // after hunk2: <<EOF
+        // bar
+        // foo
+        // bar
+        // EOF
+
+        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        assert_eq!(new1.added.start, 1);
+        assert_eq!(new2.added.start, 3);
+    }
+
+    #[test]
+    fn test_commute_removal_after_other_hunks() {
+        let block = |start, lines: &[&[u8]]| owned::Block {
+            start,
+            lines: Arc::new(lines.iter().map(|&line| owned::Line::from(line)).collect()),
+            trailing_newline: true,
+        };
+        // the second hunk of a commit that removed lines 3 and 4, and
+        // then lines 10 and 11, which were between lines 7 and 8 after it
+        let removal = owned::Hunk {
+            removed: block(10, &[b"a\n", b"b\n"]),
+            added: block(7, &[]),
+        };
+        // changes line 7, which is right above where they were
+        let next = owned::Hunk {
+            removed: block(7, &[b"c\n"]),
+            added: block(7, &[b"d\n"]),
+        };
+        assert!(commute(&removal, &next).is_none());
+        // but line 6 is one line away
+        let above = owned::Hunk {
+            removed: block(6, &[b"c\n"]),
+            added: block(6, &[b"d\n"]),
+        };
+        assert!(commute(&removal, &above).is_some());
+    }
+
+    #[test]
+    fn test_commute_trivial_add() {
+        let mut line = ::std::iter::repeat(owned::Line::from(&b"bar\n"[..]));
+        let hunk1 = owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new((&mut line).take(4).collect::<Vec<_>>()),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 0,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+        };
+        let hunk2 = owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new((&mut line).take(2).collect::<Vec<_>>()),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 0,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+        };
+
+        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        assert_eq!(new1.added.lines.len(), 2);
+        assert_eq!(new2.added.lines.len(), 4);
+    }
+
+    #[test]
+    fn test_commute_trivial_remove() {
+        let mut line = ::std::iter::repeat(owned::Line::from(&b"bar\n"[..]));
+        let hunk1 = owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 4,
+                lines: Arc::new((&mut line).take(4).collect::<Vec<_>>()),
+                trailing_newline: true,
+            },
+        };
+        let hunk2 = owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 2,
+                lines: Arc::new((&mut line).take(2).collect::<Vec<_>>()),
+                trailing_newline: true,
+            },
+        };
+
+        let (new1, new2) = commute(&hunk1, &hunk2).unwrap();
+        assert_eq!(new1.removed.lines.len(), 2);
+        assert_eq!(new2.removed.lines.len(), 4);
+    }
+
+    #[test]
+    fn test_commute_patch() {
+        // example init: <<EOF
+        // foo
+        // foo
+        // EOF
+        let patch = vec![
+            owned::Hunk {
+                added: owned::Block {
+                    start: 1,
+                    lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                    trailing_newline: true,
+                },
+                removed: owned::Block {
+                    start: 0,
+                    lines: Arc::new(vec![]),
+                    trailing_newline: true,
+                },
+            },
+            owned::Hunk {
+                added: owned::Block {
+                    start: 3,
+                    lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                    trailing_newline: true,
+                },
+                removed: owned::Block {
+                    start: 1,
+                    lines: Arc::new(vec![]),
+                    trailing_newline: true,
+                },
+            },
+        ];
+        // after patch: <<EOF
+        // bar
+        // foo
+        // bar
+        // foo
+        // EOF
+        let hunk = owned::Hunk {
+            added: owned::Block {
+                start: 5,
+                lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 4,
+                lines: Arc::new(vec![]),
+                trailing_newline: true,
+            },
+        };
+        // after hunk: <<EOF
+        // bar
+        // foo
+        // bar
+        // foo
+        // bar
+        // EOF
+
+        let commuted = commute_diff_before(&hunk, &patch, false).unwrap();
+        assert_eq!(commuted.added.start, 3);
+    }
+
+    #[test]
+    fn test_commute_patch_reports_conflict() {
+        let patch = vec![
+            owned::Hunk {
+                added: owned::Block {
+                    start: 1,
+                    lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                    trailing_newline: true,
+                },
+                removed: owned::Block {
+                    start: 1,
+                    lines: Arc::new(vec![owned::Line::from(&b"foo\n"[..])]),
+                    trailing_newline: true,
+                },
+            },
+            owned::Hunk {
+                added: owned::Block {
+                    start: 5,
+                    lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                    trailing_newline: true,
+                },
+                removed: owned::Block {
+                    start: 5,
+                    lines: Arc::new(vec![owned::Line::from(&b"foo\n"[..])]),
+                    trailing_newline: true,
+                },
+            },
+        ];
+        // modifies the line that the first hunk of the patch modified
+        let hunk = owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![owned::Line::from(&b"baz\n"[..])]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                trailing_newline: true,
+            },
+        };
+
+        let conflict = commute_diff_before(&hunk, &patch, false).unwrap_err();
+        assert_eq!(conflict.header(), patch[0].header());
+    }
+
+    fn one_line_hunk(start: usize, removed: &[u8], added: &[u8]) -> owned::Hunk {
+        owned::Hunk {
+            added: owned::Block {
+                start,
+                lines: Arc::new(vec![owned::Line::from(added)]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start,
+                lines: Arc::new(vec![owned::Line::from(removed)]),
+                trailing_newline: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_commute_intra_line() {
+        let first = one_line_hunk(2, b"call(a, b)\n", b"call(aaa, b)\n");
+        let second = one_line_hunk(2, b"call(aaa, b)\n", b"call(aaa, bb)\n");
+        assert!(commute(&first, &second).is_none());
+
+        let (new_second, new_first) = commute_intra_line(&first, &second).unwrap();
+        assert_eq!(
+            *new_second.removed.lines,
+            vec![owned::Line::from(&b"call(a, b)\n"[..])]
+        );
+        assert_eq!(
+            *new_second.added.lines,
+            vec![owned::Line::from(&b"call(a, bb)\n"[..])]
+        );
+        assert_eq!(
+            *new_first.removed.lines,
+            vec![owned::Line::from(&b"call(a, bb)\n"[..])]
+        );
+        assert_eq!(
+            *new_first.added.lines,
+            vec![owned::Line::from(&b"call(aaa, bb)\n"[..])]
+        );
+
+        // and the same with the second change left of the first
+        let first = one_line_hunk(2, b"call(a, b)\n", b"call(a, bbb)\n");
+        let second = one_line_hunk(2, b"call(a, bbb)\n", b"call(aa, bbb)\n");
+        let (new_first, new_second) = commute_intra_line(&first, &second).unwrap();
+        assert_eq!(
+            *new_first.added.lines,
+            vec![owned::Line::from(&b"call(aa, b)\n"[..])]
+        );
+        assert_eq!(
+            *new_second.added.lines,
+            vec![owned::Line::from(&b"call(aa, bbb)\n"[..])]
+        );
+    }
+
+    #[test]
+    fn test_moved_from() {
+        let block = |start, lines: &[&[u8]]| owned::Block {
+            start,
+            lines: Arc::new(lines.iter().map(|&line| owned::Line::from(line)).collect()),
+            trailing_newline: true,
+        };
+        let patch = vec![
+            owned::Hunk {
+                removed: block(2, &[b"fn f() {}\n", b"\n"]),
+                added: block(1, &[]),
+            },
+            owned::Hunk {
+                removed: block(5, &[b"\n"]),
+                added: block(4, &[]),
+            },
+            owned::Hunk {
+                removed: block(8, &[]),
+                added: block(7, &[b"fn f() {}\n", b"\n"]),
+            },
+            owned::Hunk {
+                removed: block(9, &[]),
+                added: block(9, &[b"\n"]),
+            },
+        ];
+        assert_eq!(moved_from(&patch[2], &patch), Some(0));
+        // the removal isn't a move of its own, and neither is a blank line
+        assert_eq!(moved_from(&patch[0], &patch), None);
+        assert_eq!(moved_from(&patch[3], &patch), None);
+    }
+
+    #[test]
+    fn test_commute_intra_line_overlap() {
+        let first = one_line_hunk(2, b"call(a, b)\n", b"call(aaa, b)\n");
+        let second = one_line_hunk(2, b"call(aaa, b)\n", b"call(ab, b)\n");
+        assert!(commute_intra_line(&first, &second).is_none());
+    }
+}
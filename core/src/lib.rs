@@ -0,0 +1,55 @@
+//! The core of [git-absorb](https://github.com/tummychow/git-absorb):
+//! finding the stack of commits that staged changes can be absorbed
+//! into, commuting each staged hunk past the commits of the stack to
+//! find the one it belongs to, and the plan that results, which it can
+//! also apply.
+//!
+//! The `git-absorb` command is one user of this crate. Other tools can
+//! absorb staged changes the same way:
+//!
+//! 1. `stack::working_stack()` returns the commits of the stack, newest
+//!    first, as bounded by the `StackOptions`.
+//! 2. The diff of each commit, and the staged diff, are parsed into an
+//!    `owned::Diff` (with the same `owned::Interner`, so that common
+//!    lines are only stored once).
+//! 3. `Plan::for_index()` finds the commit of the stack that each staged
+//!    hunk can't be commuted past, if any, which the hunk goes into as a
+//!    `HunkAssignment` of the `Plan`. `target::commute_patch()` does
+//!    this for the hunks of one file, and `target::find_target()` for a
+//!    single hunk, for tools that plan some files differently, which
+//!    `Plan::push_commuted()` adds to a plan.
+//! 4. `apply::commit_fixups()` commits each hunk as a fixup of its
+//!    target, or `apply::commit_fixups_with()` with the commits that a
+//!    tool makes itself, or `apply::apply_assignment()` applies a hunk
+//!    to a tree.
+//!
+//! The diffs should be made without context lines, since commutation
+//! works on the changed lines only.
+//!
+//! The functions that log take an `Option<&slog::Logger>`, which can
+//! be `None` to log nothing (so that tools don't need slog unless they
+//! want the logs). Messages are logged in English, and those about
+//! commuting a file or a hunk carry its `path` and `header`.
+
+#[macro_use]
+pub extern crate slog;
+
+pub mod apply;
+pub mod commute;
+pub mod owned;
+pub mod plan;
+pub mod stack;
+pub mod target;
+
+pub use plan::{FileChange, HunkAssignment, Placement, Plan, SkipReason, SkippedPath};
+pub use stack::{working_stack, BaseStrategy, Boundary, StackOptions};
+pub use target::{commute_patch, find_target, split_by_target, CommuteOptions, StackDiff};
+
+/// Returns the logger, or one that discards what is logged to it if
+/// there is none, which is how the functions of the crate take theirs.
+pub(crate) fn or_discard(logger: Option<&slog::Logger>) -> slog::Logger {
+    match logger {
+        Some(logger) => logger.clone(),
+        None => slog::Logger::root(slog::Discard, o!()),
+    }
+}
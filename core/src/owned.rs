@@ -0,0 +1,498 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::hash_map::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// A line of a hunk, including its newline. Lines are shared between
+/// all of the hunks that contain them, see `Interner`.
+pub type Line = Arc<[u8]>;
+
+/// Deduplicates the lines of the diffs parsed with it, so that a line
+/// that many hunks contain (eg: one that a commit adds and the next one
+/// removes, or that each commit of the stack touches) is only stored
+/// once, rather than once for each hunk of each commit.
+#[derive(Debug, Default)]
+pub struct Interner {
+    lines: HashSet<Line>,
+}
+impl Interner {
+    pub fn intern(&mut self, line: &[u8]) -> Line {
+        if let Some(interned) = self.lines.get(line) {
+            return interned.clone();
+        }
+        let interned = Line::from(line);
+        self.lines.insert(interned.clone());
+        interned
+    }
+}
+
+#[derive(Debug)]
+pub struct Diff {
+    patches: Vec<Patch>,
+    by_new: HashMap<Vec<u8>, usize>,
+    by_old: HashMap<Vec<u8>, usize>,
+}
+impl ::std::ops::Deref for Diff {
+    type Target = [Patch];
+    fn deref(&self) -> &[Patch] {
+        self.patches.as_slice()
+    }
+}
+impl Diff {
+    /// Parses the deltas of the diff whose new path `keep` returns
+    /// `true` for, and leaves the others out.
+    pub fn filtered<F>(diff: &git2::Diff, keep: F, interner: &mut Interner) -> Result<Self>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut patches = Vec::with_capacity(diff.deltas().len());
+        for (delta_idx, delta) in diff.deltas().enumerate() {
+            if !delta.new_file().path_bytes().is_some_and(&keep) {
+                continue;
+            }
+            patches.push(Patch::new(
+                &mut git2::Patch::from_diff(diff, delta_idx)?
+                    .ok_or_else(|| anyhow!("got empty delta"))?,
+                interner,
+            )?);
+        }
+        Self::from_patches(patches)
+    }
+
+    /// Builds a diff out of patches that were already parsed (eg: ones
+    /// that were cached).
+    pub fn from_patches(patches: Vec<Patch>) -> Result<Self> {
+        let mut ret = Diff {
+            patches: Vec::with_capacity(patches.len()),
+            by_old: HashMap::new(),
+            by_new: HashMap::new(),
+        };
+
+        for patch in patches {
+            // an added file has no old path, even though git reports
+            // the new path there, and that path may also be the old
+            // path of a file that was renamed away
+            if patch.status != git2::Delta::Added {
+                if ret.by_old.contains_key(&patch.old_path) {
+                    // TODO: would this case be hit if the diff was put through copy detection?
+                    return Err(anyhow!("old path already occupied"));
+                }
+                ret.by_old.insert(patch.old_path.clone(), ret.patches.len());
+            }
+            if ret.by_new.contains_key(&patch.new_path) {
+                return Err(anyhow!("new path already occupied"));
+            }
+            ret.by_new.insert(patch.new_path.clone(), ret.patches.len());
+            ret.patches.push(patch);
+        }
+
+        Ok(ret)
+    }
+    pub fn into_patches(self) -> Vec<Patch> {
+        self.patches
+    }
+
+    pub fn by_new(&self, path: &[u8]) -> Option<&Patch> {
+        self.by_new.get(path).map(|&idx| &self.patches[idx])
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: usize,
+    pub lines: Arc<Vec<Line>>,
+    pub trailing_newline: bool,
+}
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    pub added: Block,
+    pub removed: Block,
+}
+impl Hunk {
+    /// Returns a hunk that neither adds nor removes any lines, standing
+    /// in for changes to a file that aren't changes to its lines.
+    pub fn empty() -> Self {
+        let empty_block = || Block {
+            start: 0,
+            lines: Arc::new(Vec::new()),
+            trailing_newline: true,
+        };
+        Hunk {
+            added: empty_block(),
+            removed: empty_block(),
+        }
+    }
+
+    pub fn new(patch: &mut git2::Patch, idx: usize, interner: &mut Interner) -> Result<Self> {
+        let (added_start, removed_start, mut added_lines, mut removed_lines) = {
+            let (hunk, _size) = patch.hunk(idx)?;
+            (
+                hunk.new_start() as usize,
+                hunk.old_start() as usize,
+                Vec::with_capacity(hunk.new_lines() as usize),
+                Vec::with_capacity(hunk.old_lines() as usize),
+            )
+        };
+        let mut added_trailing_newline = true;
+        let mut removed_trailing_newline = true;
+
+        for line_idx in 0..patch.num_lines_in_hunk(idx)? {
+            let line = patch.line_in_hunk(idx, line_idx)?;
+            match line.origin() {
+                '+' => {
+                    if line.num_lines() > 1 {
+                        return Err(anyhow!("wrong number of lines in hunk"));
+                    }
+                    if line
+                        .new_lineno()
+                        .ok_or_else(|| anyhow!("added line did not have lineno"))?
+                        as usize
+                        != added_start + added_lines.len()
+                    {
+                        return Err(anyhow!("added line did not reach expected lineno"));
+                    }
+                    added_lines.push(interner.intern(line.content()))
+                }
+                '-' => {
+                    if line.num_lines() > 1 {
+                        return Err(anyhow!("wrong number of lines in hunk"));
+                    }
+                    if line
+                        .old_lineno()
+                        .ok_or_else(|| anyhow!("removed line did not have lineno"))?
+                        as usize
+                        != removed_start + removed_lines.len()
+                    {
+                        return Err(anyhow!("removed line did not reach expected lineno",));
+                    }
+                    removed_lines.push(interner.intern(line.content()))
+                }
+                // unchanged lines between two changes that are grouped
+                // into one hunk, which it removes and adds back
+                ' ' => {
+                    if line.old_lineno() != Some((removed_start + removed_lines.len()) as u32)
+                        || line.new_lineno() != Some((added_start + added_lines.len()) as u32)
+                    {
+                        return Err(anyhow!("context line did not reach expected lineno"));
+                    }
+                    let content = interner.intern(line.content());
+                    removed_lines.push(content.clone());
+                    added_lines.push(content);
+                }
+                // neither side of the last unchanged line has a newline
+                '=' => {
+                    if !removed_trailing_newline || !added_trailing_newline {
+                        return Err(anyhow!("nneof was already detected"));
+                    };
+                    removed_trailing_newline = false;
+                    added_trailing_newline = false
+                }
+                '>' => {
+                    if !removed_trailing_newline {
+                        return Err(anyhow!("removed nneof was already detected"));
+                    };
+                    removed_trailing_newline = false
+                }
+                '<' => {
+                    if !added_trailing_newline {
+                        return Err(anyhow!("added nneof was already detected"));
+                    };
+                    added_trailing_newline = false
+                }
+                _ => return Err(anyhow!("unknown line type {:?}", line.origin())),
+            };
+        }
+
+        {
+            let (hunk, _size) = patch.hunk(idx)?;
+            if added_lines.len() != hunk.new_lines() as usize {
+                return Err(anyhow!("hunk added block size mismatch"));
+            }
+            if removed_lines.len() != hunk.old_lines() as usize {
+                return Err(anyhow!("hunk removed block size mismatch"));
+            }
+        }
+
+        Ok(Hunk {
+            added: Block {
+                start: added_start,
+                lines: Arc::new(added_lines),
+                trailing_newline: added_trailing_newline,
+            },
+            removed: Block {
+                start: removed_start,
+                lines: Arc::new(removed_lines),
+                trailing_newline: removed_trailing_newline,
+            },
+        })
+    }
+
+    /// Returns the unchanged lines around this hunk.
+    ///
+    /// Any given hunk has four anchor points:
+    ///
+    /// - the last unchanged line before it, on the removed side
+    /// - the first unchanged line after it, on the removed side
+    /// - the last unchanged line before it, on the added side
+    /// - the first unchanged line after it, on the added side
+    ///
+    /// This function returns those four line numbers, in that order.
+    pub fn anchors(&self) -> (usize, usize, usize, usize) {
+        match (self.removed.lines.len(), self.added.lines.len()) {
+            (0, 0) => (0, 1, 0, 1),
+            // the start of an empty block is the line before it, which
+            // is only the same line on both sides if no hunk before this
+            // one changed the number of lines
+            (removed_len, 0) => (
+                self.removed.start - 1,
+                self.removed.start + removed_len,
+                self.added.start,
+                self.added.start + 1,
+            ),
+            (0, added_len) => (
+                self.removed.start,
+                self.removed.start + 1,
+                self.added.start - 1,
+                self.added.start + added_len,
+            ),
+            (removed_len, added_len) => (
+                self.removed.start - 1,
+                self.removed.start + removed_len,
+                self.added.start - 1,
+                self.added.start + added_len,
+            ),
+        }
+    }
+
+    pub fn changed_offset(&self) -> isize {
+        self.added.lines.len() as isize - self.removed.lines.len() as isize
+    }
+
+    pub fn header(&self) -> String {
+        format!(
+            "-{},{} +{},{}",
+            self.removed.start,
+            self.removed.lines.len(),
+            self.added.start,
+            self.added.lines.len()
+        )
+    }
+
+    /// Returns whether this hunk replaces lines one-for-one, so that it
+    /// can be cut into smaller hunks with `slice()`.
+    pub fn is_replacement(&self) -> bool {
+        self.added.lines.len() == self.removed.lines.len()
+    }
+
+    /// Returns whether this hunk only replaces lines with the same lines
+    /// with different line endings (ie: CRLF with LF, or vice versa).
+    pub fn only_changes_line_endings(&self) -> bool {
+        let strip_cr = |line: &[u8]| -> Vec<u8> {
+            match line {
+                [rest @ .., b'\r', b'\n'] => [rest, b"\n"].concat(),
+                [rest @ .., b'\r'] => rest.to_vec(),
+                _ => line.to_vec(),
+            }
+        };
+        self.is_replacement()
+            && !self.added.lines.is_empty()
+            && self.added.trailing_newline == self.removed.trailing_newline
+            && self
+                .removed
+                .lines
+                .iter()
+                .zip(self.added.lines.iter())
+                .all(|(removed, added)| strip_cr(removed) == strip_cr(added))
+    }
+
+    /// Returns the part of a one-for-one replacement hunk that replaces
+    /// the lines in `range`, counted from the start of the hunk.
+    pub fn slice(&self, range: std::ops::Range<usize>) -> Self {
+        debug_assert!(self.is_replacement());
+        let last = range.end == self.added.lines.len();
+        let slice_block = |block: &Block| Block {
+            start: block.start + range.start,
+            lines: Arc::new(block.lines[range.clone()].to_vec()),
+            // only the last line of a block can lack a newline
+            trailing_newline: !last || block.trailing_newline,
+        };
+        Hunk {
+            added: slice_block(&self.added),
+            removed: slice_block(&self.removed),
+        }
+    }
+
+    /// Cuts the hunk in two, so that the first part removes the first
+    /// `removed` lines and adds the first `added` lines of it, and the
+    /// second part changes the rest.
+    ///
+    /// Both parts must remove and add some lines, so that their starts
+    /// keep meaning the same thing.
+    pub fn split_at(&self, removed: usize, added: usize) -> (Self, Self) {
+        debug_assert!(removed > 0 && removed < self.removed.lines.len());
+        debug_assert!(added > 0 && added < self.added.lines.len());
+        let split_block = |block: &Block, at: usize| {
+            (
+                Block {
+                    start: block.start,
+                    lines: Arc::new(block.lines[..at].to_vec()),
+                    trailing_newline: true,
+                },
+                Block {
+                    start: block.start + at,
+                    lines: Arc::new(block.lines[at..].to_vec()),
+                    trailing_newline: block.trailing_newline,
+                },
+            )
+        };
+        let (first_removed, second_removed) = split_block(&self.removed, removed);
+        let (first_added, second_added) = split_block(&self.added, added);
+        (
+            Hunk {
+                removed: first_removed,
+                added: first_added,
+            },
+            Hunk {
+                removed: second_removed,
+                added: second_added,
+            },
+        )
+    }
+
+    pub fn shift_added_block(mut self, by: isize) -> Self {
+        self.added.start = (self.added.start as isize + by) as usize;
+        self
+    }
+
+    pub fn shift_both_blocks(mut self, by: isize) -> Self {
+        self.removed.start = (self.removed.start as isize + by) as usize;
+        self.added.start = (self.added.start as isize + by) as usize;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct Patch {
+    pub old_path: Vec<u8>,
+    #[allow(dead_code)]
+    pub old_id: git2::Oid,
+    pub old_mode: git2::FileMode,
+    pub new_path: Vec<u8>,
+    #[allow(dead_code)]
+    pub new_id: git2::Oid,
+    pub new_mode: git2::FileMode,
+    pub status: git2::Delta,
+    pub is_binary: bool,
+    pub hunks: Vec<Hunk>,
+}
+impl Patch {
+    pub fn new(patch: &mut git2::Patch, interner: &mut Interner) -> Result<Self> {
+        let mut ret = Patch {
+            old_path: patch
+                .delta()
+                .old_file()
+                .path_bytes()
+                .map(Vec::from)
+                .ok_or_else(|| anyhow!("delta with empty old path"))?,
+            old_id: patch.delta().old_file().id(),
+            old_mode: patch.delta().old_file().mode(),
+            new_path: patch
+                .delta()
+                .new_file()
+                .path_bytes()
+                .map(Vec::from)
+                .ok_or_else(|| anyhow!("delta with empty new path"))?,
+            new_id: patch.delta().new_file().id(),
+            new_mode: patch.delta().new_file().mode(),
+            status: patch.delta().status(),
+            is_binary: patch.delta().flags().is_binary(),
+            hunks: Vec::with_capacity(patch.num_hunks()),
+        };
+        if patch.delta().nfiles() < 1 || patch.delta().nfiles() > 2 {
+            return Err(anyhow!("delta with multiple files"));
+        }
+
+        for idx in 0..patch.num_hunks() {
+            ret.hunks.push(Hunk::new(patch, idx, interner)?);
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_changes_line_endings() {
+        let mut interner = Interner::default();
+        let mut hunk = |removed: &[&[u8]], added: &[&[u8]]| {
+            let mut block = |lines: &[&[u8]]| Block {
+                start: 1,
+                lines: Arc::new(lines.iter().map(|line| interner.intern(line)).collect()),
+                trailing_newline: true,
+            };
+            Hunk {
+                removed: block(removed),
+                added: block(added),
+            }
+        };
+        assert!(hunk(&[b"foo\r\n", b"bar\n"], &[b"foo\n", b"bar\r\n"]).only_changes_line_endings());
+        assert!(!hunk(&[b"foo\r\n"], &[b"fo\n"]).only_changes_line_endings());
+        assert!(!hunk(&[b"foo\r\n"], &[b"foo\n", b"bar\n"]).only_changes_line_endings());
+    }
+
+    #[test]
+    fn test_split_at() {
+        let block = |start, lines: &[&[u8]]| Block {
+            start,
+            lines: Arc::new(lines.iter().map(|&line| Line::from(line)).collect()),
+            trailing_newline: false,
+        };
+        let hunk = Hunk {
+            removed: block(3, &[b"a\n", b"fn g() {\n", b"b"]),
+            added: block(3, &[b"a2\n", b"a3\n", b"fn g() {\n", b"b2"]),
+        };
+        let (first, second) = hunk.split_at(1, 2);
+        assert_eq!(first.header(), "-3,1 +3,2");
+        assert_eq!(second.header(), "-4,2 +5,2");
+        assert!(first.removed.trailing_newline && first.added.trailing_newline);
+        assert!(!second.removed.trailing_newline && !second.added.trailing_newline);
+    }
+
+    #[test]
+    fn test_anchors_of_empty_blocks_are_on_their_own_side() {
+        let block = |start, lines: &[&[u8]]| Block {
+            start,
+            lines: Arc::new(lines.iter().map(|&line| Line::from(line)).collect()),
+            trailing_newline: true,
+        };
+        // the hunks before these ones added two lines, so the empty side
+        // is not at the same line as the other one
+        let removal = Hunk {
+            removed: block(10, &[b"a\n", b"b\n"]),
+            added: block(7, &[]),
+        };
+        assert_eq!(removal.header(), "-10,2 +7,0");
+        assert_eq!(removal.anchors(), (9, 12, 7, 8));
+        let addition = Hunk {
+            removed: block(5, &[]),
+            added: block(8, &[b"a\n"]),
+        };
+        assert_eq!(addition.header(), "-5,0 +8,1");
+        assert_eq!(addition.anchors(), (5, 6, 7, 9));
+    }
+
+    #[test]
+    fn test_interner_shares_lines() {
+        let mut interner = Interner::default();
+        let first = interner.intern(b"foo\n");
+        let second = interner.intern(b"foo\n");
+        let other = interner.intern(b"bar\n");
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &other));
+        assert_eq!(&*second, b"foo\n");
+    }
+}
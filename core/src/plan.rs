@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+
+use crate::owned;
+use crate::stack;
+use crate::target::{self, CommuteOptions, StackDiff};
+
+/// A single staged hunk, and the stack commit it will be absorbed
+/// into.
+#[derive(Clone)]
+pub struct HunkAssignment<'repo> {
+    /// The path of the file in HEAD that this hunk applies to.
+    pub path: Vec<u8>,
+    /// The hunk, with its coordinates adjusted as if it were the only
+    /// hunk staged for this path.
+    pub hunk: owned::Hunk,
+    /// The commit that this hunk will be committed as a fixup of, or
+    /// `None` if the hunk will be left in the index.
+    pub target: Option<git2::Commit<'repo>>,
+    /// Why the hunk will be left in the index, if it has no target.
+    pub skip_reason: Option<SkipReason>,
+    /// Set if the hunk changes its whole file, rather than modifying
+    /// some of its lines.
+    pub file_change: Option<FileChange>,
+}
+impl<'repo> HunkAssignment<'repo> {
+    /// Changes the target of this hunk. If the hunk is left without a
+    /// target, `reason` is recorded, unless the hunk already had no
+    /// target for some other reason.
+    pub fn retarget(&mut self, target: Option<git2::Commit<'repo>>, reason: SkipReason) {
+        self.skip_reason = match target {
+            Some(_) => None,
+            None => Some(self.skip_reason.unwrap_or(reason)),
+        };
+        self.target = target;
+    }
+}
+
+/// A staged change to a whole file, rather than to some of its lines.
+/// Creations and deletions are planned as a single hunk that adds or
+/// removes every line, other changes as an empty hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    /// The file was created with the given mode.
+    Added(git2::FileMode),
+    Deleted,
+    /// The mode of the file was changed to the given mode.
+    Mode(git2::FileMode),
+    /// The file was replaced as a whole by the given object (eg: the
+    /// commit that a submodule points to), with the given mode.
+    Replaced(git2::Oid, git2::FileMode),
+}
+
+/// The reason that a staged change was left in the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The hunk commutes with every commit in the stack.
+    NoTarget,
+    /// The hunk was dropped from the plan by the user, or by
+    /// `absorb.targetCommand`.
+    Dropped,
+    /// The file was not modified in place, created or deleted (eg: it
+    /// was renamed, or it is empty), so none of its hunks were
+    /// considered. `Plan::for_index()` skips created and deleted files
+    /// this way too.
+    NotModified(git2::Delta),
+    /// The file is binary, and absorbing binary files is not enabled.
+    Binary,
+    /// The hunk belongs to a merge commit, which cannot be fixed up
+    /// without flattening it.
+    Merge,
+    /// The hunk belongs to a commit whose message excludes it from
+    /// being fixed up, per `absorb.skipMessagePattern` or
+    /// `absorb.onlyMessagePattern`.
+    Excluded,
+    /// The file is bigger than `absorb.maxFileSize`, or has a line
+    /// longer than `absorb.maxLineLength`, and `absorb.largeFiles` is
+    /// set to skip such files.
+    TooLarge,
+    /// The hunk only changes the line endings of lines, in a file whose
+    /// line endings git normalizes.
+    LineEndings,
+    /// The repository is a partial clone, and some versions of the file
+    /// couldn't be fetched from its promisor remote.
+    Missing,
+    /// The file matches one of the patterns of `absorb.excludePaths`.
+    ExcludedPath,
+    /// The attributes of the file mark it as generated, with
+    /// `linguist-generated` or `-diff`.
+    Generated,
+    /// The hunk belongs to a commit that touched more files than
+    /// `absorb.skipBulkCommits` (or `--skip-bulk`) allows.
+    Bulk,
+}
+impl SkipReason {
+    /// Returns the name of the reason, as printed by `--porcelain` and
+    /// `--output-format json`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SkipReason::NoTarget => "no-target",
+            SkipReason::Dropped => "dropped",
+            SkipReason::NotModified(_) => "not-modified",
+            SkipReason::Binary => "binary",
+            SkipReason::Merge => "merge",
+            SkipReason::Excluded => "excluded",
+            SkipReason::TooLarge => "too-large",
+            SkipReason::LineEndings => "line-endings",
+            SkipReason::Missing => "missing",
+            SkipReason::ExcludedPath => "excluded-path",
+            SkipReason::Generated => "generated",
+            SkipReason::Bulk => "bulk",
+        }
+    }
+}
+
+/// Where `Plan::push_commuted()` puts a hunk.
+pub enum Placement<'repo> {
+    /// Into the commit that the hunk can't be commuted past, or into
+    /// those of its pieces if it was split.
+    Commuted,
+    /// Into this commit.
+    Target(git2::Commit<'repo>),
+    /// Left in the index, for this reason.
+    Skip(SkipReason),
+}
+
+/// A staged file that was not considered for absorption at all.
+#[derive(Debug, Clone)]
+pub struct SkippedPath {
+    /// The path of the file in the index.
+    pub path: Vec<u8>,
+    pub reason: SkipReason,
+}
+
+/// The full set of hunk assignments computed for the index, in the
+/// order that the hunks appear in the index.
+#[derive(Default)]
+pub struct Plan<'repo> {
+    /// Every staged hunk of the files that were considered, whether it
+    /// has a target or not.
+    pub assignments: Vec<HunkAssignment<'repo>>,
+    /// The staged files whose hunks were not considered.
+    pub skipped_paths: Vec<SkippedPath>,
+    /// The commits of the stack, newest first, which tells in which
+    /// order the fixups are committed.
+    pub stack: Vec<git2::Oid>,
+    /// How many commits of the stack have each summary (see
+    /// `target_locator()`).
+    pub summary_counts: HashMap<String, u64>,
+}
+impl<'repo> Plan<'repo> {
+    /// Starts a plan with no assignments, for the commits of the stack
+    /// (newest first).
+    pub fn new(stack: &[(git2::Commit<'repo>, owned::Diff)]) -> Self {
+        Plan {
+            stack: stack.iter().map(|(commit, _)| commit.id()).collect(),
+            summary_counts: stack::summary_counts(stack.iter().map(|(commit, _)| commit)),
+            ..Plan::default()
+        }
+    }
+
+    /// Plans where each hunk of the staged diff goes, among the commits
+    /// of the stack (newest first, each with its diff), with the same
+    /// commutation as `git absorb`: each hunk of a file that was
+    /// modified in place goes into the newest commit that it can't be
+    /// commuted past, and binary files are skipped.
+    ///
+    /// `git absorb` also absorbs created, deleted and binary files as a
+    /// whole, and checks whether the targets may be fixed up (eg: that
+    /// they aren't merge commits), which is left to the caller here.
+    pub fn for_index(
+        stack: &[(git2::Commit<'repo>, owned::Diff)],
+        index: &owned::Diff,
+        options: &CommuteOptions,
+        logger: Option<&slog::Logger>,
+    ) -> Self {
+        let stack_diffs: Vec<_> = stack
+            .iter()
+            .map(|(commit, diff)| StackDiff {
+                id: commit.id(),
+                summary: commit.summary().unwrap_or_default().to_owned(),
+                diff,
+            })
+            .collect();
+        let mut plan = Plan::new(stack);
+        for patch in index.iter() {
+            let reason = match patch.status {
+                git2::Delta::Modified if patch.is_binary => Some(SkipReason::Binary),
+                git2::Delta::Modified => None,
+                status => Some(SkipReason::NotModified(status)),
+            };
+            if let Some(reason) = reason {
+                plan.skipped_paths.push(SkippedPath {
+                    path: patch.new_path.clone(),
+                    reason,
+                });
+                continue;
+            }
+            let commuted = target::commute_patch(&stack_diffs, patch, options, logger);
+            let placed: Result<(), std::convert::Infallible> =
+                plan.push_commuted(stack, patch, commuted, |_, _, _| Ok(Placement::Commuted));
+            if let Err(never) = placed {
+                match never {}
+            }
+        }
+        plan
+    }
+
+    /// Adds the hunks of a file that was modified in place, from where
+    /// each of them was commuted to (see `target::commute_patch()`), to
+    /// the plan. `place` is given each hunk as it is in the patch, the
+    /// same hunk as if it were the only one of the patch, and where it
+    /// was commuted to, and tells where it goes.
+    pub fn push_commuted<E>(
+        &mut self,
+        stack: &[(git2::Commit<'repo>, owned::Diff)],
+        patch: &owned::Patch,
+        commuted: Vec<target::Commuted>,
+        mut place: impl FnMut(
+            &owned::Hunk,
+            &owned::Hunk,
+            &mut target::Commuted,
+        ) -> Result<Placement<'repo>, E>,
+    ) -> Result<(), E> {
+        let mut preceding_hunks_offset = 0isize;
+        for (hunk, mut commuted) in patch.hunks.iter().zip(commuted) {
+            // To properly handle files ("patches" in libgit2 lingo) with multiple hunks, we
+            // need to find the updated line coordinates (`header`) of the current hunk in
+            // two cases:
+            // 1) As if it were the only hunk in the index. This only involves shifting the
+            // "added" side *up* by the offset introduced by the preceding hunks:
+            let isolated_hunk = hunk.clone().shift_added_block(-preceding_hunks_offset);
+
+            // 2) When applied on top of the previously committed hunks. This requires shifting
+            // both the "added" and the "removed" sides of the previously isolated hunk *down*
+            // by the offset of the committed hunks. Which hunks end up committed isn't known
+            // until the plan is final, so this happens in `hunks_to_apply()`.
+
+            // The offset is the number of lines added minus the number of lines removed by a hunk.
+            // To aid in understanding these arithmetic, here's an illustration.
+            // There are two hunks in the original patch, each adding one line ("line2" and
+            // "line5"). Assuming the first hunk (with offset = -1) was already processed
+            // and applied, the table shows the three versions of the patch, with line numbers
+            // on the <A>dded and <R>emoved sides for each:
+            // |----------------|-----------|------------------|
+            // |                |           | applied on top   |
+            // | original patch | isolated  | of the preceding |
+            // |----------------|-----------|------------------|
+            // | <R> <A>        | <R> <A>   | <R> <A>          |
+            // |----------------|-----------|------------------|
+            // |  1   1  line1  |  1   1    |  1   1   line1   |
+            // |  2      line2  |  2   2    |  2   2   line3   |
+            // |  3   2  line3  |  3   3    |  3   3   line4   |
+            // |  4   3  line4  |  4   4    |  4       line5   |
+            // |  5      line5  |  5        |                  |
+            // |----------------|-----------|------------------|
+            // |       So the second hunk's `header` is:       |
+            // |   -5,1 +3,0    | -5,1 +4,0 |    -4,1 +3,0     |
+            // |----------------|-----------|------------------|
+            preceding_hunks_offset += hunk.changed_offset();
+
+            let (pieces, reason) = match place(hunk, &isolated_hunk, &mut commuted)? {
+                Placement::Commuted => {
+                    let target = commuted.target;
+                    let pieces = commuted
+                        .pieces
+                        .unwrap_or_else(|| vec![(isolated_hunk, target)])
+                        .into_iter()
+                        .map(|(hunk, target)| (hunk, target.map(|idx| stack[idx].0.clone())))
+                        .collect();
+                    (pieces, SkipReason::NoTarget)
+                }
+                Placement::Target(target) => {
+                    (vec![(isolated_hunk, Some(target))], SkipReason::NoTarget)
+                }
+                Placement::Skip(reason) => (vec![(isolated_hunk, None)], reason),
+            };
+            for (hunk, target) in pieces {
+                self.assignments.push(HunkAssignment {
+                    path: patch.old_path.clone(),
+                    hunk,
+                    skip_reason: match target {
+                        Some(_) => None,
+                        None => Some(reason),
+                    },
+                    target,
+                    file_change: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how the message of a fixup refers to its target: by its
+    /// summary, unless it has none or another commit of the stack has
+    /// the same one (which autosquash would confuse it with), in which
+    /// case by its id.
+    pub fn target_locator(&self, target: &git2::Commit) -> String {
+        match target.summary() {
+            Some(summary) if self.summary_counts.get(summary) == Some(&1) => summary.to_owned(),
+            _ => target.id().to_string(),
+        }
+    }
+
+    /// Returns the hunks that will actually be committed, with their
+    /// coordinates adjusted so that each hunk can be applied on top of
+    /// the ones that precede it.
+    ///
+    /// The hunks of older targets come first: squashing a fixup moves it
+    /// before the fixups of newer commits, which it would conflict with
+    /// if it was committed on top of one that changes the lines next to
+    /// it. Otherwise the hunks stay in the order of the index.
+    pub fn hunks_to_apply(&self) -> Vec<(&HunkAssignment<'repo>, owned::Hunk)> {
+        let depth = |assignment: &HunkAssignment| {
+            assignment
+                .target
+                .as_ref()
+                .and_then(|target| self.stack.iter().position(|&id| id == target.id()))
+                .unwrap_or(0)
+        };
+        let mut order: Vec<_> = self
+            .assignments
+            .iter()
+            .enumerate()
+            .filter(|(_, assignment)| assignment.target.is_some())
+            .collect();
+        order.sort_by_key(|&(_, assignment)| std::cmp::Reverse(depth(assignment)));
+
+        let mut ret = Vec::with_capacity(order.len());
+        let mut applied: HashMap<&[u8], Vec<(usize, isize)>> = HashMap::new();
+        for (idx, assignment) in order {
+            let applied = applied.entry(assignment.path.as_slice()).or_default();
+            // The hunk is stored as if it were the only one in the
+            // file, so it only has to be shifted down by the offset of
+            // the hunks above it that were committed before it. Hunks
+            // that stay in the index don't contribute to that offset.
+            let applied_hunks_offset: isize = applied
+                .iter()
+                .filter(|&&(applied_idx, _)| applied_idx < idx)
+                .map(|&(_, offset)| offset)
+                .sum();
+            ret.push((
+                assignment,
+                assignment
+                    .hunk
+                    .clone()
+                    .shift_both_blocks(applied_hunks_offset),
+            ));
+            applied.push((idx, assignment.hunk.changed_offset()));
+        }
+        ret
+    }
+}
@@ -0,0 +1,1074 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::{HashMap, HashSet};
+
+/// The number of commits that the stack stops at by default, when no
+/// base is given.
+pub const MAX_STACK: usize = 10;
+
+/// What bounds the stack found by `working_stack()`. `git-absorb` fills
+/// these in from its arguments and the `absorb.*` configuration.
+#[derive(Debug, Clone)]
+pub struct StackOptions<'a> {
+    /// The commits (or ranges of commits, like `base..HEAD`) that the
+    /// stack stops at. If empty, the stack stops at the default base and
+    /// at the other local branches, and at `max_stack` commits.
+    pub base: &'a [&'a str],
+    /// Continue past commits by other authors, and on a detached HEAD.
+    pub force: bool,
+    /// Continue past commits that were pushed to a remote.
+    pub force_pushed: bool,
+    /// The emails of other authors whose commits are treated as the
+    /// user's own, on top of `author_allowlist`.
+    pub include_authors: &'a [&'a str],
+    /// The most commits in the stack when no base is given.
+    pub max_stack: usize,
+    /// The commit that the stack stops at when no base is given, instead
+    /// of the upstream of the branch.
+    pub default_base: Option<String>,
+    /// How the stack is bounded by the default base.
+    pub base_strategy: BaseStrategy,
+    /// The remotes whose remote-tracking branches mark commits as pushed,
+    /// or `None` for all remotes.
+    pub pushed_remotes: Option<Vec<String>>,
+    /// The emails of other authors whose commits are treated as the
+    /// user's own.
+    pub author_allowlist: Vec<String>,
+    /// Continue past merge commits, along their first parent.
+    pub traverse_merges: bool,
+    /// How the first parents of HEAD are walked, or `None` to walk them
+    /// with libgit2 (see `first_parents()`).
+    pub revwalk: Option<&'a dyn Revwalk>,
+}
+impl Default for StackOptions<'_> {
+    fn default() -> Self {
+        StackOptions {
+            base: &[],
+            force: false,
+            force_pushed: false,
+            include_authors: &[],
+            max_stack: MAX_STACK,
+            default_base: None,
+            base_strategy: BaseStrategy::MergeBase,
+            pushed_remotes: None,
+            author_allowlist: Vec::new(),
+            traverse_merges: false,
+            revwalk: None,
+        }
+    }
+}
+
+/// Why the stack ends where it does. Every variant but `End` holds the
+/// commit right after the stack, which was left out of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Boundary {
+    /// The stack reached the base, another branch or the root commit.
+    End,
+    /// The commit is a merge commit, and merges are not traversed.
+    Merge(git2::Oid),
+    /// The commit was pushed to a remote.
+    Pushed(git2::Oid),
+    /// The commit was authored by someone else.
+    Author(git2::Oid),
+    /// The stack has `max_stack` commits already.
+    Limit(git2::Oid),
+    /// The commit is at the shallow boundary of the repository.
+    Shallow(git2::Oid),
+}
+
+/// Returns the commits that staged changes can be absorbed into, newest
+/// first, starting at HEAD and following first parents.
+pub fn working_stack<'repo>(
+    repo: &'repo git2::Repository,
+    options: &StackOptions,
+    logger: Option<&slog::Logger>,
+) -> Result<Vec<git2::Commit<'repo>>> {
+    working_stack_with_boundary(repo, options, logger).map(|(stack, _)| stack)
+}
+
+/// Like `working_stack()`, but also returns why the stack ends there.
+pub fn working_stack_with_boundary<'repo>(
+    repo: &'repo git2::Repository,
+    options: &StackOptions,
+    logger: Option<&slog::Logger>,
+) -> Result<(Vec<git2::Commit<'repo>>, Boundary)> {
+    let logger = &crate::or_discard(logger);
+    let user_provided_base = options.base;
+    let force = options.force;
+    let head = repo.head()?;
+    debug!(logger, "head found"; "head" => head.name());
+
+    if !head.is_branch() {
+        if !force {
+            return Err(anyhow!("HEAD is not a branch, use --force to override"));
+        } else {
+            warn!(
+                logger,
+                "HEAD is not a branch, but --force used to continue."
+            );
+        }
+    }
+
+    // the commits whose ancestors (and themselves) are left out of the
+    // stack
+    let mut hidden = Vec::new();
+    if !user_provided_base.is_empty() {
+        for base_commit in base_commits(repo, user_provided_base)? {
+            hidden.push(base_commit);
+            debug!(logger, "commit hidden"; "commit" => base_commit.to_string());
+        }
+    } else {
+        if let Some((default_base, name)) = default_base(repo, &head, options, Some(logger))? {
+            hidden.push(default_base);
+            debug!(logger, "default base hidden"; "commit" => default_base.to_string());
+            if let (BaseStrategy::ForkPoint, Some(name)) = (options.base_strategy, name) {
+                // unwrap() is safe here, as repo.head() resolves HEAD
+                let head_id = head.target().unwrap();
+                if let Some(fork_point) = fork_point(repo, &name, head_id)? {
+                    hidden.push(fork_point);
+                    debug!(logger, "fork point hidden"; "commit" => fork_point.to_string());
+                }
+            }
+        }
+        for branch in repo.branches(Some(git2::BranchType::Local))? {
+            let (branch, _) = branch?;
+            let branch = branch.get();
+
+            match branch.name() {
+                Some(name) if Some(name) != head.name() => {
+                    hidden.push(branch.peel_to_commit()?.id());
+                    debug!(logger, "branch hidden"; "branch" => name);
+                }
+                name => {
+                    debug!(logger, "branch not hidden"; "branch" => name);
+                }
+            };
+        }
+    }
+
+    let shallow = shallow_commits(repo);
+    let revwalk = match options.revwalk {
+        Some(revwalk) => revwalk.first_parents(repo, &hidden)?,
+        None => first_parents(repo, &hidden)?,
+    };
+    debug!(logger, "head pushed"; "head" => head.name());
+
+    let pushed_tips = if options.force_pushed {
+        Vec::new()
+    } else {
+        remote_tips(repo, options.pushed_remotes.as_deref())?
+    };
+
+    // libgit2 can't tell which commits are ancestors of the pushed ones
+    // past the shallow boundary either
+    let pushed = if shallow.is_empty() {
+        None
+    } else {
+        Some(shallow_ancestors(repo, &pushed_tips, &shallow)?)
+    };
+
+    let traverse_merges = options.traverse_merges;
+    let mut ret = Vec::new();
+    let mut commits_considered = 0usize;
+    let sig = repo.signature();
+    let mailmap = repo.mailmap().ok();
+    let mut allowed_emails = options.author_allowlist.clone();
+    allowed_emails.extend(
+        options
+            .include_authors
+            .iter()
+            .map(|&email| email.to_owned()),
+    );
+    let mut boundary = Boundary::End;
+    for rev in revwalk {
+        commits_considered += 1;
+        let commit = repo.find_commit(rev?)?;
+        if commit.parents().len() > 1 {
+            if !traverse_merges {
+                warn!(logger, "Will not fix up past the merge commit"; "commit" => commit.id().to_string());
+                boundary = Boundary::Merge(commit.id());
+                break;
+            }
+            debug!(logger, "following the first parent of merge commit"; "commit" => commit.id().to_string());
+        }
+        let pushed = match pushed {
+            Some(ref pushed) => pushed.contains(&commit.id()),
+            None => pushed_tips.iter().any(|&tip| {
+                tip == commit.id() || repo.graph_descendant_of(tip, commit.id()).unwrap_or(false)
+            }),
+        };
+        if pushed {
+            warn!(logger, "Will not fix up past commits that were pushed to a remote, use --force-pushed to override";
+                  "commit" => commit.id().to_string());
+            boundary = Boundary::Pushed(commit.id());
+            break;
+        }
+        if let Ok(ref sig) = sig {
+            if !force && !is_mine(&commit.author(), sig, mailmap.as_ref(), &allowed_emails) {
+                warn!(logger, "Will not fix up past commits not authored by you, use --force or --include-author to override";
+                      "commit" => commit.id().to_string());
+                boundary = Boundary::Author(commit.id());
+                break;
+            }
+        }
+        if ret.len() == options.max_stack && user_provided_base.is_empty() {
+            warn!(logger, "stack limit reached, use --base or configure absorb.maxStack to override";
+                  "limit" => ret.len());
+            boundary = Boundary::Limit(commit.id());
+            break;
+        }
+        if shallow.contains(&commit.id()) {
+            warn!(logger, "Will not fix up past the shallow boundary of the repository, use git fetch --deepen or --unshallow to go further";
+                  "commit" => commit.id().to_string());
+            boundary = Boundary::Shallow(commit.id());
+            break;
+        }
+        debug!(logger, "commit pushed onto stack"; "commit" => commit.id().to_string());
+        ret.push(commit);
+    }
+    if commits_considered == 0 {
+        if user_provided_base.is_empty() {
+            warn!(logger, "Please use --base to specify a base commit.");
+        } else {
+            warn!(logger, "Please try a different --base");
+        }
+    }
+    Ok((ret, boundary))
+}
+
+/// Returns the commits that a shallow clone was cut at, whose parents
+/// it doesn't have, as listed in the `shallow` file of the repository.
+pub(crate) fn shallow_commits(repo: &git2::Repository) -> HashSet<git2::Oid> {
+    if !repo.is_shallow() {
+        return HashSet::new();
+    }
+    // a linked worktree shares the file of the main repository
+    let git_dir = match std::fs::read_to_string(repo.path().join("commondir")) {
+        Ok(common) => repo.path().join(common.trim_end()),
+        Err(_) => repo.path().to_path_buf(),
+    };
+    std::fs::read_to_string(git_dir.join("shallow"))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| git2::Oid::from_str(line.trim()).ok())
+        .collect()
+}
+
+/// How `working_stack()` walks the history, so that it can be read some
+/// other way than through libgit2.
+pub trait Revwalk: Sync {
+    /// Returns the first parents of HEAD, newest first, up to (and not
+    /// including) the first one that is one of the hidden commits or an
+    /// ancestor of them.
+    fn first_parents<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        hidden: &[git2::Oid],
+    ) -> Result<Box<dyn Iterator<Item = Result<git2::Oid>> + 'r>>;
+}
+
+impl std::fmt::Debug for dyn Revwalk + '_ {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Revwalk")
+    }
+}
+
+/// Walks the first parents of HEAD with libgit2, like `Revwalk`.
+pub fn first_parents<'r>(
+    repo: &'r git2::Repository,
+    hidden: &[git2::Oid],
+) -> Result<Box<dyn Iterator<Item = Result<git2::Oid>> + 'r>> {
+    // libgit2 can't walk past the commits that a shallow clone was cut
+    // at, whose parents are missing, so those are walked by hand
+    let shallow = shallow_commits(repo);
+    if !shallow.is_empty() {
+        return Ok(Box::new(
+            shallow_walk(repo, hidden, &shallow)?.into_iter().map(Ok),
+        ));
+    }
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+    revwalk.push_head()?;
+    revwalk.simplify_first_parent()?;
+    for &commit in hidden {
+        revwalk.hide(commit)?;
+    }
+    Ok(Box::new(revwalk.map(|rev| rev.map_err(Into::into))))
+}
+
+/// Walks the first parents of HEAD like `first_parents()`, up to and including the first commit that is either
+/// a shallow commit or an ancestor of (or one of) the hidden commits.
+fn shallow_walk(
+    repo: &git2::Repository,
+    hidden: &[git2::Oid],
+    shallow: &HashSet<git2::Oid>,
+) -> Result<Vec<git2::Oid>> {
+    let ancestors = shallow_ancestors(repo, hidden, shallow)?;
+    let mut ret = Vec::new();
+    let mut next = Some(repo.head()?.peel_to_commit()?.id());
+    while let Some(id) = next.filter(|id| !ancestors.contains(id)) {
+        ret.push(id);
+        if shallow.contains(&id) {
+            break;
+        }
+        next = repo.find_commit(id)?.parent_ids().next();
+    }
+    Ok(ret)
+}
+
+/// Returns the commits reachable from the tips (including themselves)
+/// in a shallow clone, which are few.
+fn shallow_ancestors(
+    repo: &git2::Repository,
+    tips: &[git2::Oid],
+    shallow: &HashSet<git2::Oid>,
+) -> Result<HashSet<git2::Oid>> {
+    let mut ret = HashSet::new();
+    let mut pending = tips.to_vec();
+    while let Some(id) = pending.pop() {
+        if !ret.insert(id) || shallow.contains(&id) {
+            continue;
+        }
+        pending.extend(repo.find_commit(id)?.parent_ids());
+    }
+    Ok(ret)
+}
+
+/// Returns the commits to hide from the stack for the given bases,
+/// each of which may hold several revisions separated by spaces. A
+/// revision is either a commit (eg: `main`, or `^main` as in `git
+/// rev-list`) or a range ending at HEAD (eg: `main..HEAD` or `main..`).
+fn base_commits(repo: &git2::Repository, bases: &[&str]) -> Result<Vec<git2::Oid>> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let mut ret = Vec::new();
+    for spec in bases.iter().flat_map(|base| base.split_whitespace()) {
+        if spec.contains("...") {
+            return Err(anyhow!(
+                "invalid base {:?}, symmetric differences are not supported",
+                spec
+            ));
+        }
+        if spec.contains("..") {
+            let range = repo.revparse(spec)?;
+            if let Some(to) = range.to() {
+                if to.peel_to_commit()?.id() != head {
+                    return Err(anyhow!(
+                        "invalid base {:?}, the range must end at HEAD",
+                        spec
+                    ));
+                }
+            }
+            // unwrap() is safe here, as a range always has a start
+            ret.push(range.from().unwrap().peel_to_commit()?.id());
+        } else {
+            let commitish = spec.strip_prefix('^').unwrap_or(spec);
+            ret.push(repo.revparse_single(commitish)?.peel_to_commit()?.id());
+        }
+    }
+    Ok(ret)
+}
+
+/// How the stack is bounded by the default base, when no base is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseStrategy {
+    /// Stop at the merge base with the default base.
+    MergeBase,
+    /// Also stop at the fork point from the default base, found with its
+    /// reflog like `git merge-base --fork-point`, so that commits that
+    /// were dropped from it by a rebase are not part of the stack.
+    ForkPoint,
+}
+
+/// Returns the commit that the stack should stop at when no base is
+/// given, along with the name of its reference if it has one: the
+/// configured default base, else the upstream of the branch, else the
+/// default branch of origin (as in `refs/remotes/origin/HEAD`). Hiding
+/// it from the revwalk leaves the commits since the merge base with it.
+pub fn default_base(
+    repo: &git2::Repository,
+    head: &git2::Reference,
+    options: &StackOptions,
+    logger: Option<&slog::Logger>,
+) -> Result<Option<(git2::Oid, Option<String>)>> {
+    let logger = &crate::or_discard(logger);
+    if let Some(default_base) = &options.default_base {
+        let commit = repo
+            .revparse_single(default_base)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|e| {
+                anyhow!(
+                    "invalid absorb.defaultBase {:?}: {}",
+                    default_base,
+                    e.message()
+                )
+            })?;
+        let name = repo
+            .resolve_reference_from_short_name(default_base)
+            .ok()
+            .and_then(|reference| reference.name().map(str::to_owned));
+        debug!(logger, "using configured default base"; "base" => default_base);
+        return Ok(Some((commit.id(), name)));
+    }
+    if head.is_branch() {
+        if let Some(name) = head.shorthand() {
+            if let Ok(upstream) = repo
+                .find_branch(name, git2::BranchType::Local)
+                .and_then(|branch| branch.upstream())
+            {
+                if let Some(id) = upstream.get().target() {
+                    let name = upstream.get().name().map(str::to_owned);
+                    debug!(logger, "using upstream as default base"; "upstream" => &name);
+                    return Ok(Some((id, name)));
+                }
+            }
+        }
+    }
+    if let Ok(reference) = repo
+        .find_reference("refs/remotes/origin/HEAD")
+        .and_then(|reference| reference.resolve())
+    {
+        if let Some(id) = reference.target() {
+            debug!(logger, "using default branch of origin as default base");
+            return Ok(Some((id, reference.name().map(str::to_owned))));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the newest commit that the reference `name` pointed to,
+/// according to its reflog, which `head` is based on.
+fn fork_point(repo: &git2::Repository, name: &str, head: git2::Oid) -> Result<Option<git2::Oid>> {
+    let reflog = match repo.reflog(name) {
+        Ok(reflog) => reflog,
+        Err(_) => return Ok(None),
+    };
+    let mut fork_point: Option<git2::Oid> = None;
+    for entry in reflog.iter() {
+        let candidate = entry.id_new();
+        if candidate.is_zero()
+            || !(candidate == head || repo.graph_descendant_of(head, candidate).unwrap_or(false))
+        {
+            continue;
+        }
+        fork_point = match fork_point {
+            Some(best) if best == candidate || repo.graph_descendant_of(best, candidate)? => {
+                Some(best)
+            }
+            _ => Some(candidate),
+        };
+    }
+    Ok(fork_point)
+}
+
+/// Returns whether a commit by `author` was made by the user, whose
+/// identity is `me`: if the two are the same once `.mailmap` is applied
+/// to both, or if the author's email is one of `allowed_emails`.
+fn is_mine(
+    author: &git2::Signature,
+    me: &git2::Signature,
+    mailmap: Option<&git2::Mailmap>,
+    allowed_emails: &[String],
+) -> bool {
+    let resolve = |sig: &git2::Signature| {
+        mailmap
+            .and_then(|mailmap| mailmap.resolve_signature(sig).ok())
+            .map(|sig| (sig.name_bytes().to_vec(), sig.email_bytes().to_vec()))
+            .unwrap_or_else(|| (sig.name_bytes().to_vec(), sig.email_bytes().to_vec()))
+    };
+    if resolve(author) == resolve(me) {
+        return true;
+    }
+    allowed_emails
+        .iter()
+        .any(|email| email.as_bytes().eq_ignore_ascii_case(author.email_bytes()))
+}
+
+/// Returns the commits that the remote-tracking branches point to, for
+/// the given remotes (or all remotes if there are none).
+fn remote_tips(repo: &git2::Repository, remotes: Option<&[String]>) -> Result<Vec<git2::Oid>> {
+    let mut tips = Vec::new();
+    for branch in repo.branches(Some(git2::BranchType::Remote))? {
+        let (branch, _) = branch?;
+        let reference = branch.get();
+        // symbolic refs like origin/HEAD point to another remote branch
+        let tip = match reference.target() {
+            Some(tip) => tip,
+            None => continue,
+        };
+        if let Some(remotes) = remotes {
+            let remote = match reference.name() {
+                Some(name) => repo.branch_remote_name(name)?,
+                None => continue,
+            };
+            if !remotes
+                .iter()
+                .any(|name| Some(name.as_str()) == remote.as_str())
+            {
+                continue;
+            }
+        }
+        tips.push(tip);
+    }
+    Ok(tips)
+}
+
+pub fn summary_counts<'repo, 'a, I>(commits: I) -> HashMap<String, u64>
+where
+    I: IntoIterator<Item = &'a git2::Commit<'repo>>,
+    // TODO: we have to use a hashmap of owned strings because the
+    // commit summary has the 'a lifetime (the commit outlives this
+    // function, but the reference to the commit does not), it would
+    // be nice if the commit summary had the 'repo lifetime instead
+    'repo: 'a,
+{
+    let mut ret = HashMap::new();
+    for commit in commits {
+        let count = ret
+            // TODO: unnecessary allocation if key already exists
+            .entry(commit.summary().unwrap_or("").to_owned())
+            .or_insert(0);
+        *count += 1;
+    }
+    ret
+}
+
+/// The prefixes of the commits that `git rebase --autosquash` squashes
+/// into another commit.
+const AUTOSQUASH_PREFIXES: &[&str] = &["fixup! ", "squash! ", "amend! "];
+
+/// Returns the subject of the commit that an autosquash commit (eg:
+/// "fixup! some subject") refers to, or `None` if it isn't one.
+pub fn autosquash_subject<'a>(commit: &'a git2::Commit) -> Option<&'a str> {
+    let mut subject = commit.summary()?;
+    let mut stripped = false;
+    // "fixup! fixup! x" squashes into x just like "fixup! x"
+    while let Some(rest) = AUTOSQUASH_PREFIXES
+        .iter()
+        .find_map(|prefix| subject.strip_prefix(prefix))
+    {
+        subject = rest;
+        stripped = true;
+    }
+    if stripped {
+        Some(subject)
+    } else {
+        None
+    }
+}
+
+/// Returns the commit that an autosquash commit in the stack will be
+/// squashed into, if it is in the stack. It is looked up by subject or
+/// by id, the same way `git rebase --autosquash` does.
+pub fn squash_target<'a, 'repo>(
+    stack: &[&'a git2::Commit<'repo>],
+    commit: &git2::Commit,
+) -> Option<&'a git2::Commit<'repo>> {
+    let subject = autosquash_subject(commit)?;
+    let position = stack.iter().position(|other| other.id() == commit.id())?;
+    stack[position + 1..]
+        .iter()
+        .find(|other| {
+            other.summary() == Some(subject)
+                || (subject.len() >= 4 && other.id().to_string().starts_with(subject))
+        })
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_repo() -> (tempfile::TempDir, git2::Repository) {
+        // the repo will be deleted when the tempdir gets dropped
+        let dir = tempfile::TempDir::new().unwrap();
+        // TODO: use in-memory ODB instead (blocked on git2 support)
+        let repo = git2::Repository::init(&dir).unwrap();
+
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "nobody").unwrap();
+        config.set_str("user.email", "nobody@example.com").unwrap();
+
+        (dir, repo)
+    }
+
+    fn empty_commit<'repo>(
+        repo: &'repo git2::Repository,
+        update_ref: &str,
+        message: &str,
+        parents: &[&git2::Commit],
+    ) -> git2::Commit<'repo> {
+        let sig = repo.signature().unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+
+        repo.find_commit(
+            repo.commit(Some(update_ref), &sig, &sig, message, &tree, parents)
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn empty_commit_chain<'repo>(
+        repo: &'repo git2::Repository,
+        update_ref: &str,
+        initial_parents: &[&git2::Commit],
+        length: usize,
+    ) -> Vec<git2::Commit<'repo>> {
+        let mut ret = Vec::with_capacity(length);
+
+        for idx in 0..length {
+            let next = if let Some(last) = ret.last() {
+                // TODO: how to deduplicate the rest of this call if last doesn't live long enough?
+                empty_commit(repo, update_ref, &idx.to_string(), &[last])
+            } else {
+                empty_commit(repo, update_ref, &idx.to_string(), initial_parents)
+            };
+            ret.push(next)
+        }
+
+        assert_eq!(ret.len(), length);
+        ret
+    }
+
+    fn assert_stack_matches_chain(length: usize, stack: &[git2::Commit], chain: &[git2::Commit]) {
+        assert_eq!(stack.len(), length);
+        for (chain_commit, stack_commit) in chain.iter().rev().take(length).zip(stack) {
+            assert_eq!(stack_commit.id(), chain_commit.id());
+        }
+    }
+
+    #[test]
+    fn test_stack_hides_other_branches() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 2);
+        repo.branch("hide", &commits[0], false).unwrap();
+
+        assert_stack_matches_chain(
+            1,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_uses_custom_revwalk() {
+        /// Walks HEAD and its parent only, whatever is hidden.
+        struct Shallow;
+        impl Revwalk for Shallow {
+            fn first_parents<'r>(
+                &self,
+                repo: &'r git2::Repository,
+                _hidden: &[git2::Oid],
+            ) -> Result<Box<dyn Iterator<Item = Result<git2::Oid>> + 'r>> {
+                let head = repo.head()?.peel_to_commit()?;
+                let parent = head.parent_id(0)?;
+                Ok(Box::new(vec![Ok(head.id()), Ok(parent)].into_iter()))
+            }
+        }
+
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        assert_stack_matches_chain(
+            2,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    revwalk: Some(&Shallow),
+                    ..Default::default()
+                },
+                None,
+            )
+            .unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_uses_custom_base() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.branch("hide", &commits[1], false).unwrap();
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    base: &[&commits[0].id().to_string()],
+                    ..Default::default()
+                },
+                None,
+            )
+            .unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_uses_several_bases() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        repo.branch("release", &commits[0], false).unwrap();
+        repo.branch("hotfix", &commits[1], false).unwrap();
+
+        for bases in [
+            &["release..HEAD", "^hotfix"][..],
+            &["^release ^hotfix"],
+            &["release", "hotfix"],
+            &["hotfix.."],
+        ] {
+            assert_stack_matches_chain(
+                2,
+                &working_stack(
+                    &repo,
+                    &StackOptions {
+                        base: bases,
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .unwrap(),
+                &commits,
+            );
+        }
+        assert!(working_stack(
+            &repo,
+            &StackOptions {
+                base: &["release..hotfix"],
+                ..Default::default()
+            },
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_stack_uses_default_base() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        repo.reference("refs/remotes/origin/main", commits[1].id(), false, "")
+            .unwrap();
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            false,
+            "",
+        )
+        .unwrap();
+
+        // the stack would stop at origin/main anyway, unless pushed
+        // commits are allowed
+        assert_stack_matches_chain(
+            2,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    force_pushed: true,
+                    ..Default::default()
+                },
+                None,
+            )
+            .unwrap(),
+            &commits,
+        );
+
+        let options = StackOptions {
+            force_pushed: true,
+            default_base: Some(commits[2].id().to_string()),
+            ..Default::default()
+        };
+        assert_stack_matches_chain(1, &working_stack(&repo, &options, None).unwrap(), &commits);
+    }
+
+    #[test]
+    fn test_stack_uses_fork_point() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        // origin/main used to point into the branch, but was then
+        // rewritten, eg: by a rebase of the upstream branch
+        repo.reference("refs/remotes/origin/main", commits[1].id(), true, "fetch")
+            .unwrap();
+        let rewritten = empty_commit(&repo, "refs/heads/rewritten", "rewritten", &[]);
+        repo.reference("refs/remotes/origin/main", rewritten.id(), true, "fetch")
+            .unwrap();
+        repo.find_reference("refs/heads/rewritten")
+            .unwrap()
+            .delete()
+            .unwrap();
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            false,
+            "",
+        )
+        .unwrap();
+
+        assert_stack_matches_chain(
+            4,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &commits,
+        );
+
+        let options = StackOptions {
+            base_strategy: BaseStrategy::ForkPoint,
+            ..Default::default()
+        };
+        assert_stack_matches_chain(2, &working_stack(&repo, &options, None).unwrap(), &commits);
+    }
+
+    #[test]
+    fn test_stack_stops_at_configured_limit() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], MAX_STACK + 2);
+        let options = StackOptions {
+            max_stack: MAX_STACK + 1,
+            ..Default::default()
+        };
+
+        assert_stack_matches_chain(
+            MAX_STACK + 1,
+            &working_stack(&repo, &options, None).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_stops_at_foreign_author() {
+        let (_dir, repo) = init_repo();
+        let old_commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.config()
+            .unwrap()
+            .set_str("user.name", "nobody2")
+            .unwrap();
+        let new_commits = empty_commit_chain(&repo, "HEAD", &[old_commits.last().unwrap()], 2);
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &new_commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_stops_at_shallow_boundary() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 4);
+        // like a clone with --depth 3
+        std::fs::write(
+            repo.path().join("shallow"),
+            format!("{}\n", commits[1].id()),
+        )
+        .unwrap();
+        let id = commits[0].id().to_string();
+        std::fs::remove_file(repo.path().join("objects").join(&id[..2]).join(&id[2..])).unwrap();
+        assert!(repo.is_shallow());
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &commits,
+        );
+        for (base, length) in [(&commits[1], 2), (&commits[2], 1)] {
+            assert_stack_matches_chain(
+                length,
+                &working_stack(
+                    &repo,
+                    &StackOptions {
+                        base: &[&base.id().to_string()],
+                        ..Default::default()
+                    },
+                    None,
+                )
+                .unwrap(),
+                &commits,
+            );
+        }
+
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        repo.reference("refs/remotes/origin/master", commits[2].id(), false, "")
+            .unwrap();
+        assert_stack_matches_chain(
+            1,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_stops_at_pushed_commits() {
+        let (_dir, repo) = init_repo();
+        let commits = empty_commit_chain(&repo, "HEAD", &[], 3);
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        repo.reference("refs/remotes/origin/master", commits[0].id(), false, "")
+            .unwrap();
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &commits,
+        );
+        assert_stack_matches_chain(
+            3,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    force_pushed: true,
+                    ..Default::default()
+                },
+                None,
+            )
+            .unwrap(),
+            &commits,
+        );
+
+        let options = StackOptions {
+            pushed_remotes: Some(vec!["upstream".to_owned()]),
+            ..Default::default()
+        };
+        assert_stack_matches_chain(3, &working_stack(&repo, &options, None).unwrap(), &commits);
+    }
+
+    #[test]
+    fn test_stack_includes_allowed_authors() {
+        let (dir, repo) = init_repo();
+        repo.config()
+            .unwrap()
+            .set_str("user.email", "old@example.com")
+            .unwrap();
+        let old_commits = empty_commit_chain(&repo, "HEAD", &[], 2);
+        repo.config()
+            .unwrap()
+            .set_str("user.email", "work@example.com")
+            .unwrap();
+        let work_commits = empty_commit_chain(&repo, "HEAD", &[old_commits.last().unwrap()], 2);
+        repo.config()
+            .unwrap()
+            .set_str("user.email", "nobody@example.com")
+            .unwrap();
+        let new_commits = empty_commit_chain(&repo, "HEAD", &[work_commits.last().unwrap()], 2);
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &new_commits,
+        );
+        assert_stack_matches_chain(
+            4,
+            &working_stack(
+                &repo,
+                &StackOptions {
+                    include_authors: &["Work@example.com"],
+                    ..Default::default()
+                },
+                None,
+            )
+            .unwrap(),
+            &new_commits,
+        );
+
+        let options = StackOptions {
+            author_allowlist: vec!["work@example.com".to_owned()],
+            ..Default::default()
+        };
+        std::fs::write(
+            dir.path().join(".mailmap"),
+            "nobody <nobody@example.com> <old@example.com>\n",
+        )
+        .unwrap();
+        assert_stack_matches_chain(
+            6,
+            &working_stack(&repo, &options, None).unwrap(),
+            &new_commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_stops_at_merges() {
+        let (_dir, repo) = init_repo();
+        let first = empty_commit(&repo, "HEAD", "first", &[]);
+        // equivalent to checkout --orphan
+        repo.set_head("refs/heads/new").unwrap();
+        let second = empty_commit(&repo, "HEAD", "second", &[]);
+        // the current commit must be the first parent
+        let merge = empty_commit(&repo, "HEAD", "merge", &[&second, &first]);
+        let commits = empty_commit_chain(&repo, "HEAD", &[&merge], 2);
+
+        assert_stack_matches_chain(
+            2,
+            &working_stack(&repo, &StackOptions::default(), None).unwrap(),
+            &commits,
+        );
+    }
+
+    #[test]
+    fn test_stack_reports_its_boundary() {
+        let (_dir, repo) = init_repo();
+        let first = empty_commit(&repo, "HEAD", "first", &[]);
+        repo.set_head("refs/heads/new").unwrap();
+        let second = empty_commit(&repo, "HEAD", "second", &[]);
+        let merge = empty_commit(&repo, "HEAD", "merge", &[&second, &first]);
+        let commits = empty_commit_chain(&repo, "HEAD", &[&merge], 2);
+
+        let (stack, boundary) =
+            working_stack_with_boundary(&repo, &StackOptions::default(), None).unwrap();
+        assert_stack_matches_chain(2, &stack, &commits);
+        assert_eq!(boundary, Boundary::Merge(merge.id()));
+
+        let options = StackOptions {
+            max_stack: 1,
+            ..Default::default()
+        };
+        let (_, boundary) = working_stack_with_boundary(&repo, &options, None).unwrap();
+        assert_eq!(boundary, Boundary::Limit(commits[0].id()));
+
+        let options = StackOptions {
+            traverse_merges: true,
+            ..Default::default()
+        };
+        let (_, boundary) = working_stack_with_boundary(&repo, &options, None).unwrap();
+        assert_eq!(boundary, Boundary::End);
+    }
+
+    #[test]
+    fn test_stack_follows_first_parent_of_merges() {
+        let (_dir, repo) = init_repo();
+        let first = empty_commit(&repo, "HEAD", "first", &[]);
+        repo.set_head("refs/heads/new").unwrap();
+        let second = empty_commit(&repo, "HEAD", "second", &[]);
+        let merge = empty_commit(&repo, "HEAD", "merge", &[&second, &first]);
+        let after = empty_commit(&repo, "HEAD", "after", &[&merge]);
+        let options = StackOptions {
+            traverse_merges: true,
+            ..Default::default()
+        };
+
+        let stack = working_stack(&repo, &options, None).unwrap();
+        let ids: Vec<_> = stack.iter().map(|commit| commit.id()).collect();
+        assert_eq!(ids, vec![after.id(), merge.id(), second.id()]);
+    }
+
+    #[test]
+    fn test_squash_target() {
+        let (_dir, repo) = init_repo();
+        let first = empty_commit(&repo, "HEAD", "first", &[]);
+        let fixup = empty_commit(&repo, "HEAD", "fixup! squash! first", &[&first]);
+        let by_id = empty_commit(
+            &repo,
+            "HEAD",
+            &format!("amend! {}", &first.id().to_string()[..7]),
+            &[&fixup],
+        );
+        let unknown = empty_commit(&repo, "HEAD", "fixup! unknown", &[&by_id]);
+        let stack = vec![&unknown, &by_id, &fixup, &first];
+
+        assert_eq!(autosquash_subject(&fixup), Some("first"));
+        assert_eq!(autosquash_subject(&first), None);
+        assert_eq!(squash_target(&stack, &fixup).unwrap().id(), first.id());
+        assert_eq!(squash_target(&stack, &by_id).unwrap().id(), first.id());
+        assert!(squash_target(&stack, &unknown).is_none());
+        assert!(squash_target(&stack, &first).is_none());
+    }
+}
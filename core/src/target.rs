@@ -0,0 +1,487 @@
+use crate::{commute, owned};
+
+/// The parts of a commit in the stack that commutation looks at, which
+/// (unlike a `git2::Commit`) can be shared between threads.
+pub struct StackDiff<'a> {
+    pub id: git2::Oid,
+    pub summary: String,
+    pub diff: &'a owned::Diff,
+}
+
+/// Finds the newest commit in the stack that the hunk cannot commute
+/// with, and returns its index, or `None` if the hunk commutes with all
+/// of them.
+///
+/// If `trace` is given, a human-readable description of each step of
+/// the search is appended to it.
+pub fn find_target(
+    stack: &[StackDiff],
+    path: &[u8],
+    hunk: &owned::Hunk,
+    whole_file: bool,
+    intra_line: bool,
+    mut trace: Option<&mut Vec<String>>,
+    logger: Option<&slog::Logger>,
+) -> Option<usize> {
+    let logger = &crate::or_discard(logger);
+    let mut commuted_old_path = path;
+    let mut commuted_index_hunk = hunk.clone();
+    let mut explain = |commit: &StackDiff, step: String| {
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.push(format!(
+                "{} {:?}: {}",
+                &commit.id.to_string()[..7],
+                commit.summary,
+                step
+            ));
+        }
+    };
+
+    for (idx, commit) in stack.iter().enumerate() {
+        let diff = commit.diff;
+        let c_logger = logger.new(o!(
+            "commit" => commit.id.to_string(),
+        ));
+        let next_patch = match diff.by_new(commuted_old_path) {
+            Some(patch) => patch,
+            // this commit doesn't touch the hunk's file, so
+            // they trivially commute, and the next commit
+            // should be considered
+            None => {
+                debug!(c_logger, "skipped commit with no path");
+                explain(
+                    commit,
+                    format!(
+                        "does not touch {}",
+                        String::from_utf8_lossy(commuted_old_path)
+                    ),
+                );
+                continue;
+            }
+        };
+
+        // sometimes we just forget some change (eg: intializing some object) that
+        // happens in a completely unrelated place with the current hunks. In those
+        // cases, might be helpful to just match the first commit touching the same
+        // file as the current hunk. Use this option with care!
+        if whole_file {
+            debug!(
+                c_logger,
+                "Commit touches the hunk file and match whole file is enabled"
+            );
+            explain(
+                commit,
+                "touches the same file, and --whole-file is used".to_owned(),
+            );
+            return Some(idx);
+        }
+
+        if next_patch.status == git2::Delta::Added {
+            debug!(c_logger, "found noncommutative commit by add");
+            explain(
+                commit,
+                "adds the file, so the hunk cannot move past it".to_owned(),
+            );
+            return Some(idx);
+        }
+        if next_patch.status == git2::Delta::Typechange {
+            debug!(c_logger, "found noncommutative commit by typechange");
+            explain(
+                commit,
+                "changes the type of the file, so the hunk cannot move past it".to_owned(),
+            );
+            return Some(idx);
+        }
+        if commuted_old_path != next_patch.old_path.as_slice() {
+            debug!(c_logger, "changed commute path";
+                   "path" => String::from_utf8_lossy(&next_patch.old_path).into_owned(),
+            );
+            explain(
+                commit,
+                format!(
+                    "renames {} to {}",
+                    String::from_utf8_lossy(&next_patch.old_path),
+                    String::from_utf8_lossy(commuted_old_path)
+                ),
+            );
+            commuted_old_path = next_patch.old_path.as_slice();
+        }
+        commuted_index_hunk = match commute::commute_diff_before(
+            &commuted_index_hunk,
+            &next_patch.hunks,
+            intra_line,
+        ) {
+            Ok(hunk) => {
+                debug!(c_logger, "commuted hunk with commit";
+                       "offset" => (hunk.added.start as i64) - (commuted_index_hunk.added.start as i64),
+                );
+                explain(
+                    commit,
+                    format!(
+                        "commutes with its hunks {}, moving the hunk from {} to {}",
+                        next_patch
+                            .hunks
+                            .iter()
+                            .map(|hunk| hunk.header())
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        commuted_index_hunk.header(),
+                        hunk.header()
+                    ),
+                );
+                hunk
+            }
+            // this commit contains a hunk that cannot
+            // commute with the hunk being absorbed
+            Err(conflict) => {
+                debug!(c_logger, "found noncommutative commit by conflict");
+                explain(
+                    commit,
+                    format!(
+                        "its hunk {} overlaps with the hunk at {}",
+                        conflict.header(),
+                        commuted_index_hunk.header()
+                    ),
+                );
+                return Some(idx);
+            }
+        };
+    }
+    if let Some(trace) = trace {
+        trace.push(format!(
+            "reached the end of the stack after {} commits, use --base to search further",
+            stack.len()
+        ));
+    }
+    None
+}
+
+/// Cuts a one-for-one replacement hunk into single lines, finds the
+/// target of each line, and joins adjacent lines with the same target
+/// back together.
+///
+/// Returns `None` if the hunk can't be split, or if all of its lines
+/// have the same target anyway.
+pub fn split_by_target(
+    stack: &[StackDiff],
+    path: &[u8],
+    hunk: &owned::Hunk,
+    intra_line: bool,
+    logger: Option<&slog::Logger>,
+) -> Option<Vec<(owned::Hunk, Option<usize>)>> {
+    let len = hunk.added.lines.len();
+    if !hunk.is_replacement() || len < 2 {
+        return None;
+    }
+
+    let mut groups: Vec<(std::ops::Range<usize>, Option<usize>)> = Vec::new();
+    for idx in 0..len {
+        let target = find_target(
+            stack,
+            path,
+            &hunk.slice(idx..idx + 1),
+            false,
+            intra_line,
+            None,
+            logger,
+        );
+        match groups.last_mut() {
+            Some((range, last_target)) if *last_target == target => range.end = idx + 1,
+            _ => groups.push((idx..idx + 1, target)),
+        }
+    }
+    if groups.len() < 2 {
+        return None;
+    }
+    Some(
+        groups
+            .into_iter()
+            .map(|(range, target)| (hunk.slice(range), target))
+            .collect(),
+    )
+}
+
+/// How `commute_patch()` finds the targets of the hunks of a file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommuteOptions {
+    /// Every hunk goes into the newest commit that touched its file.
+    pub whole_file: bool,
+    /// Hunks commute with changes to other parts of the same lines.
+    pub intra_line: bool,
+    /// Replacement hunks are split by target, see `split_by_target()`.
+    pub split: bool,
+    /// Each step of the search is described in `Commuted::trace`.
+    pub explain: bool,
+    /// A block of lines that was moved within the file goes into the
+    /// commit that its lines were removed from, as long as it commutes
+    /// that far.
+    pub detect_moves: bool,
+}
+
+/// Where commutation puts a staged hunk, as found by `commute_patch()`,
+/// with commits given by their index in the stack.
+#[derive(Debug, Default)]
+pub struct Commuted {
+    /// The commit that the hunk can't be commuted past, if any.
+    pub target: Option<usize>,
+    /// The steps of the search, with `CommuteOptions::explain`.
+    pub trace: Option<Vec<String>>,
+    /// The pieces that the hunk was split into, with their targets, if
+    /// it was split.
+    pub pieces: Option<Vec<(owned::Hunk, Option<usize>)>>,
+    /// Set if the hunk only commuted this far with `intra_line`.
+    pub by_intra_line: bool,
+}
+
+/// Finds the targets of the hunks of a staged patch, which are commuted
+/// independently of each other, in the order of the patch. The patch
+/// should be of a file that was modified in place.
+pub fn commute_patch(
+    stack: &[StackDiff],
+    patch: &owned::Patch,
+    options: &CommuteOptions,
+    logger: Option<&slog::Logger>,
+) -> Vec<Commuted> {
+    let logger = crate::or_discard(logger).new(o!(
+        "path" => String::from_utf8_lossy(&patch.new_path).into_owned(),
+    ));
+    // each hunk is commuted as if it were the only one in the file
+    let mut preceding_hunks_offset = 0isize;
+    let mut commuted: Vec<_> = patch
+        .hunks
+        .iter()
+        .map(|index_hunk| {
+            let isolated_hunk = index_hunk
+                .clone()
+                .shift_added_block(-preceding_hunks_offset);
+            preceding_hunks_offset += index_hunk.changed_offset();
+            let logger = logger.new(o!("header" => index_hunk.header()));
+            let mut trace = if options.explain {
+                Some(Vec::new())
+            } else {
+                None
+            };
+            let target = find_target(
+                stack,
+                &patch.new_path,
+                &isolated_hunk,
+                options.whole_file,
+                options.intra_line,
+                trace.as_mut(),
+                Some(&logger),
+            );
+            let pieces = if !options.split || options.whole_file {
+                None
+            } else {
+                split_by_target(
+                    stack,
+                    &patch.new_path,
+                    &isolated_hunk,
+                    options.intra_line,
+                    Some(&logger),
+                )
+            };
+            // which only matters if the hunk went somewhere else
+            // than it would have without it
+            let by_intra_line = options.intra_line && {
+                let targets = |pieces: &Option<Vec<(owned::Hunk, Option<usize>)>>| {
+                    pieces
+                        .as_ref()
+                        .map(|pieces| pieces.iter().map(|(_, target)| *target).collect::<Vec<_>>())
+                };
+                let plain_target = find_target(
+                    stack,
+                    &patch.new_path,
+                    &isolated_hunk,
+                    options.whole_file,
+                    false,
+                    None,
+                    None,
+                );
+                let plain_pieces = match pieces {
+                    Some(_) => split_by_target(stack, &patch.new_path, &isolated_hunk, false, None),
+                    None => None,
+                };
+                plain_target != target || targets(&plain_pieces) != targets(&pieces)
+            };
+            Commuted {
+                target,
+                trace,
+                pieces,
+                by_intra_line,
+            }
+        })
+        .collect();
+    // a block of lines that was moved within the file goes into the
+    // commit that its lines were removed from, rather than being
+    // split from them, as long as it commutes that far
+    if options.detect_moves && !options.whole_file {
+        for (hunk_idx, hunk) in patch.hunks.iter().enumerate() {
+            let from = match commute::moved_from(hunk, &patch.hunks) {
+                Some(from) => from,
+                None => continue,
+            };
+            let target = match commuted[from].target {
+                Some(target) if commuted[hunk_idx].target.is_none_or(|idx| idx >= target) => target,
+                _ => continue,
+            };
+            debug!(logger, "moved block follows its removed lines";
+                   "header" => hunk.header(),
+                   "commit" => stack[target].id.to_string(),
+            );
+            let removed_header = patch.hunks[from].header();
+            let moved = &mut commuted[hunk_idx];
+            if let Some(trace) = moved.trace.as_mut() {
+                trace.push(format!(
+                    "moves the lines removed by the hunk at {}, so it goes into {}",
+                    removed_header,
+                    &stack[target].id.to_string()[..7]
+                ));
+            }
+            moved.target = Some(target);
+        }
+    }
+    commuted
+}
+
+/// Commutes the hunk past the commits of the stack before `idx`, like
+/// `find_target()` does, and returns the path of its file and the hunk
+/// as they are right after the commit at `idx`, or `None` if it can't
+/// be commuted that far.
+pub(crate) fn commute_to<'a>(
+    stack: &[StackDiff<'a>],
+    path: &'a [u8],
+    hunk: &owned::Hunk,
+    intra_line: bool,
+    idx: usize,
+) -> Option<(&'a [u8], owned::Hunk)> {
+    let mut path = path;
+    let mut hunk = hunk.clone();
+    for commit in &stack[..idx] {
+        let patch = match commit.diff.by_new(path) {
+            Some(patch) => patch,
+            None => continue,
+        };
+        if patch.status != git2::Delta::Modified && patch.status != git2::Delta::Renamed {
+            return None;
+        }
+        path = patch.old_path.as_slice();
+        hunk = commute::commute_diff_before(&hunk, &patch.hunks, intra_line).ok()?;
+    }
+    Some((path, hunk))
+}
+
+fn is_blank(line: &owned::Line) -> bool {
+    line.iter().all(u8::is_ascii_whitespace)
+}
+
+/// Returns the number of blank lines at the start of the block, and
+/// the lines after them, without the blank lines at the end.
+fn trim_blank(lines: &[owned::Line]) -> (usize, &[owned::Line]) {
+    let start = lines.iter().take_while(|line| is_blank(line)).count();
+    let end = lines.len()
+        - lines[start..]
+            .iter()
+            .rev()
+            .take_while(|line| is_blank(line))
+            .count();
+    (start, &lines[start..end])
+}
+
+/// Compares blocks of lines without the whitespace around them, which
+/// moving a block of code into another file often changes (and a diff
+/// may put a blank line on either side of a moved block), returning
+/// how many lines further down the second block the lines of the first
+/// one are.
+fn similar(a: &[owned::Line], b: &[owned::Line]) -> Option<isize> {
+    let (a_start, a) = trim_blank(a);
+    let (b_start, b) = trim_blank(b);
+    if a.is_empty()
+        || a.len() != b.len()
+        || a.iter()
+            .zip(b)
+            .any(|(a, b)| a.trim_ascii() != b.trim_ascii())
+    {
+        return None;
+    }
+    Some(b_start as isize - a_start as isize)
+}
+
+/// Returns the hunk of the diff that added the lines that the hunk
+/// changes in `path`, if they were all added by the same hunk.
+///
+/// The hunk must be as it is right after the diff, see `commute_to()`.
+pub(crate) fn added_block<'a>(
+    diff: &'a owned::Diff,
+    path: &[u8],
+    hunk: &owned::Hunk,
+) -> Option<&'a owned::Hunk> {
+    let (upper, lower, _, _) = hunk.anchors();
+    // lines added inside the block were moved along with it, unlike
+    // lines added right before or after it
+    let (inside_upper, inside_lower) = match hunk.removed.lines.len() {
+        0 => (0, 1),
+        _ => (1, 0),
+    };
+    diff.by_new(path)?.hunks.iter().find(|added| {
+        !added.added.lines.is_empty()
+            && upper + inside_upper >= added.added.start
+            && lower + inside_lower <= added.added.start + added.added.lines.len()
+    })
+}
+
+/// Commutes the hunk past the commits of the stack before `idx`, and
+/// returns the path of its file and the hunk as they are right after
+/// the commit at `idx`, if the hunk only changes lines that this commit
+/// added. Those lines may have been moved from another file, which
+/// `moved_from_file()` finds in the whole diff of the commit (since the
+/// diffs of the stack usually only have the staged files).
+pub fn added_by<'a>(
+    stack: &[StackDiff<'a>],
+    path: &'a [u8],
+    hunk: &owned::Hunk,
+    intra_line: bool,
+    idx: usize,
+) -> Option<(&'a [u8], owned::Hunk)> {
+    let (path, hunk) = commute_to(stack, path, hunk, intra_line, idx)?;
+    added_block(stack[idx].diff, path, &hunk)?;
+    Some((path, hunk))
+}
+
+/// If the hunk only changes lines that the diff added to `path`, and
+/// the diff also removed the same lines (give or take the whitespace
+/// around them) from another file, returns that file and the hunk as
+/// it would apply there, before the lines were moved.
+///
+/// The hunk must be as it is right after the diff, see `added_by()`.
+pub fn moved_from_file<'a>(
+    diff: &'a owned::Diff,
+    path: &[u8],
+    hunk: &owned::Hunk,
+) -> Option<(&'a [u8], owned::Hunk)> {
+    let added = added_block(diff, path, hunk)?;
+    diff.iter()
+        .filter(|patch| {
+            patch.new_path != path
+                && matches!(patch.status, git2::Delta::Modified | git2::Delta::Deleted)
+        })
+        .find_map(|patch| {
+            let (removed, shift) = patch.hunks.iter().find_map(|removed| {
+                similar(&added.added.lines, &removed.removed.lines).map(|shift| (removed, shift))
+            })?;
+            let (upper, _, _, _) = hunk.anchors();
+            let offset = (upper + 1 - added.added.start) as isize + shift;
+            if offset < 0 {
+                return None;
+            }
+            let offset = offset as usize;
+            let lines = removed
+                .removed
+                .lines
+                .get(offset..offset + hunk.removed.lines.len())?;
+            let delta = removed.removed.start as isize + shift - added.added.start as isize;
+            let mut moved = hunk.clone().shift_both_blocks(delta);
+            moved.removed.lines = std::sync::Arc::new(lines.to_vec());
+            Some((patch.old_path.as_slice(), moved))
+        })
+}
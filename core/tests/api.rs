@@ -0,0 +1,154 @@
+use git_absorb_core::{apply, owned, target, working_stack, Plan, StackOptions};
+
+use std::path::Path;
+
+/// Writes `content` to `path` in the working tree and stages it.
+fn stage_file(repo: &git2::Repository, path: &str, content: &str) {
+    std::fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+}
+
+/// Commits the current index on top of HEAD.
+fn commit_index(repo: &git2::Repository, message: &str) {
+    let sig = repo.signature().unwrap();
+    let tree = repo
+        .find_tree(repo.index().unwrap().write_tree().unwrap())
+        .unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+        .unwrap();
+}
+
+/// Parses the diff of the commit (or of the index, if there is none)
+/// against its parent (or HEAD), without context lines.
+fn diff(
+    repo: &git2::Repository,
+    commit: Option<&git2::Commit>,
+    interner: &mut owned::Interner,
+) -> owned::Diff {
+    let mut options = git2::DiffOptions::new();
+    options.context_lines(0);
+    let diff = match commit {
+        Some(commit) => {
+            let parent = commit.parent(0).ok().map(|parent| parent.tree().unwrap());
+            repo.diff_tree_to_tree(
+                parent.as_ref(),
+                Some(&commit.tree().unwrap()),
+                Some(&mut options),
+            )
+        }
+        None => {
+            let head = repo.head().unwrap().peel_to_tree().unwrap();
+            repo.diff_tree_to_index(Some(&head), None, Some(&mut options))
+        }
+    }
+    .unwrap();
+    owned::Diff::filtered(&diff, |_| true, interner).unwrap()
+}
+
+/// Makes a repository in a temporary directory, with an identity.
+fn init_repo() -> (tempfile::TempDir, git2::Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init(dir.path()).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "nobody").unwrap();
+    config.set_str("user.email", "nobody@example.com").unwrap();
+    (dir, repo)
+}
+
+/// Plans the staged changes among the commits of the stack.
+fn plan(repo: &git2::Repository) -> Plan<'_> {
+    let stack = working_stack(repo, &StackOptions::default(), None).unwrap();
+    let mut interner = owned::Interner::default();
+    let stack: Vec<_> = stack
+        .into_iter()
+        .map(|commit| {
+            let diff = diff(repo, Some(&commit), &mut interner);
+            (commit, diff)
+        })
+        .collect();
+    let index = diff(repo, None, &mut interner);
+    Plan::for_index(&stack, &index, &target::CommuteOptions::default(), None)
+}
+
+#[test]
+fn test_absorbs_through_the_public_api() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", "a\n\n\n\n");
+    commit_index(&repo, "first");
+    stage_file(&repo, "file", "a\n\n\n\nb\n");
+    commit_index(&repo, "second");
+    stage_file(&repo, "file", "a2\n\n\n\nb2\n");
+
+    let stack = working_stack(&repo, &StackOptions::default(), None).unwrap();
+    assert_eq!(stack.len(), 2);
+    let mut interner = owned::Interner::default();
+    let stack: Vec<_> = stack
+        .into_iter()
+        .map(|commit| {
+            let diff = diff(&repo, Some(&commit), &mut interner);
+            (commit, diff)
+        })
+        .collect();
+    let index = diff(&repo, None, &mut interner);
+
+    let plan = Plan::for_index(&stack, &index, &target::CommuteOptions::default(), None);
+    let targets: Vec<_> = plan
+        .assignments
+        .iter()
+        .map(|assignment| assignment.target.as_ref().map(|target| target.id()))
+        .collect();
+    assert_eq!(targets, [Some(stack[1].0.id()), Some(stack[0].0.id())]);
+
+    let signature = repo.signature().unwrap();
+    let fixups = apply::commit_fixups(&repo, &plan, &signature).unwrap();
+    assert_eq!(fixups.len(), 2);
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.id(), fixups[1]);
+    assert_eq!(head.summary(), Some("fixup! second"));
+    assert_eq!(head.parent(0).unwrap().summary(), Some("fixup! first"));
+    // everything that was staged was absorbed
+    let index_tree = repo.index().unwrap().write_tree().unwrap();
+    assert_eq!(head.tree_id(), index_tree);
+}
+
+#[test]
+fn test_fixups_of_commits_with_the_same_summary_refer_to_their_id() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", "a\n\n\n\n");
+    commit_index(&repo, "fix");
+    stage_file(&repo, "file", "a\n\n\n\nb\n");
+    commit_index(&repo, "fix");
+    stage_file(&repo, "other", "c\n");
+    commit_index(&repo, "other");
+    stage_file(&repo, "file", "a2\n\n\n\nb2\n");
+    stage_file(&repo, "other", "c2\n");
+
+    let plan = plan(&repo);
+    let signature = repo.signature().unwrap();
+    let fixups = apply::commit_fixups(&repo, &plan, &signature).unwrap();
+    let summaries: Vec<_> = fixups
+        .iter()
+        .map(|&fixup| {
+            repo.find_commit(fixup)
+                .unwrap()
+                .summary()
+                .unwrap()
+                .to_owned()
+        })
+        .collect();
+    // autosquash would squash a "fixup! fix" into the newest "fix", so
+    // those fixups name their target by its id
+    let [second, first] = [1, 2].map(|idx| plan.stack[idx].to_string());
+    assert_eq!(
+        summaries,
+        [
+            format!("fixup! {}", first),
+            format!("fixup! {}", second),
+            "fixup! other".to_owned(),
+        ]
+    );
+}
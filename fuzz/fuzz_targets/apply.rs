@@ -0,0 +1,34 @@
+//! Applies a hunk to a blob the way fixups are committed, and checks
+//! that it changes the file to what the hunk was made from.
+
+#![no_main]
+
+use git_absorb_core::{apply, plan};
+use libfuzzer_sys::fuzz_target;
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = common::Input::new(data);
+    let base = input.lines(16);
+    let (hunk, changed) = input.hunk(&base);
+
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init_bare(dir.path()).unwrap();
+    let blob = repo.blob(&base.concat()).unwrap();
+    let mut treebuilder = repo.treebuilder(None).unwrap();
+    treebuilder.insert("file", blob, 0o100644).unwrap();
+    let tree = repo.find_tree(treebuilder.write().unwrap()).unwrap();
+
+    let assignment = plan::HunkAssignment {
+        path: b"file".to_vec(),
+        hunk: hunk.clone(),
+        target: None,
+        skip_reason: None,
+        file_change: None,
+    };
+    let applied = apply::apply_assignment(&repo, &tree, &assignment, &hunk).unwrap();
+    let entry = applied.get_name("file").unwrap();
+    let blob = repo.find_blob(entry.id()).unwrap();
+    assert_eq!(blob.content(), &changed.concat()[..]);
+});
@@ -0,0 +1,68 @@
+//! Makes up files and hunks out of the bytes that the fuzzer passes in.
+
+use git_absorb_core::owned;
+
+use std::sync::Arc;
+
+/// Hands out the bytes of the input one at a time, and zeroes once they
+/// run out, so that every input makes up something.
+pub struct Input<'a>(std::slice::Iter<'a, u8>);
+impl<'a> Input<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Input(data.iter())
+    }
+
+    pub fn byte(&mut self) -> u8 {
+        self.0.next().copied().unwrap_or(0)
+    }
+
+    /// Makes up up to `max` lines. They are taken from just a few
+    /// different ones, so that hunks often touch the same lines, or
+    /// repeat a single line.
+    pub fn lines(&mut self, max: u8) -> Vec<owned::Line> {
+        let len = self.byte() % (max + 1);
+        (0..len)
+            .map(|_| owned::Line::from(&[b'a' + self.byte() % 4, b'\n'][..]))
+            .collect()
+    }
+
+    /// Makes up a hunk that changes `file`, and returns it along with
+    /// the file that it changes it to. The hunk is the only hunk of its
+    /// diff, so its removed and added sides start at the same line.
+    pub fn hunk(&mut self, file: &[owned::Line]) -> (owned::Hunk, Vec<owned::Line>) {
+        let at = usize::from(self.byte()) % (file.len() + 1);
+        let removed = usize::from(self.byte()) % (file.len() - at + 1);
+        let added = self.lines(4);
+        let mut changed = file.to_vec();
+        changed.splice(at..at + removed, added.iter().cloned());
+        // the start of an empty block is the line before it
+        let start = |len: usize| if len == 0 { at } else { at + 1 };
+        let hunk = owned::Hunk {
+            removed: owned::Block {
+                start: start(removed),
+                lines: Arc::new(file[at..at + removed].to_vec()),
+                trailing_newline: true,
+            },
+            added: owned::Block {
+                start: start(added.len()),
+                lines: Arc::new(added),
+                trailing_newline: true,
+            },
+        };
+        (hunk, changed)
+    }
+}
+
+/// Applies the hunk to `file` the way they are applied to blobs, from
+/// the last unchanged line above it, or returns `None` if the lines that
+/// it removes aren't there.
+pub fn apply(file: &[owned::Line], hunk: &owned::Hunk) -> Option<Vec<owned::Line>> {
+    let (at, _, _, _) = hunk.anchors();
+    let removed = &hunk.removed.lines;
+    if file.get(at..at + removed.len())? != &removed[..] {
+        return None;
+    }
+    let mut changed = file.to_vec();
+    changed.splice(at..at + removed.len(), hunk.added.lines.iter().cloned());
+    Some(changed)
+}
@@ -0,0 +1,156 @@
+use anyhow::Result;
+
+use std::collections::HashMap;
+
+use crate::backend::Backend;
+use crate::config_file::Layered;
+use crate::i18n::tr;
+use crate::plan;
+use crate::rebase::merge;
+use crate::sign;
+use crate::stack;
+
+const FIXUP_PREFIX: &str = "fixup! ";
+
+/// Folds the hunks that target a commit which already has a fixup
+/// among the fixups at the top of the stack (eg: from a previous run)
+/// into that fixup, instead of committing a second fixup for it.
+///
+/// The fixups are rewritten by cherry-picking them onto each other, and
+/// HEAD is moved to the last of them. If any of the cherry-picks would
+/// conflict, nothing is rewritten. Returns the ids of the amended
+/// fixups, by the id of the commit they target.
+#[allow(clippy::too_many_arguments)]
+pub fn amend_existing_fixups(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    stack: &[&git2::Commit],
+    plan: &plan::Plan,
+    signature: &git2::Signature,
+    signer: Option<&sign::Signer>,
+    logger: &slog::Logger,
+) -> Result<HashMap<git2::Oid, git2::Oid>> {
+    // the fixups at the top of the stack, newest first
+    let fixups: Vec<_> = stack
+        .iter()
+        .take_while(|commit| {
+            commit
+                .summary()
+                .is_some_and(|summary| summary.starts_with(FIXUP_PREFIX))
+        })
+        .collect();
+    let mut fixup_by_target = HashMap::new();
+    for (idx, fixup) in fixups.iter().enumerate() {
+        if let Some(target) = stack::squash_target(stack, fixup) {
+            fixup_by_target.entry(target.id()).or_insert(idx);
+        }
+        // hunks that conflict with the fixup itself belong in it too
+        fixup_by_target.insert(fixup.id(), idx);
+    }
+
+    // for each fixup to amend, the tree of HEAD with only the hunks
+    // that target the same commit applied
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let mut amended_trees = HashMap::new();
+    for assignment in &plan.assignments {
+        let target = match &assignment.target {
+            Some(target) => target.id(),
+            None => continue,
+        };
+        let idx = match fixup_by_target.get(&target) {
+            Some(&idx) => idx,
+            None => continue,
+        };
+        if amended_trees.contains_key(&idx) {
+            continue;
+        }
+        let only_target = plan::Plan {
+            assignments: plan
+                .assignments
+                .iter()
+                .cloned()
+                .map(|mut assignment| {
+                    if assignment.target.as_ref().map(|commit| commit.id()) != Some(target) {
+                        assignment.target = None;
+                    }
+                    assignment
+                })
+                .collect(),
+            skipped_paths: Vec::new(),
+            stack: plan.stack.clone(),
+            summary_counts: plan.summary_counts.clone(),
+        };
+        let mut tree = head_tree.clone();
+        for (assignment, hunk) in only_target.hunks_to_apply() {
+            tree = git_absorb_core::apply::apply_assignment(repo, &tree, assignment, &hunk)?;
+        }
+        amended_trees.insert(idx, tree);
+    }
+    if amended_trees.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // rewrite the fixups from the oldest one that is amended
+    let oldest = *amended_trees.keys().max().unwrap();
+    let mut parent = fixups[oldest].parent(0)?;
+    let mut amended = HashMap::new();
+    for idx in (0..=oldest).rev() {
+        let fixup = fixups[idx];
+        let mut tree = if parent.id() == fixup.parent_id(0)? {
+            fixup.tree()?
+        } else {
+            match merge(
+                repo,
+                &fixup.parent(0)?.tree()?,
+                &parent.tree()?,
+                &fixup.tree()?,
+            )? {
+                Some(tree) => tree,
+                None => {
+                    debug!(logger, "could not rebase existing fixup, creating new fixups";
+                           "fixup" => fixup.id().to_string(),
+                    );
+                    return Ok(HashMap::new());
+                }
+            }
+        };
+        if let Some(amended_tree) = amended_trees.get(&idx) {
+            tree = match merge(repo, &head_tree, &tree, amended_tree)? {
+                Some(tree) => tree,
+                None => {
+                    debug!(logger, "could not amend existing fixup, creating new fixups";
+                           "fixup" => fixup.id().to_string(),
+                    );
+                    return Ok(HashMap::new());
+                }
+            };
+        }
+        parent = repo.find_commit(sign::commit(
+            repo,
+            backend,
+            signer,
+            &fixup.author(),
+            signature,
+            fixup.message().unwrap_or_default(),
+            &tree,
+            &[&parent],
+        )?)?;
+        if amended_trees.contains_key(&idx) {
+            for (target, &fixup_idx) in &fixup_by_target {
+                if fixup_idx == idx {
+                    amended.insert(*target, parent.id());
+                }
+            }
+            info!(logger, "{}", tr("amended existing fixup");
+                  "fixup" => fixup.summary().unwrap_or_default(),
+                  "commit" => parent.id().to_string(),
+            );
+        }
+    }
+    repo.head()?.set_target(
+        parent.id(),
+        &crate::config::reflog_message(settings, "amend existing fixups"),
+    )?;
+    Ok(amended)
+}
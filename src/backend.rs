@@ -0,0 +1,535 @@
+use anyhow::Result;
+use git_absorb_core::stack;
+
+use crate::git_cli::{self, DiffTarget};
+use crate::DiffAlgorithm;
+use crate::{default_diff_options, intent_to_add_entry, is_intent_to_add, path_from_bytes};
+
+/// How a `Backend` diffs the staged changes and the commits of the stack.
+#[derive(Debug, Clone, Copy)]
+pub struct DiffSettings {
+    pub algorithm: DiffAlgorithm,
+    /// Changes are grouped into hunks as if they had this many lines of
+    /// context, see `default_diff_options()`.
+    pub context: u32,
+    /// Whether the repository is a partial clone, where renames that
+    /// can't be detected because the blobs are missing are left as
+    /// deletions and creations.
+    pub partial_clone: bool,
+}
+
+/// How the repository is read and written, which `absorb.backend`
+/// selects: walking the stack (see `stack::Revwalk`), diffing, reading
+/// and writing the index, and creating commits. Objects and references
+/// are always looked up through libgit2.
+pub trait Backend: stack::Revwalk {
+    /// The value of `absorb.backend` that selects this backend.
+    fn name(&self) -> &'static str;
+
+    /// Diffs the tree against the index, or against the working tree
+    /// with `DiffTarget::Workdir`, for the files that match the
+    /// pathspecs (all of them if there are none). Files added with `git
+    /// add --intent-to-add` may be diffed as new, empty files.
+    fn diff_index<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        tree: &git2::Tree,
+        target: DiffTarget,
+        settings: DiffSettings,
+        pathspecs: &[&str],
+    ) -> Result<git2::Diff<'r>>;
+
+    /// Diffs the commit against its first parent (or the empty tree),
+    /// with renames detected.
+    fn diff_commit<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        commit: &git2::Commit,
+        settings: DiffSettings,
+    ) -> Result<git2::Diff<'r>>;
+
+    /// Writes the index as a tree.
+    ///
+    /// Like `git write-tree`, this leaves out the intent-to-add entries,
+    /// which libgit2 would write as empty files.
+    fn write_index_tree(&self, repo: &git2::Repository) -> Result<git2::Oid>;
+
+    /// Returns the entries of the index that were added with `git add
+    /// --intent-to-add`. Their mode may be unknown, which doesn't matter
+    /// to `read_index_tree()`.
+    fn intent_to_add_entries(&self, repo: &git2::Repository) -> Result<Vec<git2::IndexEntry>>;
+
+    /// Replaces the index with the tree.
+    ///
+    /// The intent-to-add entries aren't part of the tree (see
+    /// `write_index_tree()`), so they are added back from
+    /// `intent_to_add`, for the files that the tree doesn't have.
+    fn read_index_tree(
+        &self,
+        repo: &git2::Repository,
+        tree: &git2::Tree,
+        intent_to_add: &[git2::IndexEntry],
+    ) -> Result<()>;
+
+    /// Stages the changes to the tracked files that match the pathspecs
+    /// (all of them if there are none), like `git add --update`.
+    fn stage_tracked(&self, repo: &git2::Repository, pathspecs: &[&str]) -> Result<()>;
+
+    /// Returns whether the index or the working tree have changes to
+    /// tracked files.
+    fn has_changes(&self, repo: &git2::Repository) -> Result<bool>;
+
+    /// Checks out `to` over the index and the working tree, which match
+    /// `from`.
+    fn checkout_tree(
+        &self,
+        repo: &git2::Repository,
+        from: &git2::Tree,
+        to: &git2::Tree,
+    ) -> Result<()>;
+
+    /// Applies the diff to the working tree and then to the index.
+    /// Either may still have other changes to the same files, as long as
+    /// they don't overlap.
+    fn apply(&self, repo: &git2::Repository, diff: &git2::Diff) -> Result<()>;
+
+    /// Whether `--and-rebase` may squash the fixups in memory (see
+    /// `rebase::autosquash()`), instead of running `git rebase`.
+    fn autosquash_in_memory(&self) -> bool;
+
+    /// Creates a commit like `Repository::commit()`, without updating
+    /// any reference. Signed commits are made by `sign::commit()`.
+    fn commit(
+        &self,
+        repo: &git2::Repository,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<git2::Oid>;
+}
+
+/// Everything goes through libgit2.
+pub struct Libgit2;
+
+/// Everything goes through git itself, for repositories that libgit2
+/// can't read the index of (eg: ones with a sparse index), or
+/// misbehaves in otherwise. Objects and references are still looked up
+/// through libgit2.
+pub struct Cli;
+
+impl stack::Revwalk for Libgit2 {
+    fn first_parents<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        hidden: &[git2::Oid],
+    ) -> Result<Box<dyn Iterator<Item = Result<git2::Oid>> + 'r>> {
+        stack::first_parents(repo, hidden)
+    }
+}
+
+impl Backend for Libgit2 {
+    fn name(&self) -> &'static str {
+        "libgit2"
+    }
+
+    fn autosquash_in_memory(&self) -> bool {
+        true
+    }
+
+    fn diff_index<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        tree: &git2::Tree,
+        target: DiffTarget,
+        settings: DiffSettings,
+        pathspecs: &[&str],
+    ) -> Result<git2::Diff<'r>> {
+        let mut options = default_diff_options(settings.algorithm, settings.context);
+        for pathspec in pathspecs {
+            options.pathspec(pathspec);
+        }
+        Ok(match target {
+            DiffTarget::Index => repo.diff_tree_to_index(Some(tree), None, Some(&mut options))?,
+            DiffTarget::Workdir => {
+                repo.diff_tree_to_workdir_with_index(Some(tree), Some(&mut options))?
+            }
+        })
+    }
+
+    fn diff_commit<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        commit: &git2::Commit,
+        settings: DiffSettings,
+    ) -> Result<git2::Diff<'r>> {
+        let parent = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree()?),
+        };
+        let mut diff = repo.diff_tree_to_tree(
+            parent.as_ref(),
+            Some(&commit.tree()?),
+            Some(&mut default_diff_options(
+                settings.algorithm,
+                settings.context,
+            )),
+        )?;
+        // detect renames, so that hunks can be followed back to the
+        // commits that touched the file under its old name
+        if let Err(e) = diff.find_similar(Some(git2::DiffFindOptions::new().renames(true))) {
+            // which reads every created and deleted file, most of which
+            // a partial clone doesn't have
+            if !settings.partial_clone || e.code() != git2::ErrorCode::NotFound {
+                return Err(e.into());
+            }
+        }
+        Ok(diff)
+    }
+
+    fn write_index_tree(&self, repo: &git2::Repository) -> Result<git2::Oid> {
+        let mut index = repo.index()?;
+        if !index.iter().any(|entry| is_intent_to_add(&entry)) {
+            return Ok(index.write_tree()?);
+        }
+        let mut staged = git2::Index::new()?;
+        for entry in index.iter().filter(|entry| !is_intent_to_add(entry)) {
+            staged.add(&entry)?;
+        }
+        Ok(staged.write_tree_to(repo)?)
+    }
+
+    fn intent_to_add_entries(&self, repo: &git2::Repository) -> Result<Vec<git2::IndexEntry>> {
+        Ok(repo.index()?.iter().filter(is_intent_to_add).collect())
+    }
+
+    fn read_index_tree(
+        &self,
+        repo: &git2::Repository,
+        tree: &git2::Tree,
+        intent_to_add: &[git2::IndexEntry],
+    ) -> Result<()> {
+        let mut index = repo.index()?;
+        index.read_tree(tree)?;
+        let mut intent_to_add = missing_from(tree, intent_to_add).peekable();
+        if intent_to_add.peek().is_some() {
+            // libgit2 refuses to add entries for missing objects
+            repo.blob(&[])?;
+        }
+        for entry in intent_to_add {
+            index.add(entry)?;
+        }
+        Ok(index.write()?)
+    }
+
+    fn stage_tracked(&self, repo: &git2::Repository, pathspecs: &[&str]) -> Result<()> {
+        let mut index = repo.index()?;
+        index.update_all(pathspecs, None)?;
+        Ok(index.write()?)
+    }
+
+    fn has_changes(&self, repo: &git2::Repository) -> Result<bool> {
+        let head_tree = repo.head()?.peel_to_tree()?;
+        let diff = repo.diff_tree_to_workdir_with_index(Some(&head_tree), None)?;
+        Ok(diff.deltas().len() > 0)
+    }
+
+    fn checkout_tree(
+        &self,
+        repo: &git2::Repository,
+        _from: &git2::Tree,
+        to: &git2::Tree,
+    ) -> Result<()> {
+        // the index and the working tree match `from`, so nothing is
+        // lost by overwriting them
+        Ok(repo.checkout_tree(
+            to.as_object(),
+            Some(git2::build::CheckoutBuilder::new().force()),
+        )?)
+    }
+
+    fn apply(&self, repo: &git2::Repository, diff: &git2::Diff) -> Result<()> {
+        repo.apply(diff, git2::ApplyLocation::WorkDir, None)?;
+        Ok(repo.apply(diff, git2::ApplyLocation::Index, None)?)
+    }
+
+    fn commit(
+        &self,
+        repo: &git2::Repository,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<git2::Oid> {
+        Ok(repo.commit(None, author, committer, message, tree, parents)?)
+    }
+}
+
+impl stack::Revwalk for Cli {
+    fn first_parents<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        hidden: &[git2::Oid],
+    ) -> Result<Box<dyn Iterator<Item = Result<git2::Oid>> + 'r>> {
+        Ok(Box::new(git_cli::rev_list(repo, hidden)?))
+    }
+}
+
+impl Backend for Cli {
+    fn name(&self) -> &'static str {
+        "cli"
+    }
+
+    fn autosquash_in_memory(&self) -> bool {
+        false
+    }
+
+    fn diff_index<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        tree: &git2::Tree,
+        target: DiffTarget,
+        settings: DiffSettings,
+        pathspecs: &[&str],
+    ) -> Result<git2::Diff<'r>> {
+        git_cli::diff(
+            repo,
+            tree,
+            target,
+            settings.algorithm,
+            settings.context,
+            pathspecs,
+        )
+    }
+
+    fn diff_commit<'r>(
+        &self,
+        repo: &'r git2::Repository,
+        commit: &git2::Commit,
+        settings: DiffSettings,
+    ) -> Result<git2::Diff<'r>> {
+        let parent = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree_id()),
+        };
+        git_cli::diff_tree(
+            repo,
+            parent,
+            commit.tree_id(),
+            settings.algorithm,
+            settings.context,
+        )
+    }
+
+    fn write_index_tree(&self, repo: &git2::Repository) -> Result<git2::Oid> {
+        git_cli::write_tree(repo)
+    }
+
+    fn intent_to_add_entries(&self, repo: &git2::Repository) -> Result<Vec<git2::IndexEntry>> {
+        // git doesn't tell their mode
+        Ok(git_cli::intent_to_add(repo)?
+            .into_iter()
+            .map(|path| intent_to_add_entry(path, git2::FileMode::Blob.into()))
+            .collect())
+    }
+
+    fn read_index_tree(
+        &self,
+        repo: &git2::Repository,
+        tree: &git2::Tree,
+        intent_to_add: &[git2::IndexEntry],
+    ) -> Result<()> {
+        let paths: Vec<_> = missing_from(tree, intent_to_add)
+            .map(|entry| entry.path.clone())
+            .collect();
+        git_cli::read_tree(repo, tree.id())?;
+        git_cli::add_intent_to_add(repo, &paths)
+    }
+
+    fn stage_tracked(&self, repo: &git2::Repository, pathspecs: &[&str]) -> Result<()> {
+        git_cli::add_tracked(repo, pathspecs)
+    }
+
+    fn has_changes(&self, repo: &git2::Repository) -> Result<bool> {
+        git_cli::has_changes(repo)
+    }
+
+    fn checkout_tree(
+        &self,
+        repo: &git2::Repository,
+        from: &git2::Tree,
+        to: &git2::Tree,
+    ) -> Result<()> {
+        git_cli::checkout_tree(repo, from.id(), to.id())
+    }
+
+    fn apply(&self, repo: &git2::Repository, diff: &git2::Diff) -> Result<()> {
+        let mut patch = Vec::new();
+        diff.print(git2::DiffFormat::Patch, |_, _, line| {
+            if let origin @ ('+' | '-' | ' ') = line.origin() {
+                patch.push(origin as u8);
+            }
+            patch.extend_from_slice(line.content());
+            true
+        })?;
+        git_cli::apply(repo, &patch, false)?;
+        git_cli::apply(repo, &patch, true)
+    }
+
+    fn commit(
+        &self,
+        repo: &git2::Repository,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: &str,
+        tree: &git2::Tree,
+        parents: &[&git2::Commit],
+    ) -> Result<git2::Oid> {
+        let parents: Vec<_> = parents.iter().map(|parent| parent.id()).collect();
+        git_cli::commit_tree(repo, author, committer, message, tree.id(), &parents)
+    }
+}
+
+/// Returns the intent-to-add entries for the files that the tree
+/// doesn't have.
+fn missing_from<'a>(
+    tree: &'a git2::Tree,
+    intent_to_add: &'a [git2::IndexEntry],
+) -> impl Iterator<Item = &'a git2::IndexEntry> {
+    intent_to_add.iter().filter(move |entry| {
+        path_from_bytes(&entry.path).is_none_or(|path| tree.get_path(path).is_err())
+    })
+}
+
+/// Returns the backend that `absorb.backend` names, if any.
+pub fn by_name(name: &str) -> Option<&'static dyn Backend> {
+    match name {
+        "libgit2" => Some(&Libgit2),
+        "cli" => Some(&Cli),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stack::Revwalk;
+    use std::path::Path;
+
+    fn init_repo() -> (tempfile::TempDir, git2::Repository) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "nobody").unwrap();
+        config.set_str("user.email", "nobody@example.com").unwrap();
+        (dir, repo)
+    }
+
+    fn commit_files(repo: &git2::Repository, files: &[(&str, &str)], message: &str) -> git2::Oid {
+        let mut index = repo.index().unwrap();
+        index.clear().unwrap();
+        for (path, content) in files {
+            std::fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = repo.signature().unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_cli_commits_like_libgit2() {
+        let (_dir, repo) = init_repo();
+        let head = repo
+            .find_commit(commit_files(&repo, &[("file", "a\n")], "first"))
+            .unwrap();
+        let tree = head.tree().unwrap();
+        let author =
+            git2::Signature::new("Some One", "one@example.com", &git2::Time::new(1, -90)).unwrap();
+        let committer =
+            git2::Signature::new("Other", "other@example.com", &git2::Time::new(1_000, 60))
+                .unwrap();
+        let message = "fixup! first\n\nbody\n";
+        assert_eq!(
+            Cli.commit(&repo, &author, &committer, message, &tree, &[&head])
+                .unwrap(),
+            Libgit2
+                .commit(&repo, &author, &committer, message, &tree, &[&head])
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cli_walks_like_libgit2() {
+        let (_dir, repo) = init_repo();
+        let first = commit_files(&repo, &[("file", "a\n")], "first");
+        commit_files(&repo, &[("file", "b\n")], "second");
+        commit_files(&repo, &[("file", "c\n")], "third");
+        let walk = |backend: &dyn Backend, hidden: &[git2::Oid]| {
+            backend
+                .first_parents(&repo, hidden)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(walk(&Cli, &[]), walk(&Libgit2, &[]));
+        assert_eq!(walk(&Cli, &[]).len(), 3);
+        assert_eq!(walk(&Cli, &[first]), walk(&Libgit2, &[first]));
+        assert_eq!(walk(&Cli, &[first]).len(), 2);
+        // the walk may stop before git is done
+        assert_eq!(Cli.first_parents(&repo, &[]).unwrap().count(), 3);
+        assert!(Cli.first_parents(&repo, &[]).unwrap().next().is_some());
+    }
+
+    #[test]
+    fn test_cli_diffs_commits_with_renames() {
+        let (_dir, repo) = init_repo();
+        let content = "a\nb\nc\nd\ne\n";
+        let first = commit_files(&repo, &[("old", content)], "first");
+        std::fs::remove_file(repo.workdir().unwrap().join("old")).unwrap();
+        let second = commit_files(&repo, &[("new", "a\nb\nc\nd\ne2\n")], "second");
+        let settings = DiffSettings {
+            algorithm: DiffAlgorithm::Myers,
+            context: 0,
+            partial_clone: false,
+        };
+        for backend in [&Cli as &dyn Backend, &Libgit2] {
+            let diff = backend
+                .diff_commit(&repo, &repo.find_commit(second).unwrap(), settings)
+                .unwrap();
+            let deltas: Vec<_> = diff
+                .deltas()
+                .map(|delta| {
+                    (
+                        delta.status(),
+                        delta.old_file().path().unwrap().to_owned(),
+                        delta.new_file().path().unwrap().to_owned(),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                deltas,
+                vec![(git2::Delta::Renamed, "old".into(), "new".into())],
+                "{}",
+                backend.name()
+            );
+            // the root commit adds everything
+            let diff = backend
+                .diff_commit(&repo, &repo.find_commit(first).unwrap(), settings)
+                .unwrap();
+            assert_eq!(diff.deltas().len(), 1, "{}", backend.name());
+            assert_eq!(
+                diff.deltas().next().unwrap().status(),
+                git2::Delta::Added,
+                "{}",
+                backend.name()
+            );
+        }
+    }
+}
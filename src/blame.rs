@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Result};
+
+use crate::i18n::tr;
+use crate::owned;
+
+/// The number of unchanged lines around a hunk that are also blamed,
+/// matching the default context of `git diff`.
+const BLAME_CONTEXT: usize = 3;
+
+/// Blames a file as of HEAD, without looking past the parent of the
+/// oldest commit in the stack.
+pub fn blame_file<'repo>(
+    repo: &'repo git2::Repository,
+    path: &[u8],
+    stack: &[&git2::Commit],
+) -> Result<git2::Blame<'repo>> {
+    let path = crate::path_from_bytes(path)
+        .ok_or_else(|| anyhow!("{}", tr("cannot blame non-utf8 path")))?;
+    let mut options = git2::BlameOptions::new();
+    options.newest_commit(repo.head()?.peel_to_commit()?.id());
+    if let Some(parent) = stack.last().and_then(|oldest| oldest.parents().next()) {
+        options.oldest_commit(parent.id());
+    }
+    Ok(repo.blame_file(path, Some(&mut options))?)
+}
+
+/// Finds the newest (or, if `oldest` is set, the oldest) commit in the
+/// stack that last touched the lines the hunk removes, or the unchanged
+/// lines around it.
+///
+/// The hunk's removed side must be in HEAD's coordinates.
+pub fn fallback_target<'a, 'repo>(
+    blame: &git2::Blame,
+    hunk: &owned::Hunk,
+    stack: &[&'a git2::Commit<'repo>],
+    oldest: bool,
+) -> Option<&'a git2::Commit<'repo>> {
+    // a hunk that only adds lines has a removed start pointing at the
+    // line it was inserted after, otherwise the start is the first
+    // removed line
+    let (first, last) = if hunk.removed.lines.is_empty() {
+        (hunk.removed.start, hunk.removed.start + 1)
+    } else {
+        (
+            hunk.removed.start,
+            hunk.removed.start + hunk.removed.lines.len() - 1,
+        )
+    };
+    let first = first.saturating_sub(BLAME_CONTEXT).max(1);
+    let last = last + BLAME_CONTEXT;
+
+    let blamed: Vec<_> = (first..=last)
+        .filter_map(|lineno| blame.get_line(lineno))
+        // lines that weren't changed within the stack are attributed to
+        // the boundary commit
+        .filter(|blame_hunk| !blame_hunk.is_boundary())
+        .map(|blame_hunk| blame_hunk.final_commit_id())
+        .collect();
+    let is_blamed = |commit: &&&git2::Commit| blamed.contains(&commit.id());
+    if oldest {
+        stack.iter().rev().find(is_blamed).copied()
+    } else {
+        stack.iter().find(is_blamed).copied()
+    }
+}
@@ -0,0 +1,224 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::i18n::trf;
+use crate::owned;
+
+/// The directory in `.git` that parsed diffs of stack commits are
+/// cached in, one file per commit.
+const CACHE_DIR: &str = "absorb-cache";
+
+/// Bumped whenever the format of the cached files changes.
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct CachedDiff {
+    version: u32,
+    options: String,
+    patches: Vec<CachedPatch>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPatch {
+    old_path: Vec<u8>,
+    old_id: String,
+    old_mode: i32,
+    new_path: Vec<u8>,
+    new_id: String,
+    new_mode: i32,
+    status: String,
+    is_binary: bool,
+    hunks: Vec<(CachedBlock, CachedBlock)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedBlock {
+    start: usize,
+    lines: Vec<Vec<u8>>,
+    trailing_newline: bool,
+}
+
+fn path(repo: &git2::Repository, commit: git2::Oid) -> PathBuf {
+    repo.path().join(CACHE_DIR).join(commit.to_string())
+}
+
+/// Returns the cached diff of the commit, if it was cached with the
+/// same `options` (a description of the options it was diffed with).
+/// Its lines are deduplicated with the `interner`.
+pub fn load(
+    repo: &git2::Repository,
+    commit: git2::Oid,
+    options: &str,
+    interner: &mut owned::Interner,
+) -> Result<Option<owned::Diff>> {
+    let content = match std::fs::read(path(repo, commit)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let cached: CachedDiff = serde_json::from_slice(&content)?;
+    if cached.version != CACHE_VERSION || cached.options != options {
+        return Ok(None);
+    }
+    let patches = cached
+        .patches
+        .into_iter()
+        .map(|patch| {
+            Ok(owned::Patch {
+                old_path: patch.old_path,
+                old_id: git2::Oid::from_str(&patch.old_id)?,
+                old_mode: file_mode(patch.old_mode)?,
+                new_path: patch.new_path,
+                new_id: git2::Oid::from_str(&patch.new_id)?,
+                new_mode: file_mode(patch.new_mode)?,
+                status: delta(&patch.status)?,
+                is_binary: patch.is_binary,
+                hunks: patch
+                    .hunks
+                    .into_iter()
+                    .map(|(added, removed)| owned::Hunk {
+                        added: block(added, interner),
+                        removed: block(removed, interner),
+                    })
+                    .collect(),
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Some(owned::Diff::from_patches(patches)?))
+}
+
+/// Caches the diff of the commit, along with the options it was
+/// diffed with.
+pub fn store(
+    repo: &git2::Repository,
+    commit: git2::Oid,
+    options: &str,
+    diff: &owned::Diff,
+) -> Result<()> {
+    let cached = CachedDiff {
+        version: CACHE_VERSION,
+        options: options.to_owned(),
+        patches: diff
+            .iter()
+            .map(|patch| CachedPatch {
+                old_path: patch.old_path.clone(),
+                old_id: patch.old_id.to_string(),
+                old_mode: patch.old_mode.into(),
+                new_path: patch.new_path.clone(),
+                new_id: patch.new_id.to_string(),
+                new_mode: patch.new_mode.into(),
+                status: format!("{:?}", patch.status),
+                is_binary: patch.is_binary,
+                hunks: patch
+                    .hunks
+                    .iter()
+                    .map(|hunk| (cached_block(&hunk.added), cached_block(&hunk.removed)))
+                    .collect(),
+            })
+            .collect(),
+    };
+    let path = path(repo, commit);
+    // unwrap() is safe here, as the path is always in the cache dir
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    // write to a temporary file first, so that a concurrent run never
+    // reads a partially written file
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&cached)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Removes the cached diffs of every commit that is not in `keep`, so
+/// that the cache only ever holds the diffs of the current stack.
+pub fn prune(repo: &git2::Repository, keep: &[git2::Oid]) -> Result<()> {
+    let entries = match std::fs::read_dir(repo.path().join(CACHE_DIR)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let kept = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| git2::Oid::from_str(name).ok())
+            .is_some_and(|oid| keep.contains(&oid));
+        if !kept {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+fn cached_block(block: &owned::Block) -> CachedBlock {
+    CachedBlock {
+        start: block.start,
+        lines: block.lines.iter().map(|line| line.to_vec()).collect(),
+        trailing_newline: block.trailing_newline,
+    }
+}
+
+fn block(block: CachedBlock, interner: &mut owned::Interner) -> owned::Block {
+    owned::Block {
+        start: block.start,
+        lines: Arc::new(
+            block
+                .lines
+                .iter()
+                .map(|line| interner.intern(line))
+                .collect(),
+        ),
+        trailing_newline: block.trailing_newline,
+    }
+}
+
+fn file_mode(mode: i32) -> Result<git2::FileMode> {
+    [
+        git2::FileMode::Unreadable,
+        git2::FileMode::Tree,
+        git2::FileMode::Blob,
+        git2::FileMode::BlobExecutable,
+        git2::FileMode::Link,
+        git2::FileMode::Commit,
+    ]
+    .iter()
+    .copied()
+    .find(|&candidate| i32::from(candidate) == mode)
+    .ok_or_else(|| {
+        anyhow!(
+            "{}",
+            trf("invalid file mode {} in cache", &[&format!("{:o}", mode)])
+        )
+    })
+}
+
+fn delta(status: &str) -> Result<git2::Delta> {
+    [
+        git2::Delta::Unmodified,
+        git2::Delta::Added,
+        git2::Delta::Deleted,
+        git2::Delta::Modified,
+        git2::Delta::Renamed,
+        git2::Delta::Copied,
+        git2::Delta::Ignored,
+        git2::Delta::Untracked,
+        git2::Delta::Typechange,
+        git2::Delta::Unreadable,
+        git2::Delta::Conflicted,
+    ]
+    .iter()
+    .copied()
+    .find(|candidate| format!("{:?}", candidate) == status)
+    .ok_or_else(|| {
+        anyhow!(
+            "{}",
+            trf(
+                "invalid delta status {} in cache",
+                &[&format!("{:?}", status)]
+            )
+        )
+    })
+}
@@ -0,0 +1,349 @@
+use anyhow::{anyhow, Result};
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use crate::i18n::{tr, trf};
+
+/// The seeds git hashes paths with for its changed-path Bloom filters.
+const BLOOM_SEEDS: (u32, u32) = (0x293a_e76f, 0x7e64_6e2c);
+
+/// The commit-graph files of a repository (a single file, or each layer
+/// of a split chain), read for their changed-path Bloom filters.
+///
+/// These are written by `git commit-graph write --changed-paths`, and
+/// allow telling that a commit definitely didn't change a path without
+/// diffing its tree.
+pub struct CommitGraph {
+    layers: Vec<Layer>,
+}
+
+struct Layer {
+    data: Vec<u8>,
+    fanout: usize,
+    oids: usize,
+    /// The number of commits in the layer, from the last entry of the
+    /// fanout.
+    count: usize,
+    bloom: Option<Bloom>,
+}
+
+struct Bloom {
+    index: usize,
+    data: usize,
+    data_len: usize,
+    hash_version: u32,
+    num_hashes: u32,
+}
+
+impl CommitGraph {
+    /// Reads the commit-graph of the repository, if it has one.
+    pub fn open(repo: &git2::Repository) -> Result<Option<Self>> {
+        // a linked worktree shares the objects of the main repository
+        let git_dir = match std::fs::read_to_string(repo.path().join("commondir")) {
+            Ok(common) => repo.path().join(common.trim_end()),
+            Err(_) => repo.path().to_path_buf(),
+        };
+        let info = git_dir.join("objects").join("info");
+        let mut layers = Vec::new();
+        match std::fs::read_to_string(info.join("commit-graphs").join("commit-graph-chain")) {
+            Ok(chain) => {
+                for hash in chain.lines().filter(|line| !line.is_empty()) {
+                    let path = info
+                        .join("commit-graphs")
+                        .join(format!("graph-{}.graph", hash));
+                    layers.push(Layer::read(&path)?);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match Layer::read(&info.join("commit-graph")) {
+                    Ok(layer) => layers.push(layer),
+                    Err(e) if not_found(&e) => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Some(CommitGraph { layers }))
+    }
+
+    /// Returns whether any layer has Bloom filters at all.
+    pub fn has_bloom_filters(&self) -> bool {
+        self.layers.iter().any(|layer| layer.bloom.is_some())
+    }
+
+    /// Returns `Some(false)` if the commit definitely didn't change the
+    /// path (or any file under it, for a directory) compared to its
+    /// first parent, `Some(true)` if it may have, and `None` if that
+    /// isn't known.
+    pub fn changed(&self, commit: git2::Oid, path: &[u8]) -> Option<bool> {
+        if path.is_empty() {
+            return None;
+        }
+        self.layers
+            .iter()
+            .find_map(|layer| layer.position(commit).map(|pos| (layer, pos)))
+            .and_then(|(layer, pos)| layer.changed(pos, path))
+    }
+}
+
+fn not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+impl Layer {
+    fn read(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path)?;
+        if data.len() < 8 || &data[..4] != b"CGPH" {
+            return Err(anyhow!(
+                "{}",
+                trf("bad commit-graph signature in {}", &[&path.display()])
+            ));
+        }
+        if data[4] != 1 {
+            return Err(anyhow!(
+                "{}",
+                trf("unsupported commit-graph version {}", &[&data[4]])
+            ));
+        }
+        if data[5] != 1 {
+            return Err(anyhow!(
+                "{}",
+                trf("unsupported commit-graph hash version {}", &[&data[5]])
+            ));
+        }
+        let num_chunks = data[6] as usize;
+
+        let mut chunks = Vec::with_capacity(num_chunks);
+        for idx in 0..=num_chunks {
+            let entry = data
+                .get(8 + idx * 12..8 + (idx + 1) * 12)
+                .ok_or_else(|| anyhow!("{}", tr("truncated commit-graph chunk table")))?;
+            let offset = u64::from_be_bytes(entry[4..].try_into().unwrap()) as usize;
+            chunks.push((&entry[..4], offset));
+        }
+        let truncated = || {
+            anyhow!(
+                "{}",
+                trf("truncated commit-graph in {}", &[&path.display()])
+            )
+        };
+        // the chunks follow each other, and the table ends with the
+        // offset of the end of the last one
+        if chunks.windows(2).any(|pair| pair[0].1 > pair[1].1) || chunks[num_chunks].1 > data.len()
+        {
+            return Err(truncated());
+        }
+        let chunk = |id: &[u8]| -> Option<(usize, usize)> {
+            let idx = chunks[..num_chunks]
+                .iter()
+                .position(|(chunk_id, _)| *chunk_id == id)?;
+            let (start, end) = (chunks[idx].1, chunks[idx + 1].1);
+            Some((start, end - start))
+        };
+
+        let (fanout, fanout_len) =
+            chunk(b"OIDF").ok_or_else(|| anyhow!("{}", tr("commit-graph has no fanout")))?;
+        if fanout_len < 256 * 4 {
+            return Err(truncated());
+        }
+        let count = be_u32(&data, fanout + 255 * 4) as usize;
+        let (oids, oids_len) =
+            chunk(b"OIDL").ok_or_else(|| anyhow!("{}", tr("commit-graph has no oids")))?;
+        if oids_len / 20 < count {
+            return Err(truncated());
+        }
+        let bloom = match (chunk(b"BIDX"), chunk(b"BDAT")) {
+            (Some((_, index_len)), _) if index_len / 4 < count => return Err(truncated()),
+            (Some((index, _)), Some((data_start, data_len))) if data_len >= 12 => Some(Bloom {
+                index,
+                data: data_start + 12,
+                data_len: data_len - 12,
+                hash_version: be_u32(&data, data_start),
+                num_hashes: be_u32(&data, data_start + 4),
+            }),
+            _ => None,
+        };
+
+        Ok(Layer {
+            data,
+            fanout,
+            oids,
+            count,
+            bloom,
+        })
+    }
+
+    fn position(&self, commit: git2::Oid) -> Option<usize> {
+        let first = commit.as_bytes()[0] as usize;
+        let start = match first {
+            0 => 0,
+            _ => be_u32(&self.data, self.fanout + (first - 1) * 4) as usize,
+        };
+        let end = be_u32(&self.data, self.fanout + first * 4) as usize;
+        let oid = |idx: usize| &self.data[self.oids + idx * 20..self.oids + (idx + 1) * 20];
+        // the fanout of a corrupt file may not be sorted
+        let hi = end.min(self.count);
+        let (mut lo, mut hi) = (start.min(hi), hi);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match oid(mid).cmp(commit.as_bytes()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    fn changed(&self, pos: usize, path: &[u8]) -> Option<bool> {
+        let bloom = self.bloom.as_ref()?;
+        // the first version of the filters hashed bytes above 0x7f
+        // as signed chars, which isn't worth replicating
+        if bloom.hash_version == 1 && !path.is_ascii() {
+            return None;
+        }
+        if bloom.hash_version != 1 && bloom.hash_version != 2 || bloom.num_hashes == 0 {
+            return None;
+        }
+        let start = match pos {
+            0 => 0,
+            _ => be_u32(&self.data, bloom.index + (pos - 1) * 4) as usize,
+        };
+        let end = be_u32(&self.data, bloom.index + pos * 4) as usize;
+        // an empty filter is one that wasn't computed
+        if start >= end || end > bloom.data_len {
+            return None;
+        }
+        let filter = &self.data[bloom.data + start..bloom.data + end];
+        Some(bloom_contains(filter, bloom.num_hashes, path))
+    }
+}
+
+/// Returns whether the filter may contain the path, which git adds to
+/// the filter along with each of its leading directories.
+fn bloom_contains(filter: &[u8], num_hashes: u32, path: &[u8]) -> bool {
+    let bits = filter.len() as u64 * 8;
+    let hash0 = murmur3(BLOOM_SEEDS.0, path);
+    let hash1 = murmur3(BLOOM_SEEDS.1, path);
+    (0..num_hashes).all(|idx| {
+        let pos = u64::from(hash0.wrapping_add(idx.wrapping_mul(hash1))) % bits;
+        filter[(pos / 8) as usize] & (1 << (pos % 8)) != 0
+    })
+}
+
+fn be_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// The 32-bit MurmurHash3 that git hashes Bloom filter keys with.
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let scramble = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut hash = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        hash ^= scramble(u32::from_le_bytes(block.try_into().unwrap()));
+        hash = hash
+            .rotate_left(13)
+            .wrapping_mul(5)
+            .wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0u32, |k, &byte| (k << 8) | u32::from(byte));
+        hash ^= scramble(k);
+    }
+
+    hash ^= data.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a commit-graph with changed-path filters for a repository
+    /// of a few commits, and returns its bytes.
+    fn write_commit_graph(dir: &Path) -> Vec<u8> {
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .current_dir(dir)
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .env("GIT_AUTHOR_NAME", "nobody")
+                .env("GIT_AUTHOR_EMAIL", "nobody@example.com")
+                .env("GIT_COMMITTER_NAME", "nobody")
+                .env("GIT_COMMITTER_EMAIL", "nobody@example.com")
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        for idx in 0..3 {
+            std::fs::write(dir.join("file"), idx.to_string()).unwrap();
+            git(&["add", "file"]);
+            git(&["commit", "-q", "-m", "commit"]);
+        }
+        git(&["commit-graph", "write", "--reachable", "--changed-paths"]);
+        std::fs::read(dir.join(".git/objects/info/commit-graph")).unwrap()
+    }
+
+    #[test]
+    fn test_truncated_commit_graph() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = write_commit_graph(dir.path());
+        let path = dir.path().join("commit-graph");
+        std::fs::write(&path, &data).unwrap();
+        let layer = Layer::read(&path).unwrap();
+        assert_eq!(layer.count, 3);
+        assert!(layer.bloom.is_some());
+
+        // the chunk table still points past the end of the file, up to
+        // the trailing checksum, which isn't read
+        for len in 0..data.len() - 20 {
+            std::fs::write(&path, &data[..len]).unwrap();
+            assert!(Layer::read(&path).is_err(), "{} bytes", len);
+        }
+
+        // a fanout chunk shorter than its 256 entries, followed by the
+        // rest of the file
+        let num_chunks = data[6] as usize;
+        let table = |idx: usize| 8 + idx * 12;
+        let fanout = (0..num_chunks)
+            .find(|&idx| &data[table(idx)..table(idx) + 4] == b"OIDF")
+            .unwrap();
+        let mut corrupt = data.clone();
+        let start = u64::from_be_bytes(
+            data[table(fanout) + 4..table(fanout) + 12]
+                .try_into()
+                .unwrap(),
+        );
+        corrupt[table(fanout + 1) + 4..table(fanout + 1) + 12]
+            .copy_from_slice(&(start + 512).to_be_bytes());
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(Layer::read(&path).is_err());
+    }
+
+    #[test]
+    fn test_murmur3() {
+        // the values from git's own tests of its implementation
+        assert_eq!(murmur3(0, b""), 0x0000_0000);
+        assert_eq!(murmur3(0, b"Hello world!"), 0x627b_0c2c);
+        assert_eq!(
+            murmur3(0, b"The quick brown fox jumps over the lazy dog"),
+            0x2e4f_f723
+        );
+    }
+}
@@ -0,0 +1,796 @@
+use anyhow::{anyhow, Result};
+
+use crate::backend::{self, Backend};
+use crate::config_file::Layered;
+use crate::i18n::trf;
+use crate::stack::{BaseStrategy, StackOptions};
+use crate::{
+    AmbiguityStrategy, AuthorStrategy, DateStrategy, DiffAlgorithm, FixupKind, LargeFiles,
+};
+
+pub const MAX_STACK_CONFIG_NAME: &str = "absorb.maxStack";
+pub use crate::stack::MAX_STACK;
+/// How many more commits than `absorb.maxStack` the stack may have in
+/// all, since only the ones that touched the staged files count.
+pub const MAX_STACK_WALK_FACTOR: usize = 10;
+
+pub const BLAME_FALLBACK_CONFIG_NAME: &str = "absorb.blameFallback";
+
+pub const INTRA_LINE_CONFIG_NAME: &str = "absorb.intraLine";
+
+pub const NEW_FILES_CONFIG_NAME: &str = "absorb.newFiles";
+
+pub const FIXUP_KIND_CONFIG_NAME: &str = "absorb.fixupKind";
+
+pub const FIXUP_MESSAGE_FORMAT_CONFIG_NAME: &str = "absorb.fixupMessageFormat";
+
+pub const FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME: &str = "absorb.fixupTargetAlwaysSHA";
+
+pub const SIGN_OFF_CONFIG_NAME: &str = "absorb.signOff";
+
+pub const COPY_TRAILERS_CONFIG_NAME: &str = "absorb.copyTrailers";
+
+pub const RUN_HOOKS_CONFIG_NAME: &str = "absorb.runHooks";
+
+pub const AUTHOR_STRATEGY_CONFIG_NAME: &str = "absorb.authorStrategy";
+
+pub const COMMITTER_DATE_CONFIG_NAME: &str = "absorb.committerDate";
+
+pub const PUSHED_REMOTES_CONFIG_NAME: &str = "absorb.pushedRemotes";
+
+pub const PROTECTED_BRANCHES_CONFIG_NAME: &str = "absorb.protectedBranches";
+
+pub const EXCLUDE_PATHS_CONFIG_NAME: &str = "absorb.excludePaths";
+
+pub const DEFAULT_BASE_CONFIG_NAME: &str = "absorb.defaultBase";
+
+pub const BASE_STRATEGY_CONFIG_NAME: &str = "absorb.baseStrategy";
+
+pub const TRAVERSE_MERGES_CONFIG_NAME: &str = "absorb.traverseMerges";
+
+pub const AUTHOR_ALLOWLIST_CONFIG_NAME: &str = "absorb.authorAllowlist";
+
+pub const SKIP_MESSAGE_PATTERN_CONFIG_NAME: &str = "absorb.skipMessagePattern";
+
+pub const ONLY_MESSAGE_PATTERN_CONFIG_NAME: &str = "absorb.onlyMessagePattern";
+
+pub const AMBIGUITY_STRATEGY_CONFIG_NAME: &str = "absorb.ambiguityStrategy";
+
+pub const TARGET_COMMAND_CONFIG_NAME: &str = "absorb.targetCommand";
+
+pub const JOBS_CONFIG_NAME: &str = "absorb.jobs";
+
+pub const CACHE_CONFIG_NAME: &str = "absorb.cache";
+
+pub const ABSORB_BINARY_CONFIG_NAME: &str = "absorb.absorbBinary";
+
+pub const SKIP_GENERATED_CONFIG_NAME: &str = "absorb.skipGenerated";
+
+pub const SKIP_BULK_COMMITS_CONFIG_NAME: &str = "absorb.skipBulkCommits";
+
+pub const DETECT_MOVES_CONFIG_NAME: &str = "absorb.detectMoves";
+
+pub const SEMANTIC_HUNKS_CONFIG_NAME: &str = "absorb.semanticHunks";
+
+pub const REFLOG_PREFIX_CONFIG_NAME: &str = "absorb.reflogPrefix";
+pub const REFLOG_PREFIX: &str = "absorb";
+
+pub const MAX_FILE_SIZE_CONFIG_NAME: &str = "absorb.maxFileSize";
+pub const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+pub const MAX_LINE_LENGTH_CONFIG_NAME: &str = "absorb.maxLineLength";
+pub const MAX_LINE_LENGTH: usize = 4096;
+
+pub const LARGE_FILES_CONFIG_NAME: &str = "absorb.largeFiles";
+
+pub const BACKEND_CONFIG_NAME: &str = "absorb.backend";
+
+pub const DIFF_ALGORITHM_CONFIG_NAME: &str = "diff.algorithm";
+
+pub const DIFF_CONTEXT_CONFIG_NAME: &str = "diff.context";
+
+pub const QUOTE_PATH_CONFIG_NAME: &str = "core.quotePath";
+
+pub const FSMONITOR_CONFIG_NAME: &str = "core.fsmonitor";
+
+pub const PARTIAL_CLONE_CONFIG_NAME: &str = "extensions.partialClone";
+
+pub const PROMISOR_CONFIG_PATTERN: &str = r"^remote\..*\.promisor$";
+
+pub fn max_stack(config: &Layered) -> usize {
+    match config.get_i64(MAX_STACK_CONFIG_NAME) {
+        Ok(max_stack) if max_stack > 0 => max_stack as usize,
+        _ => MAX_STACK,
+    }
+}
+
+/// Returns the number of threads that files are commuted on, where 0
+/// means one for each CPU.
+pub fn jobs(config: &Layered) -> usize {
+    match config.get_i64(JOBS_CONFIG_NAME) {
+        Ok(jobs) if jobs > 0 => jobs as usize,
+        _ => 0,
+    }
+}
+
+/// Returns the size in bytes above which a staged file isn't commuted
+/// line by line, where 0 means there is no limit.
+pub fn max_file_size(config: &Layered) -> u64 {
+    match config.get_i64(MAX_FILE_SIZE_CONFIG_NAME) {
+        Ok(size) if size >= 0 => size as u64,
+        _ => MAX_FILE_SIZE,
+    }
+}
+
+/// Returns the length in bytes of a changed line above which a staged
+/// file isn't commuted line by line, where 0 means there is no limit.
+pub fn max_line_length(config: &Layered) -> usize {
+    match config.get_i64(MAX_LINE_LENGTH_CONFIG_NAME) {
+        Ok(length) if length >= 0 => length as usize,
+        _ => MAX_LINE_LENGTH,
+    }
+}
+
+pub fn large_files(config: &Layered) -> Result<LargeFiles> {
+    match config.get_string(LARGE_FILES_CONFIG_NAME) {
+        Ok(large_files) => match large_files.as_str() {
+            "wholeFile" => Ok(LargeFiles::WholeFile),
+            "skip" => Ok(LargeFiles::Skip),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected wholeFile or skip",
+                    &[&format!("{:?}", large_files), &LARGE_FILES_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(LargeFiles::WholeFile),
+    }
+}
+
+pub fn diff_algorithm(config: &Layered) -> Result<DiffAlgorithm> {
+    match config.get_string(DIFF_ALGORITHM_CONFIG_NAME) {
+        Ok(algorithm) => match algorithm.as_str() {
+            "default" | "myers" => Ok(DiffAlgorithm::Myers),
+            "minimal" => Ok(DiffAlgorithm::Minimal),
+            "patience" => Ok(DiffAlgorithm::Patience),
+            "histogram" => Ok(DiffAlgorithm::Histogram),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected myers, minimal, patience or histogram",
+                    &[&format!("{:?}", algorithm), &DIFF_ALGORITHM_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(DiffAlgorithm::Myers),
+    }
+}
+
+/// Returns the number of context lines that hunks are grouped by, which
+/// is 0 unless `diff.context` is set (rather than git's default of 3),
+/// so that hunks are as small as possible.
+pub fn diff_context(config: &Layered) -> Result<u32> {
+    match config.get_i64(DIFF_CONTEXT_CONFIG_NAME) {
+        Ok(context) if context >= 0 => Ok(context as u32),
+        Ok(context) => Err(anyhow!(
+            "{}",
+            trf(
+                "invalid value {} for {}, expected a non-negative number",
+                &[&context, &DIFF_CONTEXT_CONFIG_NAME]
+            )
+        )),
+        Err(_) => Ok(0),
+    }
+}
+
+pub fn backend(config: &Layered) -> Result<&'static dyn Backend> {
+    match config.get_string(BACKEND_CONFIG_NAME) {
+        Ok(name) => match backend::by_name(&name) {
+            Some(backend) => Ok(backend),
+            // gitoxide isn't a dependency yet, so there's nothing to
+            // select, but the value is reserved for it
+            None if name == "gix" => Err(anyhow!(
+                "{}",
+                trf(
+                    "the gix backend for {} is not available in this build",
+                    &[&BACKEND_CONFIG_NAME]
+                )
+            )),
+            None => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected libgit2 or cli",
+                    &[&format!("{:?}", name), &BACKEND_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(&backend::Libgit2),
+    }
+}
+
+/// Returns whether paths with bytes outside of ASCII are quoted, which
+/// git does by default.
+pub fn quote_path(repo: &git2::Repository) -> bool {
+    repo.config()
+        .and_then(|config| config.get_bool(QUOTE_PATH_CONFIG_NAME))
+        .unwrap_or(true)
+}
+
+/// Returns the remote that a partial clone fetches its missing objects
+/// from, if the repository is one.
+pub fn promisor_remote(repo: &git2::Repository) -> Option<String> {
+    let config = repo.config().ok()?;
+    if let Ok(remote) = config.get_string(PARTIAL_CLONE_CONFIG_NAME) {
+        return Some(remote).filter(|remote| !remote.is_empty());
+    }
+    // newer versions of git mark the remote instead
+    let mut entries = config.entries(Some(PROMISOR_CONFIG_PATTERN)).ok()?;
+    while let Some(entry) = entries.next() {
+        let entry = entry.ok()?;
+        let remote = entry
+            .name()
+            .and_then(|name| name.strip_prefix("remote."))
+            .and_then(|name| name.strip_suffix(".promisor"));
+        if let (Some(remote), Ok(true)) = (remote, config.get_bool(entry.name()?)) {
+            return Some(remote.to_owned());
+        }
+    }
+    None
+}
+
+/// Returns whether git uses a filesystem monitor (its builtin daemon,
+/// or a hook like the one for watchman) to tell which files of the
+/// working tree changed, instead of checking every file.
+pub fn fsmonitor(repo: &git2::Repository) -> bool {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    match config.get_bool(FSMONITOR_CONFIG_NAME) {
+        Ok(enabled) => enabled,
+        // the path of a hook
+        Err(_) => config
+            .get_string(FSMONITOR_CONFIG_NAME)
+            .is_ok_and(|hook| !hook.is_empty()),
+    }
+}
+
+pub fn cache(config: &Layered) -> bool {
+    config.get_bool(CACHE_CONFIG_NAME).unwrap_or(true)
+}
+
+pub fn blame_fallback(config: &Layered) -> bool {
+    config.get_bool(BLAME_FALLBACK_CONFIG_NAME).unwrap_or(false)
+}
+
+pub fn intra_line(config: &Layered) -> bool {
+    config.get_bool(INTRA_LINE_CONFIG_NAME).unwrap_or(false)
+}
+
+pub fn new_files(config: &Layered) -> bool {
+    config.get_bool(NEW_FILES_CONFIG_NAME).unwrap_or(false)
+}
+
+pub fn absorb_binary(config: &Layered) -> bool {
+    config.get_bool(ABSORB_BINARY_CONFIG_NAME).unwrap_or(false)
+}
+
+/// Returns whether files that the attributes mark as generated (or not
+/// to be diffed) are left in the index.
+pub fn skip_generated(config: &Layered) -> bool {
+    config.get_bool(SKIP_GENERATED_CONFIG_NAME).unwrap_or(true)
+}
+
+/// Returns whether hunks are cut where functions start, for the files
+/// whose language (or diff driver) tells where that is.
+pub fn semantic_hunks(config: &Layered) -> bool {
+    config.get_bool(SEMANTIC_HUNKS_CONFIG_NAME).unwrap_or(false)
+}
+
+/// Returns the message of the reflog entry of a ref update for
+/// `action`, after `absorb.reflogPrefix` (`absorb` by default), like
+/// `absorb: autosquash rebase`. An empty prefix leaves the action alone.
+pub fn reflog_message(config: &Layered, action: &str) -> String {
+    let prefix = config
+        .get_string(REFLOG_PREFIX_CONFIG_NAME)
+        .unwrap_or_else(|_| REFLOG_PREFIX.to_owned());
+    if prefix.is_empty() {
+        action.to_owned()
+    } else {
+        format!("{}: {}", prefix, action)
+    }
+}
+
+/// Returns whether a block of lines that is moved within a file follows
+/// the lines it was moved from into their commit.
+pub fn detect_moves(config: &Layered) -> bool {
+    config.get_bool(DETECT_MOVES_CONFIG_NAME).unwrap_or(false)
+}
+
+/// Returns the number of files above which the commits that touched
+/// them are never fixed up, where 0 means there is no limit.
+pub fn skip_bulk_commits(config: &Layered) -> usize {
+    match config.get_i64(SKIP_BULK_COMMITS_CONFIG_NAME) {
+        Ok(files) if files > 0 => files as usize,
+        _ => 0,
+    }
+}
+
+pub fn fixup_kind(config: &Layered) -> Result<FixupKind> {
+    match config.get_string(FIXUP_KIND_CONFIG_NAME) {
+        Ok(kind) => match kind.as_str() {
+            "fixup" => Ok(FixupKind::Fixup),
+            "squash" => Ok(FixupKind::Squash),
+            "amend" => Ok(FixupKind::Amend),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected fixup, squash or amend",
+                    &[&format!("{:?}", kind), &FIXUP_KIND_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(FixupKind::Fixup),
+    }
+}
+
+pub fn ambiguity_strategy(config: &Layered) -> Result<AmbiguityStrategy> {
+    match config.get_string(AMBIGUITY_STRATEGY_CONFIG_NAME) {
+        Ok(strategy) => match strategy.as_str() {
+            "nearest" => Ok(AmbiguityStrategy::Nearest),
+            "oldest" => Ok(AmbiguityStrategy::Oldest),
+            "prompt" => Ok(AmbiguityStrategy::Prompt),
+            "skip" => Ok(AmbiguityStrategy::Skip),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected nearest, oldest, prompt or skip",
+                    &[&format!("{:?}", strategy), &AMBIGUITY_STRATEGY_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(AmbiguityStrategy::Skip),
+    }
+}
+
+/// Returns the command that picks the target of each hunk.
+pub fn target_command(config: &Layered) -> Option<String> {
+    config
+        .get_string(TARGET_COMMAND_CONFIG_NAME)
+        .ok()
+        .filter(|command| !command.is_empty())
+}
+
+pub fn fixup_message_format(config: &Layered) -> Option<String> {
+    config.get_string(FIXUP_MESSAGE_FORMAT_CONFIG_NAME).ok()
+}
+
+pub fn fixup_by_sha(config: &Layered) -> bool {
+    config
+        .get_bool(FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME)
+        .unwrap_or(false)
+}
+
+pub fn signoff(config: &Layered) -> bool {
+    config.get_bool(SIGN_OFF_CONFIG_NAME).unwrap_or(false)
+}
+
+/// Returns the comma-separated remotes whose remote-tracking branches
+/// mark commits as pushed, or `None` for all remotes.
+pub fn pushed_remotes(config: &Layered) -> Option<Vec<String>> {
+    config
+        .get_string(PUSHED_REMOTES_CONFIG_NAME)
+        .map(|remotes| {
+            remotes
+                .split(',')
+                .map(str::trim)
+                .filter(|remote| !remote.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .ok()
+}
+
+/// Returns the comma-separated glob patterns of the branches that must
+/// not be absorbed into.
+pub fn protected_branches(config: &Layered) -> Vec<String> {
+    config
+        .get_string(PROTECTED_BRANCHES_CONFIG_NAME)
+        .map(|patterns| {
+            patterns
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the comma-separated gitignore-style patterns of the staged
+/// files that are never absorbed.
+pub fn exclude_paths(config: &Layered) -> Vec<String> {
+    config
+        .get_string(EXCLUDE_PATHS_CONFIG_NAME)
+        .map(|patterns| {
+            patterns
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn default_base(config: &Layered) -> Option<String> {
+    config.get_string(DEFAULT_BASE_CONFIG_NAME).ok()
+}
+
+/// Returns the comma-separated emails of authors whose commits are
+/// treated as the user's own.
+pub fn author_allowlist(config: &Layered) -> Vec<String> {
+    config
+        .get_string(AUTHOR_ALLOWLIST_CONFIG_NAME)
+        .map(|emails| {
+            emails
+                .split(',')
+                .map(str::trim)
+                .filter(|email| !email.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the comma-separated trailer tokens to copy from the target
+/// of each fixup.
+pub fn copy_trailers(config: &Layered) -> Vec<String> {
+    config
+        .get_string(COPY_TRAILERS_CONFIG_NAME)
+        .map(|tokens| {
+            tokens
+                .split(',')
+                .map(str::trim)
+                .filter(|token| !token.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn run_hooks(config: &Layered) -> bool {
+    config.get_bool(RUN_HOOKS_CONFIG_NAME).unwrap_or(false)
+}
+
+pub fn author_strategy(config: &Layered) -> Result<AuthorStrategy> {
+    match config.get_string(AUTHOR_STRATEGY_CONFIG_NAME) {
+        Ok(strategy) => match strategy.as_str() {
+            "me" => Ok(AuthorStrategy::Me),
+            "target" => Ok(AuthorStrategy::Target),
+            "env" => Ok(AuthorStrategy::Env),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected me, target or env",
+                    &[&format!("{:?}", strategy), &AUTHOR_STRATEGY_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(AuthorStrategy::Me),
+    }
+}
+
+pub fn committer_date(config: &Layered) -> Result<DateStrategy> {
+    match config.get_string(COMMITTER_DATE_CONFIG_NAME) {
+        Ok(strategy) => match strategy.as_str() {
+            "now" => Ok(DateStrategy::Now),
+            "target" => Ok(DateStrategy::Target),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected now or target",
+                    &[&format!("{:?}", strategy), &COMMITTER_DATE_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(DateStrategy::Now),
+    }
+}
+
+pub fn base_strategy(config: &Layered) -> Result<BaseStrategy> {
+    match config.get_string(BASE_STRATEGY_CONFIG_NAME) {
+        Ok(strategy) => match strategy.as_str() {
+            "merge-base" => Ok(BaseStrategy::MergeBase),
+            "fork-point" => Ok(BaseStrategy::ForkPoint),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected merge-base or fork-point",
+                    &[&format!("{:?}", strategy), &BASE_STRATEGY_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(BaseStrategy::MergeBase),
+    }
+}
+
+/// Returns whether the stack continues past merge commits, along their
+/// first parent.
+pub fn traverse_merges(config: &Layered) -> Result<bool> {
+    match config.get_string(TRAVERSE_MERGES_CONFIG_NAME) {
+        Ok(traverse) => match traverse.as_str() {
+            "stop" => Ok(false),
+            "first-parent" => Ok(true),
+            _ => Err(anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}, expected stop or first-parent",
+                    &[&format!("{:?}", traverse), &TRAVERSE_MERGES_CONFIG_NAME]
+                )
+            )),
+        },
+        Err(_) => Ok(false),
+    }
+}
+
+/// Returns the options of the stack that are configured, leaving the
+/// ones given on the command line to their defaults.
+pub fn stack_options(config: &Layered) -> Result<StackOptions<'static>> {
+    Ok(StackOptions {
+        max_stack: max_stack(config),
+        default_base: default_base(config),
+        base_strategy: base_strategy(config)?,
+        pushed_remotes: pushed_remotes(config),
+        author_allowlist: author_allowlist(config),
+        traverse_merges: traverse_merges(config)?,
+        ..Default::default()
+    })
+}
+
+fn message_pattern(config: &Layered, name: &str) -> Result<Option<regex::Regex>> {
+    match config.get_string(name) {
+        Ok(pattern) => regex::Regex::new(&pattern).map(Some).map_err(|e| {
+            anyhow!(
+                "{}",
+                trf(
+                    "invalid value {} for {}: {}",
+                    &[&format!("{:?}", pattern), &name, &e]
+                )
+            )
+        }),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Returns the pattern of the subjects of commits that are never fixed up.
+pub fn skip_message_pattern(config: &Layered) -> Result<Option<regex::Regex>> {
+    message_pattern(config, SKIP_MESSAGE_PATTERN_CONFIG_NAME)
+}
+
+/// Returns the pattern of the subjects of the only commits that are
+/// fixed up.
+pub fn only_message_pattern(config: &Layered) -> Result<Option<regex::Regex>> {
+    message_pattern(config, ONLY_MESSAGE_PATTERN_CONFIG_NAME)
+}
+
+/// The kinds of values of the settings, to validate them.
+enum Kind {
+    Boolean,
+    /// A number that is at least this.
+    Number(i64),
+    /// Anything goes.
+    Text,
+    /// One of a few values, or a pattern, which its function parses.
+    Parsed(fn(&Layered) -> Result<()>),
+}
+
+/// Every setting that git-absorb reads from its configuration, which
+/// can also be set in `config_file`'s files.
+const SETTINGS: &[(&str, Kind)] = &[
+    (MAX_STACK_CONFIG_NAME, Kind::Number(1)),
+    (BLAME_FALLBACK_CONFIG_NAME, Kind::Boolean),
+    (INTRA_LINE_CONFIG_NAME, Kind::Boolean),
+    (NEW_FILES_CONFIG_NAME, Kind::Boolean),
+    (
+        FIXUP_KIND_CONFIG_NAME,
+        Kind::Parsed(|config| fixup_kind(config).map(drop)),
+    ),
+    (FIXUP_MESSAGE_FORMAT_CONFIG_NAME, Kind::Text),
+    (FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, Kind::Boolean),
+    (SIGN_OFF_CONFIG_NAME, Kind::Boolean),
+    (COPY_TRAILERS_CONFIG_NAME, Kind::Text),
+    (RUN_HOOKS_CONFIG_NAME, Kind::Boolean),
+    (
+        AUTHOR_STRATEGY_CONFIG_NAME,
+        Kind::Parsed(|config| author_strategy(config).map(drop)),
+    ),
+    (
+        COMMITTER_DATE_CONFIG_NAME,
+        Kind::Parsed(|config| committer_date(config).map(drop)),
+    ),
+    (PUSHED_REMOTES_CONFIG_NAME, Kind::Text),
+    (PROTECTED_BRANCHES_CONFIG_NAME, Kind::Text),
+    (EXCLUDE_PATHS_CONFIG_NAME, Kind::Text),
+    (DEFAULT_BASE_CONFIG_NAME, Kind::Text),
+    (
+        BASE_STRATEGY_CONFIG_NAME,
+        Kind::Parsed(|config| base_strategy(config).map(drop)),
+    ),
+    (
+        TRAVERSE_MERGES_CONFIG_NAME,
+        Kind::Parsed(|config| traverse_merges(config).map(drop)),
+    ),
+    (AUTHOR_ALLOWLIST_CONFIG_NAME, Kind::Text),
+    (
+        SKIP_MESSAGE_PATTERN_CONFIG_NAME,
+        Kind::Parsed(|config| skip_message_pattern(config).map(drop)),
+    ),
+    (
+        ONLY_MESSAGE_PATTERN_CONFIG_NAME,
+        Kind::Parsed(|config| only_message_pattern(config).map(drop)),
+    ),
+    (
+        AMBIGUITY_STRATEGY_CONFIG_NAME,
+        Kind::Parsed(|config| ambiguity_strategy(config).map(drop)),
+    ),
+    (TARGET_COMMAND_CONFIG_NAME, Kind::Text),
+    (JOBS_CONFIG_NAME, Kind::Number(0)),
+    (CACHE_CONFIG_NAME, Kind::Boolean),
+    (ABSORB_BINARY_CONFIG_NAME, Kind::Boolean),
+    (SKIP_GENERATED_CONFIG_NAME, Kind::Boolean),
+    (SKIP_BULK_COMMITS_CONFIG_NAME, Kind::Number(0)),
+    (DETECT_MOVES_CONFIG_NAME, Kind::Boolean),
+    (SEMANTIC_HUNKS_CONFIG_NAME, Kind::Boolean),
+    (REFLOG_PREFIX_CONFIG_NAME, Kind::Text),
+    (MAX_FILE_SIZE_CONFIG_NAME, Kind::Number(0)),
+    (MAX_LINE_LENGTH_CONFIG_NAME, Kind::Number(0)),
+    (
+        LARGE_FILES_CONFIG_NAME,
+        Kind::Parsed(|config| large_files(config).map(drop)),
+    ),
+    (
+        BACKEND_CONFIG_NAME,
+        Kind::Parsed(|config| backend(config).map(drop)),
+    ),
+    (
+        DIFF_ALGORITHM_CONFIG_NAME,
+        Kind::Parsed(|config| diff_algorithm(config).map(drop)),
+    ),
+    (
+        DIFF_CONTEXT_CONFIG_NAME,
+        Kind::Parsed(|config| diff_context(config).map(drop)),
+    ),
+];
+
+/// The command line flags and options that stand for a setting. They have
+/// no `GIT_ABSORB_*` variable of their own: the setting's is read with the
+/// rest of the configuration, and the flag wins over it.
+pub const FLAG_SETTINGS: &[(&str, &str)] = &[
+    ("blame-fallback", BLAME_FALLBACK_CONFIG_NAME),
+    ("intra-line", INTRA_LINE_CONFIG_NAME),
+    ("detect-moves", DETECT_MOVES_CONFIG_NAME),
+    ("new-files", NEW_FILES_CONFIG_NAME),
+    ("squash", FIXUP_KIND_CONFIG_NAME),
+    ("amend", FIXUP_KIND_CONFIG_NAME),
+    ("fixup-by-sha", FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME),
+    ("signoff", SIGN_OFF_CONFIG_NAME),
+    ("hooks", RUN_HOOKS_CONFIG_NAME),
+    ("jobs", JOBS_CONFIG_NAME),
+    ("skip-bulk", SKIP_BULK_COMMITS_CONFIG_NAME),
+    ("diff-algorithm", DIFF_ALGORITHM_CONFIG_NAME),
+    ("unified", DIFF_CONTEXT_CONFIG_NAME),
+];
+
+/// The placeholders of `absorb.fixupMessageFormat`.
+const MESSAGE_PLACEHOLDERS: &[&str] = &["prefix", "subject", "sha", "path"];
+
+/// The number of single-character edits between two names, to suggest
+/// the setting that a misspelled one was meant to be.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Checks every setting of git-absorb, in git's configuration, in the
+/// configuration files and in the environment, instead of falling back
+/// to the default of those that are misspelled or invalid. Returns a
+/// description of each problem.
+pub fn validate(config: &Layered) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    for (name, source) in config.names()? {
+        if SETTINGS
+            .iter()
+            .any(|(known, _)| known.eq_ignore_ascii_case(&name))
+        {
+            continue;
+        }
+        let closest = SETTINGS
+            .iter()
+            .map(|(known, _)| (distance(&known.to_lowercase(), &name.to_lowercase()), known))
+            .min();
+        problems.push(match closest {
+            Some((distance, known)) if distance <= 2 => format!(
+                "{} in {} is not a setting of git absorb, did you mean {}?",
+                name, source, known
+            ),
+            _ => format!("{} in {} is not a setting of git absorb", name, source),
+        });
+    }
+
+    let raw = |name: &str| config.get_string(name).unwrap_or_default();
+    let set = |e: &git2::Error| e.code() != git2::ErrorCode::NotFound;
+    for &(name, ref kind) in SETTINGS {
+        match kind {
+            Kind::Boolean => match config.get_bool(name) {
+                Err(e) if set(&e) => problems.push(format!(
+                    "invalid value {:?} for {}, expected a boolean",
+                    raw(name),
+                    name
+                )),
+                _ => {}
+            },
+            Kind::Number(min) => match config.get_i64(name) {
+                Ok(number) if number < *min => problems.push(format!(
+                    "invalid value {} for {}, expected a number of at least {}",
+                    number, name, min
+                )),
+                Err(e) if set(&e) => problems.push(format!(
+                    "invalid value {:?} for {}, expected a number",
+                    raw(name),
+                    name
+                )),
+                _ => {}
+            },
+            Kind::Text => {}
+            Kind::Parsed(parse) => {
+                if let Err(e) = parse(config) {
+                    problems.push(e.to_string());
+                }
+            }
+        }
+    }
+
+    // settings that undo each other
+    if let Some(format) = fixup_message_format(config) {
+        let mut rest = format.as_str();
+        while let Some(start) = rest.find('{') {
+            rest = &rest[start + 1..];
+            if let Some(end) = rest.find('}') {
+                if !MESSAGE_PLACEHOLDERS.contains(&&rest[..end]) {
+                    problems.push(format!(
+                        "{} has an unknown placeholder {{{}}}, expected {{{}}}",
+                        FIXUP_MESSAGE_FORMAT_CONFIG_NAME,
+                        &rest[..end],
+                        MESSAGE_PLACEHOLDERS.join("}, {")
+                    ));
+                }
+            }
+        }
+        if fixup_by_sha(config) {
+            problems.push(format!(
+                "{} is ignored, since {} is set",
+                FIXUP_TARGET_ALWAYS_SHA_CONFIG_NAME, FIXUP_MESSAGE_FORMAT_CONFIG_NAME
+            ));
+        }
+    }
+    if target_command(config).is_some()
+        && matches!(ambiguity_strategy(config), Ok(AmbiguityStrategy::Prompt))
+    {
+        problems.push(format!(
+            "{} = prompt asks for targets that {} then replaces",
+            AMBIGUITY_STRATEGY_CONFIG_NAME, TARGET_COMMAND_CONFIG_NAME
+        ));
+    }
+    Ok(problems)
+}
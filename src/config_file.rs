@@ -0,0 +1,613 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::i18n::{tr, trf};
+
+/// The name of the configuration file in the git directory.
+pub const REPO_FILE_NAME: &str = "absorb.toml";
+
+/// A value of a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// The value as git would have it in its configuration, where a list
+    /// is separated by commas.
+    fn to_config_string(&self) -> String {
+        match self {
+            Value::String(value) => value.clone(),
+            Value::Integer(value) => value.to_string(),
+            Value::Boolean(value) => value.to_string(),
+            Value::Array(values) => values
+                .iter()
+                .map(Value::to_config_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+}
+
+/// A configuration file in TOML, whose tables are the sections of git's
+/// configuration (eg: `[absorb]`, `[diff]`). Keys outside of any table
+/// are in the `absorb` section. Like in git, the section and the key
+/// are matched without regard to case.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigFile {
+    path: Option<PathBuf>,
+    values: HashMap<String, Value>,
+}
+
+impl ConfigFile {
+    /// Reads the file, which may not exist.
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => {
+                return Err(anyhow!(
+                    "{}",
+                    trf("could not read {}: {}", &[&path.display(), &e])
+                ))
+            }
+        };
+        let mut file = Self::parse(&content).map_err(|e| {
+            anyhow!(
+                "{}",
+                trf("invalid config file {}: {}", &[&path.display(), &e])
+            )
+        })?;
+        file.path = Some(path.to_owned());
+        Ok(file)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut values = HashMap::new();
+        let mut table = "absorb".to_owned();
+        let mut lines = content.lines().enumerate();
+        while let Some((idx, line)) = lines.next() {
+            let line_number = idx + 1;
+            let mut rest = skip_whitespace(line);
+            if rest.is_empty() || rest.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = rest.strip_prefix('[') {
+                let (keys, after) = parse_keys(header)
+                    .map_err(|e| anyhow!("{}", trf("line {}: {}", &[&line_number, &e])))?;
+                match skip_whitespace(after).strip_prefix(']') {
+                    Some(after) if is_end(after) => table = keys.join("."),
+                    _ => return Err(anyhow!("{}", trf("line {}: expected ]", &[&line_number]))),
+                }
+                continue;
+            }
+
+            let (keys, after) = parse_keys(rest)
+                .map_err(|e| anyhow!("{}", trf("line {}: {}", &[&line_number, &e])))?;
+            rest = match skip_whitespace(after).strip_prefix('=') {
+                Some(after) => skip_whitespace(after),
+                None => return Err(anyhow!("{}", trf("line {}: expected =", &[&line_number]))),
+            };
+            // arrays can go on over several lines
+            let mut text = rest.to_owned();
+            let value = loop {
+                match parse_value(&text) {
+                    Ok((value, after)) if is_end(after) => break value,
+                    Ok(_) => {
+                        return Err(anyhow!(
+                            "{}",
+                            trf("line {}: expected the end of line", &[&line_number])
+                        ))
+                    }
+                    Err(Incomplete) if text.starts_with('[') => match lines.next() {
+                        Some((_, line)) => {
+                            text.push('\n');
+                            text.push_str(line);
+                        }
+                        None => {
+                            return Err(anyhow!(
+                                "{}",
+                                trf("line {}: unterminated array", &[&line_number])
+                            ))
+                        }
+                    },
+                    Err(Incomplete) => {
+                        return Err(anyhow!(
+                            "{}",
+                            trf("line {}: invalid value", &[&line_number])
+                        ));
+                    }
+                }
+            };
+            let name = format!("{}.{}", table, keys.join(".")).to_lowercase();
+            if values.insert(name, value).is_some() {
+                return Err(anyhow!(
+                    "{}",
+                    trf("line {}: duplicate key", &[&line_number])
+                ));
+            }
+        }
+        Ok(ConfigFile { path: None, values })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn get(&self, name: &str) -> Option<&Value> {
+        self.values.get(&name.to_lowercase())
+    }
+}
+
+/// The value couldn't be parsed, maybe because it goes on after the end
+/// of the line.
+struct Incomplete;
+
+fn skip_whitespace(text: &str) -> &str {
+    text.trim_start_matches([' ', '\t'])
+}
+
+fn is_end(text: &str) -> bool {
+    let text = skip_whitespace(text);
+    text.is_empty() || text.starts_with('#')
+}
+
+/// Parses dotted keys (eg: `absorb.maxStack` or `"diff".algorithm`).
+fn parse_keys(text: &str) -> Result<(Vec<String>, &str)> {
+    let mut keys = Vec::new();
+    let mut rest = skip_whitespace(text);
+    loop {
+        let (key, after) = if rest.starts_with(['"', '\'']) {
+            match parse_string(rest) {
+                Ok((Value::String(key), after)) => (key, after),
+                _ => return Err(anyhow!("{}", tr("invalid key"))),
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                return Err(anyhow!("{}", tr("expected a key")));
+            }
+            (rest[..end].to_owned(), &rest[end..])
+        };
+        keys.push(key);
+        rest = skip_whitespace(after);
+        match rest.strip_prefix('.') {
+            Some(after) => rest = skip_whitespace(after),
+            None => return Ok((keys, rest)),
+        }
+    }
+}
+
+fn parse_value(text: &str) -> std::result::Result<(Value, &str), Incomplete> {
+    let text = skip_whitespace(text);
+    if text.starts_with(['"', '\'']) {
+        return parse_string(text);
+    }
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut values = Vec::new();
+        loop {
+            rest = skip_array_whitespace(rest);
+            if let Some(after) = rest.strip_prefix(']') {
+                return Ok((Value::Array(values), after));
+            }
+            let (value, after) = parse_value(rest)?;
+            values.push(value);
+            rest = skip_array_whitespace(after);
+            if let Some(after) = rest.strip_prefix(',') {
+                rest = after;
+            } else if !rest.starts_with(']') {
+                return Err(Incomplete);
+            }
+        }
+    }
+    let end = text
+        .find(|c: char| c.is_whitespace() || c == ',' || c == ']' || c == '#')
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    match word {
+        "true" => Ok((Value::Boolean(true), rest)),
+        "false" => Ok((Value::Boolean(false), rest)),
+        _ => word
+            .replace('_', "")
+            .parse()
+            .map(|value| (Value::Integer(value), rest))
+            .map_err(|_| Incomplete),
+    }
+}
+
+/// Skips the whitespace, newlines and comments between the values of an
+/// array.
+fn skip_array_whitespace(mut text: &str) -> &str {
+    loop {
+        text = text.trim_start();
+        match text.strip_prefix('#') {
+            Some(comment) => text = comment.find('\n').map_or("", |end| &comment[end..]),
+            None => return text,
+        }
+    }
+}
+
+fn parse_string(text: &str) -> std::result::Result<(Value, &str), Incomplete> {
+    if let Some(rest) = text.strip_prefix('\'') {
+        // a literal string has no escapes
+        let end = rest.find(['\'', '\n']).ok_or(Incomplete)?;
+        if rest[end..].starts_with('\n') {
+            return Err(Incomplete);
+        }
+        return Ok((Value::String(rest[..end].to_owned()), &rest[end + 1..]));
+    }
+    let rest = text.strip_prefix('"').ok_or(Incomplete)?;
+    let mut value = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Ok((Value::String(value), &rest[idx + 1..])),
+            '\n' => return Err(Incomplete),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('r') => value.push('\r'),
+                Some(escape @ ('u' | 'U')) => {
+                    let len = if escape == 'u' { 4 } else { 8 };
+                    let hex: String = chars.by_ref().take(len).map(|(_, c)| c).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or(Incomplete)?;
+                    value.push(c);
+                }
+                _ => return Err(Incomplete),
+            },
+            c => value.push(c),
+        }
+    }
+    Err(Incomplete)
+}
+
+/// Where the per-user configuration file is:
+/// `$XDG_CONFIG_HOME/git-absorb/config.toml`, or
+/// `~/.config/git-absorb/config.toml`.
+pub fn user_path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME")
+                .filter(|path| !path.is_empty())
+                .map(|home| PathBuf::from(home).join(".config"))
+        })?;
+    Some(config_home.join("git-absorb").join("config.toml"))
+}
+
+/// The prefix of the environment variables that override settings.
+pub const ENV_PREFIX: &str = "GIT_ABSORB_";
+
+fn env_vars() -> HashMap<String, String> {
+    std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect()
+}
+
+/// The environment variable that overrides the setting: the key in
+/// snake case for `absorb` (eg: `GIT_ABSORB_MAX_STACK` for
+/// `absorb.maxStack`), and prefixed with the section for the others (eg:
+/// `GIT_ABSORB_DIFF_ALGORITHM` for `diff.algorithm`).
+pub fn env_name(name: &str) -> String {
+    let name = name.strip_prefix("absorb.").unwrap_or(name);
+    let mut ret = ENV_PREFIX.to_owned();
+    let mut previous = None;
+    for c in name.chars() {
+        if c == '.' || c == '-' {
+            ret.push('_');
+        } else {
+            if c.is_ascii_uppercase() && previous.is_some_and(|p: char| p.is_ascii_lowercase()) {
+                ret.push('_');
+            }
+            ret.push(c.to_ascii_uppercase());
+        }
+        previous = Some(c);
+    }
+    ret
+}
+
+/// The configuration of git-absorb, looked up in order in the
+/// `GIT_ABSORB_*` environment variables, in the repository's
+/// `absorb.toml`, in git's configuration, and in the per-user
+/// `config.toml`.
+///
+/// It is read once for each run, and then passed to whatever needs a
+/// setting (see `config`).
+pub struct Layered {
+    env: HashMap<String, String>,
+    repo_file: ConfigFile,
+    git: Option<git2::Config>,
+    user_file: ConfigFile,
+}
+
+fn not_found(name: &str) -> git2::Error {
+    git2::Error::new(
+        git2::ErrorCode::NotFound,
+        git2::ErrorClass::Config,
+        format!("config value '{}' was not found", name),
+    )
+}
+
+fn invalid(name: &str, expected: &str) -> git2::Error {
+    git2::Error::new(
+        git2::ErrorCode::GenericError,
+        git2::ErrorClass::Config,
+        format!("config value '{}' is not {}", name, expected),
+    )
+}
+
+impl Layered {
+    /// Opens the configuration of the repository, reading the
+    /// environment and the configuration files, which fails if one of
+    /// them is invalid.
+    pub fn open(repo: &git2::Repository) -> Result<Self> {
+        let user_file = match user_path() {
+            Some(path) => ConfigFile::read(&path)?,
+            None => ConfigFile::default(),
+        };
+        Ok(Layered {
+            env: env_vars(),
+            repo_file: ConfigFile::read(&repo.path().join(REPO_FILE_NAME))?,
+            git: repo.config().ok(),
+            user_file,
+        })
+    }
+
+    /// Like `open()`, but leaves out the files that are invalid, so that
+    /// the rest of the configuration can still be reported.
+    pub fn open_lenient(repo: &git2::Repository) -> Self {
+        Layered {
+            env: env_vars(),
+            repo_file: ConfigFile::read(&repo.path().join(REPO_FILE_NAME)).unwrap_or_default(),
+            git: repo.config().ok(),
+            user_file: user_path()
+                .and_then(|path| ConfigFile::read(&path).ok())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Reads only the `GIT_ABSORB_*` variables, for the command line,
+    /// which is parsed before there is a repository.
+    pub fn env_only() -> Self {
+        Layered {
+            env: env_vars(),
+            repo_file: ConfigFile::default(),
+            git: None,
+            user_file: ConfigFile::default(),
+        }
+    }
+
+    /// Returns the variable that stands for a command line flag or
+    /// option, with its value if it is set. The flags that stand for a
+    /// setting have none (see `config::FLAG_SETTINGS`), so that there is
+    /// one variable for each setting.
+    pub fn env_flag(&self, flag: &str) -> Option<(String, &str)> {
+        if config::FLAG_SETTINGS.iter().any(|&(name, _)| name == flag) {
+            return None;
+        }
+        let var = env_name(flag);
+        let value = self.env.get(&var)?;
+        Some((var, value.as_str()))
+    }
+
+    /// Returns the configuration files that exist.
+    pub fn files(&self) -> Vec<&Path> {
+        [&self.repo_file, &self.user_file]
+            .iter()
+            .filter_map(|file| file.path())
+            .collect()
+    }
+
+    /// Returns the names of the settings in the configuration files, and
+    /// in the `absorb` section of git's configuration, with where each
+    /// is set.
+    pub fn names(&self) -> Result<Vec<(String, String)>> {
+        let mut names = Vec::new();
+        for file in &[&self.repo_file, &self.user_file] {
+            let path = match file.path() {
+                Some(path) => path,
+                None => continue,
+            };
+            let mut keys: Vec<_> = file.values.keys().cloned().collect();
+            keys.sort();
+            names.extend(
+                keys.into_iter()
+                    .map(|key| (key, path.display().to_string())),
+            );
+        }
+        if let Some(config) = &self.git {
+            let mut entries = config.entries(Some(r"^absorb\."))?;
+            while let Some(entry) = entries.next() {
+                let name = (
+                    entry?.name().unwrap_or_default().to_owned(),
+                    "git's configuration".to_owned(),
+                );
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// Returns the value of the first of the files (or git) that has one.
+    fn find<T>(
+        &self,
+        name: &str,
+        from_file: impl Fn(&Value) -> Option<T>,
+        from_git: impl Fn(&git2::Config) -> std::result::Result<T, git2::Error>,
+        expected: &str,
+    ) -> std::result::Result<T, git2::Error> {
+        let parse = |value: &Value| from_file(value).ok_or_else(|| invalid(name, expected));
+        if let Some(value) = self.env.get(&env_name(name)) {
+            return parse(&Value::String(value.clone()));
+        }
+        let in_file = |file: &ConfigFile| file.get(name).map(parse);
+        if let Some(value) = in_file(&self.repo_file) {
+            return value;
+        }
+        if let Some(git) = &self.git {
+            match from_git(git) {
+                Err(e) if e.code() == git2::ErrorCode::NotFound => {}
+                value => return value,
+            }
+        }
+        in_file(&self.user_file).unwrap_or_else(|| Err(not_found(name)))
+    }
+
+    pub fn get_string(&self, name: &str) -> std::result::Result<String, git2::Error> {
+        self.find(
+            name,
+            |value| Some(value.to_config_string()),
+            |git| git.get_string(name),
+            "a string",
+        )
+    }
+
+    pub fn get_i64(&self, name: &str) -> std::result::Result<i64, git2::Error> {
+        self.find(
+            name,
+            |value| match value {
+                Value::Integer(value) => Some(*value),
+                Value::String(value) => value.parse().ok(),
+                _ => None,
+            },
+            |git| git.get_i64(name),
+            "a number",
+        )
+    }
+
+    pub fn get_bool(&self, name: &str) -> std::result::Result<bool, git2::Error> {
+        self.find(
+            name,
+            |value| match value {
+                Value::Boolean(value) => Some(*value),
+                Value::String(value) => git2::Config::parse_bool(value.as_str()).ok(),
+                _ => None,
+            },
+            |git| git.get_bool(name),
+            "a boolean",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_file() {
+        let file = ConfigFile::parse(
+            r#"
+# keys outside of a table are absorb's
+maxStack = 1_000
+[absorb]
+protectedBranches = [
+    "main", # the default branch
+    'release/*',
+]
+"fixupMessageFormat" = "fixup! {summary}\n"
+[diff]
+algorithm = "patience" # trailing comment
+"#,
+        )
+        .unwrap();
+        assert_eq!(file.get("absorb.maxstack"), Some(&Value::Integer(1000)));
+        assert_eq!(
+            file.get("absorb.protectedBranches")
+                .map(Value::to_config_string),
+            Some("main,release/*".to_owned())
+        );
+        assert_eq!(
+            file.get("absorb.fixupMessageFormat"),
+            Some(&Value::String("fixup! {summary}\n".to_owned()))
+        );
+        assert_eq!(
+            file.get("diff.algorithm"),
+            Some(&Value::String("patience".to_owned()))
+        );
+
+        for invalid in &[
+            "maxStack",
+            "maxStack = ",
+            "maxStack = 1 2",
+            "maxStack = 1\nmaxStack = 2",
+            "[absorb",
+            "list = [1, 2",
+            "name = \"unterminated",
+        ] {
+            assert!(ConfigFile::parse(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_env_overrides_files() {
+        assert_eq!(env_name("absorb.maxStack"), "GIT_ABSORB_MAX_STACK");
+        assert_eq!(
+            env_name("absorb.fixupTargetAlwaysSHA"),
+            "GIT_ABSORB_FIXUP_TARGET_ALWAYS_SHA"
+        );
+        assert_eq!(env_name("diff.algorithm"), "GIT_ABSORB_DIFF_ALGORITHM");
+
+        let config = Layered {
+            env: vec![
+                ("GIT_ABSORB_MAX_STACK".to_owned(), "7".to_owned()),
+                ("GIT_ABSORB_CACHE".to_owned(), "off".to_owned()),
+                ("GIT_ABSORB_JOBS".to_owned(), "many".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            repo_file: ConfigFile::parse("maxStack = 5\nsignOff = true").unwrap(),
+            git: None,
+            user_file: ConfigFile::parse("signOff = false\nbackend = 'cli'").unwrap(),
+        };
+        assert_eq!(config.get_i64("absorb.maxStack").unwrap(), 7);
+        assert!(!config.get_bool("absorb.cache").unwrap());
+        assert!(config.get_i64("absorb.jobs").is_err());
+        assert!(config.get_bool("absorb.signOff").unwrap());
+        assert_eq!(config.get_string("absorb.backend").unwrap(), "cli");
+        assert_eq!(
+            config.get_string("absorb.newFiles").unwrap_err().code(),
+            git2::ErrorCode::NotFound
+        );
+    }
+
+    #[test]
+    fn test_flags_share_the_variable_of_their_setting() {
+        let config = Layered {
+            env: vec![
+                ("GIT_ABSORB_SIGN_OFF".to_owned(), "0".to_owned()),
+                ("GIT_ABSORB_SIGNOFF".to_owned(), "1".to_owned()),
+                ("GIT_ABSORB_DRY_RUN".to_owned(), "1".to_owned()),
+            ]
+            .into_iter()
+            .collect(),
+            repo_file: ConfigFile::default(),
+            git: None,
+            user_file: ConfigFile::parse("signOff = true").unwrap(),
+        };
+        // --signoff is absorb.signOff, whose variable wins over the files
+        assert!(config.env_flag("signoff").is_none());
+        assert!(!config.get_bool("absorb.signOff").unwrap());
+        // the flags without a setting have their own
+        assert_eq!(
+            config.env_flag("dry-run"),
+            Some(("GIT_ABSORB_DRY_RUN".to_owned(), "1"))
+        );
+        assert!(config.env_flag("force").is_none());
+    }
+}
@@ -0,0 +1,350 @@
+use anyhow::Result;
+
+use std::io::Write;
+
+use crate::config_file::Layered;
+use crate::stack::{self, Boundary};
+use crate::{config, sign, Config};
+
+/// The extensions that libgit2 knows how to open a repository with.
+const SUPPORTED_EXTENSIONS: &[&str] = &["noop", "objectformat", "worktreeconfig"];
+
+fn short(id: git2::Oid) -> String {
+    id.to_string()[..7].to_owned()
+}
+
+fn describe_commit(repo: &git2::Repository, id: git2::Oid) -> String {
+    match repo.find_commit(id) {
+        Ok(commit) => format!("{} {:?}", short(id), commit.summary().unwrap_or_default()),
+        Err(_) => short(id),
+    }
+}
+
+/// Writes everything about the repository and the configuration that
+/// affects how changes are absorbed, with a hint for each problem.
+pub fn report(repo: &git2::Repository, config: &Config, out: &mut dyn Write) -> Result<()> {
+    let hint = |out: &mut dyn Write, hint: &str| writeln!(out, "  hint: {}", hint);
+
+    match repo.workdir() {
+        Some(workdir) => writeln!(out, "repository: {}", workdir.display())?,
+        None => writeln!(out, "repository: {} (bare)", repo.path().display())?,
+    }
+
+    let state = repo.state();
+    if state == git2::RepositoryState::Clean {
+        writeln!(out, "state: clean")?;
+    } else {
+        writeln!(out, "state: {} in progress", crate::describe_state(state))?;
+        if !config.force_state {
+            hint(
+                out,
+                "git absorb refuses to run until it is finished, unless --force-state is given",
+            )?;
+        }
+    }
+
+    // an invalid file is reported, and the rest of the configuration is
+    // still looked at without it
+    let settings = match Layered::open(repo) {
+        Ok(settings) if settings.files().is_empty() => {
+            writeln!(out, "config files: none")?;
+            settings
+        }
+        Ok(settings) => {
+            let files: Vec<_> = settings
+                .files()
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            writeln!(out, "config files: {}", files.join(", "))?;
+            settings
+        }
+        Err(e) => {
+            writeln!(out, "config files: invalid")?;
+            hint(out, &e.to_string())?;
+            Layered::open_lenient(repo)
+        }
+    };
+    let settings = &settings;
+    if let Ok(problems) = config::validate(settings) {
+        if problems.is_empty() {
+            writeln!(out, "settings: valid")?;
+        } else {
+            let count = if problems.len() == 1 {
+                "problem"
+            } else {
+                "problems"
+            };
+            writeln!(out, "settings: {} {}", problems.len(), count)?;
+            for problem in &problems {
+                hint(out, problem)?;
+            }
+        }
+    }
+
+    let sparse = crate::is_sparse_index(repo);
+    match config::backend(settings) {
+        Ok(_) if sparse => writeln!(out, "backend: cli (the index is sparse)")?,
+        Ok(backend) => writeln!(out, "backend: {}", backend.name())?,
+        Err(e) => {
+            writeln!(out, "backend: invalid")?;
+            hint(out, &e.to_string())?;
+        }
+    }
+
+    let mut extensions = Vec::new();
+    if let Ok(git_config) = repo.config() {
+        if let Ok(mut entries) = git_config.entries(Some(r"^extensions\.")) {
+            while let Some(Ok(entry)) = entries.next() {
+                if let Some(name) = entry
+                    .name()
+                    .and_then(|name| name.strip_prefix("extensions."))
+                {
+                    extensions.push(name.to_lowercase());
+                }
+            }
+        }
+    }
+    if extensions.is_empty() {
+        writeln!(out, "extensions: none")?;
+    } else {
+        writeln!(out, "extensions: {}", extensions.join(", "))?;
+    }
+    for extension in &extensions {
+        if extension == "partialclone" {
+            continue;
+        }
+        if !SUPPORTED_EXTENSIONS.contains(&extension.as_str()) {
+            hint(
+                out,
+                &format!(
+                    "extensions.{} is not supported by libgit2, set {} = cli if git absorb misbehaves",
+                    extension,
+                    config::BACKEND_CONFIG_NAME
+                ),
+            )?;
+        }
+    }
+
+    writeln!(
+        out,
+        "shallow: {}",
+        if repo.is_shallow() { "yes" } else { "no" }
+    )?;
+    match config::promisor_remote(repo) {
+        Some(remote) => writeln!(out, "partial clone: yes, from {}", remote)?,
+        None => writeln!(out, "partial clone: no")?,
+    }
+
+    let signature = repo.signature();
+    match &signature {
+        Ok(signature) => writeln!(out, "identity: {}", crate::identity(signature))?,
+        Err(_) => {
+            writeln!(out, "identity: not configured")?;
+            hint(
+                out,
+                "set user.name and user.email, which commits in the stack must be authored with",
+            )?;
+        }
+    }
+    let options = config::stack_options(settings)?;
+    let max_stack = options.max_stack;
+    let options = stack::StackOptions {
+        base: &config.base,
+        force: config.force,
+        force_pushed: config.force || config.force_pushed,
+        include_authors: &config.include_authors,
+        max_stack: max_stack.saturating_mul(config::MAX_STACK_WALK_FACTOR),
+        ..options
+    };
+    if !options.author_allowlist.is_empty() || !options.include_authors.is_empty() {
+        let mut emails = options.author_allowlist.clone();
+        emails.extend(
+            options
+                .include_authors
+                .iter()
+                .map(|&email| email.to_owned()),
+        );
+        writeln!(out, "other authors: {}", emails.join(", "))?;
+    }
+
+    let head = repo.head()?;
+    match head.shorthand().filter(|_| head.is_branch()) {
+        Some(branch) => writeln!(out, "branch: {}", branch)?,
+        None => writeln!(out, "branch: none (HEAD is detached)")?,
+    }
+    if let Some((_, pattern)) = crate::protected_branch(repo, settings)? {
+        writeln!(
+            out,
+            "  it is protected, matching {:?} in {}",
+            pattern,
+            config::PROTECTED_BRANCHES_CONFIG_NAME
+        )?;
+        hint(out, "use --force to absorb on it anyway")?;
+    }
+
+    if !options.base.is_empty() {
+        writeln!(out, "base: {} (from --base)", options.base.join(", "))?;
+    } else {
+        let source = if options.default_base.is_some() {
+            config::DEFAULT_BASE_CONFIG_NAME
+        } else {
+            "the upstream of the branch, or the default branch of origin"
+        };
+        match stack::default_base(repo, &head, &options, None) {
+            Ok(Some((id, name))) => writeln!(
+                out,
+                "base: {} (from {})",
+                name.unwrap_or_else(|| describe_commit(repo, id)),
+                source
+            )?,
+            Ok(None) => {
+                writeln!(out, "base: none")?;
+                hint(
+                    out,
+                    &format!(
+                        "the stack only stops at other branches, set an upstream or {}",
+                        config::DEFAULT_BASE_CONFIG_NAME
+                    ),
+                )?;
+            }
+            Err(e) => {
+                writeln!(out, "base: invalid")?;
+                hint(out, &e.to_string())?;
+            }
+        }
+        if options.base_strategy == stack::BaseStrategy::ForkPoint {
+            writeln!(out, "  also stopping at the fork point from the base")?;
+        }
+    }
+
+    match &options.pushed_remotes {
+        Some(remotes) => writeln!(out, "pushed remotes: {}", remotes.join(", "))?,
+        None => writeln!(out, "pushed remotes: all")?,
+    }
+
+    match stack::working_stack_with_boundary(repo, &options, None) {
+        Ok((stack, boundary)) => {
+            let commits = if stack.len() == 1 {
+                "commit"
+            } else {
+                "commits"
+            };
+            if options.base.is_empty() {
+                writeln!(
+                    out,
+                    "stack: {} {}, of at most {}, and at most {} touching the staged files ({})",
+                    stack.len(),
+                    commits,
+                    options.max_stack,
+                    max_stack,
+                    config::MAX_STACK_CONFIG_NAME
+                )?;
+            } else {
+                writeln!(out, "stack: {} {}", stack.len(), commits)?;
+            }
+            let merges = stack
+                .iter()
+                .filter(|commit| commit.parent_count() > 1)
+                .count();
+            if merges > 0 {
+                writeln!(
+                    out,
+                    "  {} of them are merge commits, whose hunks are left in the index",
+                    merges
+                )?;
+            }
+            match boundary {
+                Boundary::End => writeln!(
+                    out,
+                    "  it ends at the base, at another branch or at the root commit"
+                )?,
+                Boundary::Merge(id) => {
+                    writeln!(
+                        out,
+                        "  it ends before the merge commit {}",
+                        describe_commit(repo, id)
+                    )?;
+                    hint(
+                        out,
+                        &format!(
+                            "set {} = first-parent to go past merges",
+                            config::TRAVERSE_MERGES_CONFIG_NAME
+                        ),
+                    )?;
+                }
+                Boundary::Pushed(id) => {
+                    writeln!(
+                        out,
+                        "  it ends before {}, which was pushed to a remote",
+                        describe_commit(repo, id)
+                    )?;
+                    hint(
+                        out,
+                        &format!(
+                            "use --force-pushed to go further, or {} to only consider some remotes",
+                            config::PUSHED_REMOTES_CONFIG_NAME
+                        ),
+                    )?;
+                }
+                Boundary::Author(id) => {
+                    let author = repo
+                        .find_commit(id)
+                        .map(|commit| crate::identity(&commit.author()))
+                        .unwrap_or_default();
+                    writeln!(
+                        out,
+                        "  it ends before {}, which was authored by {}",
+                        describe_commit(repo, id),
+                        author
+                    )?;
+                    hint(
+                        out,
+                        &format!(
+                            "use --include-author or {} if that's you",
+                            config::AUTHOR_ALLOWLIST_CONFIG_NAME
+                        ),
+                    )?;
+                }
+                Boundary::Limit(id) => {
+                    writeln!(
+                        out,
+                        "  it ends at the limit, before {}",
+                        describe_commit(repo, id)
+                    )?;
+                    hint(
+                        out,
+                        &format!("use --base, or raise {}", config::MAX_STACK_CONFIG_NAME),
+                    )?;
+                }
+                Boundary::Shallow(id) => {
+                    writeln!(
+                        out,
+                        "  it ends at the shallow boundary, at {}",
+                        describe_commit(repo, id)
+                    )?;
+                    hint(out, "use git fetch --deepen or --unshallow to go further")?;
+                }
+            }
+        }
+        Err(e) => {
+            writeln!(out, "stack: none")?;
+            hint(out, &e.to_string())?;
+        }
+    }
+
+    match signature {
+        Ok(signature) => {
+            match sign::signer(repo, config.gpg_sign, config.signing_key, &signature) {
+                Ok(Some(signer)) => writeln!(out, "signing: {}", signer.describe())?,
+                Ok(None) => writeln!(out, "signing: off")?,
+                Err(e) => {
+                    writeln!(out, "signing: invalid")?;
+                    hint(out, &e.to_string())?;
+                }
+            }
+        }
+        Err(_) => writeln!(out, "signing: unknown, without an identity")?,
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+
+use std::fs;
+use std::process::Command;
+
+use crate::i18n::trf;
+
+/// Returns the editor that git would use.
+fn editor(repo: &git2::Repository) -> String {
+    std::env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| {
+            repo.config()
+                .and_then(|config| config.get_string("core.editor"))
+                .ok()
+        })
+        .or_else(|| std::env::var("VISUAL").ok())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .unwrap_or_else(|| "vi".to_owned())
+}
+
+/// Opens `initial` in the user's editor, and returns the edited text,
+/// without the lines that start with '#'.
+pub fn edit(repo: &git2::Repository, initial: &str) -> Result<String> {
+    edit_with(repo, &editor(repo), initial)
+}
+
+fn edit_with(repo: &git2::Repository, editor: &str, initial: &str) -> Result<String> {
+    let path = repo.path().join("ABSORB_EDITMSG");
+    fs::write(&path, initial)?;
+    // like git, run the editor through the shell, so that it can
+    // contain arguments
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(editor)
+        .arg(&path)
+        .status()?;
+    if !status.success() {
+        return Err(anyhow!(
+            "{}",
+            trf(
+                "editor {} exited with {}",
+                &[&format!("{:?}", editor), &status]
+            )
+        ));
+    }
+    let edited = fs::read_to_string(&path)?;
+    fs::remove_file(&path)?;
+    Ok(edited
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_strips_comments() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = git2::Repository::init(&dir).unwrap();
+        let edited = edit_with(&repo, "printf 'message\\n# comment\\n' >>", "# initial\n").unwrap();
+        assert_eq!(edited, "message");
+        assert!(!repo.path().join("ABSORB_EDITMSG").exists());
+    }
+}
@@ -0,0 +1,171 @@
+/// Returns whether `text` matches the glob `pattern`, in which `*`
+/// matches any run of characters (including `/`) and `?` matches any
+/// one character. This is how the branches of `absorb.protectedBranches`
+/// are matched, and each component of `absorb.excludePaths`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+    // the positions to resume from when a later character mismatches
+    // after the last star
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// One of the gitignore-style patterns of `absorb.excludePaths`.
+struct Pattern {
+    /// The components of the pattern, where `**` matches any number of
+    /// directories.
+    components: Vec<String>,
+    /// The pattern contains a slash, so it is matched from the top of
+    /// the repository rather than against any file or directory name.
+    anchored: bool,
+    /// The pattern ends with a slash, so it only matches directories.
+    directory: bool,
+    /// The pattern starts with `!`, so it includes again the paths that
+    /// earlier patterns excluded.
+    negated: bool,
+}
+
+/// Returns whether the components of the pattern match the first of the
+/// components of the path.
+fn match_components(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skipped| match_components(rest, &path[skipped..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((component, path)) => glob_match(first, component) && match_components(rest, path),
+            None => false,
+        },
+    }
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let (directory, pattern) = match pattern.strip_suffix('/') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            components: pattern.split('/').map(str::to_owned).collect(),
+            anchored,
+            directory,
+            negated,
+        })
+    }
+
+    /// Returns whether the pattern matches the file, or one of the
+    /// directories it is in.
+    fn matches(&self, path: &[&str]) -> bool {
+        // the number of leading components that the pattern may match,
+        // which must leave the file out for a directory pattern
+        let ends = if self.directory {
+            1..path.len()
+        } else {
+            1..path.len() + 1
+        };
+        if self.anchored {
+            ends.into_iter()
+                .any(|end| match_components(&self.components, &path[..end]))
+        } else {
+            ends.into_iter()
+                .any(|end| glob_match(&self.components[0], path[end - 1]))
+        }
+    }
+}
+
+/// The staged files that are never absorbed, per `absorb.excludePaths`.
+#[derive(Default)]
+pub struct ExcludePaths {
+    patterns: Vec<Pattern>,
+}
+
+impl ExcludePaths {
+    pub fn new(patterns: &[String]) -> Self {
+        ExcludePaths {
+            patterns: patterns
+                .iter()
+                .filter_map(|pattern| Pattern::parse(pattern))
+                .collect(),
+        }
+    }
+
+    /// Returns whether the path is excluded, like gitignore would ignore
+    /// it: by the last pattern that matches it, unless that one is
+    /// negated.
+    pub fn matches(&self, path: &[u8]) -> bool {
+        let path = String::from_utf8_lossy(path);
+        let components: Vec<_> = path.split('/').collect();
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(&components))
+            .is_some_and(|pattern| !pattern.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("master", "master"));
+        assert!(!glob_match("master", "master2"));
+        assert!(glob_match("release/*", "release/1.0"));
+        assert!(glob_match("release/*", "release/1.0/hotfix"));
+        assert!(!glob_match("release/*", "feature/release"));
+        assert!(glob_match("*-wip", "some-wip"));
+        assert!(glob_match("v?", "v1"));
+        assert!(!glob_match("v?", "v10"));
+    }
+
+    #[test]
+    fn test_exclude_paths() {
+        let exclude = ExcludePaths::new(&[
+            "*.lock".to_owned(),
+            "vendor/**".to_owned(),
+            "package-lock.json".to_owned(),
+            "build/".to_owned(),
+            "/docs/*.md".to_owned(),
+            "!docs/keep.md".to_owned(),
+        ]);
+        assert!(exclude.matches(b"Cargo.lock"));
+        assert!(exclude.matches(b"sub/dir/yarn.lock"));
+        assert!(exclude.matches(b"vendor/a/b.c"));
+        assert!(!exclude.matches(b"src/vendor/a.c"));
+        assert!(exclude.matches(b"web/package-lock.json"));
+        assert!(exclude.matches(b"web/build/out.js"));
+        assert!(!exclude.matches(b"build"));
+        assert!(exclude.matches(b"docs/index.md"));
+        assert!(!exclude.matches(b"docs/sub/index.md"));
+        assert!(!exclude.matches(b"docs/keep.md"));
+        assert!(!exclude.matches(b"src/main.rs"));
+    }
+}
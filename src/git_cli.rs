@@ -0,0 +1,473 @@
+use anyhow::{anyhow, Result};
+
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use crate::i18n::{tr, trf};
+use crate::DiffAlgorithm;
+
+/// What a tree is diffed against by `diff()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffTarget {
+    /// The staged changes, like `git diff --cached`.
+    Index,
+    /// The staged and unstaged changes, like `git diff HEAD`.
+    Workdir,
+}
+
+/// Makes the command run at the top of the working tree (or in the git
+/// directory of a bare repository), on the same git directory, working
+/// tree and index as `repo`.
+///
+/// `repo` may have been opened from `GIT_DIR`, `GIT_WORK_TREE` and
+/// `GIT_INDEX_FILE`, which the command would otherwise inherit as is,
+/// and resolve against the directory it runs in if they are relative.
+pub fn in_repo<'c>(command: &'c mut Command, repo: &git2::Repository) -> &'c mut Command {
+    command
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        .env("GIT_DIR", absolute(repo.path()));
+    if let Some(workdir) = repo.workdir() {
+        command.env("GIT_WORK_TREE", absolute(workdir));
+    }
+    // libgit2 can't open a sparse index, which leaves GIT_INDEX_FILE
+    // as it was
+    if let Some(index) = repo.index().ok().as_ref().and_then(git2::Index::path) {
+        command.env("GIT_INDEX_FILE", absolute(index));
+    } else if let Some(index) = std::env::var_os("GIT_INDEX_FILE") {
+        command.env("GIT_INDEX_FILE", absolute(Path::new(&index)));
+    }
+    command
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+/// Runs git in the repository with the arguments, and returns what it
+/// printed.
+fn git(repo: &git2::Repository, args: &[&str]) -> Result<Vec<u8>> {
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).args(args);
+    let output = command
+        .output()
+        .map_err(|e| anyhow!("{}", trf("could not run git {}: {}", &[&args[0], &e])))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            trf(
+                "git {} failed: {}",
+                &[&args[0], &String::from_utf8_lossy(&output.stderr).trim()]
+            )
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Runs the git command (named `name` in errors), writes the input to
+/// its stdin, and returns what it printed.
+fn run_with_input(command: &mut Command, name: &str, input: &[u8]) -> Result<Vec<u8>> {
+    command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("{}", trf("could not run git {}: {}", &[&name, &e])))?;
+    child.stdin.take().unwrap().write_all(input)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            trf(
+                "git {} failed: {}",
+                &[&name, &String::from_utf8_lossy(&output.stderr).trim()]
+            )
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// The options that `diff()` and `diff_tree()` format their patch with,
+/// which the user's configuration must not change, only what is in it.
+fn patch_format(algorithm: DiffAlgorithm, context: u32) -> Vec<String> {
+    let mut ret: Vec<String> = [
+        "--no-color",
+        "--no-ext-diff",
+        "--no-textconv",
+        "--no-relative",
+        "--binary",
+        "--full-index",
+        "--unified=0",
+        "--src-prefix=a/",
+        "--dst-prefix=b/",
+    ]
+    .iter()
+    .map(|&arg| arg.to_owned())
+    .collect();
+    ret.push(format!("--diff-algorithm={}", algorithm.as_str()));
+    ret.push(format!(
+        "--inter-hunk-context={}",
+        context.saturating_mul(2)
+    ));
+    ret
+}
+
+/// Diffs the tree against the index or the working tree by running
+/// `git diff`, and parses its output, so that libgit2 never reads the
+/// index itself.
+///
+/// The diff is made like with `default_diff_options()`: without
+/// context lines or rename detection, with hunks grouped as if they had
+/// `context` lines of context, and with full object ids.
+pub fn diff(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    target: DiffTarget,
+    algorithm: DiffAlgorithm,
+    context: u32,
+    pathspecs: &[&str],
+) -> Result<git2::Diff<'static>> {
+    let tree = tree.id().to_string();
+    let format = patch_format(algorithm, context);
+    let mut args = vec!["diff"];
+    if target == DiffTarget::Index {
+        args.push("--cached");
+    }
+    args.extend(format.iter().map(String::as_str));
+    args.extend([
+        "--no-renames",
+        // files added with `git add --intent-to-add` aren't staged
+        "--ita-invisible-in-index",
+        &tree,
+        "--",
+    ]);
+    args.extend(pathspecs);
+    let patch = git(repo, &args)?;
+    Ok(git2::Diff::from_buffer(&patch)?)
+}
+
+/// Diffs the trees by running `git diff-tree`, with renames detected,
+/// and parses its output. With no `from`, everything in `to` is added.
+///
+/// The diff is made like with `diff()`, which libgit2 can't detect
+/// renames in once it is parsed.
+pub fn diff_tree(
+    repo: &git2::Repository,
+    from: Option<git2::Oid>,
+    to: git2::Oid,
+    algorithm: DiffAlgorithm,
+    context: u32,
+) -> Result<git2::Diff<'static>> {
+    let from = match from {
+        Some(from) => from,
+        None => git2::Oid::hash_object(git2::ObjectType::Tree, &[])?,
+    };
+    let (from, to) = (from.to_string(), to.to_string());
+    let format = patch_format(algorithm, context);
+    let mut args = vec!["diff-tree", "-p", "--find-renames"];
+    args.extend(format.iter().map(String::as_str));
+    args.extend([&from, &to, "--"]);
+    let patch = git(repo, &args)?;
+    Ok(git2::Diff::from_buffer(&patch)?)
+}
+
+/// Formats the signature the way git reads it from `GIT_AUTHOR_NAME`,
+/// `GIT_AUTHOR_EMAIL` and `GIT_AUTHOR_DATE` (or their committer
+/// equivalents).
+fn identity(signature: &git2::Signature) -> (String, String, String) {
+    let when = signature.when();
+    let offset = when.offset_minutes();
+    let date = format!(
+        "@{} {}{:02}{:02}",
+        when.seconds(),
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+    );
+    (
+        String::from_utf8_lossy(signature.name_bytes()).into_owned(),
+        String::from_utf8_lossy(signature.email_bytes()).into_owned(),
+        date,
+    )
+}
+
+/// Creates a commit without updating any reference by running `git
+/// commit-tree`, which doesn't sign it (see `sign::commit()`).
+pub fn commit_tree(
+    repo: &git2::Repository,
+    author: &git2::Signature,
+    committer: &git2::Signature,
+    message: &str,
+    tree: git2::Oid,
+    parents: &[git2::Oid],
+) -> Result<git2::Oid> {
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).args(["commit-tree", "--no-gpg-sign"]);
+    for parent in parents {
+        command.arg("-p").arg(parent.to_string());
+    }
+    command.arg(tree.to_string());
+    for (prefix, signature) in [("GIT_AUTHOR", author), ("GIT_COMMITTER", committer)] {
+        let (name, email, date) = identity(signature);
+        command
+            .env(format!("{}_NAME", prefix), name)
+            .env(format!("{}_EMAIL", prefix), email)
+            .env(format!("{}_DATE", prefix), date);
+    }
+    let output = run_with_input(&mut command, "commit-tree", message.as_bytes())?;
+    let id = std::str::from_utf8(&output)
+        .map_err(|_| anyhow!("{}", tr("git commit-tree returned a non-utf8 id")))?;
+    Ok(git2::Oid::from_str(id.trim())?)
+}
+
+/// The commits that `git rev-list` prints, as it prints them.
+pub struct RevList {
+    child: Child,
+    lines: std::io::Lines<BufReader<ChildStdout>>,
+}
+
+impl Iterator for RevList {
+    type Item = Result<git2::Oid>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.lines.next()? {
+            Ok(line) => Some(git2::Oid::from_str(line.trim()).map_err(Into::into)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+impl Drop for RevList {
+    fn drop(&mut self) {
+        // the walk usually stops long before git is done printing
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Walks the first parents of HEAD, newest first, without the hidden
+/// commits and their ancestors, by running `git rev-list`. The commits
+/// are read as git prints them, so that walking only part of a long
+/// history doesn't wait for all of it.
+pub fn rev_list(repo: &git2::Repository, hidden: &[git2::Oid]) -> Result<RevList> {
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).args(["rev-list", "--first-parent", "--topo-order", "HEAD"]);
+    for commit in hidden {
+        command.arg(format!("^{}", commit));
+    }
+    let mut child = command
+        .arg("--")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow!("{}", trf("could not run git rev-list: {}", &[&e])))?;
+    // unwrap() is safe here, as stdout is piped
+    let stdout = child.stdout.take().unwrap();
+    Ok(RevList {
+        child,
+        lines: BufReader::new(stdout).lines(),
+    })
+}
+
+/// Replaces the index with the tree by running `git read-tree --reset`,
+/// which (unlike writing the index with libgit2) keeps it sparse, and
+/// keeps the files outside of the sparse-checkout out of the working
+/// tree. Unlike `-m`, it doesn't refuse to run when files have unstaged
+/// changes.
+pub fn read_tree(repo: &git2::Repository, tree: git2::Oid) -> Result<()> {
+    git(repo, &["read-tree", "--reset", &tree.to_string()])?;
+    Ok(())
+}
+
+/// Updates the index and the working tree from one tree to the other by
+/// running `git read-tree -m -u`, which refuses to overwrite changes.
+pub fn checkout_tree(repo: &git2::Repository, from: git2::Oid, to: git2::Oid) -> Result<()> {
+    git(
+        repo,
+        &["read-tree", "-m", "-u", &from.to_string(), &to.to_string()],
+    )?;
+    Ok(())
+}
+
+/// Applies the patch to the working tree, or to the index with `cached`,
+/// by running `git apply`.
+pub fn apply(repo: &git2::Repository, patch: &[u8], cached: bool) -> Result<()> {
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).arg("apply");
+    if cached {
+        command.arg("--cached");
+    }
+    run_with_input(command.arg("-"), "apply", patch).map(drop)
+}
+
+/// Returns whether the index or the working tree have changes to
+/// tracked files, by running `git diff --quiet HEAD`.
+pub fn has_changes(repo: &git2::Repository) -> Result<bool> {
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).args(["diff", "--quiet", "--no-ext-diff", "HEAD", "--"]);
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("{}", trf("could not run git diff: {}", &[&e])))?;
+    match status.code() {
+        Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        _ => Err(anyhow!("{}", tr("git diff failed"))),
+    }
+}
+
+/// Drops the stash entry at the position by running `git stash drop`.
+pub fn stash_drop(repo: &git2::Repository, position: usize) -> Result<()> {
+    git(
+        repo,
+        &[
+            "stash",
+            "drop",
+            "--quiet",
+            &format!("stash@{{{}}}", position),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns the major and minor version of git, from `git --version`, or
+/// `None` if it can't be run or its version can't be parsed.
+pub fn version() -> Option<(u32, u32)> {
+    let output = Command::new("git").arg("--version").output().ok()?;
+    parse_version(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses the output of `git --version`, eg: "git version 2.39.2" or
+/// "git version 2.37.1 (Apple Git-137.1)".
+fn parse_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.trim().strip_prefix("git version ")?;
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Records the index and the working tree as a stash commit, without
+/// adding it to the stash list or touching anything, by running `git
+/// stash create`. Returns `None` if there are no changes to record.
+pub fn stash_create(repo: &git2::Repository) -> Result<Option<git2::Oid>> {
+    let output = git(repo, &["stash", "create"])?;
+    let output = String::from_utf8_lossy(&output);
+    match output.trim() {
+        "" => Ok(None),
+        id => Ok(Some(git2::Oid::from_str(id)?)),
+    }
+}
+
+/// Adds the stash commit to the top of the stash list by running `git
+/// stash store`.
+pub fn stash_store(repo: &git2::Repository, stash: git2::Oid, message: &str) -> Result<()> {
+    git(
+        repo,
+        &[
+            "stash",
+            "store",
+            "--quiet",
+            "-m",
+            message,
+            &stash.to_string(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Returns the paths that were added with `git add --intent-to-add`,
+/// which `git diff` shows as new files in the working tree.
+pub fn intent_to_add(repo: &git2::Repository) -> Result<Vec<Vec<u8>>> {
+    let output = git(
+        repo,
+        &[
+            "diff",
+            "--name-only",
+            "-z",
+            "--no-renames",
+            "--no-relative",
+            "--ita-invisible-in-index",
+            "--diff-filter=A",
+        ],
+    )?;
+    Ok(output
+        .split(|&byte| byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(<[u8]>::to_vec)
+        .collect())
+}
+
+/// Adds the paths to the index with `git add --intent-to-add`.
+pub fn add_intent_to_add(repo: &git2::Repository, paths: &[Vec<u8>]) -> Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).args([
+        "--literal-pathspecs",
+        "add",
+        "--intent-to-add",
+        "--pathspec-from-file=-",
+        "--pathspec-file-nul",
+    ]);
+    run_with_input(&mut command, "add", &paths.join(&0)).map(drop)
+}
+
+/// Fetches the objects from the promisor remote of a partial clone in a
+/// single batch, the way git fetches the objects it is missing itself.
+pub fn fetch_objects(repo: &git2::Repository, remote: &str, ids: &[git2::Oid]) -> Result<()> {
+    let mut input = Vec::new();
+    for id in ids {
+        writeln!(input, "{}", id)?;
+    }
+    let mut command = Command::new("git");
+    in_repo(&mut command, repo).args([
+        "-c",
+        "fetch.negotiationAlgorithm=noop",
+        "fetch",
+        remote,
+        "--no-tags",
+        "--no-write-fetch-head",
+        "--recurse-submodules=no",
+        "--filter=blob:none",
+        "--stdin",
+    ]);
+    run_with_input(&mut command, "fetch", &input).map(drop)
+}
+
+/// Stages the changes to the tracked files that match the pathspecs by
+/// running `git add --update`.
+pub fn add_tracked(repo: &git2::Repository, pathspecs: &[&str]) -> Result<()> {
+    let mut args = vec!["add", "--update", "--"];
+    args.extend(pathspecs);
+    git(repo, &args)?;
+    Ok(())
+}
+
+/// Writes the index as a tree by running `git write-tree`.
+pub fn write_tree(repo: &git2::Repository) -> Result<git2::Oid> {
+    let output = git(repo, &["write-tree"])?;
+    let id = std::str::from_utf8(&output)
+        .map_err(|_| anyhow!("{}", tr("git write-tree returned a non-utf8 id")))?;
+    Ok(git2::Oid::from_str(id.trim())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("git version 2.39.2\n"), Some((2, 39)));
+        assert_eq!(
+            parse_version("git version 2.37.1 (Apple Git-137.1)\n"),
+            Some((2, 37))
+        );
+        assert_eq!(parse_version("git version 2.44.0.windows.1"), Some((2, 44)));
+        assert_eq!(parse_version("not git"), None);
+    }
+}
@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::i18n::trf;
+
+/// Returns the path of the hook, if it exists and can be run.
+fn find_hook(repo: &git2::Repository, name: &str) -> Option<PathBuf> {
+    let dir = match repo
+        .config()
+        .and_then(|config| config.get_path("core.hooksPath"))
+    {
+        // a relative hooks path is relative to the working tree, or to
+        // the git directory in a bare repository
+        Ok(dir) if dir.is_relative() => repo.workdir().unwrap_or_else(|| repo.path()).join(dir),
+        Ok(dir) => dir,
+        Err(_) => repo.path().join("hooks"),
+    };
+    let hook = dir.join(name);
+    if !is_executable(&hook) {
+        return None;
+    }
+    Some(hook)
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn run_hook(
+    repo: &git2::Repository,
+    name: &str,
+    hook: &Path,
+    args: &[&Path],
+    index: &Path,
+) -> Result<()> {
+    let mut command = Command::new(hook);
+    let status = crate::git_cli::in_repo(&mut command, repo)
+        .args(args)
+        .env("GIT_INDEX_FILE", index)
+        .status()
+        .map_err(|e| anyhow!("{}", trf("could not run {} hook: {}", &[&name, &e])))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "{}",
+            trf("{} hook failed with {}", &[&name, &status])
+        ));
+    }
+    Ok(())
+}
+
+/// Runs the pre-commit and commit-msg hooks for a commit of `tree`
+/// with `message`, and returns the message as changed by the
+/// commit-msg hook.
+///
+/// Since the commit is not made from the index, the pre-commit hook
+/// sees an index that contains `tree`.
+pub fn run_commit_hooks(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    message: &str,
+) -> Result<String> {
+    let index_path = repo.path().join("ABSORB_HOOK_INDEX");
+    let message_path = repo.path().join("ABSORB_COMMIT_MSG");
+    let result = (|| {
+        let pre_commit = find_hook(repo, "pre-commit");
+        let commit_msg = find_hook(repo, "commit-msg");
+        if pre_commit.is_none() && commit_msg.is_none() {
+            return Ok(message.to_owned());
+        }
+
+        let mut index = git2::Index::open(&index_path)?;
+        index.read_tree(tree)?;
+        index.write()?;
+        if let Some(hook) = pre_commit {
+            run_hook(repo, "pre-commit", &hook, &[], &index_path)?;
+        }
+        match commit_msg {
+            Some(hook) => {
+                fs::write(&message_path, message)?;
+                run_hook(repo, "commit-msg", &hook, &[&message_path], &index_path)?;
+                Ok(fs::read_to_string(&message_path)?)
+            }
+            None => Ok(message.to_owned()),
+        }
+    })();
+    for path in [&index_path, &message_path] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    result
+}
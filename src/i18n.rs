@@ -0,0 +1,380 @@
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+mod fr;
+
+/// The translations into each language, keyed by the English messages,
+/// like the catalogs of gettext.
+const CATALOGS: &[(&str, &[(&str, &str)])] = &[("fr", fr::MESSAGES)];
+
+type Catalog = &'static [(&'static str, &'static str)];
+
+/// The locale that messages are shown in: `ABSORB_LANG` if it's set,
+/// else the first of `LC_ALL`, `LC_MESSAGES` and `LANG` that is, like
+/// gettext does.
+pub fn locale() -> Option<String> {
+    ["ABSORB_LANG", "LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// The catalog for the locale (eg: `fr_CA.UTF-8`), found by the locale
+/// without its encoding and modifier, or else by its language alone.
+/// `C` and `POSIX` (and English) have none.
+fn catalog(locale: &str) -> Option<Catalog> {
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+    let language = locale.split('_').next().unwrap_or_default();
+    [locale, language].iter().find_map(|name| {
+        CATALOGS
+            .iter()
+            .find(|(catalog, _)| catalog == name)
+            .map(|&(_, messages)| messages)
+    })
+}
+
+fn current() -> Option<Catalog> {
+    static CURRENT: OnceLock<Option<Catalog>> = OnceLock::new();
+    *CURRENT.get_or_init(|| locale().as_deref().and_then(catalog))
+}
+
+fn find(catalog: Option<Catalog>, msgid: &str) -> Option<&'static str> {
+    catalog?
+        .iter()
+        .find(|(english, _)| *english == msgid)
+        .map(|&(_, translated)| translated)
+}
+
+fn lookup(catalog: Option<Catalog>, msgid: &'static str) -> &'static str {
+    find(catalog, msgid).unwrap_or(msgid)
+}
+
+fn fill(template: &str, args: &[&dyn Display]) -> String {
+    let mut ret = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut pieces = template.split("{}");
+    ret.push_str(pieces.next().unwrap_or_default());
+    for piece in pieces {
+        if let Some(arg) = args.next() {
+            ret.push_str(&arg.to_string());
+        }
+        ret.push_str(piece);
+    }
+    ret
+}
+
+/// Returns the arguments that `message` was made of, if it was made
+/// from `template` by replacing each `{}` with one.
+fn unfill<'a>(template: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let mut pieces = template.split("{}");
+    let rest = message.strip_prefix(pieces.next().unwrap_or_default())?;
+    let mut pieces: Vec<_> = pieces.collect();
+    let last = pieces.pop()?;
+    let mut rest = rest.strip_suffix(last)?;
+    let mut args = Vec::with_capacity(pieces.len() + 1);
+    for piece in pieces {
+        let (arg, after) = rest.split_once(piece)?;
+        args.push(arg);
+        rest = after;
+    }
+    args.push(rest);
+    Some(args)
+}
+
+/// Translates a message that was already filled, like the errors and
+/// the log messages of git-absorb-core, by finding the message of the
+/// catalog it was made from. The one with the longest text outside of
+/// its `{}` wins, so that "{} hunk" doesn't translate every message
+/// that ends with "hunk".
+fn translate_filled(catalog: Option<Catalog>, message: &str) -> Option<String> {
+    if let Some(translated) = find(catalog, message) {
+        return Some(translated.to_owned());
+    }
+    catalog?
+        .iter()
+        .filter(|(english, _)| english.contains("{}"))
+        .filter_map(|(english, translated)| {
+            let args = unfill(english, message)?;
+            Some((english.len() - 2 * args.len(), translated, args))
+        })
+        .max_by_key(|(literal, _, _)| *literal)
+        .map(|(_, translated, args)| {
+            let args: Vec<&dyn Display> = args.iter().map(|arg| arg as &dyn Display).collect();
+            fill(translated, &args)
+        })
+}
+
+/// Translates the message into the language of the locale, if its
+/// catalog has it.
+pub fn tr(msgid: &'static str) -> &'static str {
+    lookup(current(), msgid)
+}
+
+/// Like `tr()`, and then replaces each `{}` of the message with the next
+/// argument.
+pub fn trf(msgid: &'static str, args: &[&dyn Display]) -> String {
+    fill(tr(msgid), args)
+}
+
+/// Translates an error for showing it. The errors of git-absorb are
+/// translated when they're made, and those of git-absorb-core, which
+/// are in English, are translated here.
+pub fn tr_error(err: &anyhow::Error) -> String {
+    let message = err.to_string();
+    translate_filled(current(), &message).unwrap_or(message)
+}
+
+/// Translates the messages that git-absorb-core logs before passing
+/// them on to the drain. The library leaves the language to the tools
+/// that use it, and so logs its messages in English.
+pub struct Translate<D> {
+    drain: D,
+    catalog: Option<Catalog>,
+}
+
+impl<D> Translate<D> {
+    /// Translates into the language of the locale.
+    pub fn new(drain: D) -> Self {
+        Translate {
+            drain,
+            catalog: current(),
+        }
+    }
+}
+
+impl<D: slog::Drain> slog::Drain for Translate<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<Self::Ok, Self::Err> {
+        let translated = match record.module().starts_with("git_absorb_core") {
+            true => translate_filled(self.catalog, &record.msg().to_string()),
+            false => None,
+        };
+        match translated {
+            Some(translated) => {
+                let rstatic = slog::RecordStatic {
+                    location: record.location(),
+                    tag: record.tag(),
+                    level: record.level(),
+                };
+                let msg = format_args!("{}", translated);
+                self.drain
+                    .log(&slog::Record::new(&rstatic, &msg, record.kv()), values)
+            }
+            None => self.drain.log(record, values),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog_is_found_by_locale_or_language() {
+        assert!(catalog("fr").is_some());
+        assert!(catalog("fr_CA.UTF-8").is_some());
+        assert!(catalog("fr_FR@euro").is_some());
+        assert!(catalog("C").is_none());
+        assert!(catalog("en_US.UTF-8").is_none());
+
+        let msgid = "No commits available to fix up, exiting";
+        assert_eq!(lookup(None, msgid), msgid);
+        assert_ne!(lookup(catalog("fr"), msgid), msgid);
+        assert_eq!(
+            lookup(catalog("fr"), "not in any catalog"),
+            "not in any catalog"
+        );
+        assert_eq!(fill("{} of {}", &[&1, &"two"]), "1 of two");
+    }
+
+    /// Records the messages that are logged to it.
+    #[derive(Clone, Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl slog::Drain for Recorder {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_translates_messages_of_the_core() {
+        let recorder = Recorder::default();
+        let drain = Translate {
+            drain: recorder.clone(),
+            catalog: catalog("fr"),
+        };
+        let logger = slog::Logger::root(drain, o!());
+        let msgid = "Will not fix up past the merge commit";
+
+        // the stack of a merge commit ends right away
+        let dir = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        let sig = git2::Signature::now("nobody", "nobody@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let root = repo.commit(None, &sig, &sig, "root", &tree, &[]).unwrap();
+        let root = repo.find_commit(root).unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "merge", &tree, &[&root, &root])
+            .unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "nobody").unwrap();
+        config.set_str("user.email", "nobody@example.com").unwrap();
+        git_absorb_core::working_stack(&repo, &Default::default(), Some(&logger)).unwrap();
+        // the messages of git-absorb are translated where they're logged
+        warn!(logger, "{}", msgid);
+
+        let messages = recorder.0.lock().unwrap();
+        let translated = lookup(catalog("fr"), msgid);
+        assert_ne!(translated, msgid);
+        assert!(messages.iter().any(|message| message == translated));
+        assert_eq!(messages.last().map(String::as_str), Some(msgid));
+    }
+
+    #[test]
+    fn test_translates_filled_messages() {
+        let fr = catalog("fr");
+        assert_eq!(
+            translate_filled(
+                fr,
+                "invalid base \"a...b\", symmetric differences are not supported"
+            )
+            .unwrap(),
+            "base \"a...b\" invalide, les différences symétriques ne sont pas prises en charge"
+        );
+        // and not as "{} hunk"
+        assert_eq!(
+            translate_filled(fr, "wrong number of lines in hunk").unwrap(),
+            lookup(fr, "wrong number of lines in hunk")
+        );
+        assert_eq!(translate_filled(fr, "not in any catalog"), None);
+        assert_eq!(translate_filled(None, "got empty delta"), None);
+    }
+
+    /// Returns the string literal that `source` starts with (after
+    /// whitespace), unescaped, if it does.
+    fn literal(source: &str) -> Option<String> {
+        let mut chars = source.trim_start().strip_prefix('"')?.chars();
+        let mut ret = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(ret),
+                '\\' => match chars.next()? {
+                    'n' => ret.push('\n'),
+                    't' => ret.push('\t'),
+                    // a line continuation
+                    '\n' => {
+                        let rest = chars.as_str().trim_start();
+                        chars = rest.chars();
+                    }
+                    c => ret.push(c),
+                },
+                c => ret.push(c),
+            }
+        }
+    }
+
+    /// Returns the messages of the calls to each of `macros` in the
+    /// source, before its tests. `skip_first` skips the first argument
+    /// of the calls, like the logger of `warn!()`.
+    fn messages(source: &str, macros: &[&str], skip_first: bool) -> Vec<String> {
+        let source = source.split("#[cfg(test)]").next().unwrap_or_default();
+        let mut ret = Vec::new();
+        for name in macros {
+            for (start, _) in source.match_indices(name) {
+                let before = source[..start].chars().next_back();
+                if before.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+                    continue;
+                }
+                let mut args = &source[start + name.len()..];
+                if skip_first {
+                    args = match args.split_once(',') {
+                        Some((_, rest)) => rest,
+                        None => continue,
+                    };
+                }
+                // messages that are picked at runtime are checked where
+                // they're written
+                if let Some(message) = literal(args) {
+                    ret.push(message.replace("{:?}", "{}"));
+                }
+            }
+        }
+        ret
+    }
+
+    fn sources(dir: &std::path::Path, ret: &mut Vec<std::path::PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                sources(&path, ret);
+            } else if path.extension().is_some_and(|ext| ext == "rs") {
+                ret.push(path);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_message_is_translated() {
+        let root = std::path::Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut files = Vec::new();
+        sources(&root.join("src"), &mut files);
+        let mut core_files = Vec::new();
+        sources(&root.join("core").join("src"), &mut core_files);
+
+        let mut missing = Vec::new();
+        for file in files.iter().chain(&core_files) {
+            // which is where the messages are looked up
+            if file.starts_with(root.join("src").join("i18n")) || file.ends_with("src/i18n.rs") {
+                continue;
+            }
+            let source = std::fs::read_to_string(file).unwrap();
+            let mut found = messages(&source, &["tr(", "trf("], false);
+            // the core leaves translating its errors and warnings to
+            // the tools that use it
+            if core_files.contains(file) {
+                found.extend(messages(&source, &["anyhow!("], false));
+                found.extend(messages(
+                    &source,
+                    &["warn!(", "info!(", "error!(", "crit!("],
+                    true,
+                ));
+            }
+            for message in found {
+                if message != "{}" && find(catalog("fr"), &message).is_none() {
+                    missing.push(format!("{}: {:?}", file.display(), message));
+                }
+            }
+        }
+        assert!(
+            missing.is_empty(),
+            "missing from fr:\n{}",
+            missing.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_translations_keep_placeholders() {
+        for (_, messages) in CATALOGS {
+            for (english, translated) in messages.iter() {
+                assert_eq!(
+                    english.matches("{}").count(),
+                    translated.matches("{}").count(),
+                    "{:?}",
+                    english
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,769 @@
+pub const MESSAGES: &[(&str, &str)] = &[
+    // errors
+    (
+        "cannot absorb while a {} is in progress, finish or abort it first, or use --force-state to override",
+        "impossible d'absorber pendant un(e) {} en cours, terminez-le ou annulez-le d'abord, ou utilisez --force-state pour passer outre",
+    ),
+    (
+        "another git absorb is running, or one was interrupted (if so, remove {})",
+        "un autre git absorb est en cours, ou l'un d'eux a été interrompu (dans ce cas, supprimez {})",
+    ),
+    (
+        "HEAD is the protected branch {} (matching {} in absorb.protectedBranches), use --force to override",
+        "HEAD est la branche protégée {} (correspondant à {} dans absorb.protectedBranches), utilisez --force pour passer outre",
+    ),
+    (
+        "{} hunks could be absorbed into the stack",
+        "{} sections pourraient être absorbées dans la pile",
+    ),
+    (
+        "{} problem was found in the configuration",
+        "{} problème a été trouvé dans la configuration",
+    ),
+    (
+        "{} problems were found in the configuration",
+        "{} problèmes ont été trouvés dans la configuration",
+    ),
+    ("the configuration is valid", "la configuration est valide"),
+    (
+        "could not record the run in the journal",
+        "impossible d'inscrire l'exécution dans le journal",
+    ),
+    ("absorb failed", "l'absorption a échoué"),
+    (
+        "--intra-line can't be used with --and-rebase, since the rebase would conflict on its fixups",
+        "--intra-line ne peut pas être utilisé avec --and-rebase, car le rebasage serait en conflit sur ses fixups",
+    ),
+    (
+        "hunk only commutes within lines, so its fixup will conflict when it is squashed",
+        "le morceau ne commute qu'à l'intérieur des lignes, donc son fixup sera en conflit lors de la fusion",
+    ),
+    (
+        "could not record the working tree, --undo will leave it as it is",
+        "impossible d'enregistrer l'arbre de travail, --undo le laissera tel quel",
+    ),
+    (
+        "the file changed since the last absorb, and was left as it is",
+        "le fichier a changé depuis la dernière absorption, et a été laissé tel quel",
+    ),
+    (
+        "cannot blame non-utf8 path",
+        "impossible d'exécuter blame sur un chemin non utf8",
+    ),
+    (
+        "invalid file mode {} in cache",
+        "mode de fichier {} invalide dans le cache",
+    ),
+    (
+        "invalid delta status {} in cache",
+        "statut de delta {} invalide dans le cache",
+    ),
+    (
+        "bad commit-graph signature in {}",
+        "signature de commit-graph incorrecte dans {}",
+    ),
+    (
+        "unsupported commit-graph version {}",
+        "version de commit-graph {} non prise en charge",
+    ),
+    (
+        "unsupported commit-graph hash version {}",
+        "version de hachage de commit-graph {} non prise en charge",
+    ),
+    (
+        "truncated commit-graph chunk table",
+        "table des blocs de commit-graph tronquée",
+    ),
+    (
+        "truncated commit-graph in {}",
+        "commit-graph tronqué dans {}",
+    ),
+    (
+        "commit-graph has no fanout",
+        "le commit-graph n'a pas de table de répartition",
+    ),
+    (
+        "commit-graph has no oids",
+        "le commit-graph n'a pas d'identifiants",
+    ),
+    (
+        "invalid value {} for {}, expected wholeFile or skip",
+        "valeur {} invalide pour {}, wholeFile ou skip attendu",
+    ),
+    (
+        "invalid value {} for {}, expected myers, minimal, patience or histogram",
+        "valeur {} invalide pour {}, myers, minimal, patience ou histogram attendu",
+    ),
+    (
+        "invalid value {} for {}, expected a non-negative number",
+        "valeur {} invalide pour {}, nombre positif ou nul attendu",
+    ),
+    (
+        "the gix backend for {} is not available in this build",
+        "le moteur gix pour {} n'est pas disponible dans cette version",
+    ),
+    (
+        "invalid value {} for {}, expected libgit2 or cli",
+        "valeur {} invalide pour {}, libgit2 ou cli attendu",
+    ),
+    (
+        "invalid value {} for {}, expected fixup, squash or amend",
+        "valeur {} invalide pour {}, fixup, squash ou amend attendu",
+    ),
+    (
+        "invalid value {} for {}, expected nearest, oldest, prompt or skip",
+        "valeur {} invalide pour {}, nearest, oldest, prompt ou skip attendu",
+    ),
+    (
+        "invalid value {} for {}, expected me, target or env",
+        "valeur {} invalide pour {}, me, target ou env attendu",
+    ),
+    (
+        "invalid value {} for {}, expected now or target",
+        "valeur {} invalide pour {}, now ou target attendu",
+    ),
+    (
+        "invalid value {} for {}, expected merge-base or fork-point",
+        "valeur {} invalide pour {}, merge-base ou fork-point attendu",
+    ),
+    (
+        "invalid value {} for {}, expected stop or first-parent",
+        "valeur {} invalide pour {}, stop ou first-parent attendu",
+    ),
+    (
+        "invalid value {} for {}: {}",
+        "valeur {} invalide pour {} : {}",
+    ),
+    (
+        "could not read {}: {}",
+        "impossible de lire {} : {}",
+    ),
+    (
+        "invalid config file {}: {}",
+        "fichier de configuration {} invalide : {}",
+    ),
+    (
+        "line {}: {}",
+        "ligne {} : {}",
+    ),
+    (
+        "line {}: expected ]",
+        "ligne {} : ] attendu",
+    ),
+    (
+        "line {}: expected =",
+        "ligne {} : = attendu",
+    ),
+    (
+        "line {}: expected the end of line",
+        "ligne {} : fin de ligne attendue",
+    ),
+    (
+        "line {}: unterminated array",
+        "ligne {} : tableau non terminé",
+    ),
+    (
+        "line {}: invalid value",
+        "ligne {} : valeur invalide",
+    ),
+    (
+        "line {}: duplicate key",
+        "ligne {} : clé en double",
+    ),
+    (
+        "invalid key",
+        "clé invalide",
+    ),
+    (
+        "expected a key",
+        "clé attendue",
+    ),
+    (
+        "editor {} exited with {}",
+        "l'éditeur {} s'est terminé avec {}",
+    ),
+    (
+        "could not run git {}: {}",
+        "impossible d'exécuter git {} : {}",
+    ),
+    (
+        "git {} failed: {}",
+        "git {} a échoué : {}",
+    ),
+    (
+        "git commit-tree returned a non-utf8 id",
+        "git commit-tree a renvoyé un identifiant non utf8",
+    ),
+    (
+        "could not run git rev-list: {}",
+        "impossible d'exécuter git rev-list : {}",
+    ),
+    (
+        "could not run git diff: {}",
+        "impossible d'exécuter git diff : {}",
+    ),
+    (
+        "git diff failed",
+        "git diff a échoué",
+    ),
+    (
+        "git write-tree returned a non-utf8 id",
+        "git write-tree a renvoyé un identifiant non utf8",
+    ),
+    (
+        "could not run {} hook: {}",
+        "impossible d'exécuter le hook {} : {}",
+    ),
+    (
+        "{} hook failed with {}",
+        "le hook {} a échoué avec {}",
+    ),
+    (
+        "line {} of {} is invalid: {}",
+        "la ligne {} de {} est invalide : {}",
+    ),
+    (
+        "--from only supports the commit at HEAD",
+        "--from ne prend en charge que le commit de HEAD",
+    ),
+    (
+        "--from requires a commit with exactly one parent",
+        "--from nécessite un commit avec exactement un parent",
+    ),
+    (
+        "{} is not a stash entry",
+        "{} n'est pas une entrée de la remise",
+    ),
+    (
+        "--stash only supports stashes made on top of HEAD",
+        "--stash ne prend en charge que les remises faites par-dessus HEAD",
+    ),
+    (
+        "cannot absorb a stash with uncommitted changes, commit or stash them first",
+        "impossible d'absorber une remise avec des modifications non commitées, commitez-les ou remisez-les d'abord",
+    ),
+    (
+        "--all cannot be used with plan files, stage the changes instead",
+        "--all ne peut pas être utilisé avec les fichiers de plan, indexez plutôt les modifications",
+    ),
+    (
+        "--target is not in the stack, use --base to extend it",
+        "--target n'est pas dans la pile, utilisez --base pour l'étendre",
+    ),
+    (
+        "could not apply the fixups to the working tree: {}",
+        "impossible d'appliquer les fixups à l'arbre de travail : {}",
+    ),
+    (
+        "{}, the run was undone",
+        "{}, l'exécution a été annulée",
+    ),
+    (
+        "{}, and the run could not be undone: {}, run git absorb --undo once that is fixed",
+        "{}, et l'exécution n'a pas pu être annulée : {}, lancez git absorb --undo une fois le problème corrigé",
+    ),
+    (
+        "invalid SOURCE_DATE_EPOCH: {}",
+        "SOURCE_DATE_EPOCH invalide : {}",
+    ),
+    (
+        "unsupported date {}, expected \"<seconds> <+hhmm>\"",
+        "date {} non prise en charge, \"<secondes> <+hhmm>\" attendu",
+    ),
+    (
+        "the stash entry was dropped while absorbing it",
+        "l'entrée de la remise a été supprimée pendant son absorption",
+    ),
+    (
+        "invalid level {} in ABSORB_LOG",
+        "niveau {} invalide dans ABSORB_LOG",
+    ),
+    (
+        "could not open {}: {}",
+        "impossible d'ouvrir {} : {}",
+    ),
+    (
+        "could not parse {}: {}",
+        "impossible d'analyser {} : {}",
+    ),
+    (
+        "cannot write rejected hunks in a bare repository",
+        "impossible d'écrire les sections rejetées dans un dépôt nu",
+    ),
+    (
+        "cannot write rejected hunks of a non-utf8 path",
+        "impossible d'écrire les sections rejetées d'un chemin non utf8",
+    ),
+    (
+        "invalid line {} in plan file: {}",
+        "ligne {} invalide dans le fichier de plan : {}",
+    ),
+    (
+        "HEAD has moved since the plan file was written",
+        "HEAD a changé depuis l'écriture du fichier de plan",
+    ),
+    (
+        "the index has changed since the plan file was written",
+        "l'index a changé depuis l'écriture du fichier de plan",
+    ),
+    (
+        "plan file lists {} hunks, but {} are staged",
+        "le fichier de plan liste {} sections, mais {} sont indexées",
+    ),
+    (
+        "plan file lists hunk {} {}, but the staged hunk is {} {}",
+        "le fichier de plan liste la section {} {}, mais la section indexée est {} {}",
+    ),
+    (
+        "could not run git rebase: {}",
+        "impossible d'exécuter git rebase : {}",
+    ),
+    (
+        "absorbing changed the index",
+        "l'absorption a modifié l'index",
+    ),
+    (
+        "absorbing everything left HEAD different from the index",
+        "tout absorber a laissé HEAD différent de l'index",
+    ),
+    (
+        "the fixups could not be squashed without conflicts",
+        "les fixups n'ont pas pu être fusionnés sans conflits",
+    ),
+    (
+        "squashing the fixups changed the tree of HEAD",
+        "fusionner les fixups a modifié l'arbre de HEAD",
+    ),
+    (
+        "a fixup was left after squashing",
+        "un fixup est resté après la fusion",
+    ),
+    (
+        "self-test failed at iteration {} of seed {}: {}, the repository is kept in {}",
+        "l'auto-test a échoué à l'itération {} de la graine {} : {}, le dépôt est conservé dans {}",
+    ),
+    (
+        "unsupported gpg.format {}",
+        "gpg.format {} non pris en charge",
+    ),
+    (
+        "user.signingKey must be set to sign commits with ssh",
+        "user.signingKey doit être défini pour signer les commits avec ssh",
+    ),
+    (
+        "could not run {}: {}",
+        "impossible d'exécuter {} : {}",
+    ),
+    (
+        "could not write to {}",
+        "impossible d'écrire dans {}",
+    ),
+    (
+        "{} failed to sign the commit: {}",
+        "{} n'a pas pu signer le commit : {}",
+    ),
+    (
+        "{} returned a non-utf8 signature",
+        "{} a renvoyé une signature non utf8",
+    ),
+    (
+        "cannot sign a commit with a non-utf8 message",
+        "impossible de signer un commit avec un message non utf8",
+    ),
+    (
+        "no absorb is in progress",
+        "aucune absorption n'est en cours",
+    ),
+    (
+        "could not abort the rebase",
+        "impossible d'annuler le rebase",
+    ),
+    (
+        "could not run {} {}: {}",
+        "impossible d'exécuter {} {} : {}",
+    ),
+    (
+        "{} {} exited with {}",
+        "{} {} s'est terminé avec {}",
+    ),
+    (
+        "{} picked {}, which is not one of the candidates",
+        "{} a choisi {}, qui ne fait pas partie des candidats",
+    ),
+    (
+        "HEAD points to a non-utf8 branch",
+        "HEAD pointe vers une branche non utf8",
+    ),
+    (
+        "cannot undo while a {} is in progress, finish or abort it first",
+        "impossible d'annuler pendant un(e) {} en cours, terminez-le ou annulez-le d'abord",
+    ),
+    (
+        "nothing to undo",
+        "rien à annuler",
+    ),
+    (
+        "{} is not a valid undo state",
+        "{} n'est pas un état d'annulation valide",
+    ),
+    // errors of git-absorb-core, translated when they are shown
+    (
+        "HEAD is not a branch, use --force to override",
+        "HEAD n'est pas une branche, utilisez --force pour passer outre",
+    ),
+    (
+        "invalid base {}, symmetric differences are not supported",
+        "base {} invalide, les différences symétriques ne sont pas prises en charge",
+    ),
+    (
+        "invalid base {}, the range must end at HEAD",
+        "base {} invalide, la plage doit se terminer à HEAD",
+    ),
+    (
+        "invalid absorb.defaultBase {}: {}",
+        "absorb.defaultBase {} invalide : {}",
+    ),
+    (
+        "got empty delta",
+        "delta vide reçu",
+    ),
+    (
+        "old path already occupied",
+        "ancien chemin déjà occupé",
+    ),
+    (
+        "new path already occupied",
+        "nouveau chemin déjà occupé",
+    ),
+    (
+        "wrong number of lines in hunk",
+        "nombre de lignes incorrect dans la section",
+    ),
+    (
+        "added line did not have lineno",
+        "la ligne ajoutée n'avait pas de numéro",
+    ),
+    (
+        "added line did not reach expected lineno",
+        "la ligne ajoutée n'a pas atteint le numéro attendu",
+    ),
+    (
+        "removed line did not have lineno",
+        "la ligne supprimée n'avait pas de numéro",
+    ),
+    (
+        "removed line did not reach expected lineno",
+        "la ligne supprimée n'a pas atteint le numéro attendu",
+    ),
+    (
+        "context line did not reach expected lineno",
+        "la ligne de contexte n'a pas atteint le numéro attendu",
+    ),
+    (
+        "nneof was already detected",
+        "l'absence de fin de ligne en fin de fichier a déjà été détectée",
+    ),
+    (
+        "removed nneof was already detected",
+        "l'absence de fin de ligne supprimée a déjà été détectée",
+    ),
+    (
+        "added nneof was already detected",
+        "l'absence de fin de ligne ajoutée a déjà été détectée",
+    ),
+    (
+        "unknown line type {}",
+        "type de ligne {} inconnu",
+    ),
+    (
+        "hunk added block size mismatch",
+        "taille du bloc ajouté de la section incohérente",
+    ),
+    (
+        "hunk removed block size mismatch",
+        "taille du bloc supprimé de la section incohérente",
+    ),
+    (
+        "delta with empty old path",
+        "delta avec un ancien chemin vide",
+    ),
+    (
+        "delta with empty new path",
+        "delta avec un nouveau chemin vide",
+    ),
+    (
+        "delta with multiple files",
+        "delta avec plusieurs fichiers",
+    ),
+    (
+        "couldn't find tree entry in tree for path",
+        "entrée d'arbre introuvable dans l'arbre pour le chemin",
+    ),
+    (
+        "couldn't find blob entry in tree for path",
+        "entrée de blob introuvable dans l'arbre pour le chemin",
+    ),
+    // states of the repository
+    ("nothing", "rien"),
+    ("merge", "fusion"),
+    ("revert", "annulation de commit"),
+    ("cherry-pick", "picorage"),
+    ("bisect", "bissection"),
+    ("rebase", "rebasage"),
+    ("git am", "git am"),
+    // the stack
+    (
+        "HEAD is not a branch, but --force used to continue.",
+        "HEAD n'est pas une branche, mais --force a été utilisé pour continuer.",
+    ),
+    (
+        "HEAD is a protected branch, but --force used to continue.",
+        "HEAD est une branche protégée, mais --force a été utilisé pour continuer.",
+    ),
+    (
+        "Will not fix up past the merge commit",
+        "Pas de correction au-delà du commit de fusion",
+    ),
+    (
+        "Will not fix up past commits that were pushed to a remote, use --force-pushed to override",
+        "Pas de correction au-delà des commits poussés vers un dépôt distant, utilisez --force-pushed pour passer outre",
+    ),
+    (
+        "Will not fix up past commits not authored by you, use --force or --include-author to override",
+        "Pas de correction au-delà des commits dont vous n'êtes pas l'auteur, utilisez --force ou --include-author pour passer outre",
+    ),
+    (
+        "stack limit reached, use --base or configure absorb.maxStack to override",
+        "limite de la pile atteinte, utilisez --base ou configurez absorb.maxStack pour passer outre",
+    ),
+    (
+        "Will not fix up past the shallow boundary of the repository, use git fetch --deepen or --unshallow to go further",
+        "Pas de correction au-delà de la limite superficielle du dépôt, utilisez git fetch --deepen ou --unshallow pour aller plus loin",
+    ),
+    (
+        "Please use --base to specify a base commit.",
+        "Veuillez utiliser --base pour indiquer un commit de base.",
+    ),
+    (
+        "Please try a different --base",
+        "Veuillez essayer une autre --base",
+    ),
+    (
+        "No commits available to fix up, exiting",
+        "Aucun commit à corriger, abandon",
+    ),
+    // planning
+    (
+        "a {} is in progress, but --force-state used to continue.",
+        "un(e) {} est en cours, mais --force-state a été utilisé pour continuer.",
+    ),
+    (
+        "skipped file with missing objects, fetch them with git fetch",
+        "fichier ignoré car des objets manquent, récupérez-les avec git fetch",
+    ),
+    (
+        "absorbing large file as a whole",
+        "absorption du gros fichier en entier",
+    ),
+    ("skipped large file", "gros fichier ignoré"),
+    (
+        "absorbing file with a textconv diff driver as a whole",
+        "absorption en entier du fichier ayant un pilote de diff textconv",
+    ),
+    (
+        "skipped hunk that only changes line endings",
+        "section ignorée car elle ne change que les fins de ligne",
+    ),
+    ("could not blame file", "impossible d'exécuter blame sur le fichier"),
+    (
+        "hunk commutes with the whole stack, but {} commits touched its file",
+        "la section commute avec toute la pile, mais {} commits ont touché son fichier",
+    ),
+    ("  candidate", "  candidat"),
+    (
+        "Could not find a commit to fix up, use --base to increase the search range.",
+        "Aucun commit à corriger trouvé, utilisez --base pour étendre la recherche.",
+    ),
+    ("{}, leaving it in the index", "{}, elle reste dans l'index"),
+    (
+        "hunk belongs to a merge commit",
+        "la section appartient à un commit de fusion",
+    ),
+    (
+        "hunk belongs to a commit excluded by its message",
+        "la section appartient à un commit exclu par son message",
+    ),
+    (
+        "{} staged file has hunks that may belong to commits past the stack limit, use --base or raise absorb.maxStack",
+        "{} fichier indexé a des sections qui pourraient appartenir à des commits au-delà de la limite de la pile, utilisez --base ou augmentez absorb.maxStack",
+    ),
+    (
+        "{} staged files have hunks that may belong to commits past the stack limit, use --base or raise absorb.maxStack",
+        "{} fichiers indexés ont des sections qui pourraient appartenir à des commits au-delà de la limite de la pile, utilisez --base ou augmentez absorb.maxStack",
+    ),
+    (
+        "hunk could also be absorbed into {} other commit",
+        "la section pourrait aussi être absorbée dans {} autre commit",
+    ),
+    (
+        "hunk could also be absorbed into {} other commits",
+        "la section pourrait aussi être absorbée dans {} autres commits",
+    ),
+    (
+        "hunk edits lines that its target moved from another file",
+        "la section modifie des lignes que sa cible a déplacées depuis un autre fichier",
+    ),
+    (
+        "self-test passed",
+        "l'autotest a réussi",
+    ),
+    (
+        "paranoid check failed, undoing the run",
+        "la vérification paranoïaque a échoué, annulation de l'exécution",
+    ),
+    (
+        "hunk has a target past the end of the stack",
+        "la section a une cible au-delà de la fin de la pile",
+    ),
+    (
+        "set absorb.traverseMerges = first-parent to go past merges",
+        "définissez absorb.traverseMerges = first-parent pour aller au-delà des fusions",
+    ),
+    (
+        "use --force-pushed to go past pushed commits",
+        "utilisez --force-pushed pour aller au-delà des commits poussés",
+    ),
+    (
+        "use --include-author or absorb.authorAllowlist to go past commits by other authors",
+        "utilisez --include-author ou absorb.authorAllowlist pour aller au-delà des commits d'autres auteurs",
+    ),
+    (
+        "use --base, or raise absorb.maxStack",
+        "utilisez --base, ou augmentez absorb.maxStack",
+    ),
+    (
+        "hunk belongs to a commit that touched too many files",
+        "la section appartient à un commit qui a modifié trop de fichiers",
+    ),
+    (
+        "Could not find a commit that created another file in the same directory for this new file. Use --target to choose one.",
+        "Aucun commit ayant créé un autre fichier du même répertoire trouvé pour ce nouveau fichier. Utilisez --target pour en choisir un.",
+    ),
+    (
+        "Left new file in the index. Use --new-files or --target to absorb it.",
+        "Nouveau fichier laissé dans l'index. Utilisez --new-files ou --target pour l'absorber.",
+    ),
+    (
+        "Could not find the commit that created this deleted file. It must have been created in the stack, and not changed since.",
+        "Impossible de trouver le commit ayant créé ce fichier supprimé. Il doit avoir été créé dans la pile, et ne pas avoir changé depuis.",
+    ),
+    (
+        "Could not find a commit in the stack that touched this file to absorb its mode change into. Use --target to choose one.",
+        "Aucun commit de la pile ayant touché ce fichier trouvé pour y absorber son changement de mode. Utilisez --target pour en choisir un.",
+    ),
+    (
+        "Could not find a commit in the stack that touched this path to absorb its change into. Use --target to choose one.",
+        "Aucun commit de la pile ayant touché ce chemin trouvé pour y absorber son changement. Utilisez --target pour en choisir un.",
+    ),
+    ("nothing left to absorb", "plus rien à absorber"),
+    (
+        "hunk should have been absorbed",
+        "la section aurait dû être absorbée",
+    ),
+    (
+        "Aborted, no changes were made.",
+        "Abandon, aucune modification n'a été faite.",
+    ),
+    (
+        "No additions staged, try adding something to the index.",
+        "Aucun ajout indexé, essayez d'ajouter quelque chose à l'index.",
+    ),
+    (
+        "watching the index, press Ctrl-C to stop",
+        "surveillance de l'index, appuyez sur Ctrl-C pour arrêter",
+    ),
+    (
+        "fetching missing objects",
+        "récupération des objets manquants",
+    ),
+    (
+        "could not fetch missing objects",
+        "impossible de récupérer les objets manquants",
+    ),
+    // committing
+    ("committed", "commit créé"),
+    ("would have committed", "aurait créé le commit"),
+    (
+        "committed leftovers",
+        "commit des restes créé",
+    ),
+    (
+        "would have committed leftovers",
+        "aurait créé le commit des restes",
+    ),
+    ("amended existing fixup", "correction existante amendée"),
+    (
+        "some hunks of the patch were not absorbed",
+        "certaines sections du patch n'ont pas été absorbées",
+    ),
+    (
+        "rejected hunks are in {}",
+        "les sections rejetées sont dans {}",
+    ),
+    (
+        "absorbed all of the source commit, dropping it",
+        "tout le commit source a été absorbé, il est supprimé",
+    ),
+    (
+        "rewrote the source commit with the remaining changes",
+        "commit source réécrit avec les modifications restantes",
+    ),
+    (
+        "could not keep the staged changes of the stash apart",
+        "impossible de garder à part les modifications indexées du remisage",
+    ),
+    (
+        "absorbed all of the stash, dropping it",
+        "tout le remisage a été absorbé, il est supprimé",
+    ),
+    (
+        "stashed the remaining changes",
+        "modifications restantes remisées",
+    ),
+    // rebasing
+    (
+        "could not autosquash in memory, running git rebase",
+        "impossible de fusionner les corrections en mémoire, lancement de git rebase",
+    ),
+    ("autosquashed fixups", "corrections fusionnées"),
+    ("updated branch", "branche mise à jour"),
+    (
+        "rebase stopped, resolve the conflict and run git absorb --continue, or run git absorb --abort to undo the whole absorb",
+        "rebasage arrêté, résolvez le conflit et lancez git absorb --continue, ou lancez git absorb --abort pour annuler toute l'absorption",
+    ),
+    ("fixup not applied yet", "correction pas encore appliquée"),
+    ("absorb finished", "absorption terminée"),
+    (
+        "restored state from before the last absorb",
+        "état d'avant la dernière absorption restauré",
+    ),
+    // summaries
+    (
+        "{} absorbed into {}, {} and {} left in the index",
+        "{} absorbée(s) dans {}, {} et {} laissé(s) dans l'index",
+    ),
+    (
+        "{} of them is generated, per its attributes",
+        "{} d'entre eux est généré, d'après ses attributs",
+    ),
+    (
+        "{} of them are generated, per their attributes",
+        "{} d'entre eux sont générés, d'après leurs attributs",
+    ),
+    ("{} file", "{} fichier"),
+    ("{} files", "{} fichiers"),
+    ("{} hunk", "{} section"),
+    ("{} hunks", "{} sections"),
+    ("{} commit", "{} commit"),
+    ("{} commits", "{} commits"),
+];
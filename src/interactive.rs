@@ -0,0 +1,377 @@
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use std::io::{BufRead, Write};
+
+use crate::owned;
+use crate::plan;
+use crate::render::{self, hunk_text};
+
+const HELP: &str = "j/k: move  h/l: change target  d: drop/restore  enter: confirm  q: abort";
+
+/// Shows the plan in a terminal UI, and lets the user change the
+/// target of each hunk before anything is committed.
+///
+/// Returns `true` if the user confirmed the (possibly edited) plan,
+/// and `false` if they aborted.
+pub fn review<'repo>(
+    plan: &mut plan::Plan<'repo>,
+    candidates: &[git2::Commit<'repo>],
+) -> Result<bool> {
+    let mut review = Review::new(plan, candidates);
+    let mut terminal = ratatui::init();
+    let result = review.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+const PATCH_HELP: &str = "\
+y - absorb this hunk into the proposed commit
+n - leave this hunk in the index
+e - absorb this hunk into a different commit
+q - quit; leave this hunk and all remaining ones in the index
+? - print help
+";
+
+/// Walks through each hunk in the plan, like `git add -p`, asking
+/// whether to accept, skip or retarget it.
+pub fn review_each<'repo, R, W>(
+    plan: &mut plan::Plan<'repo>,
+    candidates: &[git2::Commit<'repo>],
+    mut input: R,
+    mut output: W,
+    color: bool,
+) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    let count = plan.assignments.len();
+    let mut quit = false;
+    for (idx, assignment) in plan.assignments.iter_mut().enumerate() {
+        if quit {
+            assignment.retarget(None, plan::SkipReason::Dropped);
+            continue;
+        }
+
+        writeln!(output, "{}", String::from_utf8_lossy(&assignment.path))?;
+        for line in hunk_text(&assignment.hunk) {
+            if color {
+                writeln!(output, "{}", render::colorize(&line))?;
+            } else {
+                writeln!(output, "{}", line)?;
+            }
+        }
+        loop {
+            match assignment.target {
+                Some(ref target) => write!(
+                    output,
+                    "({}/{}) Absorb into {}? [y,n,e,q,?] ",
+                    idx + 1,
+                    count,
+                    describe(target)
+                )?,
+                None => write!(
+                    output,
+                    "({}/{}) No commit found, leave in index? [y,e,q,?] ",
+                    idx + 1,
+                    count
+                )?,
+            }
+            output.flush()?;
+
+            let answer = match read_answer(&mut input)? {
+                Some(answer) => answer,
+                // treat the end of input like quitting
+                None => "q".to_owned(),
+            };
+            match answer.as_str() {
+                "y" => break,
+                "n" => {
+                    assignment.retarget(None, plan::SkipReason::Dropped);
+                    break;
+                }
+                "q" => {
+                    assignment.retarget(None, plan::SkipReason::Dropped);
+                    quit = true;
+                    break;
+                }
+                "e" => {
+                    if let Some(target) = choose_target(candidates, &mut input, &mut output)? {
+                        assignment.retarget(Some(target), plan::SkipReason::Dropped);
+                        break;
+                    }
+                }
+                _ => write!(output, "{}", PATCH_HELP)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn choose_target<'repo, R, W>(
+    candidates: &[git2::Commit<'repo>],
+    input: &mut R,
+    output: &mut W,
+) -> Result<Option<git2::Commit<'repo>>>
+where
+    R: BufRead,
+    W: Write,
+{
+    for (idx, commit) in candidates.iter().enumerate() {
+        writeln!(output, "{:3}) {}", idx + 1, describe(commit))?;
+    }
+    write!(output, "Absorb into which commit? [number or commit id] ")?;
+    output.flush()?;
+
+    let answer = read_answer(input)?.unwrap_or_default();
+    let chosen = match answer.parse::<usize>() {
+        Ok(number) if number >= 1 => candidates.get(number - 1),
+        _ if answer.len() >= 4 => candidates
+            .iter()
+            .find(|commit| commit.id().to_string().starts_with(&answer)),
+        _ => None,
+    };
+    if chosen.is_none() {
+        writeln!(output, "No such commit: {}", answer)?;
+    }
+    Ok(chosen.cloned())
+}
+
+fn read_answer<R: BufRead>(input: &mut R) -> Result<Option<String>> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(line.trim().to_owned()))
+}
+
+fn describe(commit: &git2::Commit) -> String {
+    format!(
+        "{} {}",
+        &commit.id().to_string()[..7],
+        commit.summary().unwrap_or("")
+    )
+}
+
+struct Review<'a, 'repo> {
+    plan: &'a mut plan::Plan<'repo>,
+    candidates: &'a [git2::Commit<'repo>],
+    // the targets that were computed for each hunk, so that dropping
+    // a hunk can be undone
+    computed: Vec<Option<git2::Commit<'repo>>>,
+    list: ListState,
+}
+
+impl<'a, 'repo> Review<'a, 'repo> {
+    fn new(plan: &'a mut plan::Plan<'repo>, candidates: &'a [git2::Commit<'repo>]) -> Self {
+        let computed = plan
+            .assignments
+            .iter()
+            .map(|assignment| assignment.target.clone())
+            .collect();
+        Review {
+            plan,
+            candidates,
+            computed,
+            list: ListState::default().with_selected(Some(0)),
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<bool> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+                KeyCode::Char('y') | KeyCode::Enter => return Ok(true),
+                KeyCode::Char('j') | KeyCode::Down => self.list.select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.list.select_previous(),
+                KeyCode::Char('l') | KeyCode::Right => self.retarget(true),
+                KeyCode::Char('h') | KeyCode::Left => self.retarget(false),
+                KeyCode::Char('d') => self.toggle_drop(),
+                _ => {}
+            }
+        }
+    }
+
+    fn selected(&self) -> usize {
+        self.list
+            .selected()
+            .unwrap_or(0)
+            .min(self.plan.assignments.len() - 1)
+    }
+
+    /// Moves the selected hunk's target to the next (older) or
+    /// previous (newer) commit in the stack. Moving past either end
+    /// leaves the hunk in the index.
+    fn retarget(&mut self, older: bool) {
+        let candidates = self.candidates;
+        let idx = self.selected();
+        let assignment = &mut self.plan.assignments[idx];
+        let position = assignment.target.as_ref().and_then(|target| {
+            candidates
+                .iter()
+                .position(|commit| commit.id() == target.id())
+        });
+        let position = match (position, older) {
+            (None, true) => Some(0),
+            (None, false) => candidates.len().checked_sub(1),
+            (Some(idx), true) if idx + 1 < candidates.len() => Some(idx + 1),
+            (Some(idx), false) if idx > 0 => Some(idx - 1),
+            (Some(_), _) => None,
+        };
+        assignment.retarget(
+            position.map(|idx| candidates[idx].clone()),
+            plan::SkipReason::Dropped,
+        );
+    }
+
+    fn toggle_drop(&mut self) {
+        let idx = self.selected();
+        let assignment = &mut self.plan.assignments[idx];
+        let target = match assignment.target {
+            Some(_) => None,
+            None => self.computed[idx].clone(),
+        };
+        assignment.retarget(target, plan::SkipReason::Dropped);
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [list_area, preview_area, help_area] = Layout::vertical([
+            Constraint::Percentage(50),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let items: Vec<_> = self
+            .plan
+            .assignments
+            .iter()
+            .map(|assignment| {
+                let target = match assignment.target {
+                    Some(ref commit) => Span::raw(describe(commit)),
+                    None => Span::styled("(left in index)", Style::default().fg(Color::DarkGray)),
+                };
+                ListItem::new(Line::from(vec![
+                    Span::raw(format!(
+                        "{} {} -> ",
+                        String::from_utf8_lossy(&assignment.path),
+                        assignment.hunk.header()
+                    )),
+                    target,
+                ]))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("absorb plan"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list);
+
+        let hunk = &self.plan.assignments[self.selected()].hunk;
+        let preview = Paragraph::new(hunk_lines(hunk))
+            .block(Block::default().borders(Borders::ALL).title("hunk"));
+        frame.render_widget(preview, preview_area);
+
+        frame.render_widget(Paragraph::new(HELP), help_area);
+    }
+}
+
+fn hunk_lines(hunk: &owned::Hunk) -> Vec<Line<'static>> {
+    hunk_text(hunk)
+        .into_iter()
+        .map(|line| {
+            let color = match line.chars().next() {
+                Some('@') => Color::Cyan,
+                Some('-') => Color::Red,
+                Some('+') => Color::Green,
+                _ => Color::Reset,
+            };
+            Line::styled(line, Style::default().fg(color))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn hunk() -> owned::Hunk {
+        owned::Hunk {
+            added: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![owned::Line::from(&b"bar\n"[..])]),
+                trailing_newline: true,
+            },
+            removed: owned::Block {
+                start: 1,
+                lines: Arc::new(vec![owned::Line::from(&b"foo\n"[..])]),
+                trailing_newline: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_review_each() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = git2::Repository::init(&dir).unwrap();
+        let sig = git2::Signature::now("nobody", "nobody@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.treebuilder(None).unwrap().write().unwrap())
+            .unwrap();
+        let first = repo
+            .find_commit(repo.commit(None, &sig, &sig, "first", &tree, &[]).unwrap())
+            .unwrap();
+        let second = repo
+            .find_commit(
+                repo.commit(None, &sig, &sig, "second", &tree, &[&first])
+                    .unwrap(),
+            )
+            .unwrap();
+        let candidates = vec![second.clone(), first.clone()];
+
+        let mut plan = plan::Plan::default();
+        for _ in 0..4 {
+            plan.assignments.push(plan::HunkAssignment {
+                path: b"file".to_vec(),
+                hunk: hunk(),
+                target: Some(second.clone()),
+                skip_reason: None,
+                file_change: None,
+            });
+        }
+
+        // skip the first hunk, retarget the second (after an invalid
+        // choice), accept the third, and quit at the fourth
+        let input: &[u8] = b"n\ne\n9\ne\n2\ny\nq\n";
+        let mut output = Vec::new();
+        review_each(&mut plan, &candidates, input, &mut output, false).unwrap();
+
+        let targets: Vec<_> = plan
+            .assignments
+            .iter()
+            .map(|assignment| assignment.target.as_ref().map(|commit| commit.id()))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![None, Some(first.id()), Some(second.id()), None]
+        );
+        assert_eq!(
+            plan.assignments[0].skip_reason,
+            Some(plan::SkipReason::Dropped)
+        );
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("No such commit: 9"));
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::i18n::{tr, trf};
+use crate::plan;
+
+/// The file in the git directory that every run is recorded in, one
+/// JSON object per line, oldest first.
+const JOURNAL_FILE: &str = "absorb/journal";
+
+/// What a run of git absorb did, as recorded in the journal.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the run finished, in seconds since the epoch.
+    pub timestamp: i64,
+    /// "absorb" or "undo".
+    pub action: String,
+    /// HEAD before the run.
+    pub head: String,
+    /// The commit that the stack was based on, or `None` if it reached
+    /// the root commit.
+    #[serde(default)]
+    pub base: Option<String>,
+    #[serde(default)]
+    pub fixups: Vec<Fixup>,
+    /// The commit of the changes that were left unabsorbed, with
+    /// `--leftovers=commit`.
+    #[serde(default)]
+    pub leftovers: Option<String>,
+    #[serde(default)]
+    pub skipped: Vec<Skipped>,
+    /// Whether the fixups were to be squashed with `--and-rebase`.
+    #[serde(default)]
+    pub rebase: bool,
+    /// The commit that `--undo` moved HEAD back to.
+    #[serde(default)]
+    pub restored: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Fixup {
+    pub commit: String,
+    pub target: String,
+    pub path: String,
+    pub header: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Skipped {
+    pub path: String,
+    /// `None` if the whole file was skipped.
+    pub header: Option<String>,
+    pub reason: String,
+}
+
+impl Entry {
+    fn new(action: &str, head: git2::Oid) -> Self {
+        Entry {
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs() as i64),
+            action: action.to_owned(),
+            head: head.to_string(),
+            base: None,
+            fixups: Vec::new(),
+            leftovers: None,
+            skipped: Vec::new(),
+            rebase: false,
+            restored: None,
+        }
+    }
+
+    /// Describes a run that absorbed hunks of the plan. `committed`
+    /// holds the fixup commits in the same order as
+    /// `plan.hunks_to_apply()`, followed by the commit of the leftovers
+    /// if there is one.
+    pub fn absorb(
+        plan: &plan::Plan,
+        committed: &[git2::Oid],
+        head: git2::Oid,
+        base: Option<git2::Oid>,
+        rebase: bool,
+    ) -> Self {
+        let mut entry = Entry::new("absorb", head);
+        entry.base = base.map(|base| base.to_string());
+        entry.rebase = rebase;
+        let mut committed = committed.iter();
+        for (assignment, hunk) in plan.hunks_to_apply() {
+            // unwrap() is safe here, as hunks_to_apply() only returns
+            // hunks that have a target
+            let target = assignment.target.as_ref().unwrap();
+            if let Some(commit) = committed.next() {
+                entry.fixups.push(Fixup {
+                    commit: commit.to_string(),
+                    target: target.id().to_string(),
+                    path: String::from_utf8_lossy(&assignment.path).into_owned(),
+                    header: hunk.header(),
+                });
+            }
+        }
+        entry.leftovers = committed.next().map(|commit| commit.to_string());
+        for assignment in &plan.assignments {
+            if let Some(reason) = assignment.skip_reason {
+                entry.skipped.push(Skipped {
+                    path: String::from_utf8_lossy(&assignment.path).into_owned(),
+                    header: Some(assignment.hunk.header()),
+                    reason: reason.as_str().to_owned(),
+                });
+            }
+        }
+        for skipped in &plan.skipped_paths {
+            entry.skipped.push(Skipped {
+                path: String::from_utf8_lossy(&skipped.path).into_owned(),
+                header: None,
+                reason: skipped.reason.as_str().to_owned(),
+            });
+        }
+        entry
+    }
+
+    /// Describes a run of `--undo` that moved HEAD from `head` back to
+    /// `restored`.
+    pub fn undo(head: git2::Oid, restored: git2::Oid) -> Self {
+        let mut entry = Entry::new("undo", head);
+        entry.restored = Some(restored.to_string());
+        entry
+    }
+}
+
+fn path(repo: &git2::Repository) -> PathBuf {
+    repo.path().join(JOURNAL_FILE)
+}
+
+/// Appends the entry to the journal. The run already happened, so
+/// failing to record it is only a warning.
+pub fn record(repo: &git2::Repository, entry: &Entry, logger: &slog::Logger) {
+    if let Err(e) = append(repo, entry) {
+        warn!(logger, "{}", tr("could not record the run in the journal");
+              "error" => e.to_string(),
+        );
+    }
+}
+
+fn append(repo: &git2::Repository, entry: &Entry) -> Result<()> {
+    let path = path(repo);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Returns the entries of the journal, oldest first.
+pub fn read(repo: &git2::Repository) -> Result<Vec<Entry>> {
+    let content = match fs::read_to_string(path(repo)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            serde_json::from_str(line).map_err(|e| {
+                anyhow!(
+                    "{}",
+                    trf(
+                        "line {} of {} is invalid: {}",
+                        &[&(idx + 1), &JOURNAL_FILE, &e]
+                    )
+                )
+            })
+        })
+        .collect()
+}
+
+/// Formats seconds since the epoch as a UTC date, like
+/// "2024-01-31 12:34:56".
+fn format_timestamp(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let seconds = timestamp.rem_euclid(86400);
+    // from the days since 1970-01-01 to the proleptic gregorian
+    // calendar, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn short(id: &str) -> &str {
+    id.get(..7).unwrap_or(id)
+}
+
+fn describe_commit(repo: &git2::Repository, id: &str) -> String {
+    let summary = git2::Oid::from_str(id)
+        .and_then(|id| repo.find_commit(id))
+        .ok()
+        .and_then(|commit| commit.summary().map(String::from));
+    match summary {
+        Some(summary) => format!("{} {:?}", short(id), summary),
+        None => short(id).to_owned(),
+    }
+}
+
+/// Writes the runs in the journal, newest first, with the fixups that
+/// each one created and the hunks that it left in the index.
+pub fn show(repo: &git2::Repository, out: &mut dyn Write) -> Result<()> {
+    for entry in read(repo)?.iter().rev() {
+        let when = format_timestamp(entry.timestamp);
+        if let Some(restored) = &entry.restored {
+            writeln!(
+                out,
+                "{} undo: {} -> {}",
+                when,
+                short(&entry.head),
+                short(restored)
+            )?;
+            continue;
+        }
+        let fixups = if entry.fixups.len() == 1 {
+            "fixup"
+        } else {
+            "fixups"
+        };
+        write!(
+            out,
+            "{} {}: {} {} on {}",
+            when,
+            entry.action,
+            entry.fixups.len(),
+            fixups,
+            short(&entry.head)
+        )?;
+        if let Some(base) = &entry.base {
+            write!(out, ", based on {}", short(base))?;
+        }
+        if entry.rebase {
+            write!(out, ", rebased")?;
+        }
+        writeln!(out)?;
+        for fixup in &entry.fixups {
+            writeln!(
+                out,
+                "  {} {} {} -> {}",
+                short(&fixup.commit),
+                fixup.path,
+                fixup.header,
+                describe_commit(repo, &fixup.target)
+            )?;
+        }
+        if let Some(leftovers) = &entry.leftovers {
+            writeln!(out, "  {} leftovers", short(leftovers))?;
+        }
+        for skipped in &entry.skipped {
+            match &skipped.header {
+                Some(header) => writeln!(
+                    out,
+                    "  skipped {} {} ({})",
+                    skipped.path, header, skipped.reason
+                )?,
+                None => writeln!(out, "  skipped {} ({})", skipped.path, skipped.reason)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951_827_696), "2000-02-29 12:34:56");
+        assert_eq!(format_timestamp(1_735_689_599), "2024-12-31 23:59:59");
+    }
+}
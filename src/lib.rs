@@ -2,276 +2,2214 @@
 extern crate slog;
 use anyhow::{anyhow, Result};
 
-mod commute;
-mod owned;
-mod stack;
+mod amend;
+mod backend;
+mod blame;
+mod cache;
+mod commit_graph;
+mod config;
+mod config_file;
+mod doctor;
+mod editor;
+mod exclude;
+mod git_cli;
+mod hooks;
+mod i18n;
+mod interactive;
+mod journal;
+mod lock;
+mod log_filter;
+mod output;
+mod patchfile;
+mod planfile;
+mod profile;
+mod rebase;
+mod render;
+mod self_test;
+mod semantic;
+mod sign;
+mod state;
+mod target_command;
+mod undo;
 
-use std::io::Write;
+pub use crate::config_file::Layered;
+pub use crate::i18n::{tr, tr_error, trf, Translate};
+pub use crate::log_filter::LogFilter;
+pub use crate::profile::Profile;
+use backend::{Backend, DiffSettings};
+use git_absorb_core::target::{self, find_target, StackDiff};
+use git_absorb_core::{apply, owned, plan, stack};
+use rayon::prelude::*;
 
+use std::collections::hash_map::{Entry, HashMap};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone)]
 pub struct Config<'a> {
     pub dry_run: bool,
     pub force: bool,
-    pub base: Option<&'a str>,
+    pub force_pushed: bool,
+    pub force_state: bool,
+    pub include_authors: Vec<&'a str>,
+    pub base: Vec<&'a str>,
+    pub from: Option<&'a str>,
+    pub stash: Option<&'a str>,
+    pub patch_file: Option<&'a str>,
+    pub target: Option<&'a str>,
     pub and_rebase: bool,
+    /// Check that committing (and squashing) the fixups lost none of the
+    /// staged changes, and undo the run otherwise.
+    pub paranoid: bool,
     pub whole_file: bool,
+    pub oldest: bool,
+    /// Commits that touched more files than this are never targeted,
+    /// overriding `absorb.skipBulkCommits`. 0 targets them.
+    pub skip_bulk: Option<usize>,
+    pub blame_fallback: bool,
+    pub intra_line: bool,
+    /// Absorb the lines that a move adds back into the commit of the
+    /// lines it removed, as `absorb.detectMoves` does.
+    pub detect_moves: bool,
+    pub new_files: bool,
+    pub diff_algorithm: Option<DiffAlgorithm>,
+    pub unified: Option<u32>,
+    pub interactive: bool,
+    pub patch: bool,
+    pub all: bool,
+    pub explain: bool,
+    /// `-v`, which also reports whether the hunks left without a target
+    /// have one past the end of the stack.
+    pub verbose: bool,
+    pub output_format: OutputFormat,
+    pub path_format: PathFormat,
+    pub fixup_kind: Option<FixupKind>,
+    pub edit_message: bool,
+    pub fixup_by_sha: bool,
+    pub signoff: bool,
+    pub gpg_sign: bool,
+    pub signing_key: Option<&'a str>,
+    pub run_hooks: bool,
+    pub date: Option<&'a str>,
+    pub rebase_options: Vec<&'a str>,
+    pub jobs: Option<usize>,
+    pub leftovers: Leftovers,
+    pub action: Action<'a>,
+    pub pathspecs: Vec<&'a str>,
+    /// Where the time spent in each phase is recorded, for `--profile`.
+    pub profile: Option<&'a Profile>,
     pub logger: &'a slog::Logger,
 }
 
-pub fn run(config: &Config) -> Result<()> {
-    let repo = git2::Repository::open_from_env()?;
+/// What to do with the fixups once they have been planned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action<'a> {
+    /// Commit the fixups straight away.
+    Absorb,
+    /// Write the plan to the given file (or stdout) without making any
+    /// commits.
+    WritePlan(Option<&'a str>),
+    /// Replace the targets in the plan with the ones in the given plan
+    /// file, and then commit the fixups.
+    ApplyPlan(&'a str),
+    /// Restore HEAD and the index to their state before the last run.
+    Undo,
+    /// Continue a rebase of `--and-rebase` that stopped on a conflict.
+    Continue,
+    /// Abort a rebase of `--and-rebase` that stopped on a conflict, and
+    /// undo the whole run.
+    Abort,
+    /// Fail if the working tree or the index has changes that could be
+    /// absorbed, without making any commits.
+    Check,
+    /// Plan the fixups again whenever the index changes, until
+    /// interrupted, and also commit them with `commit`.
+    Watch { commit: bool },
+    /// Report everything that affects absorbing in the repository,
+    /// without absorbing anything.
+    Doctor,
+    /// Report the settings that are unknown, invalid or that conflict
+    /// with each other, without absorbing anything.
+    ValidateConfig,
+    /// Absorb the staged changes of made-up histories in temporary
+    /// repositories, checking that the fixups squash back into the same
+    /// tree, from the given seed.
+    SelfTest { iterations: usize, seed: u64 },
+    /// Show the runs recorded in the journal, newest first.
+    Log,
+}
+
+/// What happens to the staged changes that could not be absorbed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Leftovers {
+    /// They are left in the index.
+    Stage,
+    /// They are committed on top of the fixups, as a single
+    /// `WIP: unabsorbed changes` commit.
+    Commit,
+}
+
+/// How the result of a run is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable log lines on stderr only.
+    Text,
+    /// A JSON description of the plan on stdout, in addition to the
+    /// log lines on stderr.
+    Json,
+    /// A stable, tab-separated line for each hunk on stdout, in
+    /// addition to the log lines on stderr.
+    Porcelain,
+}
+
+/// How paths are written on stdout (in the porcelain format, diffstats
+/// and previewed patches).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathFormat {
+    /// Quoted like git quotes them, escaping bytes outside of ASCII as
+    /// well unless `core.quotePath` is false.
+    Quoted,
+    /// Written as they are, with invalid UTF-8 replaced.
+    Literal,
+}
+
+/// The kind of commit that is created for each fixup, named after the
+/// prefix that `git rebase --autosquash` looks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixupKind {
+    /// The commit's changes are squashed into the target, and its
+    /// message is discarded.
+    Fixup,
+    /// The commit's changes are squashed into the target, and its
+    /// message is appended to the target's message.
+    Squash,
+    /// The commit's changes are squashed into the target, and its
+    /// message replaces the target's message.
+    Amend,
+}
+impl FixupKind {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            FixupKind::Fixup => "fixup! ",
+            FixupKind::Squash => "squash! ",
+            FixupKind::Amend => "amend! ",
+        }
+    }
+}
+
+/// Whose identity each fixup is authored with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorStrategy {
+    /// The configured identity of the user, like `git commit`.
+    Me,
+    /// The author (and author date) of the fixup's target, so that
+    /// attribution is kept once it is squashed.
+    Target,
+    /// `GIT_AUTHOR_NAME`, `GIT_AUTHOR_EMAIL` and `GIT_AUTHOR_DATE`,
+    /// falling back to the configured identity for any that are unset.
+    Env,
+}
+
+/// What to do with a hunk that commutes with every commit in the
+/// stack, when some of them touched its file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmbiguityStrategy {
+    /// Absorb it into the newest commit that touched its file.
+    Nearest,
+    /// Absorb it into the oldest commit that touched its file.
+    Oldest,
+    /// Ask which of the commits that touched its file to absorb it
+    /// into.
+    Prompt,
+    /// Leave it in the index.
+    Skip,
+}
+
+/// The algorithm that staged changes and stack commits are diffed
+/// with, like `git diff --diff-algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    Myers,
+    Minimal,
+    Patience,
+    /// libgit2 has no histogram diff, so this diffs like patience does
+    /// (which histogram extends), except with the `cli` backend.
+    Histogram,
+}
+impl DiffAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiffAlgorithm::Myers => "myers",
+            DiffAlgorithm::Minimal => "minimal",
+            DiffAlgorithm::Patience => "patience",
+            DiffAlgorithm::Histogram => "histogram",
+        }
+    }
+}
+
+/// What to do with a staged file that is too big to commute line by
+/// line, per `absorb.maxFileSize` and `absorb.maxLineLength`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LargeFiles {
+    /// Absorb all of its hunks into the newest commit that touched it,
+    /// as `--whole-file` does.
+    WholeFile,
+    /// Leave it in the index, with a warning.
+    Skip,
+}
+
+/// Which time each fixup is committed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateStrategy {
+    /// The current time.
+    Now,
+    /// The committer date of the fixup's target.
+    Target,
+}
+
+/// What a run found, which is reported through the exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every staged change was (or would have been) absorbed. Actions
+    /// other than absorbing (eg: `--undo`) also report this.
+    Absorbed,
+    /// Some staged changes were absorbed, and the others had no target
+    /// and were left in the index.
+    PartiallyAbsorbed,
+    /// None of the staged changes had a target.
+    NothingAbsorbed,
+    /// Nothing was staged.
+    NothingStaged,
+}
+
+impl Outcome {
+    /// The exit code of the outcome. A real run exits with 0 when it
+    /// succeeded, unless it is `strict` (`--strict-exit`). A dry run
+    /// exits with 2 when some hunks have no target, whether or not
+    /// others do, and only tells the two apart when it is `strict`.
+    pub fn exit_code(self, dry_run: bool, strict: bool) -> i32 {
+        match self {
+            _ if !dry_run && !strict => 0,
+            Outcome::Absorbed => 0,
+            Outcome::PartiallyAbsorbed if strict => 7,
+            Outcome::PartiallyAbsorbed | Outcome::NothingAbsorbed => 2,
+            Outcome::NothingStaged => 3,
+        }
+    }
+}
+
+/// The errors that scripts may want to tell apart from the others, each
+/// with its own exit code. Every other error exits with 1.
+///
+/// This is written by hand rather than derived, as `thiserror` isn't a
+/// dependency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A merge, rebase or similar is in progress, and `--force-state`
+    /// wasn't given.
+    InProgress(git2::RepositoryState),
+    /// Another run holds the lock on the repository.
+    Locked(std::path::PathBuf),
+    /// HEAD is a branch matching this pattern of
+    /// `absorb.protectedBranches`, and `--force` wasn't given.
+    Protected { branch: String, pattern: String },
+    /// `--check` found this many hunks that could be absorbed.
+    Absorbable(usize),
+    /// `config --validate` found this many problems in the settings.
+    InvalidConfig(usize),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::InProgress(_) | Error::Locked(_) | Error::Protected { .. } => 4,
+            Error::Absorbable(_) => 5,
+            Error::InvalidConfig(_) => 6,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::InProgress(state) => f.write_str(&trf(
+                "cannot absorb while a {} is in progress, finish or abort it first, or use --force-state to override",
+                &[&tr(describe_state(*state))],
+            )),
+            Error::Locked(path) => f.write_str(&trf(
+                "another git absorb is running, or one was interrupted (if so, remove {})",
+                &[&path.display()],
+            )),
+            Error::Protected { branch, pattern } => f.write_str(&trf(
+                "HEAD is the protected branch {} (matching {} in absorb.protectedBranches), use --force to override",
+                &[branch, &format!("{:?}", pattern)],
+            )),
+            Error::Absorbable(count) => f.write_str(&trf(
+                "{} hunks could be absorbed into the stack",
+                &[count],
+            )),
+            Error::InvalidConfig(count) => f.write_str(&trf(
+                if *count == 1 {
+                    "{} problem was found in the configuration"
+                } else {
+                    "{} problems were found in the configuration"
+                },
+                &[count],
+            )),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The exit code for an error returned by a run: the one of its
+/// [`Error`], or 1.
+pub fn exit_code(err: &anyhow::Error) -> i32 {
+    err.downcast_ref::<Error>().map_or(1, Error::exit_code)
+}
+
+pub fn run(config: &Config) -> Result<Outcome> {
+    // which makes its own repositories
+    if let Action::SelfTest { iterations, seed } = config.action {
+        return self_test::run(config, iterations, seed);
+    }
+    let repo = {
+        let _timer = profile::start(config.profile, "discovery");
+        open_repository()?
+    };
     debug!(config.logger, "repository found"; "path" => repo.path().to_str());
 
-    let stack = stack::working_stack(&repo, config.base, config.force, config.logger)?;
+    run_with_repo(config, &repo)
+}
+
+/// Opens the repository like git does, from `GIT_DIR`, `GIT_WORK_TREE`,
+/// `GIT_INDEX_FILE` and friends, or by searching up from the current
+/// directory.
+fn open_repository() -> Result<git2::Repository> {
+    // libgit2 refuses to open a repository from the environment when
+    // GIT_WORK_TREE is set, so that one is applied by hand
+    let work_tree = std::env::var_os("GIT_WORK_TREE").filter(|path| !path.is_empty());
+    let work_tree = match work_tree {
+        Some(work_tree) => work_tree,
+        None => return Ok(git2::Repository::open_from_env()?),
+    };
+    std::env::remove_var("GIT_WORK_TREE");
+    let repo = git2::Repository::open_from_env();
+    std::env::set_var("GIT_WORK_TREE", &work_tree);
+    let repo = repo?;
+    repo.set_workdir(&std::env::current_dir()?.join(work_tree), false)?;
+    Ok(repo)
+}
+
+fn run_with_repo(config: &Config, repo: &git2::Repository) -> Result<Outcome> {
+    let done = |()| Outcome::Absorbed;
+    match config.action {
+        Action::Absorb => with_plan(config, repo, |session, planned| {
+            absorb(config, repo, session, planned)
+        }),
+        Action::WritePlan(path) => with_plan(config, repo, |session, planned| {
+            write_plan(repo, session, &planned, path)
+        }),
+        Action::ApplyPlan(path) => with_plan(config, repo, |session, mut planned| {
+            planfile::read(repo, session.backend, &mut planned.plan, path)?;
+            absorb(config, repo, session, planned)
+        }),
+        Action::Check => with_plan(config, repo, |_, planned| {
+            check(config, repo, &planned.plan)
+        }),
+        Action::Undo => undo_last_run(config, repo),
+        Action::Continue => {
+            let session = Session::open(config, repo)?;
+            state::resume(repo, &session.settings, config.logger).map(done)
+        }
+        Action::Abort => {
+            let session = Session::open(config, repo)?;
+            state::abort(repo, &session.settings, session.backend, config.logger).map(done)
+        }
+        // each run takes the lock (and looks at the index) on its own
+        Action::Watch { commit } => watch(config, repo, commit),
+        Action::Doctor => doctor::report(repo, config, &mut std::io::stdout()).map(done),
+        Action::ValidateConfig => validate_config(config, &Layered::open(repo)?),
+        // self tests make their own repositories, without opening one
+        // (see `run()`)
+        Action::SelfTest { iterations, seed } => self_test::run(config, iterations, seed),
+        Action::Log => journal::show(repo, &mut std::io::stdout()).map(done),
+    }
+}
+
+/// Restores HEAD and the index to their state before the last run.
+fn undo_last_run(config: &Config, repo: &git2::Repository) -> Result<Outcome> {
+    let session = Session::open(config, repo)?;
+    let head = repo.head()?.peel_to_commit()?.id();
+    undo::restore(repo, &session.settings, session.backend, config.logger)?;
+    let restored = repo.head()?.peel_to_commit()?.id();
+    journal::record(repo, &journal::Entry::undo(head, restored), config.logger);
+    Ok(Outcome::Absorbed)
+}
+
+/// Reports the settings that are unknown, invalid or that conflict with
+/// each other.
+fn validate_config(config: &Config, settings: &Layered) -> Result<Outcome> {
+    let problems = config::validate(settings)?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        return Err(Error::InvalidConfig(problems.len()).into());
+    }
+    info!(config.logger, "{}", tr("the configuration is valid"));
+    Ok(Outcome::Absorbed)
+}
+
+/// What the actions that change the repository have in common: the
+/// settings, the lock on the repository and the backend.
+struct Session {
+    settings: Layered,
+    backend: &'static dyn Backend,
+    _lock: Option<lock::Lock>,
+}
+
+impl Session {
+    fn open(config: &Config, repo: &git2::Repository) -> Result<Self> {
+        // the settings are read once for the whole run, which also
+        // reports an invalid configuration file before anything happens
+        let settings = Layered::open(repo)?;
+
+        // dry runs don't change anything, so they can run alongside
+        // others
+        let lock = if config.dry_run || config.action == Action::Check {
+            None
+        } else {
+            Some(lock::Lock::acquire(repo)?)
+        };
+
+        // libgit2 can't read a sparse index at all, so git reads and
+        // writes it instead, without expanding it. libgit2 still looks
+        // up attributes in the index though, so it gets an expanded copy
+        // that is only kept in memory
+        let sparse = is_sparse_index(repo);
+        if sparse {
+            debug!(config.logger, "index is sparse, reading it with git");
+            let mut index = git2::Index::new()?;
+            index.read_tree(&repo.find_tree(git_cli::write_tree(repo)?)?)?;
+            repo.set_index(&mut index)?;
+        }
+        let backend = match config::backend(&settings)? {
+            _ if sparse => &backend::Cli,
+            backend => backend,
+        };
+        Ok(Session {
+            settings,
+            backend,
+            _lock: lock,
+        })
+    }
+
+    /// Writes the index as a tree.
+    fn index_tree(&self, config: &Config, repo: &git2::Repository) -> Result<git2::Oid> {
+        let _timer = profile::start(config.profile, "index write");
+        self.backend.write_index_tree(repo)
+    }
+}
+
+/// The staged changes, and where they go among the commits of the
+/// stack.
+struct Planned<'repo> {
+    plan: plan::Plan<'repo>,
+    stack: Vec<(git2::Commit<'repo>, owned::Diff)>,
+    index: owned::Diff,
+    /// How many of the staged files had hunks that were planned.
+    patches_considered: usize,
+    /// The commit whose changes take the place of the index, with
+    /// `--from`, `--stash` or `--patch-file`.
+    source: Option<git2::Commit<'repo>>,
+    /// The commit that the fixups are committed on top of.
+    head_commit: git2::Commit<'repo>,
+    /// HEAD before the run, as the journal records it.
+    original_head: git2::Oid,
+    /// What was staged before `--all` staged the rest.
+    original_index: Option<(git2::Oid, Vec<git2::IndexEntry>)>,
+    /// The hunks of the patch file that don't apply to HEAD.
+    rejected: std::collections::BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Plans the staged changes (see `plan_staged()`) and hands them to
+/// the action, unless there is no stack to absorb them into.
+fn with_plan<'repo>(
+    config: &Config,
+    repo: &'repo git2::Repository,
+    action: impl FnOnce(&Session, Planned<'repo>) -> Result<Outcome>,
+) -> Result<Outcome> {
+    let session = Session::open(config, repo)?;
+    match plan_staged(config, repo, &session)? {
+        Ok(planned) => action(&session, planned),
+        Err(outcome) => Ok(outcome),
+    }
+}
+
+/// Writes the plan to the file (or stdout), without committing anything.
+fn write_plan(
+    repo: &git2::Repository,
+    session: &Session,
+    planned: &Planned,
+    path: Option<&str>,
+) -> Result<Outcome> {
+    let candidates: Vec<_> = planned
+        .stack
+        .iter()
+        .map(|(commit, _)| commit.clone())
+        .collect();
+    planfile::write(repo, session.backend, &planned.plan, &candidates, path)?;
+    Ok(outcome(&planned.plan, &planned.index))
+}
+
+/// Fails if any of the hunks of the plan would have been absorbed.
+fn check(config: &Config, repo: &git2::Repository, plan: &plan::Plan) -> Result<Outcome> {
+    let offending = plan.hunks_to_apply().len();
+    if offending == 0 {
+        info!(config.logger, "{}", tr("nothing left to absorb"));
+        return Ok(Outcome::Absorbed);
+    }
+    for (assignment, hunk) in plan.hunks_to_apply() {
+        warn!(config.logger, "{}", tr("hunk should have been absorbed");
+              "path" => String::from_utf8_lossy(&assignment.path).into_owned(),
+              "header" => hunk.header(),
+              "commit" => assignment.target.as_ref().map(|target| target.id().to_string()),
+        );
+    }
+    output::print_diffstat(plan, output::PathStyle::new(repo, config.path_format))?;
+    Err(Error::Absorbable(offending).into())
+}
+
+/// Finds the stack, and plans where each staged change goes in it, or
+/// returns the outcome of the run if there is no stack.
+fn plan_staged<'repo>(
+    config: &Config,
+    repo: &'repo git2::Repository,
+    session: &Session,
+) -> Result<std::result::Result<Planned<'repo>, Outcome>> {
+    let settings = &session.settings;
+    let backend = session.backend;
+    let index_tree = || session.index_tree(config, repo);
+
+    if repo.state() != git2::RepositoryState::Clean {
+        if !config.force_state {
+            return Err(Error::InProgress(repo.state()).into());
+        }
+        warn!(
+            config.logger,
+            "{}",
+            trf(
+                "a {} is in progress, but --force-state used to continue.",
+                &[&tr(describe_state(repo.state()))]
+            )
+        );
+    }
+
+    // the hunks of a patch file that don't apply to HEAD, and (later)
+    // the ones that couldn't be absorbed
+    let mut rejected = std::collections::BTreeMap::new();
+    // when absorbing an existing commit, its changes take the place of
+    // the index, and its parent takes the place of HEAD
+    let source = match (config.from, config.stash) {
+        (Some(rev), _) => {
+            let source = repo.revparse_single(rev)?.peel_to_commit()?;
+            if source.id() != repo.head()?.peel_to_commit()?.id() {
+                return Err(anyhow!("{}", tr("--from only supports the commit at HEAD")));
+            }
+            if source.parents().len() != 1 {
+                return Err(anyhow!(
+                    "{}",
+                    tr("--from requires a commit with exactly one parent")
+                ));
+            }
+            Some(source)
+        }
+        // a stash is a commit on top of the commit it was made on, with
+        // the working tree as its tree, and the index and the untracked
+        // files recorded in its other parents
+        (None, Some(stash)) => {
+            // like git stash, a bare number stands for stash@{<n>}
+            let rev = match stash.parse::<usize>() {
+                Ok(n) => format!("stash@{{{}}}", n),
+                Err(_) => stash.to_owned(),
+            };
+            let source = repo.revparse_single(&rev)?.peel_to_commit()?;
+            if stash_position(repo, source.id())?.is_none() {
+                return Err(anyhow!("{}", trf("{} is not a stash entry", &[&stash])));
+            }
+            if source.parent_id(0)? != repo.head()?.peel_to_commit()?.id() {
+                return Err(anyhow!(
+                    "{}",
+                    tr("--stash only supports stashes made on top of HEAD")
+                ));
+            }
+            // the fixups are checked out at the end
+            if !config.dry_run && backend.has_changes(repo)? {
+                return Err(anyhow!("{}", tr("cannot absorb a stash with uncommitted changes, commit or stash them first")));
+            }
+            Some(source)
+        }
+        // so does a patch file, applied to HEAD as a commit that nothing
+        // refers to
+        (None, None) => match config.patch_file {
+            Some(path) => {
+                let head = repo.head()?.peel_to_commit()?;
+                let applied = patchfile::apply(repo, &head.tree()?, &patchfile::read(path)?)?;
+                rejected = applied.rejected;
+                let signature = user_signature(repo, None)?;
+                Some(repo.find_commit(repo.commit(
+                    None,
+                    &signature,
+                    &signature,
+                    "git-absorb patch\n",
+                    &applied.tree,
+                    &[&head],
+                )?)?)
+            }
+            None => None,
+        },
+    };
+
+    // absorb.maxStack only limits the commits that touched the staged
+    // files (see below), so the walk goes further
+    let stack_options = config::stack_options(settings)?;
+    let max_stack = stack_options.max_stack;
+    let stack_options = stack::StackOptions {
+        base: &config.base,
+        force: config.force,
+        force_pushed: config.force || config.force_pushed,
+        include_authors: &config.include_authors,
+        max_stack: max_stack.saturating_mul(config::MAX_STACK_WALK_FACTOR),
+        revwalk: Some(backend),
+        ..stack_options
+    };
+    if let Some((branch, pattern)) = protected_branch(repo, settings)? {
+        if !config.force {
+            return Err(Error::Protected { branch, pattern }.into());
+        }
+        warn!(config.logger, "{}", tr("HEAD is a protected branch, but --force used to continue.");
+              "branch" => branch,
+        );
+    }
+    let (mut stack, mut boundary) = {
+        let _timer = profile::start(config.profile, "stack walk");
+        stack::working_stack_with_boundary(repo, &stack_options, Some(config.logger))?
+    };
+    if let Some(ref source) = source {
+        // the source commit is at HEAD, so it's always the first
+        // commit in the stack, if it's there at all
+        if stack.first().map(|commit| commit.id()) == Some(source.id()) {
+            stack.remove(0);
+        }
+    }
     if stack.is_empty() {
-        crit!(config.logger, "No commits available to fix up, exiting");
-        return Ok(());
+        crit!(
+            config.logger,
+            "{}",
+            tr("No commits available to fix up, exiting")
+        );
+        match config.output_format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => output::print_json(&plan::Plan::default(), &[])?,
+            OutputFormat::Porcelain => output::print_porcelain(
+                &plan::Plan::default(),
+                &[],
+                output::PathStyle::new(repo, config.path_format),
+            )?,
+        }
+        let nothing_staged = source.is_none() && index_tree()? == repo.head()?.peel_to_tree()?.id();
+        return Ok(Err(if nothing_staged {
+            Outcome::NothingStaged
+        } else {
+            Outcome::NothingAbsorbed
+        }));
     }
 
-    let mut diff_options = Some({
-        let mut ret = git2::DiffOptions::new();
-        ret.context_lines(0)
-            .id_abbrev(40)
-            .ignore_filemode(true)
-            .ignore_submodules(true);
+    let diff_algorithm = match config.diff_algorithm {
+        Some(algorithm) => algorithm,
+        None => config::diff_algorithm(settings)?,
+    };
+    let context = match config.unified {
+        Some(context) => context,
+        None => config::diff_context(settings)?,
+    };
+    // the pathspecs restrict which staged changes are absorbed, but
+    // the stack is still diffed in full so that renames can be followed
+    // (only the files that matter are parsed, see below)
+    let diff_settings = DiffSettings {
+        algorithm: diff_algorithm,
+        context,
+        partial_clone: config::promisor_remote(repo).is_some(),
+    };
+    let mut index_diff_options = Some({
+        let mut ret = default_diff_options(diff_algorithm, context);
+        for pathspec in &config.pathspecs {
+            ret.pathspec(pathspec);
+        }
         ret
     });
 
-    let (stack, summary_counts): (Vec<_>, _) = {
+    if config.all && matches!(config.action, Action::WritePlan(_) | Action::ApplyPlan(_)) {
+        return Err(anyhow!(
+            "{}",
+            tr("--all cannot be used with plan files, stage the changes instead")
+        ));
+    }
+    // like `git commit -a`, the changes to the tracked files are staged
+    // first (and unstaged again below if nothing gets committed), and a
+    // dry run reads them from the working tree instead
+    if config.and_rebase && (config.intra_line || config::intra_line(settings)) {
+        return Err(anyhow!(
+            "{}",
+            tr("--intra-line can't be used with --and-rebase, since the rebase would conflict on its fixups")
+        ));
+    }
+    let original_index = if config.all && source.is_none() && !config.dry_run {
+        let original_index = (index_tree()?, backend.intent_to_add_entries(repo)?);
+        stage_tracked(repo, backend, &config.pathspecs)?;
+        Some(original_index)
+    } else {
+        None
+    };
+    let read_workdir = config.action == Action::Check || (config.all && config.dry_run);
+
+    // what the journal records as HEAD before the run
+    let original_head = repo.head()?.peel_to_commit()?.id();
+    let head_commit = match source {
+        Some(ref source) => source.parent(0)?,
+        None => repo.head()?.peel_to_commit()?,
+    };
+    let head_tree = head_commit.tree()?;
+    // the lines that the staged changes remove are usually the lines
+    // that some commit in the stack added, so they're all shared
+    let mut interner = owned::Interner::default();
+    let index_timer = profile::start(config.profile, "index diff");
+    let mut index_diff = match source {
+        Some(ref source) => repo.diff_tree_to_tree(
+            Some(&head_tree),
+            Some(&source.tree()?),
+            index_diff_options.as_mut(),
+        )?,
+        // unstaged changes should have been absorbed too, which libgit2
+        // checks every file of the working tree for, while git only
+        // checks the ones that a filesystem monitor says changed
+        None if read_workdir => {
+            let backend = match config::fsmonitor(repo) {
+                true => &backend::Cli,
+                false => backend,
+            };
+            backend.diff_index(
+                repo,
+                &head_tree,
+                git_cli::DiffTarget::Workdir,
+                diff_settings,
+                &config.pathspecs,
+            )?
+        }
+        None => backend.diff_index(
+            repo,
+            &head_tree,
+            git_cli::DiffTarget::Index,
+            diff_settings,
+            &config.pathspecs,
+        )?,
+    };
+    drop(index_timer);
+    // a partial clone doesn't have the blobs of most old versions of
+    // files, which libgit2 can't fetch on its own, so the ones that the
+    // staged files (and the stack) need are fetched up front
+    let promisor_remote = config::promisor_remote(repo);
+    let unavailable = match promisor_remote {
+        Some(ref remote) => {
+            let mut diffs = vec![index_diff];
+            for commit in &stack {
+                let parent = match commit.parents().len() {
+                    0 => None,
+                    _ => Some(commit.parent(0)?.tree()?),
+                };
+                diffs.push(repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?);
+            }
+            let unavailable = fetch_missing_blobs(repo, remote, &diffs, config.logger);
+            index_diff = diffs.swap_remove(0);
+            unavailable
+        }
+        None => std::collections::HashSet::new(),
+    };
+    // libgit2 diffs the files added with `git add --intent-to-add` as
+    // new, empty files, but nothing of them is staged yet
+    let intent_to_add: std::collections::HashSet<Vec<u8>> = match source {
+        None if !read_workdir => backend
+            .intent_to_add_entries(repo)?
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect(),
+        _ => std::collections::HashSet::new(),
+    };
+    // files like lockfiles and generated files are often changed along
+    // with everything, so they're left staged instead of being absorbed
+    // wherever they last changed
+    let exclude_paths = exclude::ExcludePaths::new(&config::exclude_paths(settings));
+    let skip_generated = config::skip_generated(settings);
+    let excluded: HashMap<Vec<u8>, plan::SkipReason> = index_diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path_bytes())
+        .filter(|path| !intent_to_add.contains(*path))
+        .filter_map(|path| {
+            let reason = if exclude_paths.matches(path) {
+                plan::SkipReason::ExcludedPath
+            } else if skip_generated && is_generated(repo, path) {
+                plan::SkipReason::Generated
+            } else {
+                return None;
+            };
+            Some((path.to_vec(), reason))
+        })
+        .collect();
+    let index = owned::Diff::filtered(
+        &index_diff,
+        |path| {
+            !unavailable.contains(path)
+                && !intent_to_add.contains(path)
+                && !excluded.contains_key(path)
+        },
+        &mut interner,
+    )?;
+    // a hunk that changes the end of one function and the start of the
+    // next one may belong to two commits
+    let index = if context == 0 && config::semantic_hunks(settings) {
+        semantic::split(repo, index, config.logger)?
+    } else {
+        index
+    };
+    trace!(config.logger, "parsed index";
+           "index" => format!("{:?}", index),
+    );
+
+    // parsing the diff of every file that a commit touched is the
+    // dominant cost in big repositories, so only the files that the
+    // staged changes could commute with are parsed: the staged paths,
+    // the paths they were renamed from, and (for --new-files) the
+    // other files next to them
+    let new_files = config.new_files || config::new_files(settings);
+    let mut paths: std::collections::HashSet<Vec<u8>> = index
+        .iter()
+        .flat_map(|patch| [patch.old_path.clone(), patch.new_path.clone()])
+        .collect();
+    let dirs: std::collections::HashSet<&[u8]> = if new_files {
+        index
+            .iter()
+            .map(|patch| parent_dir(&patch.new_path))
+            .collect()
+    } else {
+        Default::default()
+    };
+    let mut diff_options_tag = stack_diff_options(backend, diff_algorithm, context, &paths, &dirs);
+
+    if config.base.is_empty() {
+        let mut relevant = 0;
+        let mut cut = None;
+        for (idx, commit) in stack.iter().enumerate() {
+            if touches_paths(repo, commit, &paths, &dirs)? {
+                relevant += 1;
+            }
+            if relevant > max_stack {
+                cut = Some(idx);
+                break;
+            }
+        }
+        if let Some(cut) = cut {
+            warn!(config.logger, "{}", tr("stack limit reached, use --base or configure absorb.maxStack to override");
+                  "limit" => max_stack,
+                  "commits" => cut,
+            );
+            boundary = stack::Boundary::Limit(stack[cut].id());
+            stack.truncate(cut);
+        }
+    }
+
+    let use_cache = config::cache(settings);
+    // the changed-path Bloom filters of the commit-graph tell which
+    // commits definitely didn't touch any of those files, and those
+    // don't have to be diffed at all
+    let graph = match commit_graph::CommitGraph::open(repo) {
+        Ok(graph) => graph.filter(commit_graph::CommitGraph::has_bloom_filters),
+        Err(e) => {
+            debug!(config.logger, "could not read commit-graph"; "err" => e.to_string());
+            None
+        }
+    };
+    let stack: Vec<_> = {
         let mut diffs = Vec::with_capacity(stack.len());
         for commit in &stack {
-            let diff = owned::Diff::new(
-                &repo.diff_tree_to_tree(
-                    if commit.parents().len() == 0 {
-                        None
-                    } else {
-                        Some(commit.parent(0)?.tree()?)
+            let _timer = profile::start(config.profile, "commit diffs");
+            // a commit's diff never changes, so it can be reused from an
+            // earlier run as long as it was made with the same options
+            if use_cache {
+                match cache::load(repo, commit.id(), &diff_options_tag, &mut interner) {
+                    Ok(Some(diff)) => {
+                        trace!(config.logger, "loaded cached commit diff";
+                               "commit" => commit.id().to_string(),
+                        );
+                        if follow_renames(&mut paths, &diff) {
+                            diff_options_tag =
+                                stack_diff_options(backend, diff_algorithm, context, &paths, &dirs);
+                        }
+                        diffs.push(diff);
+                        continue;
                     }
-                    .as_ref(),
-                    Some(&commit.tree()?),
-                    diff_options.as_mut(),
-                )?,
+                    Ok(None) => {}
+                    Err(e) => debug!(config.logger, "could not load cached commit diff";
+                                     "commit" => commit.id().to_string(),
+                                     "err" => e.to_string(),
+                    ),
+                }
+            }
+            if let Some(ref graph) = graph {
+                let untouched = paths
+                    .iter()
+                    .map(Vec::as_slice)
+                    .chain(dirs.iter().copied())
+                    .all(|path| graph.changed(commit.id(), path) == Some(false));
+                if untouched {
+                    trace!(config.logger, "skipped commit by its bloom filter";
+                           "commit" => commit.id().to_string(),
+                    );
+                    diffs.push(owned::Diff::from_patches(Vec::new())?);
+                    continue;
+                }
+            }
+            let diff = commit_diff(
+                repo,
+                backend,
+                commit,
+                diff_settings,
+                |path| paths.contains(path) || dirs.contains(parent_dir(path)),
+                &mut interner,
             )?;
             trace!(config.logger, "parsed commit diff";
                    "commit" => commit.id().to_string(),
                    "diff" => format!("{:?}", diff),
             );
+            if use_cache {
+                if let Err(e) = cache::store(repo, commit.id(), &diff_options_tag, &diff) {
+                    debug!(config.logger, "could not cache commit diff";
+                           "commit" => commit.id().to_string(),
+                           "err" => e.to_string(),
+                    );
+                }
+            }
+            // the older commits are diffed with the paths that renames
+            // widened the filter to, and cached under those
+            if follow_renames(&mut paths, &diff) {
+                diff_options_tag =
+                    stack_diff_options(backend, diff_algorithm, context, &paths, &dirs);
+            }
             diffs.push(diff);
         }
+        if use_cache {
+            let ids: Vec<_> = stack.iter().map(|commit| commit.id()).collect();
+            if let Err(e) = cache::prune(repo, &ids) {
+                debug!(config.logger, "could not prune diff cache"; "err" => e.to_string());
+            }
+        }
 
-        let summary_counts = stack::summary_counts(&stack);
-        (
-            stack.into_iter().zip(diffs.into_iter()).collect(),
-            summary_counts,
-        )
+        stack.into_iter().zip(diffs).collect()
     };
 
-    let mut head_tree = repo.head()?.peel_to_tree()?;
-    let index = owned::Diff::new(&repo.diff_tree_to_index(
-        Some(&head_tree),
-        None,
-        diff_options.as_mut(),
-    )?)?;
-    trace!(config.logger, "parsed index";
-           "index" => format!("{:?}", index),
-    );
+    let forced_target = match config.target {
+        Some(rev) => {
+            let target = repo.revparse_single(rev)?.peel_to_commit()?;
+            let target = stack
+                .iter()
+                .map(|(commit, _)| commit)
+                .find(|commit| commit.id() == target.id())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{}",
+                        tr("--target is not in the stack, use --base to extend it")
+                    )
+                })?;
+            Some(target)
+        }
+        None => None,
+    };
 
-    let signature = repo
-        .signature()
-        .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))?;
-    let mut head_commit = repo.head()?.peel_to_commit()?;
+    let blame_fallback = config.blame_fallback || config::blame_fallback(settings);
+    let ambiguity_strategy = if config.oldest {
+        AmbiguityStrategy::Oldest
+    } else {
+        config::ambiguity_strategy(settings)?
+    };
+    let intra_line = config.intra_line || config::intra_line(settings);
+    let absorb_binary = config::absorb_binary(settings);
+    // minified or generated files can have so many hunks, or such long
+    // lines, that commuting them line by line takes forever
+    let large_files = config::large_files(settings)?;
+    let max_file_size = config::max_file_size(settings);
+    let max_line_length = config::max_line_length(settings);
+    let is_large: Vec<bool> = index
+        .iter()
+        .map(|patch| is_large_file(repo, patch, max_file_size, max_line_length))
+        .collect();
+    // the hunks of a file whose diff driver converts it before diffing
+    // (eg: to decrypt it) aren't the ones users see, and the converted
+    // hunks couldn't be applied to the file anyway
+    let textconv: Vec<Option<String>> = index
+        .iter()
+        .map(|patch| match patch.status {
+            git2::Delta::Modified if !patch.is_binary => textconv_driver(repo, &patch.new_path),
+            _ => None,
+        })
+        .collect();
+    let stack_commits: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+    let mut blames = HashMap::new();
+
+    // the hunks of each file are commuted independently of the other
+    // files, so all files are commuted in parallel up front, and the
+    // plan is assembled from the results in order below
+    let stack_diffs: Vec<_> = stack
+        .iter()
+        .map(|(commit, diff)| StackDiff {
+            id: commit.id(),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+            diff,
+        })
+        .collect();
+    let commute = forced_target.is_none();
+    let detect_moves = config.detect_moves || config::detect_moves(settings);
+    // the whole diffs of the commits that targets were found in, for
+    // finding blocks that they moved from other files
+    let mut moved_diffs = HashMap::new();
+    let commute_file = |(patch_idx, patch): (usize, &owned::Patch)| -> Vec<target::Commuted> {
+        let large = is_large[patch_idx];
+        let whole_file = config.whole_file || (large && large_files == LargeFiles::WholeFile);
+        if !commute
+            || (whole_file && config.oldest)
+            || (large && large_files == LargeFiles::Skip)
+            || textconv[patch_idx].is_some()
+            || patch.status != git2::Delta::Modified
+        {
+            return patch.hunks.iter().map(|_| Default::default()).collect();
+        }
+        let options = target::CommuteOptions {
+            whole_file,
+            intra_line,
+            // hunks grouped by context are kept whole, like `git diff`
+            // shows them
+            split: !whole_file && context == 0,
+            explain: config.explain,
+            detect_moves,
+        };
+        target::commute_patch(&stack_diffs, patch, &options, Some(config.logger))
+    };
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.jobs.unwrap_or_else(|| config::jobs(settings)))
+        .build()?;
+    let mut commuted: Vec<Vec<target::Commuted>> = {
+        let _timer = profile::start(config.profile, "commutation");
+        pool.install(|| index.par_iter().enumerate().map(commute_file).collect())
+    };
 
+    let mut plan = plan::Plan::new(&stack);
+    // the traces of --explain, by path and header, for the hunks whose
+    // target may still turn out to be ineligible
+    let mut traces: HashMap<(Vec<u8>, String), Vec<String>> = HashMap::new();
+    let mut unavailable: Vec<_> = unavailable.into_iter().collect();
+    unavailable.sort();
+    for path in unavailable {
+        warn!(config.logger, "{}", tr("skipped file with missing objects, fetch them with git fetch");
+              "path" => String::from_utf8_lossy(&path).into_owned(),
+        );
+        plan.skipped_paths.push(plan::SkippedPath {
+            path,
+            reason: plan::SkipReason::Missing,
+        });
+    }
+    let mut excluded: Vec<_> = excluded.into_iter().collect();
+    excluded.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (path, reason) in excluded {
+        debug!(config.logger, "skipped excluded file";
+               "path" => String::from_utf8_lossy(&path).into_owned(),
+               "reason" => reason.as_str(),
+        );
+        plan.skipped_paths.push(plan::SkippedPath { path, reason });
+    }
     let mut patches_considered = 0usize;
-    'patch: for index_patch in index.iter() {
+    'patch: for (patch_idx, index_patch) in index.iter().enumerate() {
         let old_path = index_patch.new_path.as_slice();
+        let whole_file =
+            config.whole_file || (is_large[patch_idx] && large_files == LargeFiles::WholeFile);
+
+        // a created or deleted file is a single hunk adding or removing
+        // every line, which is absorbed as a whole (empty files have no
+        // hunk, and are skipped below)
+        let file_change = match index_patch.status {
+            git2::Delta::Added => Some(plan::FileChange::Added(index_patch.new_mode)),
+            git2::Delta::Deleted => Some(plan::FileChange::Deleted),
+            _ => None,
+        };
+        let is_submodule = index_patch.old_mode == git2::FileMode::Commit
+            || index_patch.new_mode == git2::FileMode::Commit;
+        if let (Some(file_change), [hunk], false) =
+            (file_change, index_patch.hunks.as_slice(), is_submodule)
+        {
+            patches_considered += 1;
+            plan.assignments.push(plan_file_change(
+                &stack,
+                index_patch,
+                hunk.clone(),
+                file_change,
+                forced_target,
+                new_files,
+                config.logger,
+            ));
+            continue 'patch;
+        }
+
+        // a submodule has no lines to commute, only the commit it points
+        // to, and a typechange replaces the file entirely, so these are
+        // absorbed into whichever commit last touched the path
+        // binary files have no lines either, and can only be absorbed
+        // as a whole if that was enabled
+        if index_patch.status == git2::Delta::Modified && index_patch.is_binary && !absorb_binary {
+            debug!(config.logger, "skipped binary file";
+                    "path" => String::from_utf8_lossy(old_path).into_owned(),
+            );
+            plan.skipped_paths.push(plan::SkippedPath {
+                path: index_patch.new_path.clone(),
+                reason: plan::SkipReason::Binary,
+            });
+            continue 'patch;
+        }
+
+        if is_large[patch_idx] {
+            match large_files {
+                LargeFiles::WholeFile => {
+                    info!(config.logger, "{}", tr("absorbing large file as a whole");
+                            "path" => String::from_utf8_lossy(old_path).into_owned(),
+                    )
+                }
+                LargeFiles::Skip => {
+                    warn!(config.logger, "{}", tr("skipped large file");
+                            "path" => String::from_utf8_lossy(old_path).into_owned(),
+                    );
+                    plan.skipped_paths.push(plan::SkippedPath {
+                        path: index_patch.new_path.clone(),
+                        reason: plan::SkipReason::TooLarge,
+                    });
+                    continue 'patch;
+                }
+            }
+        }
+
+        if let Some(ref driver) = textconv[patch_idx] {
+            info!(config.logger, "{}", tr("absorbing file with a textconv diff driver as a whole");
+                  "path" => String::from_utf8_lossy(old_path).into_owned(),
+                  "driver" => driver,
+            );
+        }
+
+        if (index_patch.status == git2::Delta::Modified
+            && (is_submodule || index_patch.is_binary || textconv[patch_idx].is_some()))
+            || index_patch.status == git2::Delta::Typechange
+        {
+            patches_considered += 1;
+            plan.assignments.push(plan_file_change(
+                &stack,
+                index_patch,
+                owned::Hunk::empty(),
+                plan::FileChange::Replaced(index_patch.new_id, index_patch.new_mode),
+                forced_target,
+                new_files,
+                config.logger,
+            ));
+            continue 'patch;
+        }
+
         if index_patch.status != git2::Delta::Modified {
             debug!(config.logger, "skipped non-modified hunk";
                     "path" => String::from_utf8_lossy(old_path).into_owned(),
                     "status" => format!("{:?}", index_patch.status),
             );
+            plan.skipped_paths.push(plan::SkippedPath {
+                path: index_patch.new_path.clone(),
+                reason: plan::SkipReason::NotModified(index_patch.status),
+            });
             continue 'patch;
         }
 
         patches_considered += 1;
 
-        let mut preceding_hunks_offset = 0isize;
-        let mut applied_hunks_offset = 0isize;
-        'hunk: for index_hunk in &index_patch.hunks {
+        let eol_normalized = normalizes_line_endings(repo, &index_patch.new_path);
+        let commuted = std::mem::take(&mut commuted[patch_idx]);
+        plan.push_commuted(&stack, index_patch, commuted, |index_hunk, isolated_hunk, commuted| -> Result<_> {
             debug!(config.logger, "next hunk";
                    "header" => index_hunk.header(),
                    "path" => String::from_utf8_lossy(old_path).into_owned(),
             );
-
-            // To properly handle files ("patches" in libgit2 lingo) with multiple hunks, we
-            // need to find the updated line coordinates (`header`) of the current hunk in
-            // two cases:
-            // 1) As if it were the only hunk in the index. This only involves shifting the
-            // "added" side *up* by the offset introduced by the preceding hunks:
-            let isolated_hunk = index_hunk
-                .clone()
-                .shift_added_block(-preceding_hunks_offset);
-
-            // 2) When applied on top of the previously committed hunks. This requires shifting
-            // both the "added" and the "removed" sides of the previously isolated hunk *down*
-            // by the offset of the committed hunks:
-            let hunk_to_apply = isolated_hunk
-                .clone()
-                .shift_both_blocks(applied_hunks_offset);
-
-            // The offset is the number of lines added minus the number of lines removed by a hunk:
-            let hunk_offset = index_hunk.changed_offset();
-
-            // To aid in understanding these arithmetic, here's an illustration.
-            // There are two hunks in the original patch, each adding one line ("line2" and
-            // "line5"). Assuming the first hunk (with offset = -1) was already processed
-            // and applied, the table shows the three versions of the patch, with line numbers
-            // on the <A>dded and <R>emoved sides for each:
-            // |----------------|-----------|------------------|
-            // |                |           | applied on top   |
-            // | original patch | isolated  | of the preceding |
-            // |----------------|-----------|------------------|
-            // | <R> <A>        | <R> <A>   | <R> <A>          |
-            // |----------------|-----------|------------------|
-            // |  1   1  line1  |  1   1    |  1   1   line1   |
-            // |  2      line2  |  2   2    |  2   2   line3   |
-            // |  3   2  line3  |  3   3    |  3   3   line4   |
-            // |  4   3  line4  |  4   4    |  4       line5   |
-            // |  5      line5  |  5        |                  |
-            // |----------------|-----------|------------------|
-            // |       So the second hunk's `header` is:       |
-            // |   -5,1 +3,0    | -5,1 +4,0 |    -4,1 +3,0     |
-            // |----------------|-----------|------------------|
-
             debug!(config.logger, "";
-                "to apply" => hunk_to_apply.header(),
                 "to commute" => isolated_hunk.header(),
-                "preceding hunks" => format!("{}/{}", applied_hunks_offset, preceding_hunks_offset),
             );
 
-            preceding_hunks_offset += hunk_offset;
-
-            // find the newest commit that the hunk cannot commute with
-            let mut dest_commit = None;
-            let mut commuted_old_path = old_path;
-            let mut commuted_index_hunk = isolated_hunk;
-
-            'commit: for &(ref commit, ref diff) in &stack {
-                let c_logger = config.logger.new(o!(
-                    "commit" => commit.id().to_string(),
-                ));
-                let next_patch = match diff.by_new(commuted_old_path) {
-                    Some(patch) => patch,
-                    // this commit doesn't touch the hunk's file, so
-                    // they trivially commute, and the next commit
-                    // should be considered
-                    None => {
-                        debug!(c_logger, "skipped commit with no path");
-                        continue 'commit;
-                    }
-                };
+            // git would have normalized these anyway, so they're only
+            // noise (eg: from a checkout with different settings) that
+            // shouldn't end up in any commit
+            if eol_normalized && index_hunk.only_changes_line_endings() {
+                info!(config.logger, "{}", tr("skipped hunk that only changes line endings");
+                      "path" => String::from_utf8_lossy(old_path).into_owned(),
+                      "header" => index_hunk.header(),
+                );
+                return Ok(plan::Placement::Skip(plan::SkipReason::LineEndings));
+            }
 
-                // sometimes we just forget some change (eg: intializing some object) that
-                // happens in a completely unrelated place with the current hunks. In those
-                // cases, might be helpful to just match the first commit touching the same
-                // file as the current hunk. Use this option with care!
-                if config.whole_file {
-                    debug!(c_logger, "Commit touches the hunk file and match whole file is enabled");
-                    dest_commit = Some(commit);
-                    break 'commit;
+            if commuted.by_intra_line {
+                warn!(config.logger, "{}", tr("hunk only commutes within lines, so its fixup will conflict when it is squashed");
+                      "path" => String::from_utf8_lossy(old_path).into_owned(),
+                      "header" => index_hunk.header(),
+                );
+            }
+            let trace = commuted.trace.take();
+            let commuted_target = commuted.target;
+            let mut dest_commit = match forced_target {
+                Some(target) => Some(target),
+                None if whole_file && config.oldest => touchers(&stack, old_path).last().copied(),
+                None => commuted.target.map(|idx| &stack[idx].0),
+            };
+            // a hunk that replaces several lines may replace lines
+            // that were introduced by different commits, in which case
+            // each part of it should be absorbed separately (it is only
+            // split when it was commuted line by line, without --target)
+            if let Some(ref pieces) = commuted.pieces {
+                for (piece, target) in pieces {
+                    debug!(config.logger, "split hunk";
+                           "header" => piece.header(),
+                           "commit" => target.map(|idx| stack[idx].0.id().to_string()),
+                    );
                 }
+                return Ok(plan::Placement::Commuted);
+            }
 
-                if next_patch.status == git2::Delta::Added {
-                    debug!(c_logger, "found noncommutative commit by add");
-                    dest_commit = Some(commit);
-                    break 'commit;
+            // the hunk can't go into the commit that first added lines
+            // that its target moved from another file, since the rebase
+            // would stop at the move, but that commit is worth knowing
+            if let (Some(target), None, true, false) =
+                (commuted_target, forced_target, detect_moves, whole_file)
+            {
+                let origin = moved_origin(
+                    repo,
+                    &stack,
+                    &stack_diffs,
+                    old_path,
+                    isolated_hunk,
+                    target,
+                    intra_line,
+                    backend,
+                    diff_settings,
+                    &mut moved_diffs,
+                    &mut interner,
+                    config.logger,
+                )?;
+                if let Some((from, origin)) = origin {
+                    info!(config.logger, "{}", tr("hunk edits lines that its target moved from another file");
+                          "path" => String::from_utf8_lossy(old_path).into_owned(),
+                          "header" => index_hunk.header(),
+                          "commit" => stack[target].0.id().to_string(),
+                          "from" => String::from_utf8_lossy(&from).into_owned(),
+                          "origin" => origin.map(|commit| commit.id().to_string()),
+                    );
                 }
-                if commuted_old_path != next_patch.old_path.as_slice() {
-                    debug!(c_logger, "changed commute path";
-                           "path" => String::from_utf8_lossy(&next_patch.old_path).into_owned(),
+            }
+
+            if dest_commit.is_none() && blame_fallback {
+                let blame = match blames.entry(index_patch.old_path.clone()) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(
+                        match blame::blame_file(repo, &index_patch.old_path, &stack_commits) {
+                            Ok(blame) => Some(blame),
+                            Err(e) => {
+                                warn!(config.logger, "{}", tr("could not blame file");
+                                      "path" => String::from_utf8_lossy(old_path).into_owned(),
+                                      "err" => e.to_string(),
+                                );
+                                None
+                            }
+                        },
+                    ),
+                };
+                dest_commit = blame.as_ref().and_then(|blame| {
+                    blame::fallback_target(blame, isolated_hunk, &stack_commits, config.oldest)
+                });
+                if let Some(commit) = dest_commit {
+                    debug!(config.logger, "found target by blame";
+                           "commit" => commit.id().to_string(),
                     );
-                    commuted_old_path = next_patch.old_path.as_slice();
                 }
-                commuted_index_hunk = match commute::commute_diff_before(
-                    &commuted_index_hunk,
-                    &next_patch.hunks,
-                ) {
-                    Some(hunk) => {
-                        debug!(c_logger, "commuted hunk with commit";
-                               "offset" => (hunk.added.start as i64) - (commuted_index_hunk.added.start as i64),
+            }
+            if dest_commit.is_none() {
+                // the hunk commutes with every commit in the stack, but
+                // any commit that touched its file could plausibly be
+                // the one it belongs to
+                let candidates = touchers(&stack, old_path);
+                if !candidates.is_empty() {
+                    info!(config.logger, "{}", trf("hunk commutes with the whole stack, but {} commits touched its file", &[&candidates.len()]);
+                          "path" => String::from_utf8_lossy(old_path).into_owned(),
+                          "header" => index_hunk.header(),
+                    );
+                    for commit in &candidates {
+                        info!(config.logger, "{}", tr("  candidate");
+                              "commit" => commit.id().to_string(),
+                              "summary" => commit.summary().unwrap_or_default().to_owned(),
                         );
-                        hunk
                     }
-                    // this commit contains a hunk that cannot
-                    // commute with the hunk being absorbed
-                    None => {
-                        debug!(c_logger, "found noncommutative commit by conflict");
-                        dest_commit = Some(commit);
-                        break 'commit;
+                }
+                dest_commit = match ambiguity_strategy {
+                    AmbiguityStrategy::Nearest => candidates.first().copied(),
+                    AmbiguityStrategy::Oldest => candidates.last().copied(),
+                    AmbiguityStrategy::Prompt if !candidates.is_empty() => {
+                        let owned: Vec<_> =
+                            candidates.iter().map(|&commit| commit.clone()).collect();
+                        let mut output = std::io::stderr();
+                        writeln!(output, "{}", String::from_utf8_lossy(old_path))?;
+                        for line in render::hunk_text(isolated_hunk) {
+                            writeln!(output, "{}", line)?;
+                        }
+                        interactive::choose_target(
+                            &owned,
+                            &mut std::io::stdin().lock(),
+                            &mut output,
+                        )?
+                        .and_then(|chosen| {
+                            candidates
+                                .iter()
+                                .copied()
+                                .find(|commit| commit.id() == chosen.id())
+                        })
                     }
+                    AmbiguityStrategy::Prompt | AmbiguityStrategy::Skip => None,
                 };
             }
-            let dest_commit = match dest_commit {
-                Some(commit) => commit,
-                // the hunk commutes with every commit in the stack,
-                // so there is no commit to absorb it into
-                None => {
-                    warn!(
-                        config.logger,
-                        "Could not find a commit to fix up, use \
-                         --base to increase the search range."
+            if dest_commit.is_none() {
+                // there is no commit to absorb it into
+                warn!(
+                    config.logger,
+                    "{}",
+                    tr("Could not find a commit to fix up, use \
+                        --base to increase the search range.")
+                );
+                for step in trace.iter().flatten() {
+                    info!(config.logger, "  {}", step;
+                          "path" => String::from_utf8_lossy(old_path).into_owned(),
+                          "header" => index_hunk.header(),
                     );
-                    continue 'hunk;
                 }
-            };
-
-            // TODO: the git2 api only supports utf8 commit messages,
-            // so it's okay to use strings instead of bytes here
-            // https://docs.rs/git2/0.7.5/src/git2/repo.rs.html#998
-            // https://libgit2.org/libgit2/#HEAD/group/commit/git_commit_create
-            let dest_commit_id = dest_commit.id().to_string();
-            let dest_commit_locator = dest_commit
-                .summary()
-                .filter(|&msg| summary_counts[msg] == 1)
-                .unwrap_or(&dest_commit_id);
-            if !config.dry_run {
-                head_tree =
-                    apply_hunk_to_tree(&repo, &head_tree, &hunk_to_apply, &index_patch.old_path)?;
-                head_commit = repo.find_commit(repo.commit(
-                    Some("HEAD"),
-                    &signature,
-                    &signature,
-                    &format!("fixup! {}\n", dest_commit_locator),
-                    &head_tree,
-                    &[&head_commit],
-                )?)?;
-                info!(config.logger, "committed";
-                      "commit" => head_commit.id().to_string(),
-                      "header" => hunk_to_apply.header(),
-                );
-            } else {
-                info!(config.logger, "would have committed";
-                      "fixup" => dest_commit_locator,
-                      "header" => hunk_to_apply.header(),
+            }
+            if let (Some(trace), Some(_)) = (trace, dest_commit) {
+                traces.insert(
+                    (index_patch.old_path.clone(), isolated_hunk.header()),
+                    trace,
                 );
             }
-            applied_hunks_offset += hunk_offset;
+            Ok(match dest_commit {
+                Some(target) => plan::Placement::Target(target.clone()),
+                None => plan::Placement::Skip(plan::SkipReason::NoTarget),
+            })
+        })?;
+
+        if index_patch.old_mode != index_patch.new_mode {
+            plan.assignments.push(plan_file_change(
+                &stack,
+                index_patch,
+                owned::Hunk::empty(),
+                plan::FileChange::Mode(index_patch.new_mode),
+                forced_target,
+                new_files,
+                config.logger,
+            ));
+        }
+    }
+
+    // a fixup (or squash) commit gets squashed into another commit, so
+    // hunks that belong to it actually belong to that commit, otherwise
+    // they would end up as "fixup! fixup!" commits
+    for assignment in &mut plan.assignments {
+        let original = match &assignment.target {
+            Some(target) => stack::squash_target(&stack_commits, target),
+            None => None,
+        };
+        if let Some(original) = original {
+            debug!(config.logger, "retargeted hunk from autosquash commit";
+                   "header" => assignment.hunk.header(),
+                   "commit" => original.id().to_string(),
+            );
+            assignment.target = Some(original.clone());
+        }
+    }
+
+    // absorb.targetCommand has the last word on where each hunk goes,
+    // among the commits that it commutes with, unless --target was
+    // given
+    if let Some(command) = config::target_command(settings).filter(|_| forced_target.is_none()) {
+        for assignment in &mut plan.assignments {
+            let newer = match (&assignment.target, assignment.skip_reason) {
+                _ if assignment.file_change.is_some() => continue,
+                (Some(target), _) => match stack_commits
+                    .iter()
+                    .position(|commit| commit.id() == target.id())
+                {
+                    Some(idx) => idx + 1,
+                    None => continue,
+                },
+                (None, Some(plan::SkipReason::NoTarget)) => stack_commits.len(),
+                _ => continue,
+            };
+            let candidates: Vec<_> = stack_commits[..newer]
+                .iter()
+                .copied()
+                .filter(|commit| stack::autosquash_subject(commit).is_none())
+                .collect();
+            let choice = target_command::pick(
+                repo,
+                &command,
+                &assignment.path,
+                &assignment.hunk,
+                assignment.target.as_ref(),
+                &candidates,
+            )?;
+            let target = match choice {
+                target_command::Choice::Keep => continue,
+                target_command::Choice::Skip => None,
+                target_command::Choice::Target(target) => Some(target.clone()),
+            };
+            debug!(config.logger, "target picked by command";
+                   "header" => assignment.hunk.header(),
+                   "commit" => target.as_ref().map(|target| target.id().to_string()),
+            );
+            assignment.retarget(target, plan::SkipReason::Dropped);
+        }
+    }
+
+    // a merge commit can be walked past (with absorb.traverseMerges),
+    // but not fixed up, since autosquashing would flatten it. commits
+    // can also be protected from fixups by their message, unless they
+    // were chosen with --target
+    let skip_pattern = config::skip_message_pattern(settings)?;
+    let only_pattern = config::only_message_pattern(settings)?;
+    // so can commits that touched many files, like reformatting the
+    // whole repository, which would otherwise take the fixups of
+    // everything near them
+    let skip_bulk = config
+        .skip_bulk
+        .unwrap_or_else(|| config::skip_bulk_commits(settings));
+    let mut touched_files = HashMap::new();
+    let mut is_bulk = |commit: &git2::Commit| -> Result<bool> {
+        if skip_bulk == 0 {
+            return Ok(false);
+        }
+        let count = match touched_files.entry(commit.id()) {
+            Entry::Occupied(entry) => *entry.get(),
+            Entry::Vacant(entry) => {
+                let parent = match commit.parent_count() {
+                    0 => None,
+                    _ => Some(commit.parent(0)?.tree()?),
+                };
+                let diff = repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), None)?;
+                *entry.insert(diff.deltas().len())
+            }
+        };
+        Ok(count > skip_bulk)
+    };
+    for assignment in &mut plan.assignments {
+        let (reason, why) = match &assignment.target {
+            Some(target) if target.parent_count() > 1 => {
+                (plan::SkipReason::Merge, "hunk belongs to a merge commit")
+            }
+            Some(target)
+                if forced_target.is_none()
+                    && !is_eligible_target(
+                        target,
+                        skip_pattern.as_ref(),
+                        only_pattern.as_ref(),
+                    ) =>
+            {
+                (
+                    plan::SkipReason::Excluded,
+                    "hunk belongs to a commit excluded by its message",
+                )
+            }
+            Some(target) if forced_target.is_none() && is_bulk(target)? => (
+                plan::SkipReason::Bulk,
+                "hunk belongs to a commit that touched too many files",
+            ),
+            _ => continue,
+        };
+        warn!(config.logger, "{}", trf("{}, leaving it in the index", &[&tr(why)]);
+              "path" => String::from_utf8_lossy(&assignment.path).into_owned(),
+              "header" => assignment.hunk.header(),
+              "commit" => assignment.target.as_ref().map(|target| target.id().to_string()),
+        );
+        let trace = traces.get(&(assignment.path.clone(), assignment.hunk.header()));
+        for step in trace.into_iter().flatten() {
+            info!(config.logger, "  {}", step;
+                  "path" => String::from_utf8_lossy(&assignment.path).into_owned(),
+                  "header" => assignment.hunk.header(),
+            );
+        }
+        assignment.retarget(None, reason);
+    }
+
+    // the hunks that commute with the whole stack may belong to a commit
+    // past its limit
+    if matches!(boundary, stack::Boundary::Limit(_)) {
+        let mut cut_off: Vec<&[u8]> = plan
+            .assignments
+            .iter()
+            .filter(|assignment| assignment.skip_reason == Some(plan::SkipReason::NoTarget))
+            .map(|assignment| assignment.path.as_slice())
+            .collect();
+        cut_off.dedup();
+        if !cut_off.is_empty() {
+            warn!(
+                config.logger,
+                "{}",
+                trf(
+                    if cut_off.len() == 1 {
+                        "{} staged file has hunks that may belong to commits past the stack limit, use --base or raise absorb.maxStack"
+                    } else {
+                        "{} staged files have hunks that may belong to commits past the stack limit, use --base or raise absorb.maxStack"
+                    },
+                    &[&cut_off.len()]
+                )
+            );
+        }
+    }
+    // or past whatever else ended the stack, which is worth knowing
+    // when deciding whether to extend it, but costs diffing the commits
+    // past it, so only when the details were asked for
+    let start = match boundary {
+        stack::Boundary::Merge(id)
+        | stack::Boundary::Pushed(id)
+        | stack::Boundary::Author(id)
+        | stack::Boundary::Limit(id) => Some(id),
+        stack::Boundary::End | stack::Boundary::Shallow(_) => None,
+    }
+    .filter(|_| config.verbose || config.explain || config.dry_run);
+    let unabsorbed: Vec<_> = plan
+        .assignments
+        .iter()
+        .filter(|assignment| assignment.skip_reason == Some(plan::SkipReason::NoTarget))
+        .collect();
+    if let (Some(start), false) = (start, unabsorbed.is_empty()) {
+        let paths: std::collections::HashSet<&[u8]> = unabsorbed
+            .iter()
+            .map(|assignment| assignment.path.as_slice())
+            .collect();
+        let mut past = Vec::new();
+        let mut id = Some(start);
+        while let (Some(commit_id), true) = (id, past.len() < max_stack) {
+            let commit = repo.find_commit(commit_id)?;
+            let diff = commit_diff(
+                repo,
+                backend,
+                &commit,
+                diff_settings,
+                |path| paths.contains(path),
+                &mut interner,
+            )?;
+            id = commit.parent_id(0).ok();
+            past.push((commit, diff));
+        }
+        let extended: Vec<_> = stack
+            .iter()
+            .chain(&past)
+            .map(|(commit, diff)| StackDiff {
+                id: commit.id(),
+                summary: commit.summary().unwrap_or_default().to_owned(),
+                diff,
+            })
+            .collect();
+        let hint = match boundary {
+            stack::Boundary::Merge(_) => {
+                "set absorb.traverseMerges = first-parent to go past merges"
+            }
+            stack::Boundary::Pushed(_) => "use --force-pushed to go past pushed commits",
+            stack::Boundary::Author(_) => {
+                "use --include-author or absorb.authorAllowlist to go past commits by other authors"
+            }
+            _ => "use --base, or raise absorb.maxStack",
+        };
+        for assignment in unabsorbed {
+            let target = find_target(
+                &extended,
+                &assignment.path,
+                &assignment.hunk,
+                config.whole_file,
+                intra_line,
+                None,
+                Some(config.logger),
+            )
+            .and_then(|idx| idx.checked_sub(stack.len()))
+            .map(|idx| &past[idx].0);
+            match target {
+                Some(target) => {
+                    info!(config.logger, "{}", tr("hunk has a target past the end of the stack");
+                          "path" => String::from_utf8_lossy(&assignment.path).into_owned(),
+                          "header" => assignment.hunk.header(),
+                          "commit" => target.id().to_string(),
+                          "summary" => target.summary().unwrap_or_default().to_owned(),
+                          "hint" => tr(hint),
+                    )
+                }
+                None => {
+                    debug!(config.logger, "hunk has no target past the end of the stack either";
+                          "path" => String::from_utf8_lossy(&assignment.path).into_owned(),
+                          "header" => assignment.hunk.header(),
+                    )
+                }
+            }
+        }
+    }
+
+    // a hunk commutes with every commit newer than its target, so any of
+    // them could take it just as well, which is worth auditing before
+    // picking another one with --target
+    if forced_target.is_none() {
+        for assignment in &plan.assignments {
+            let target = match (&assignment.target, &assignment.file_change) {
+                (Some(target), None) => target,
+                _ => continue,
+            };
+            let idx = match stack_commits
+                .iter()
+                .position(|commit| commit.id() == target.id())
+            {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let mut others = Vec::new();
+            for commit in &stack_commits[..idx] {
+                if commit.parent_count() <= 1
+                    && stack::autosquash_subject(commit).is_none()
+                    && is_eligible_target(commit, skip_pattern.as_ref(), only_pattern.as_ref())
+                    && !is_bulk(commit)?
+                {
+                    others.push(*commit);
+                }
+            }
+            if others.is_empty() {
+                continue;
+            }
+            log_candidates(config, assignment, target, &others);
+        }
+    }
+
+    Ok(Ok(Planned {
+        plan,
+        stack,
+        index,
+        patches_considered,
+        source,
+        head_commit,
+        original_head,
+        original_index,
+        rejected,
+    }))
+}
+
+/// Commits the fixups of the plan, and squashes them with
+/// `--and-rebase`.
+fn absorb(
+    config: &Config,
+    repo: &git2::Repository,
+    session: &Session,
+    planned: Planned,
+) -> Result<Outcome> {
+    let settings = &session.settings;
+    let backend = session.backend;
+    let index_tree = || session.index_tree(config, repo);
+    let Planned {
+        mut plan,
+        stack,
+        index,
+        patches_considered,
+        source,
+        mut head_commit,
+        original_head,
+        original_index,
+        mut rejected,
+    } = planned;
+    let mut head_tree = head_commit.tree()?;
+    let stack_commits: Vec<_> = stack.iter().map(|(commit, _)| commit).collect();
+
+    if config.patch && !plan.assignments.is_empty() {
+        let candidates: Vec<_> = stack.iter().map(|(commit, _)| commit.clone()).collect();
+        interactive::review_each(
+            &mut plan,
+            &candidates,
+            std::io::stdin().lock(),
+            std::io::stderr(),
+            render::use_color(repo, std::io::stderr().is_terminal()),
+        )?;
+    }
+
+    if config.interactive && !plan.assignments.is_empty() {
+        let candidates: Vec<_> = stack.iter().map(|(commit, _)| commit.clone()).collect();
+        if !interactive::review(&mut plan, &candidates)? {
+            warn!(config.logger, "{}", tr("Aborted, no changes were made."));
+            if let Some((original_index, ref intent_to_add)) = original_index {
+                backend.read_index_tree(repo, &repo.find_tree(original_index)?, intent_to_add)?;
+            }
+            return Ok(Outcome::Absorbed);
+        }
+    }
+
+    let signature = user_signature(repo, commit_time(config.date)?)?;
+    let signer = sign::signer(repo, config.gpg_sign, config.signing_key, &signature)?;
+    let fixup_kind = match config.fixup_kind {
+        Some(fixup_kind) => fixup_kind,
+        None => config::fixup_kind(settings)?,
+    };
+    let message_format = config::fixup_message_format(settings);
+    let fixup_by_sha = config.fixup_by_sha || config::fixup_by_sha(settings);
+    let signoff = config.signoff || config::signoff(settings);
+    let copy_trailers = config::copy_trailers(settings);
+    let run_hooks = config.run_hooks || config::run_hooks(settings);
+    let author_strategy = config::author_strategy(settings)?;
+    let date_strategy = config::committer_date(settings)?;
+    // messages are only written once per target (and path, which the
+    // message format may include), even if several hunks are absorbed
+    // into it
+    let mut messages = HashMap::new();
+    // HEAD can only be updated as we go if the fixups are committed on
+    // top of it
+    let update_ref = match source {
+        Some(_) => None,
+        None => Some("HEAD"),
+    };
+
+    // the index can only be committed as it is when absorbing it, not
+    // a commit given with --from
+    let leftover_tree = match source {
+        None if config.leftovers == Leftovers::Commit => Some(repo.find_tree(index_tree()?)?),
+        _ => None,
+    };
+    let commit_leftovers = leftover_tree
+        .as_ref()
+        .is_some_and(|tree| tree.id() != head_tree.id());
+
+    if !config.dry_run && (!plan.hunks_to_apply().is_empty() || commit_leftovers) {
+        // --undo restores what was staged before --all staged the rest
+        let (index_tree, intent_to_add) = match original_index {
+            Some(original_index) => original_index,
+            None => (index_tree()?, backend.intent_to_add_entries(repo)?),
+        };
+        undo::record(
+            repo,
+            settings,
+            index_tree,
+            &intent_to_add,
+            &signature,
+            config.logger,
+        )?;
+    } else if let Some((original_index, intent_to_add)) = original_index {
+        backend.read_index_tree(repo, &repo.find_tree(original_index)?, &intent_to_add)?;
+    }
+
+    // what --paranoid compares HEAD and the index with once the fixups
+    // are committed
+    let paranoid = if config.paranoid && !config.dry_run && source.is_none() {
+        Some((head_tree.id(), index_tree()?))
+    } else {
+        None
+    };
+
+    // fixups from a previous run can only be rewritten if HEAD is
+    // updated at the end
+    let amended = if !config.dry_run && source.is_none() && !plan.hunks_to_apply().is_empty() {
+        amend::amend_existing_fixups(
+            repo,
+            settings,
+            backend,
+            &stack_commits,
+            &plan,
+            &signature,
+            signer.as_ref(),
+            config.logger,
+        )?
+    } else {
+        HashMap::new()
+    };
+    if !amended.is_empty() {
+        head_commit = repo.head()?.peel_to_commit()?;
+        head_tree = head_commit.tree()?;
+    }
+
+    let color = render::use_color(repo, std::io::stdout().is_terminal());
+    let path_style = output::PathStyle::new(repo, config.path_format);
+    let mut committed = Vec::new();
+    // the reflog entry of the last fixup sums up the run
+    let (fixup_count, target_count) = {
+        let targets: Vec<_> = plan
+            .hunks_to_apply()
+            .iter()
+            .filter_map(|(assignment, _)| assignment.target.as_ref())
+            .map(git2::Commit::id)
+            .filter(|id| !amended.contains_key(id))
+            .collect();
+        let distinct: std::collections::HashSet<_> = targets.iter().collect();
+        (targets.len(), distinct.len())
+    };
+    let locator = |target: &git2::Commit| match fixup_by_sha {
+        true => target.id().to_string(),
+        false => plan.target_locator(target),
+    };
+    if !config.dry_run {
+        let _timer = profile::start(config.profile, "commit creation");
+        let mut created = 0;
+        let mut last_created = None;
+        committed = apply::commit_fixups_with(repo, &plan, &head_commit, &amended, |fixup| {
+            // TODO: the git2 api only supports utf8 commit messages,
+            // so it's okay to use strings instead of bytes here
+            // https://docs.rs/git2/0.7.5/src/git2/repo.rs.html#998
+            // https://libgit2.org/libgit2/#HEAD/group/commit/git_commit_create
+            let dest_commit = fixup.target;
+            let message = match messages.entry((dest_commit.id(), fixup.assignment.path.clone())) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut trailers =
+                        copied_trailers(dest_commit.message().unwrap_or_default(), &copy_trailers);
+                    if signoff {
+                        trailers.push(format!("Signed-off-by: {}", identity(&signature)));
+                    }
+                    entry.insert(add_trailers(
+                        fixup_message(
+                            repo,
+                            fixup_kind,
+                            config.edit_message,
+                            message_format.as_deref(),
+                            dest_commit,
+                            &locator(dest_commit),
+                            &fixup.assignment.path,
+                        )?,
+                        &trailers,
+                    ))
+                }
+            };
+            let message = if run_hooks {
+                hooks::run_commit_hooks(repo, fixup.tree, message)?
+            } else {
+                message.clone()
+            };
+            let (author, committer) =
+                fixup_identities(&signature, dest_commit, author_strategy, date_strategy)?;
+            let commit = sign::commit(
+                repo,
+                backend,
+                signer.as_ref(),
+                &author,
+                &committer,
+                &message,
+                fixup.tree,
+                &[fixup.parent],
+            )?;
+            created += 1;
+            if let Some(update_ref) = update_ref {
+                let action = if created == fixup_count {
+                    format!(
+                        "created {} {} for {} {}",
+                        fixup_count,
+                        if fixup_count == 1 { "fixup" } else { "fixups" },
+                        target_count,
+                        if target_count == 1 {
+                            "target"
+                        } else {
+                            "targets"
+                        }
+                    )
+                } else {
+                    format!("commit {}", message.lines().next().unwrap_or_default())
+                };
+                repo.find_reference(update_ref)?
+                    .resolve()?
+                    .set_target(commit, &config::reflog_message(settings, &action))?;
+            }
+            info!(config.logger, "{}", tr("committed");
+                  "commit" => commit.to_string(),
+                  "header" => fixup.hunk.header(),
+            );
+            last_created = Some(commit);
+            Ok(commit)
+        })?;
+        if let Some(last_created) = last_created {
+            head_commit = repo.find_commit(last_created)?;
+            head_tree = head_commit.tree()?;
+        }
+    } else {
+        for (assignment, hunk_to_apply) in plan.hunks_to_apply() {
+            // unwrap() is safe here, as hunks_to_apply() only returns
+            // hunks that have a target
+            let dest_commit = assignment.target.as_ref().unwrap();
+            info!(config.logger, "{}", tr("would have committed");
+                  "fixup" => locator(dest_commit),
+                  "header" => hunk_to_apply.header(),
+            );
+            // stdout is reserved for the JSON document otherwise
+            if config.output_format == OutputFormat::Text {
+                let mut stdout = std::io::stdout().lock();
+                for line in render::patch_text(&assignment.path, &hunk_to_apply, path_style) {
+                    if color {
+                        writeln!(stdout, "{}", render::colorize(&line))?;
+                    } else {
+                        writeln!(stdout, "{}", line)?;
+                    }
+                }
+            }
+        }
+    }
+
+    // whatever is still staged (including files that were skipped
+    // as a whole) is the difference between the index and the fixups
+    if let Some(leftover_tree) = leftover_tree.filter(|tree| tree.id() != head_tree.id()) {
+        if !config.dry_run {
+            let message = "WIP: unabsorbed changes\n";
+            head_commit = repo.find_commit(sign::commit(
+                repo,
+                backend,
+                signer.as_ref(),
+                &signature,
+                &signature,
+                message,
+                &leftover_tree,
+                &[&head_commit],
+            )?)?;
+            repo.find_reference("HEAD")?.resolve()?.set_target(
+                head_commit.id(),
+                &config::reflog_message(settings, "commit the unabsorbed changes"),
+            )?;
+            info!(config.logger, "{}", tr("committed leftovers");
+                  "commit" => head_commit.id().to_string(),
+            );
+        } else {
+            info!(config.logger, "{}", tr("would have committed leftovers"));
+        }
+    }
+
+    // there is nothing to check (or undo) if nothing was committed
+    let paranoid = paranoid.filter(|_| !committed.is_empty() || commit_leftovers);
+    if let Some((original_head, original_index)) = paranoid {
+        let problem = check_invariants(
+            repo,
+            &plan,
+            original_head,
+            original_index,
+            index_tree()?,
+            commit_leftovers,
+        )?;
+        if let Some(problem) = problem {
+            return Err(undo_after_check(
+                repo,
+                settings,
+                backend,
+                &problem,
+                config.logger,
+            ));
         }
     }
 
+    if !config.dry_run && !committed.is_empty() {
+        // unwrap() is safe here, as we exit early if the stack is empty
+        let base = stack.last().unwrap().0.parent_ids().next();
+        let entry =
+            journal::Entry::absorb(&plan, &committed, original_head, base, config.and_rebase);
+        journal::record(repo, &entry, config.logger);
+    }
+
+    if let Some(ref source) = source {
+        if !config.dry_run && !committed.is_empty() && config.stash.is_some() {
+            restash(
+                repo,
+                settings,
+                backend,
+                source,
+                &head_commit,
+                &signature,
+                config.logger,
+            )?;
+        } else if !config.dry_run && !committed.is_empty() && config.patch_file.is_some() {
+            // the index and the working tree get the absorbed hunks,
+            // like they would have had if the patch had been staged
+            let head_tree = repo.head()?.peel_to_tree()?;
+            patchfile::apply_to_workdir(repo, backend, &head_tree, &head_commit.tree()?).map_err(
+                |e| {
+                    anyhow!(
+                        "{}",
+                        trf("could not apply the fixups to the working tree: {}", &[&e])
+                    )
+                },
+            )?;
+            repo.head()?.set_target(
+                head_commit.id(),
+                &config::reflog_message(settings, "absorb patch into the stack"),
+            )?;
+        } else if !config.dry_run && !committed.is_empty() {
+            rewrite_source(
+                repo,
+                settings,
+                backend,
+                source,
+                &head_commit,
+                &signature,
+                signer.as_ref(),
+                config.logger,
+            )?;
+        }
+    }
+
+    match config.output_format {
+        OutputFormat::Text => {}
+        OutputFormat::Json => output::print_json(&plan, &committed)?,
+        OutputFormat::Porcelain => output::print_porcelain(&plan, &committed, path_style)?,
+    }
+    if !plan.assignments.is_empty() || !plan.skipped_paths.is_empty() {
+        for line in output::summary_lines(&plan) {
+            info!(config.logger, "{}", line);
+        }
+    }
+
+    let mut outcome = outcome(&plan, &index);
+    if let (Some(source), Some(_)) = (&source, config.patch_file) {
+        if !config.dry_run {
+            let remainder =
+                repo.diff_tree_to_tree(Some(&head_commit.tree()?), Some(&source.tree()?), None)?;
+            for (path, hunks) in patchfile::rejects(&remainder)? {
+                rejected.entry(path).or_default().extend(hunks);
+            }
+        }
+        if !rejected.is_empty() {
+            warn!(config.logger, "{}", tr("some hunks of the patch were not absorbed"); "files" => rejected.len());
+            if !config.dry_run {
+                for path in patchfile::write_rejects(repo, &rejected)? {
+                    warn!(
+                        config.logger,
+                        "{}",
+                        trf("rejected hunks are in {}", &[&path.display()])
+                    );
+                }
+            }
+            outcome = if !plan.hunks_to_apply().is_empty() {
+                Outcome::PartiallyAbsorbed
+            } else {
+                Outcome::NothingAbsorbed
+            };
+        }
+    }
     if patches_considered == 0 {
         warn!(
             config.logger,
-            "No additions staged, try adding something to the index."
+            "{}",
+            tr("No additions staged, try adding something to the index.")
         );
     } else if config.and_rebase {
         use std::process::Command;
+        let _timer = profile::start(config.profile, "rebase");
         // unwrap() is safe here, as we exit early if the stack is empty
         let last_commit_in_stack = &stack.last().unwrap().0;
-        // The stack isn't supposed to have any merge commits, per the check in working_stack()
+        // the stack only has merge commits with absorb.traverseMerges,
+        // in which case it only follows their first parent
         let number_of_parents = last_commit_in_stack.parents().len();
-        assert!(number_of_parents <= 1);
+
+        let base = match number_of_parents {
+            0 => None,
+            _ => Some(last_commit_in_stack.parent(0)?),
+        };
+        // options for git rebase (eg: --update-refs) can only be
+        // honored by git rebase itself
+        let fixups_tree = head_commit.tree_id();
+        if config.rebase_options.is_empty() && backend.autosquash_in_memory() {
+            if rebase::autosquash(
+                repo,
+                settings,
+                backend,
+                base.as_ref(),
+                &signature,
+                signer.as_ref(),
+                config.logger,
+            )? {
+                if let Some((_, original_index)) = paranoid {
+                    let problem = check_squashed(repo, fixups_tree)?.or_else(|| {
+                        (index_tree().ok() != Some(original_index))
+                            .then(|| "squashing the fixups changed the index".to_owned())
+                    });
+                    if let Some(problem) = problem {
+                        return Err(undo_after_check(
+                            repo,
+                            settings,
+                            backend,
+                            &problem,
+                            config.logger,
+                        ));
+                    }
+                }
+                return Ok(outcome);
+            }
+            info!(
+                config.logger,
+                "{}",
+                tr("could not autosquash in memory, running git rebase")
+            );
+        }
 
         let mut command = Command::new("git");
-        command.args(&["rebase", "--interactive", "--autosquash"]);
+        git_cli::in_repo(&mut command, repo).args(["rebase", "--interactive", "--autosquash"]);
+        // git rebase names its reflog entries after GIT_REFLOG_ACTION
+        command.env(
+            "GIT_REFLOG_ACTION",
+            config::reflog_message(settings, "autosquash rebase"),
+        );
+        // git rebase refuses to start with a dirty working tree (or with
+        // hunks that were left in the index), so stash them around the
+        // rebase unless rebase.autoStash is explicitly disabled. git
+        // restores the stash even if the rebase is aborted
+        let autostash = repo
+            .config()
+            .and_then(|config| config.get_bool("rebase.autoStash"))
+            .unwrap_or(true);
+        if autostash
+            && !config
+                .rebase_options
+                .iter()
+                .any(|option| option.ends_with("autostash"))
+        {
+            command.arg("--autostash");
+        }
+        // move the branches stacked below HEAD along with the commits
+        // they point to, like the in-memory autosquash does
+        let update_refs = rebase_update_refs(
+            repo.config()
+                .and_then(|config| config.get_bool("rebase.updateRefs"))
+                .ok(),
+            git_cli::version(),
+        );
+        if update_refs
+            && !config
+                .rebase_options
+                .iter()
+                .any(|option| option.ends_with("update-refs"))
+        {
+            command.arg("--update-refs");
+        }
+        // keep the merges that the stack walked past
+        if stack.iter().any(|(commit, _)| commit.parent_count() > 1)
+            && !config
+                .rebase_options
+                .iter()
+                .any(|option| option.starts_with("--rebase-merges") || *option == "-r")
+        {
+            command.arg("--rebase-merges");
+        }
+        // the options go before the upstream, which git rebase expects
+        // to be its last argument
+        command.args(&config.rebase_options);
 
         if number_of_parents == 0 {
             command.arg("--root");
@@ -281,86 +2219,4723 @@ pub fn run(config: &Config) -> Result<()> {
             let base_commit_sha = last_commit_in_stack.parent(0)?.id().to_string();
             command.arg(&base_commit_sha);
         }
+        if config.output_format != OutputFormat::Text {
+            // keep stdout reserved for the JSON or porcelain output
+            command.stdout(std::io::stderr());
+        }
 
         // Don't check that we have successfully absorbed everything, nor git's
         // exit code -- as git will print helpful messages on its own.
         command.status().expect("could not run git rebase");
+        if !config.dry_run && repo.state() != git2::RepositoryState::Clean {
+            state::record(repo, &committed, config.logger)?;
+        } else if paranoid.is_some() {
+            // git rebase stashed and restored the index, which may have
+            // merged it differently, so only HEAD can be checked
+            if let Some(problem) = check_squashed(repo, fixups_tree)? {
+                return Err(undo_after_check(
+                    repo,
+                    settings,
+                    backend,
+                    &problem,
+                    config.logger,
+                ));
+            }
+        }
     }
 
-    Ok(())
+    Ok(outcome)
 }
 
-fn apply_hunk_to_tree<'repo>(
-    repo: &'repo git2::Repository,
-    base: &git2::Tree,
-    hunk: &owned::Hunk,
-    path: &[u8],
-) -> Result<git2::Tree<'repo>> {
-    let mut treebuilder = repo.treebuilder(Some(base))?;
-
-    // recurse into nested tree if applicable
-    if let Some(slash) = path.iter().position(|&x| x == b'/') {
-        let (first, rest) = path.split_at(slash);
-        let rest = &rest[1..];
-
-        let (subtree, submode) = {
-            let entry = treebuilder
-                .get(first)?
-                .ok_or_else(|| anyhow!("couldn't find tree entry in tree for path"))?;
-            (repo.find_tree(entry.id())?, entry.filemode())
-        };
-        // TODO: loop instead of recursing to avoid potential stack overflow
-        let result_subtree = apply_hunk_to_tree(repo, &subtree, hunk, rest)?;
-
-        treebuilder.insert(first, result_subtree.id(), submode)?;
-        return Ok(repo.find_tree(treebuilder.write()?)?);
-    }
-
-    let (blob, mode) = {
-        let entry = treebuilder
-            .get(path)?
-            .ok_or_else(|| anyhow!("couldn't find blob entry in tree for path"))?;
-        (repo.find_blob(entry.id())?, entry.filemode())
-    };
-
-    // TODO: convert path to OsStr and pass it during blob_writer
-    // creation, to get gitattributes handling (note that converting
-    // &[u8] to &std::path::Path is only possible on unixy platforms)
-    let mut blobwriter = repo.blob_writer(None)?;
-    let old_content = blob.content();
-    let (old_start, _, _, _) = hunk.anchors();
-
-    // first, write the lines from the old content that are above the
-    // hunk
-    let old_content = {
-        let (pre, post) = split_lines_after(old_content, old_start);
-        blobwriter.write_all(pre)?;
-        post
-    };
-    // next, write the added side of the hunk
-    for line in &*hunk.added.lines {
-        blobwriter.write_all(line)?;
-    }
-    // if this hunk removed lines from the old content, those must be
-    // skipped
-    let (_, old_content) = split_lines_after(old_content, hunk.removed.lines.len());
-    // finally, write the remaining lines of the old content
-    blobwriter.write_all(old_content)?;
-
-    treebuilder.insert(path, blobwriter.commit()?, mode)?;
-    Ok(repo.find_tree(treebuilder.write()?)?)
-}
-
-/// Return slices for lines [1..n] and [n+1; ...]
-fn split_lines_after(content: &[u8], n: usize) -> (&[u8], &[u8]) {
-    let split_index = if n > 0 {
-        memchr::Memchr::new(b'\n', content)
-            .fuse() // TODO: is fuse necessary here?
-            .nth(n - 1) // the position of '\n' ending the `n`-th line
-            .map(|x| x + 1)
-            .unwrap_or_else(|| content.len())
+/// Returns whether git rebase should be given `--update-refs`: if
+/// `rebase.updateRefs` says so, or else if git is recent enough to know
+/// the option (it came with git 2.38).
+fn rebase_update_refs(configured: Option<bool>, git_version: Option<(u32, u32)>) -> bool {
+    configured.unwrap_or_else(|| git_version.is_some_and(|version| version >= (2, 38)))
+}
+
+/// How often `--watch` checks whether the index changed. It polls, as
+/// there is no crate for filesystem events to build with.
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// What `--watch` compares to tell that the index changed. git replaces
+/// the index rather than writing into it, which changes one of these
+/// even if it's written twice within the same second.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct IndexStamp {
+    modified: Option<std::time::SystemTime>,
+    len: u64,
+    inode: u64,
+}
+
+impl IndexStamp {
+    /// Returns the stamp of the index at `path`, or `None` if there is
+    /// no index.
+    fn of(path: &Path) -> Option<Self> {
+        std::fs::metadata(path).ok().map(|metadata| IndexStamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(&metadata),
+            #[cfg(not(unix))]
+            inode: 0,
+        })
+    }
+}
+
+/// Runs as a dry run (or commits the fixups, with `commit`) every time
+/// the index changes, which is checked every `WATCH_INTERVAL`. This
+/// never returns unless it's interrupted, or fails.
+fn watch(config: &Config, repo: &git2::Repository, commit: bool) -> Result<Outcome> {
+    let mut run_config = config.clone();
+    run_config.action = Action::Absorb;
+    run_config.dry_run = config.dry_run || !commit;
+
+    let index_path = match repo.index().ok().as_ref().and_then(git2::Index::path) {
+        Some(path) => path.to_path_buf(),
+        None => match std::env::var_os("GIT_INDEX_FILE") {
+            Some(path) => PathBuf::from(path),
+            None => repo.path().join("index"),
+        },
+    };
+
+    info!(config.logger, "{}", tr("watching the index, press Ctrl-C to stop");
+          "path" => index_path.display().to_string(),
+    );
+    let mut last = None;
+    loop {
+        watch_once(&run_config, repo, &index_path, &mut last)?;
+        std::thread::sleep(WATCH_INTERVAL);
+    }
+}
+
+/// Runs once with `run_config` if the index at `index_path` changed
+/// since `last`, the stamp after the previous run (`None` before the
+/// first one), which is updated. Failed runs are only reported.
+///
+/// Returns whether it ran.
+fn watch_once(
+    run_config: &Config,
+    repo: &git2::Repository,
+    index_path: &Path,
+    last: &mut Option<Option<IndexStamp>>,
+) -> Result<bool> {
+    if *last == Some(IndexStamp::of(index_path)) {
+        return Ok(false);
+    }
+    // a sparse index is only kept in memory, and read again by the run
+    // itself
+    if let Some(mut index) = repo.index().ok().filter(|index| index.path().is_some()) {
+        index.read(false)?;
+    }
+    if let Err(e) = run_with_repo(run_config, repo) {
+        warn!(run_config.logger, "{}", tr("absorb failed"); "err" => tr_error(&e));
+    }
+    // committing the fixups may have rewritten the index
+    *last = Some(IndexStamp::of(index_path));
+    Ok(true)
+}
+
+/// Checks, for `--paranoid`, that committing the fixups kept everything
+/// that was staged: the index is still `original_index`, the files
+/// whose hunks were all absorbed are the same in HEAD as in the index,
+/// and no other file changed in HEAD since `original_head`. If the
+/// leftovers were committed too, HEAD must be the index.
+///
+/// Returns what went wrong, if anything did.
+fn check_invariants(
+    repo: &git2::Repository,
+    plan: &plan::Plan,
+    original_head: git2::Oid,
+    original_index: git2::Oid,
+    index: git2::Oid,
+    leftovers_committed: bool,
+) -> Result<Option<String>> {
+    if index != original_index {
+        return Ok(Some("committing the fixups changed the index".to_owned()));
+    }
+    let head = repo.head()?.peel_to_tree()?;
+    if leftovers_committed {
+        return Ok((head.id() != index).then(|| {
+            "HEAD is different from the index after committing the leftovers".to_owned()
+        }));
+    }
+
+    // whether each planned path had all of its hunks absorbed
+    let mut absorbed: HashMap<&[u8], bool> = HashMap::new();
+    for assignment in &plan.assignments {
+        *absorbed.entry(&assignment.path).or_insert(true) &= assignment.target.is_some();
+    }
+    let entry = |tree: &git2::Tree, path: &[u8]| {
+        path_from_bytes(path)
+            .and_then(|path| tree.get_path(path).ok())
+            .map(|entry| (entry.id(), entry.filemode()))
+    };
+    let index = repo.find_tree(index)?;
+    let original_head = repo.find_tree(original_head)?;
+    for (&path, &all) in &absorbed {
+        if all && entry(&head, path) != entry(&index, path) {
+            return Ok(Some(format!(
+                "{} is different in HEAD and in the index, although all of its hunks were absorbed",
+                String::from_utf8_lossy(path)
+            )));
+        }
+    }
+    let diff = repo.diff_tree_to_tree(Some(&original_head), Some(&head), None)?;
+    for delta in diff.deltas() {
+        let path = delta
+            .new_file()
+            .path_bytes()
+            .or_else(|| delta.old_file().path_bytes())
+            .unwrap_or_default();
+        let planned = plan
+            .assignments
+            .iter()
+            .any(|assignment| assignment.path == path && assignment.target.is_some());
+        if !planned {
+            return Ok(Some(format!(
+                "the fixups changed {}, none of whose hunks were absorbed",
+                String::from_utf8_lossy(path)
+            )));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks, for `--paranoid`, that squashing the fixups left HEAD with
+/// the same tree as `fixups_tree`, the tree of the last fixup.
+fn check_squashed(repo: &git2::Repository, fixups_tree: git2::Oid) -> Result<Option<String>> {
+    let head = repo.head()?.peel_to_commit()?;
+    Ok((head.tree_id() != fixups_tree)
+        .then(|| "squashing the fixups changed the tree of HEAD".to_owned()))
+}
+
+/// Undoes the run after a `--paranoid` check found `problem`, and
+/// returns the error to fail with.
+fn undo_after_check(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    problem: &str,
+    logger: &slog::Logger,
+) -> anyhow::Error {
+    crit!(logger, "{}", tr("paranoid check failed, undoing the run"); "problem" => problem);
+    match undo::restore(repo, settings, backend, logger) {
+        Ok(()) => anyhow!("{}", trf("{}, the run was undone", &[&problem])),
+        Err(e) => anyhow!(
+            "{}",
+            trf(
+                "{}, and the run could not be undone: {}, run git absorb --undo once that is fixed",
+                &[&problem, &e]
+            )
+        ),
+    }
+}
+
+/// Returns whether the staged changes were all absorbed, according to
+/// the (final) plan.
+fn outcome(plan: &plan::Plan, index: &owned::Diff) -> Outcome {
+    if index.is_empty() {
+        Outcome::NothingStaged
+    } else if plan.hunks_to_apply().is_empty() {
+        Outcome::NothingAbsorbed
+    } else if !plan.skipped_paths.is_empty()
+        || plan
+            .assignments
+            .iter()
+            .any(|assignment| assignment.target.is_none())
+    {
+        Outcome::PartiallyAbsorbed
     } else {
-        0
+        Outcome::Absorbed
+    }
+}
+
+/// Returns whether the subject of `target` allows it to be fixed up,
+/// according to `absorb.skipMessagePattern` and
+/// `absorb.onlyMessagePattern`.
+fn is_eligible_target(
+    target: &git2::Commit,
+    skip_pattern: Option<&regex::Regex>,
+    only_pattern: Option<&regex::Regex>,
+) -> bool {
+    let subject = target.summary().unwrap_or_default();
+    !skip_pattern.is_some_and(|pattern| pattern.is_match(subject))
+        && only_pattern.is_none_or(|pattern| pattern.is_match(subject))
+}
+
+/// Returns whether the index is a sparse index (see
+/// `git sparse-checkout`), whose directory entries libgit2 refuses to
+/// read.
+/// Returns the branch that HEAD is on, and the first pattern of
+/// `absorb.protectedBranches` that it matches, if it matches one.
+fn protected_branch(
+    repo: &git2::Repository,
+    settings: &Layered,
+) -> Result<Option<(String, String)>> {
+    let head = repo.head()?;
+    let branch = match head.shorthand().filter(|_| head.is_branch()) {
+        Some(branch) => branch,
+        None => return Ok(None),
+    };
+    Ok(config::protected_branches(settings)
+        .into_iter()
+        .find(|pattern| exclude::glob_match(pattern, branch))
+        .map(|pattern| (branch.to_owned(), pattern)))
+}
+
+fn is_sparse_index(repo: &git2::Repository) -> bool {
+    match repo.index() {
+        Ok(_) => false,
+        Err(e) => e.class() == git2::ErrorClass::Index && e.message().contains("'sdir'"),
+    }
+}
+
+/// Stages the changes to the tracked files that match the pathspecs
+/// (all of them if there are none), like `git add --update`.
+fn stage_tracked(repo: &git2::Repository, backend: &dyn Backend, pathspecs: &[&str]) -> Result<()> {
+    // git only checks the files that a filesystem monitor says changed
+    match config::fsmonitor(repo) {
+        true => backend::Cli.stage_tracked(repo, pathspecs),
+        false => backend.stage_tracked(repo, pathspecs),
+    }
+}
+
+/// Returns whether the entry was added with `git add --intent-to-add`,
+/// which only records that the file will be added, without staging it.
+fn is_intent_to_add(entry: &git2::IndexEntry) -> bool {
+    git2::IndexEntryExtendedFlag::from_bits_truncate(entry.flags_extended).is_intent_to_add()
+}
+
+/// Makes an entry like the ones `git add --intent-to-add` adds, for an
+/// empty file whose stat data is unknown (so that git always looks at
+/// the file in the working tree).
+fn intent_to_add_entry(path: Vec<u8>, mode: u32) -> git2::IndexEntry {
+    git2::IndexEntry {
+        ctime: git2::IndexTime::new(0, 0),
+        mtime: git2::IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: git2::Oid::hash_object(git2::ObjectType::Blob, &[]).unwrap(),
+        flags: 0,
+        flags_extended: git2::IndexEntryExtendedFlag::INTENT_TO_ADD.bits(),
+        path,
+    }
+}
+
+/// Describes an operation that is in progress in the repository.
+fn describe_state(state: git2::RepositoryState) -> &'static str {
+    match state {
+        git2::RepositoryState::Clean => "nothing",
+        git2::RepositoryState::Merge => "merge",
+        git2::RepositoryState::Revert | git2::RepositoryState::RevertSequence => "revert",
+        git2::RepositoryState::CherryPick | git2::RepositoryState::CherryPickSequence => {
+            "cherry-pick"
+        }
+        git2::RepositoryState::Bisect => "bisect",
+        git2::RepositoryState::Rebase
+        | git2::RepositoryState::RebaseInteractive
+        | git2::RepositoryState::RebaseMerge => "rebase",
+        git2::RepositoryState::ApplyMailbox | git2::RepositoryState::ApplyMailboxOrRebase => {
+            "git am"
+        }
+    }
+}
+
+/// Plans a change to a whole file, which can't be commuted like a hunk,
+/// so its target is chosen based on which commits touched the file.
+fn plan_file_change<'repo>(
+    stack: &[(git2::Commit<'repo>, owned::Diff)],
+    patch: &owned::Patch,
+    hunk: owned::Hunk,
+    file_change: plan::FileChange,
+    forced_target: Option<&git2::Commit<'repo>>,
+    new_files: bool,
+    logger: &slog::Logger,
+) -> plan::HunkAssignment<'repo> {
+    let path = patch.new_path.as_slice();
+    let dest_commit = match (forced_target, file_change) {
+        (Some(target), _) => Ok(target),
+        (None, plan::FileChange::Added(_)) if new_files => sibling_creator(stack, path).ok_or(
+            "Could not find a commit that created another file in the same directory \
+                 for this new file. Use --target to choose one.",
+        ),
+        (None, plan::FileChange::Added(_)) => {
+            Err("Left new file in the index. Use --new-files or --target to absorb it.")
+        }
+        (None, plan::FileChange::Deleted) => creator(stack, path).ok_or(
+            "Could not find the commit that created this deleted file. It must have been \
+             created in the stack, and not changed since.",
+        ),
+        (None, plan::FileChange::Mode(_)) => toucher(stack, path).ok_or(
+            "Could not find a commit in the stack that touched this file to absorb its mode \
+             change into. Use --target to choose one.",
+        ),
+        (None, plan::FileChange::Replaced(..)) => toucher(stack, path).ok_or(
+            "Could not find a commit in the stack that touched this path to absorb its \
+             change into. Use --target to choose one.",
+        ),
+    };
+    debug!(logger, "whole file change";
+           "path" => String::from_utf8_lossy(path).into_owned(),
+           "change" => format!("{:?}", file_change),
+           "commit" => dest_commit.ok().map(|commit| commit.id().to_string()),
+    );
+    if let Err(message) = dest_commit {
+        warn!(logger, "{}", tr(message);
+              "path" => String::from_utf8_lossy(path).into_owned(),
+        );
+    }
+    plan::HunkAssignment {
+        path: patch.new_path.clone(),
+        hunk,
+        target: dest_commit.ok().cloned(),
+        skip_reason: match dest_commit {
+            Ok(_) => None,
+            Err(_) => Some(plan::SkipReason::NoTarget),
+        },
+        file_change: Some(file_change),
+    }
+}
+
+/// Returns the newest commit in the stack that touched the file.
+fn toucher<'a, 'repo>(
+    stack: &'a [(git2::Commit<'repo>, owned::Diff)],
+    path: &[u8],
+) -> Option<&'a git2::Commit<'repo>> {
+    stack
+        .iter()
+        .find(|(_, diff)| diff.by_new(path).is_some())
+        .map(|(commit, _)| commit)
+}
+
+/// Returns every commit in the stack that touched the file, newest
+/// first, following the file through renames.
+fn touchers<'a, 'repo>(
+    stack: &'a [(git2::Commit<'repo>, owned::Diff)],
+    path: &[u8],
+) -> Vec<&'a git2::Commit<'repo>> {
+    let mut path = path;
+    let mut ret = Vec::new();
+    for (commit, diff) in stack {
+        if let Some(patch) = diff.by_new(path) {
+            ret.push(commit);
+            path = patch.old_path.as_slice();
+        }
+    }
+    ret
+}
+
+/// Returns the newest commit in the stack that touched the file, if
+/// that commit created it.
+fn creator<'a, 'repo>(
+    stack: &'a [(git2::Commit<'repo>, owned::Diff)],
+    path: &[u8],
+) -> Option<&'a git2::Commit<'repo>> {
+    stack
+        .iter()
+        .find_map(|(commit, diff)| diff.by_new(path).map(|patch| (commit, patch)))
+        .filter(|(_, patch)| patch.status == git2::Delta::Added)
+        .map(|(commit, _)| commit)
+}
+
+/// Returns the newest commit in the stack that created another file in
+/// the same directory as the given path.
+fn sibling_creator<'a, 'repo>(
+    stack: &'a [(git2::Commit<'repo>, owned::Diff)],
+    path: &[u8],
+) -> Option<&'a git2::Commit<'repo>> {
+    let dir = parent_dir(path);
+    stack
+        .iter()
+        .find(|(_, diff)| {
+            diff.iter().any(|patch| {
+                patch.status == git2::Delta::Added
+                    && patch.new_path != path
+                    && parent_dir(&patch.new_path) == dir
+            })
+        })
+        .map(|(commit, _)| commit)
+}
+
+/// Returns whether a modified text file is bigger than `max_file_size`
+/// bytes, before or after the change, or the change has a line longer
+/// than `max_line_length` bytes, where 0 disables either limit.
+fn is_large_file(
+    repo: &git2::Repository,
+    patch: &owned::Patch,
+    max_file_size: u64,
+    max_line_length: usize,
+) -> bool {
+    if patch.status != git2::Delta::Modified || patch.is_binary {
+        return false;
+    }
+    // a file that is only in the working tree has no blob to look up
+    let too_big = max_file_size > 0
+        && repo.odb().is_ok_and(|odb| {
+            [patch.old_id, patch.new_id]
+                .iter()
+                .filter_map(|&id| odb.read_header(id).ok())
+                .any(|(size, _)| size as u64 > max_file_size)
+        });
+    let too_long = max_line_length > 0
+        && patch.hunks.iter().any(|hunk| {
+            hunk.added
+                .lines
+                .iter()
+                .chain(hunk.removed.lines.iter())
+                .any(|line| line.len() > max_line_length)
+        });
+    too_big || too_long
+}
+
+/// Returns whether git normalizes the line endings of the file, per its
+/// `text` and `eol` attributes, or else `core.autocrlf`.
+fn normalizes_line_endings(repo: &git2::Repository, path: &[u8]) -> bool {
+    let path = match path_from_bytes(path) {
+        Some(path) => path,
+        None => return false,
+    };
+    let attr = |name| {
+        repo.get_attr(path, name, git2::AttrCheckFlags::FILE_THEN_INDEX)
+            .map(git2::AttrValue::from_string)
+            .unwrap_or(git2::AttrValue::Unspecified)
+    };
+    match attr("text") {
+        git2::AttrValue::False => return false,
+        git2::AttrValue::True | git2::AttrValue::String(_) => return true,
+        _ => {}
+    }
+    if let git2::AttrValue::String(_) = attr("eol") {
+        return true;
+    }
+    repo.config()
+        .and_then(|config| config.get_string("core.autocrlf"))
+        .is_ok_and(|autocrlf| autocrlf == "true" || autocrlf == "input")
+}
+
+/// Returns whether the attributes mark the file as generated, with
+/// `linguist-generated` (like GitHub does), or as not worth diffing,
+/// with `-diff`.
+fn is_generated(repo: &git2::Repository, path: &[u8]) -> bool {
+    let path = match path_from_bytes(path) {
+        Some(path) => path,
+        None => return false,
+    };
+    let attr = |name| {
+        repo.get_attr(path, name, git2::AttrCheckFlags::FILE_THEN_INDEX)
+            .map(git2::AttrValue::from_string)
+            .unwrap_or(git2::AttrValue::Unspecified)
+    };
+    let generated = match attr("linguist-generated") {
+        git2::AttrValue::True => true,
+        git2::AttrValue::String(value) => value == "true",
+        _ => false,
+    };
+    generated || attr("diff") == git2::AttrValue::False
+}
+
+/// Returns the name of the diff driver that the attributes assign to
+/// the path, if that driver converts the file before diffing it, with
+/// `diff.<driver>.textconv` or `diff.<driver>.command`.
+///
+/// libgit2 diffs the raw contents of such files, and only honours the
+/// `binary` setting of a driver.
+fn textconv_driver(repo: &git2::Repository, path: &[u8]) -> Option<String> {
+    let path = path_from_bytes(path)?;
+    let value = repo
+        .get_attr(path, "diff", git2::AttrCheckFlags::FILE_THEN_INDEX)
+        .ok()?;
+    let driver = match git2::AttrValue::from_string(value) {
+        git2::AttrValue::String(driver) => driver,
+        _ => return None,
     };
-    content.split_at(split_index)
+    let config = repo.config().ok()?;
+    let converts = ["textconv", "command"].iter().any(|key| {
+        config
+            .get_string(&format!("diff.{}.{}", driver, key))
+            .is_ok()
+    });
+    if converts {
+        Some(driver.to_owned())
+    } else {
+        None
+    }
+}
+
+/// Returns the path that git's bytes stand for, which is always
+/// possible on unix, and only for valid UTF-8 elsewhere.
+fn path_from_bytes(path: &[u8]) -> Option<&Path> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(Path::new(std::ffi::OsStr::from_bytes(path)))
+    }
+    #[cfg(not(unix))]
+    {
+        std::str::from_utf8(path).ok().map(Path::new)
+    }
+}
+
+/// Fetches the blobs on either side of the deltas of the diffs that the
+/// repository doesn't have yet (as it is a partial clone), in a single
+/// batch from the promisor remote. Only the blobs of the files that the
+/// first diff (the staged changes) touches are fetched, and of the files
+/// that those may have been renamed from.
+///
+/// Returns the paths whose blobs are still missing afterwards (eg: when
+/// the remote can't be reached).
+fn fetch_missing_blobs(
+    repo: &git2::Repository,
+    remote: &str,
+    diffs: &[git2::Diff],
+    logger: &slog::Logger,
+) -> std::collections::HashSet<Vec<u8>> {
+    let staged: std::collections::HashSet<&[u8]> = diffs[0]
+        .deltas()
+        .flat_map(|delta| [delta.old_file().path_bytes(), delta.new_file().path_bytes()])
+        .flatten()
+        .collect();
+    let odb = match repo.odb() {
+        Ok(odb) => odb,
+        Err(_) => return Default::default(),
+    };
+    // (path, blob) for every blob that is needed
+    let mut needed = Vec::new();
+    for diff in diffs {
+        // a staged file may have been created by renaming a file that a
+        // commit deleted
+        let renamed = diff.deltas().any(|delta| {
+            delta.status() == git2::Delta::Added
+                && delta
+                    .new_file()
+                    .path_bytes()
+                    .is_some_and(|path| staged.contains(path))
+        });
+        for delta in diff.deltas() {
+            for file in [delta.old_file(), delta.new_file()] {
+                let path = match file.path_bytes() {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let wanted =
+                    staged.contains(path) || (renamed && delta.status() == git2::Delta::Deleted);
+                if wanted && !file.id().is_zero() && file.mode() != git2::FileMode::Commit {
+                    needed.push((path.to_vec(), file.id()));
+                }
+            }
+        }
+    }
+    needed.retain(|(_, id)| !odb.exists(*id));
+    if needed.is_empty() {
+        return Default::default();
+    }
+
+    let mut ids: Vec<_> = needed.iter().map(|(_, id)| *id).collect();
+    ids.sort();
+    ids.dedup();
+    info!(logger, "{}", tr("fetching missing objects"); "remote" => remote, "count" => ids.len());
+    match git_cli::fetch_objects(repo, remote, &ids) {
+        // the fetched pack isn't known to the odb until it is refreshed
+        Ok(()) => drop(odb.refresh()),
+        Err(e) => {
+            warn!(logger, "{}", tr("could not fetch missing objects"); "err" => e.to_string())
+        }
+    }
+    needed
+        .into_iter()
+        .filter(|(_, id)| !odb.exists(*id))
+        .map(|(path, _)| path)
+        .collect()
+}
+
+/// Returns the directory that contains the path, which is empty for
+/// the root of the repository.
+/// Diffs the commit against its first parent, and parses the files that
+/// `keep` accepts.
+/// Logs the other commits that a hunk could have been absorbed into, at
+/// the info level for a dry run and at the debug level otherwise.
+fn log_candidates(
+    config: &Config,
+    assignment: &plan::HunkAssignment,
+    target: &git2::Commit,
+    others: &[&git2::Commit],
+) {
+    let message = trf(
+        if others.len() == 1 {
+            "hunk could also be absorbed into {} other commit"
+        } else {
+            "hunk could also be absorbed into {} other commits"
+        },
+        &[&others.len()],
+    );
+    let path = String::from_utf8_lossy(&assignment.path).into_owned();
+    if config.dry_run {
+        info!(config.logger, "{}", message;
+              "path" => &path,
+              "header" => assignment.hunk.header(),
+              "commit" => target.id().to_string(),
+        );
+    } else {
+        debug!(config.logger, "{}", message;
+               "path" => &path,
+               "header" => assignment.hunk.header(),
+               "commit" => target.id().to_string(),
+        );
+    }
+    for commit in others {
+        if config.dry_run {
+            info!(config.logger, "{}", tr("  candidate");
+                  "commit" => commit.id().to_string(),
+                  "summary" => commit.summary().unwrap_or_default().to_owned(),
+            );
+        } else {
+            debug!(config.logger, "{}", tr("  candidate");
+                   "commit" => commit.id().to_string(),
+                   "summary" => commit.summary().unwrap_or_default().to_owned(),
+            );
+        }
+    }
+}
+
+/// If the hunk edits lines that its target (the commit at `target` in
+/// the stack) moved from another file, returns that file, and the older
+/// commit of the stack that the hunk would belong to there, if any.
+#[allow(clippy::too_many_arguments)]
+fn moved_origin<'a, 'repo>(
+    repo: &git2::Repository,
+    stack: &'a [(git2::Commit<'repo>, owned::Diff)],
+    stack_diffs: &[StackDiff],
+    path: &[u8],
+    hunk: &owned::Hunk,
+    target: usize,
+    intra_line: bool,
+    backend: &dyn Backend,
+    diff_settings: DiffSettings,
+    moved_diffs: &mut HashMap<git2::Oid, owned::Diff>,
+    interner: &mut owned::Interner,
+    logger: &slog::Logger,
+) -> Result<Option<(Vec<u8>, Option<&'a git2::Commit<'repo>>)>> {
+    let (path, hunk) =
+        match git_absorb_core::target::added_by(stack_diffs, path, hunk, intra_line, target) {
+            Some((path, hunk)) => (path.to_vec(), hunk),
+            None => return Ok(None),
+        };
+    let (commit, _) = &stack[target];
+    // the diffs of the stack only have the staged files, and the lines
+    // were moved from another one
+    let full = match moved_diffs.entry(commit.id()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(commit_diff(
+            repo,
+            backend,
+            commit,
+            diff_settings,
+            |_| true,
+            interner,
+        )?),
+    };
+    let (from, moved) = match git_absorb_core::target::moved_from_file(full, &path, &hunk) {
+        Some((from, moved)) => (from.to_vec(), moved),
+        None => return Ok(None),
+    };
+    debug!(logger, "hunk edits lines moved from another file";
+           "commit" => commit.id().to_string(),
+           "from" => String::from_utf8_lossy(&from).into_owned(),
+    );
+
+    let mut paths = std::collections::HashSet::new();
+    paths.insert(from.clone());
+    let mut older = Vec::with_capacity(stack.len() - target - 1);
+    for (commit, _) in &stack[target + 1..] {
+        let diff = commit_diff(
+            repo,
+            backend,
+            commit,
+            diff_settings,
+            |path| paths.contains(path),
+            interner,
+        )?;
+        follow_renames(&mut paths, &diff);
+        older.push((commit, diff));
+    }
+    let older_diffs: Vec<_> = older
+        .iter()
+        .map(|(commit, diff)| StackDiff {
+            id: commit.id(),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+            diff,
+        })
+        .collect();
+    let origin = find_target(
+        &older_diffs,
+        &from,
+        &moved,
+        false,
+        intra_line,
+        None,
+        Some(logger),
+    )
+    .map(|idx| older[idx].0);
+    Ok(Some((from, origin)))
+}
+
+fn commit_diff(
+    repo: &git2::Repository,
+    backend: &dyn Backend,
+    commit: &git2::Commit,
+    settings: DiffSettings,
+    keep: impl Fn(&[u8]) -> bool,
+    interner: &mut owned::Interner,
+) -> Result<owned::Diff> {
+    let diff = backend.diff_commit(repo, commit, settings)?;
+    owned::Diff::filtered(&diff, keep, interner)
+}
+
+/// Returns whether the commit touched one of the paths, or a file in
+/// one of the directories.
+fn touches_paths(
+    repo: &git2::Repository,
+    commit: &git2::Commit,
+    paths: &std::collections::HashSet<Vec<u8>>,
+    dirs: &std::collections::HashSet<&[u8]>,
+) -> Result<bool> {
+    // the top of the repository has every file in it
+    if dirs.contains(&&[][..]) {
+        return Ok(true);
+    }
+    let mut options = git2::DiffOptions::new();
+    for path in paths {
+        options.pathspec(path.as_slice());
+    }
+    for dir in dirs {
+        options.pathspec(*dir);
+    }
+    let parent = match commit.parent_count() {
+        0 => None,
+        _ => Some(commit.parent(0)?.tree()?),
+    };
+    let diff =
+        repo.diff_tree_to_tree(parent.as_ref(), Some(&commit.tree()?), Some(&mut options))?;
+    Ok(diff.deltas().len() > 0)
+}
+
+fn parent_dir(path: &[u8]) -> &[u8] {
+    match path.iter().rposition(|&x| x == b'/') {
+        Some(slash) => &path[..slash],
+        None => &[],
+    }
+}
+
+/// Adds the paths that the files in `paths` were renamed from in the
+/// diff, since older commits touched those files under those paths.
+/// Returns whether there were any.
+fn follow_renames(paths: &mut std::collections::HashSet<Vec<u8>>, diff: &owned::Diff) -> bool {
+    let mut widened = false;
+    for patch in diff.iter() {
+        if patch.old_path != patch.new_path && paths.contains(&patch.new_path) {
+            widened |= paths.insert(patch.old_path.clone());
+        }
+    }
+    widened
+}
+
+/// Describes `default_diff_options()`, along with the rename detection
+/// of the stack's diffs (which git does a little differently than
+/// libgit2) and the paths and directories they were limited to, so that
+/// cached diffs are only reused if they were made the same way.
+fn stack_diff_options(
+    backend: &dyn Backend,
+    algorithm: DiffAlgorithm,
+    context: u32,
+    paths: &std::collections::HashSet<Vec<u8>>,
+    dirs: &std::collections::HashSet<&[u8]>,
+) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut paths: Vec<_> = paths.iter().map(Vec::as_slice).collect();
+    paths.sort();
+    let mut dirs: Vec<_> = dirs.iter().collect();
+    dirs.sort();
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    (paths, dirs).hash(&mut hasher);
+    format!(
+        "context-lines=0,typechange,renames,backend={},algorithm={},context={},paths={:016x}",
+        backend.name(),
+        algorithm.as_str(),
+        context,
+        hasher.finish()
+    )
+}
+
+/// Returns the options that staged changes and stack commits are
+/// diffed with.
+///
+/// Hunks never include context lines, since those would keep hunks
+/// from commuting with commits that only changed the lines around
+/// them, but with `context` lines of context, changes are grouped into
+/// hunks the way `git diff -U<context>` groups them.
+fn default_diff_options(algorithm: DiffAlgorithm, context: u32) -> git2::DiffOptions {
+    let mut ret = git2::DiffOptions::new();
+    ret.context_lines(0)
+        // hunks of `git diff` merge when their context would overlap
+        .interhunk_lines(context.saturating_mul(2))
+        .id_abbrev(40)
+        // report a file that becomes a symlink (or vice versa) as one
+        // change, rather than as a deletion and a creation of the same
+        // path
+        .include_typechange(true);
+    match algorithm {
+        DiffAlgorithm::Myers => {}
+        DiffAlgorithm::Minimal => {
+            ret.minimal(true);
+        }
+        DiffAlgorithm::Patience | DiffAlgorithm::Histogram => {
+            ret.patience(true);
+        }
+    }
+    ret
+}
+
+/// Returns the message of a fixup commit of the given kind for the
+/// target, optionally letting the user edit the part of a squash! or
+/// amend! message that ends up in the target's message.
+///
+/// The subject is either the prefix followed by `locator`, or `format`
+/// with its placeholders filled in.
+fn fixup_message(
+    repo: &git2::Repository,
+    fixup_kind: FixupKind,
+    edit: bool,
+    format: Option<&str>,
+    target: &git2::Commit,
+    locator: &str,
+    path: &[u8],
+) -> Result<String> {
+    let subject = match format {
+        Some(format) => expand_placeholders(format, |placeholder| match placeholder {
+            "prefix" => Some(fixup_kind.prefix().trim_end().to_owned()),
+            "subject" => Some(target.summary().unwrap_or_default().to_owned()),
+            "sha" => Some(target.id().to_string()),
+            "path" => Some(String::from_utf8_lossy(path).into_owned()),
+            _ => None,
+        }),
+        None => format!("{}{}", fixup_kind.prefix(), locator),
+    };
+    let body = match fixup_kind {
+        FixupKind::Fixup => return Ok(format!("{}\n", subject)),
+        FixupKind::Squash => String::new(),
+        // the commit replaces the target's message, which is kept as is
+        // unless it's edited
+        FixupKind::Amend => target.message().unwrap_or_default().trim().to_owned(),
+    };
+    let body = if edit {
+        editor::edit(
+            repo,
+            &format!(
+                "{}\n\n# Write the message for \"{}\". For squash!, it is appended to\n\
+                 # the message of the commit, for amend! it replaces the message.\n\
+                 # Lines starting with '#' will be ignored.\n",
+                body, subject
+            ),
+        )?
+    } else {
+        body
+    };
+    Ok(if body.is_empty() {
+        format!("{}\n", subject)
+    } else {
+        format!("{}\n\n{}\n", subject, body)
+    })
+}
+
+/// Returns the time to create commits at, if it is fixed by `--date`
+/// or `SOURCE_DATE_EPOCH` rather than being the current time.
+fn commit_time(date: Option<&str>) -> Result<Option<git2::Time>> {
+    if let Some(date) = date {
+        return Ok(Some(parse_date(date)?));
+    }
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) if !epoch.is_empty() => {
+            Ok(Some(parse_date(&epoch).map_err(|e| {
+                anyhow!("{}", trf("invalid SOURCE_DATE_EPOCH: {}", &[&e]))
+            })?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the user's identity, at `when` or else the current time.
+fn user_signature(
+    repo: &git2::Repository,
+    when: Option<git2::Time>,
+) -> Result<git2::Signature<'static>> {
+    let signature = repo
+        .signature()
+        .or_else(|_| git2::Signature::now("nobody", "nobody@example.com"))?;
+    Ok(match when {
+        Some(when) => git2::Signature::new(
+            &String::from_utf8_lossy(signature.name_bytes()),
+            &String::from_utf8_lossy(signature.email_bytes()),
+            &when,
+        )?,
+        None => signature.to_owned(),
+    })
+}
+
+/// Returns the author and committer of a fixup of `target`, according
+/// to the configured strategies. `signature` is the user's identity.
+fn fixup_identities(
+    signature: &git2::Signature,
+    target: &git2::Commit,
+    author_strategy: AuthorStrategy,
+    date_strategy: DateStrategy,
+) -> Result<(git2::Signature<'static>, git2::Signature<'static>)> {
+    let name = String::from_utf8_lossy(signature.name_bytes());
+    let email = String::from_utf8_lossy(signature.email_bytes());
+    let author = match author_strategy {
+        AuthorStrategy::Me => signature.to_owned(),
+        AuthorStrategy::Target => target.author().to_owned(),
+        AuthorStrategy::Env => {
+            let var = |name| std::env::var(name).ok().filter(|value| !value.is_empty());
+            let when = match var("GIT_AUTHOR_DATE") {
+                Some(date) => parse_date(&date)?,
+                None => signature.when(),
+            };
+            git2::Signature::new(
+                &var("GIT_AUTHOR_NAME").unwrap_or_else(|| name.to_string()),
+                &var("GIT_AUTHOR_EMAIL").unwrap_or_else(|| email.to_string()),
+                &when,
+            )?
+        }
+    };
+    let committer = match date_strategy {
+        DateStrategy::Now => signature.to_owned(),
+        DateStrategy::Target => git2::Signature::new(&name, &email, &target.committer().when())?,
+    };
+    Ok((author, committer))
+}
+
+/// Parses a date in git's internal format, `<seconds> <+hhmm>`, with
+/// an optional `@` before the seconds and an optional offset.
+fn parse_date(date: &str) -> Result<git2::Time> {
+    let invalid = || {
+        anyhow!(
+            "{}",
+            trf(
+                "unsupported date {}, expected \"<seconds> <+hhmm>\"",
+                &[&format!("{:?}", date)]
+            )
+        )
+    };
+    let mut parts = date.split_whitespace();
+    let seconds = parts
+        .next()
+        .map(|seconds| seconds.trim_start_matches('@'))
+        .and_then(|seconds| seconds.parse::<i64>().ok())
+        .ok_or_else(invalid)?;
+    let offset = match parts.next() {
+        Some(offset) => {
+            let (sign, digits) = match offset.as_bytes().first() {
+                Some(b'+') => (1, &offset[1..]),
+                Some(b'-') => (-1, &offset[1..]),
+                _ => return Err(invalid()),
+            };
+            if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(invalid());
+            }
+            // unwrap() is safe here, as the digits were checked above
+            let hours: i32 = digits[..2].parse().unwrap();
+            let minutes: i32 = digits[2..].parse().unwrap();
+            sign * (hours * 60 + minutes)
+        }
+        None => 0,
+    };
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(git2::Time::new(seconds, offset))
+}
+
+/// Formats the identity of a signature as "Name <email>".
+fn identity(signature: &git2::Signature) -> String {
+    format!(
+        "{} <{}>",
+        String::from_utf8_lossy(signature.name_bytes()),
+        String::from_utf8_lossy(signature.email_bytes())
+    )
+}
+
+/// Returns the trailers in the last paragraph of the message whose
+/// token is one of `tokens`, ignoring case.
+fn copied_trailers(message: &str, tokens: &[String]) -> Vec<String> {
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    let message = message.trim_end();
+    // the subject alone is never a trailer
+    if !message.contains("\n\n") {
+        return Vec::new();
+    }
+    message
+        .rsplit("\n\n")
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| {
+            line.split_once(':').is_some_and(|(token, _)| {
+                tokens
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(token.trim()))
+            })
+        })
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Appends the trailers (eg: "Signed-off-by: Name <email>") that the
+/// message doesn't have yet. They are added to the message's last
+/// paragraph if that already consists of trailers, otherwise they form a
+/// new paragraph.
+fn add_trailers(message: String, trailers: &[String]) -> String {
+    let message = message.trim_end();
+    let trailers: Vec<_> = trailers
+        .iter()
+        .filter(|trailer| !message.lines().any(|line| line == trailer.as_str()))
+        .collect();
+    if trailers.is_empty() {
+        return format!("{}\n", message);
+    }
+
+    let is_trailer = |line: &str| {
+        line.split_once(": ").is_some_and(|(token, _)| {
+            !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+    };
+    let last_paragraph = message.rsplit("\n\n").next().unwrap_or_default();
+    let has_body = message.contains("\n\n");
+    let mut ret = message.to_owned();
+    ret.push_str(if has_body && last_paragraph.lines().all(is_trailer) {
+        "\n"
+    } else {
+        "\n\n"
+    });
+    for trailer in trailers {
+        ret.push_str(trailer);
+        ret.push('\n');
+    }
+    ret
+}
+
+/// Replaces each `{name}` in `format` by the value of the placeholder.
+/// Unknown placeholders are left as they are.
+fn expand_placeholders<F>(format: &str, value: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut ret = String::with_capacity(format.len());
+    let mut rest = format;
+    while let Some(open) = rest.find('{') {
+        ret.push_str(&rest[..open]);
+        rest = &rest[open..];
+        let expanded = rest
+            .find('}')
+            .and_then(|close| value(&rest[1..close]).map(|value| (value, close)));
+        match expanded {
+            Some((value, close)) => {
+                ret.push_str(&value);
+                rest = &rest[close + 1..];
+            }
+            None => {
+                ret.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+/// Replaces the source commit of `--from` with the fixups that were
+/// committed on top of its parent, followed by whatever is left of the
+/// source commit.
+#[allow(clippy::too_many_arguments)]
+fn rewrite_source(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    source: &git2::Commit,
+    fixups_tip: &git2::Commit,
+    signature: &git2::Signature,
+    signer: Option<&sign::Signer>,
+    logger: &slog::Logger,
+) -> Result<()> {
+    let new_head = if fixups_tip.tree_id() == source.tree_id() {
+        info!(logger, "{}", tr("absorbed all of the source commit, dropping it");
+              "commit" => source.id().to_string(),
+        );
+        fixups_tip.id()
+    } else {
+        // the fixups only contain changes from the source commit, so
+        // the source commit's tree is exactly the fixups plus whatever
+        // could not be absorbed
+        let remainder = sign::commit(
+            repo,
+            backend,
+            signer,
+            &source.author(),
+            signature,
+            source.message().unwrap_or(""),
+            &source.tree()?,
+            &[fixups_tip],
+        )?;
+        info!(logger, "{}", tr("rewrote the source commit with the remaining changes");
+              "commit" => remainder.to_string(),
+        );
+        remainder
+    };
+    repo.head()?.set_target(
+        new_head,
+        &config::reflog_message(settings, "redistribute commit into the stack"),
+    )?;
+    Ok(())
+}
+
+/// Returns the position of the stash entry in the stash list.
+fn stash_position(repo: &git2::Repository, stash: git2::Oid) -> Result<Option<usize>> {
+    Ok(repo
+        .reflog("refs/stash")?
+        .iter()
+        .position(|entry| entry.id_new() == stash))
+}
+
+/// Checks out the fixups of a stash (which was made on top of HEAD,
+/// with nothing else changed since), and replaces the stash entry with
+/// what couldn't be absorbed.
+fn restash(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    stash: &git2::Commit,
+    fixups_tip: &git2::Commit,
+    signature: &git2::Signature,
+    logger: &slog::Logger,
+) -> Result<()> {
+    let position = stash_position(repo, stash.id())?
+        .ok_or_else(|| anyhow!("{}", tr("the stash entry was dropped while absorbing it")))?;
+    let reflog_message = repo
+        .reflog("refs/stash")?
+        .get(position)
+        .and_then(|entry| entry.message().map(str::to_owned))
+        .unwrap_or_else(|| stash.summary().unwrap_or_default().to_owned());
+
+    let base = stash.parent(0)?;
+    // nothing was changed in the working tree or the index (see above)
+    backend.checkout_tree(repo, &base.tree()?, &fixups_tip.tree()?)?;
+    repo.head()?.set_target(
+        fixups_tip.id(),
+        &config::reflog_message(settings, "absorb stash into the stack"),
+    )?;
+
+    // the fixups only contain changes from the working tree of the
+    // stash, like in rewrite_source(), and the index of the stash is
+    // moved onto them like `git stash apply --index` would (unless
+    // that conflicts, in which case it's only kept in the working tree)
+    let index_commit = stash.parent(1)?;
+    let mut index = repo.merge_trees(
+        &base.tree()?,
+        &fixups_tip.tree()?,
+        &index_commit.tree()?,
+        None,
+    )?;
+    let index_tree = if index.has_conflicts() {
+        warn!(
+            logger,
+            "{}",
+            tr("could not keep the staged changes of the stash apart")
+        );
+        fixups_tip.tree()?
+    } else {
+        repo.find_tree(index.write_tree_to(repo)?)?
+    };
+    let untracked = stash.parents().nth(2);
+
+    git_cli::stash_drop(repo, position)?;
+    if stash.tree_id() == fixups_tip.tree_id()
+        && index_tree.id() == fixups_tip.tree_id()
+        && untracked.is_none()
+    {
+        info!(logger, "{}", tr("absorbed all of the stash, dropping it");
+              "commit" => stash.id().to_string(),
+        );
+        return Ok(());
+    }
+    let index_commit = repo.find_commit(repo.commit(
+        None,
+        &index_commit.author(),
+        signature,
+        index_commit.message().unwrap_or(""),
+        &index_tree,
+        &[fixups_tip],
+    )?)?;
+    let mut parents = vec![fixups_tip.clone(), index_commit];
+    parents.extend(untracked);
+    let remainder = repo.commit(
+        None,
+        &stash.author(),
+        signature,
+        stash.message().unwrap_or(""),
+        &stash.tree()?,
+        &parents.iter().collect::<Vec<_>>(),
+    )?;
+    git_cli::stash_store(repo, remainder, &reflog_message)?;
+    info!(logger, "{}", tr("stashed the remaining changes");
+          "commit" => remainder.to_string(),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn empty_slog() -> slog::Logger {
+        slog::Logger::root(slog::Discard, o!())
+    }
+
+    /// Records the messages that are logged to it.
+    #[derive(Clone, Default)]
+    struct Recorder(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl slog::Drain for Recorder {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    fn default_config(logger: &slog::Logger) -> Config<'_> {
+        Config {
+            dry_run: false,
+            force: false,
+            force_pushed: false,
+            force_state: false,
+            include_authors: Vec::new(),
+            base: Vec::new(),
+            from: None,
+            stash: None,
+            patch_file: None,
+            target: None,
+            and_rebase: false,
+            paranoid: false,
+            whole_file: false,
+            oldest: false,
+            blame_fallback: false,
+            intra_line: false,
+            detect_moves: false,
+            new_files: false,
+            diff_algorithm: None,
+            unified: None,
+            interactive: false,
+            patch: false,
+            all: false,
+            explain: false,
+            verbose: false,
+            output_format: OutputFormat::Text,
+            path_format: PathFormat::Quoted,
+            fixup_kind: None,
+            edit_message: false,
+            fixup_by_sha: false,
+            signoff: false,
+            gpg_sign: false,
+            signing_key: None,
+            run_hooks: false,
+            date: None,
+            rebase_options: Vec::new(),
+            jobs: None,
+            skip_bulk: None,
+            leftovers: Leftovers::Stage,
+            action: Action::Absorb,
+            pathspecs: Vec::new(),
+            profile: None,
+            logger,
+        }
+    }
+
+    fn init_repo() -> (tempfile::TempDir, git2::Repository) {
+        // the repo will be deleted when the tempdir gets dropped
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = git2::Repository::init(&dir).unwrap();
+
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "nobody").unwrap();
+        config.set_str("user.email", "nobody@example.com").unwrap();
+
+        (dir, repo)
+    }
+
+    /// Writes `content` to `path` in the working tree and stages it.
+    fn stage_file(repo: &git2::Repository, path: &str, content: &str) {
+        std::fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(path)).unwrap();
+        index.write().unwrap();
+    }
+
+    /// Commits the current index on top of HEAD.
+    fn commit_index<'repo>(repo: &'repo git2::Repository, message: &str) -> git2::Commit<'repo> {
+        let sig = repo.signature().unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repo.find_commit(
+            repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    /// Returns the messages of the commits reachable from HEAD, newest
+    /// first.
+    fn log(repo: &git2::Repository) -> Vec<String> {
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        revwalk
+            .map(|oid| {
+                repo.find_commit(oid.unwrap())
+                    .unwrap()
+                    .message()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect()
+    }
+
+    /// Returns the ids of the commits reachable from HEAD, newest
+    /// first.
+    fn log_ids(repo: &git2::Repository) -> Vec<String> {
+        let mut revwalk = repo.revwalk().unwrap();
+        revwalk.push_head().unwrap();
+        revwalk.map(|oid| oid.unwrap().to_string()).collect()
+    }
+
+    fn nothing_left_in_index(repo: &git2::Repository) -> bool {
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let diff = repo
+            .diff_tree_to_index(Some(&head_tree), None, None)
+            .unwrap();
+        diff.deltas().len() == 0
+    }
+
+    /// Sets up a repo with two commits, each of which adds a line to
+    /// the same file, and stages an edit to each of those lines.
+    fn prepare_two_line_repo() -> (tempfile::TempDir, git2::Repository) {
+        let (dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file", "a\n\n\n\nb\n");
+        commit_index(&repo, "second");
+        stage_file(&repo, "file", "a2\n\n\n\nb2\n");
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_absorbs_each_hunk_into_its_commit() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_commutes_files_in_parallel() {
+        let (_dir, repo) = init_repo();
+        for idx in 0..8 {
+            stage_file(&repo, &format!("file{}", idx), "a\n\n\n\n");
+        }
+        commit_index(&repo, "first");
+        for idx in 0..8 {
+            stage_file(&repo, &format!("file{}", idx), "a\n\n\n\nb\n");
+        }
+        commit_index(&repo, "second");
+        for idx in 0..8 {
+            stage_file(&repo, &format!("file{}", idx), "a2\n\n\n\nb2\n");
+        }
+
+        let logger = empty_slog();
+        let config = Config {
+            jobs: Some(4),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        // the fixups of the first commit are committed first
+        let mut expected = ["fixup! second\n"].repeat(8);
+        expected.extend(["fixup! first\n"].repeat(8));
+        expected.extend(["second", "first"]);
+        assert_eq!(log(&repo), expected);
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_caches_stack_diffs() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let paths = vec![b"file".to_vec()].into_iter().collect();
+        let options = stack_diff_options(
+            &backend::Libgit2,
+            DiffAlgorithm::Myers,
+            0,
+            &paths,
+            &Default::default(),
+        );
+        let mut interner = owned::Interner::default();
+        let cached = cache::load(&repo, head.id(), &options, &mut interner)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].hunks.len(), 1);
+        assert!(cache::load(&repo, head.id(), "other", &mut interner)
+            .unwrap()
+            .is_none());
+
+        // the cached diffs give the same result, and the cache is
+        // pruned down to the stack once it changes
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        let cached = std::fs::read_dir(repo.path().join("absorb-cache"))
+            .unwrap()
+            .count();
+        assert_eq!(cached, 2);
+    }
+
+    #[test]
+    fn test_skips_commits_by_bloom_filter() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "other", "a\n");
+        commit_index(&repo, "second");
+        let status = std::process::Command::new("git")
+            .args(["commit-graph", "write", "--reachable", "--changed-paths"])
+            .current_dir(repo.workdir().unwrap())
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let graph = commit_graph::CommitGraph::open(&repo).unwrap().unwrap();
+        assert!(graph.has_bloom_filters());
+        let first = repo.revparse_single("HEAD~").unwrap().id();
+        let second = repo.revparse_single("HEAD").unwrap().id();
+        assert_eq!(graph.changed(first, b"file"), Some(true));
+        assert_eq!(graph.changed(second, b"file"), Some(false));
+        assert_eq!(graph.changed(second, b"other"), Some(true));
+
+        stage_file(&repo, "file", "b\n");
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! first\n", "second", "first"]);
+    }
+
+    #[test]
+    fn test_only_parses_staged_paths_in_stack() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n");
+        stage_file(&repo, "other", "a\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file", "b\n");
+        stage_file(&repo, "other", "b\n");
+        commit_index(&repo, "second");
+        stage_file(&repo, "file", "c\n");
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        let second = repo.revparse_single("HEAD~").unwrap().id();
+        let paths = vec![b"file".to_vec()].into_iter().collect();
+        let options = stack_diff_options(
+            &backend::Libgit2,
+            DiffAlgorithm::Myers,
+            0,
+            &paths,
+            &Default::default(),
+        );
+        let cached = cache::load(&repo, second, &options, &mut Default::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            cached
+                .iter()
+                .map(|patch| patch.new_path.as_slice())
+                .collect::<Vec<_>>(),
+            vec![b"file"]
+        );
+    }
+
+    #[test]
+    fn test_refuses_protected_branch() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str("absorb.protectedBranches", "main, mast*")
+            .unwrap();
+        let logger = empty_slog();
+
+        let err = run_with_repo(&default_config(&logger), &repo).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Error>(),
+            Some(Error::Protected { pattern, .. }) if pattern == "mast*"
+        ));
+        assert_eq!(exit_code(&err), 4);
+        assert!(
+            err.to_string().contains("(matching \"mast*\" in"),
+            "{}",
+            err
+        );
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        let config = Config {
+            force: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_dry_run_makes_no_commits() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        assert!(!nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_dry_run_outcome() {
+        let logger = empty_slog();
+        let config = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+
+        let (_dir, repo) = prepare_two_line_repo();
+        assert_eq!(run_with_repo(&config, &repo).unwrap(), Outcome::Absorbed);
+
+        stage_file(&repo, "new", "line\n");
+        assert_eq!(
+            run_with_repo(&config, &repo).unwrap(),
+            Outcome::PartiallyAbsorbed
+        );
+
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "first");
+        assert_eq!(
+            run_with_repo(&config, &repo).unwrap(),
+            Outcome::NothingStaged
+        );
+    }
+
+    #[test]
+    fn test_outcome_exit_codes() {
+        let outcomes = [
+            Outcome::Absorbed,
+            Outcome::PartiallyAbsorbed,
+            Outcome::NothingAbsorbed,
+            Outcome::NothingStaged,
+        ];
+        let codes = |dry_run, strict| -> Vec<_> {
+            outcomes
+                .iter()
+                .map(|outcome| outcome.exit_code(dry_run, strict))
+                .collect()
+        };
+        // a real run succeeds, unless it is strict
+        assert_eq!(codes(false, false), [0, 0, 0, 0]);
+        assert_eq!(codes(false, true), [0, 7, 2, 3]);
+        // a dry run tells whether some hunks have no target
+        assert_eq!(codes(true, false), [0, 2, 2, 3]);
+        assert_eq!(codes(true, true), [0, 7, 2, 3]);
+    }
+
+    #[test]
+    fn test_watch_runs_only_when_the_index_changes() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = default_config(&logger);
+        let index_path = repo.path().join("index");
+
+        // the first check always runs
+        let mut last = None;
+        assert!(watch_once(&config, &repo, &index_path, &mut last).unwrap());
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert_eq!(last, Some(IndexStamp::of(&index_path)));
+
+        // nothing was staged since
+        assert!(!watch_once(&config, &repo, &index_path, &mut last).unwrap());
+        assert_eq!(log(&repo).len(), 4);
+
+        // staging again runs again, which amends the fixup of first
+        let head = repo.head().unwrap().target();
+        stage_file(&repo, "file", "a3\n\n\n\nb2\n");
+        assert!(watch_once(&config, &repo, &index_path, &mut last).unwrap());
+        assert_ne!(repo.head().unwrap().target(), head);
+        assert_eq!(log(&repo).len(), 4);
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_check_finds_absorbable_changes() {
+        let logger = empty_slog();
+        let config = Config {
+            action: Action::Check,
+            ..default_config(&logger)
+        };
+
+        let (_dir, repo) = prepare_two_line_repo();
+        let err = run_with_repo(&config, &repo).unwrap_err();
+        assert_eq!(err.downcast_ref::<Error>(), Some(&Error::Absorbable(2)));
+        assert_eq!(exit_code(&err), 5);
+        assert_eq!(log(&repo), vec!["second", "first"]);
+
+        // unstaged changes count too
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "first");
+        assert!(run_with_repo(&config, &repo).is_ok());
+        std::fs::write(repo.workdir().unwrap().join("file"), "changed\n").unwrap();
+        assert!(run_with_repo(&config, &repo).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_with_fsmonitor() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let logger = empty_slog();
+        let config = Config {
+            action: Action::Check,
+            ..default_config(&logger)
+        };
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "first");
+        // a hook that fails makes git check every file anyway
+        let hook = repo.path().join("fsmonitor");
+        std::fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        repo.config()
+            .unwrap()
+            .set_str(config::FSMONITOR_CONFIG_NAME, hook.to_str().unwrap())
+            .unwrap();
+        assert!(config::fsmonitor(&repo));
+        assert!(run_with_repo(&config, &repo).is_ok());
+        std::fs::write(repo.workdir().unwrap().join("file"), "changed\n").unwrap();
+        assert!(run_with_repo(&config, &repo).is_err());
+    }
+
+    #[test]
+    fn test_summary_lines() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let second = repo.head().unwrap().peel_to_commit().unwrap();
+        let first = second.parent(0).unwrap();
+        let hunk = |start| {
+            let block = |line: &[u8]| owned::Block {
+                start,
+                lines: std::sync::Arc::new(vec![owned::Line::from(line)]),
+                trailing_newline: true,
+            };
+            owned::Hunk {
+                added: block(b"x\n"),
+                removed: block(b"y\n"),
+            }
+        };
+        let mut plan = plan::Plan::default();
+        for (start, target) in [(1, Some(&first)), (5, Some(&second)), (7, None)] {
+            plan.assignments.push(plan::HunkAssignment {
+                path: b"file".to_vec(),
+                hunk: hunk(start),
+                target: target.cloned(),
+                skip_reason: target.map_or(Some(plan::SkipReason::NoTarget), |_| None),
+                file_change: None,
+            });
+        }
+
+        let lines = output::summary_lines(&plan);
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines[0].ends_with(" first  | 1 file, 1 hunk, +1 -1"),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1].ends_with(" second | 1 file, 1 hunk, +1 -1"),
+            "{}",
+            lines[1]
+        );
+        assert_eq!(
+            lines[2],
+            "2 hunks absorbed into 2 commits, 1 hunk and 0 files left in the index"
+        );
+    }
+
+    #[test]
+    fn test_porcelain_lines() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let second = repo.head().unwrap().peel_to_commit().unwrap();
+        let block = |start, line: &[u8]| owned::Block {
+            start,
+            lines: std::sync::Arc::new(vec![owned::Line::from(line)]),
+            trailing_newline: true,
+        };
+        let mut plan = plan::Plan::default();
+        plan.assignments.push(plan::HunkAssignment {
+            path: b"file".to_vec(),
+            hunk: owned::Hunk {
+                added: block(5, b"b2\n"),
+                removed: block(5, b"b\n"),
+            },
+            target: Some(second.clone()),
+            skip_reason: None,
+            file_change: None,
+        });
+        plan.assignments.push(plan::HunkAssignment {
+            path: b"file".to_vec(),
+            hunk: owned::Hunk {
+                added: block(1, b"a2\n"),
+                removed: block(1, b"a\n"),
+            },
+            target: None,
+            skip_reason: Some(plan::SkipReason::Excluded),
+            file_change: None,
+        });
+        plan.skipped_paths.push(plan::SkippedPath {
+            path: b"image.png".to_vec(),
+            reason: plan::SkipReason::Binary,
+        });
+
+        assert_eq!(
+            output::porcelain_lines(
+                &plan,
+                &[],
+                output::PathStyle::new(&repo, PathFormat::Quoted)
+            ),
+            vec![
+                "version\t1".to_owned(),
+                format!("fixup\tfile\t-5,1 +5,1\t{}\t-", second.id()),
+                "skip\tfile\t-1,1 +1,1\texcluded".to_owned(),
+                "skip\timage.png\t-\tbinary".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_file_round_trip() {
+        let (dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let plan_path = dir.path().join("plan");
+        let plan_path = plan_path.to_str().unwrap();
+
+        let config = Config {
+            action: Action::WritePlan(Some(plan_path)),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["second", "first"]);
+
+        // leave the hunk that would have gone into the first commit in
+        // the index
+        let plan = std::fs::read_to_string(plan_path).unwrap();
+        let short_first = &log_ids(&repo)[1][..7];
+        let plan = plan.replace(&format!("fixup {} ", short_first), "skip ");
+        std::fs::write(plan_path, plan).unwrap();
+
+        let config = Config {
+            action: Action::ApplyPlan(plan_path),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        assert!(!nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_plan_file_rejects_stale_index() {
+        let (dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let plan_path = dir.path().join("plan");
+        let plan_path = plan_path.to_str().unwrap();
+
+        let config = Config {
+            action: Action::WritePlan(Some(plan_path)),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        stage_file(&repo, "file", "a3\n\n\n\nb3\n");
+
+        let config = Config {
+            action: Action::ApplyPlan(plan_path),
+            ..default_config(&logger)
+        };
+        assert!(run_with_repo(&config, &repo).is_err());
+        assert_eq!(log(&repo), vec!["second", "first"]);
+    }
+
+    #[test]
+    fn test_blame_fallback_finds_nearby_commit() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+        let base = commit_index(&repo, "base");
+        stage_file(&repo, "file", "1\n2\n3\n4\nx5\n6\n7\n8\n9\n10\n");
+        commit_index(&repo, "edit");
+        // this commutes with the edit of line 5, so only blame can
+        // find a target for it
+        stage_file(&repo, "file", "1\n2\n3\n4\nx5\n6\n7\ny8\n9\n10\n");
+
+        let logger = empty_slog();
+        let base = base.id().to_string();
+        let config = Config {
+            base: vec![&base],
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["edit", "base"]);
+
+        let config = Config {
+            base: vec![&base],
+            blame_fallback: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! edit\n", "edit", "base"]);
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_ambiguity_strategies() {
+        let prepare = || {
+            let (dir, repo) = init_repo();
+            stage_file(&repo, "file", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+            let base = commit_index(&repo, "base").id().to_string();
+            stage_file(&repo, "file", "x1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+            commit_index(&repo, "first");
+            stage_file(&repo, "file", "x1\n2\n3\n4\n5\n6\n7\n8\n9\nx10\n");
+            commit_index(&repo, "second");
+            // commutes with both edits
+            stage_file(&repo, "file", "x1\n2\n3\n4\ny5\n6\n7\n8\n9\nx10\n");
+            (dir, repo, base)
+        };
+        let logger = empty_slog();
+
+        for (strategy, expected) in &[
+            ("skip", vec!["second", "first", "base"]),
+            (
+                "nearest",
+                vec!["fixup! second\n", "second", "first", "base"],
+            ),
+            ("oldest", vec!["fixup! first\n", "second", "first", "base"]),
+        ] {
+            let (_dir, repo, base) = prepare();
+            repo.config()
+                .unwrap()
+                .set_str("absorb.ambiguityStrategy", strategy)
+                .unwrap();
+            let config = Config {
+                base: vec![&base],
+                ..default_config(&logger)
+            };
+            run_with_repo(&config, &repo).unwrap();
+            assert_eq!(&log(&repo), expected, "{}", strategy);
+        }
+
+        let (_dir, repo, _base) = prepare();
+        repo.config()
+            .unwrap()
+            .set_str("absorb.ambiguityStrategy", "latest")
+            .unwrap();
+        assert!(run_with_repo(&default_config(&logger), &repo).is_err());
+    }
+
+    #[test]
+    fn test_repo_config_file_overrides_git_config() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 1)
+            .unwrap();
+        let path = repo.path().join(config_file::REPO_FILE_NAME);
+        std::fs::write(
+            &path,
+            "[absorb]\nmaxStack = 5\nprotectedBranches = [\"main\", \"release/*\"]\n",
+        )
+        .unwrap();
+        let settings = Layered::open(&repo).unwrap();
+        assert_eq!(config::max_stack(&settings), 5);
+        assert_eq!(
+            config::protected_branches(&settings),
+            vec!["main", "release/*"]
+        );
+        // the others still come from git
+        assert_eq!(config::diff_context(&settings).unwrap(), 0);
+
+        // an invalid file fails the run up front
+        std::fs::write(&path, "maxStack = [").unwrap();
+        assert!(Layered::open(&repo).is_err());
+        let logger = empty_slog();
+        let err = run_with_repo(&default_config(&logger), &repo).unwrap_err();
+        assert!(err.to_string().contains("invalid config file"), "{}", err);
+        assert_eq!(log(&repo).len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config::max_stack(&Layered::open(&repo).unwrap()), 1);
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            action: Action::ValidateConfig,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        let mut git_config = repo.config().unwrap();
+        git_config.set_str("absorb.maxStak", "5").unwrap();
+        git_config
+            .set_str(config::CACHE_CONFIG_NAME, "maybe")
+            .unwrap();
+        git_config
+            .set_str(config::FIXUP_MESSAGE_FORMAT_CONFIG_NAME, "{prefix} {sujet}")
+            .unwrap();
+        std::fs::write(
+            repo.path().join(config_file::REPO_FILE_NAME),
+            "maxStack = 0\n",
+        )
+        .unwrap();
+        let problems = config::validate(&Layered::open(&repo).unwrap()).unwrap();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].contains("did you mean absorb.maxStack?"));
+        let err = run_with_repo(&config, &repo).unwrap_err();
+        assert_eq!(exit_code(&err), 6);
+    }
+
+    #[test]
+    fn test_doctor_reports_the_end_of_the_stack() {
+        let (_dir, repo) = init_repo();
+        for i in 0..12 {
+            stage_file(&repo, "file", &format!("{}\n", i));
+            commit_index(&repo, "commit");
+        }
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 1)
+            .unwrap();
+        let logger = empty_slog();
+        let mut report = Vec::new();
+        doctor::report(&repo, &default_config(&logger), &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+
+        assert!(report.contains("state: clean\n"), "{}", report);
+        assert!(report.contains("identity: nobody <nobody@example.com>\n"));
+        assert!(report.contains(
+            "stack: 10 commits, of at most 10, and at most 1 touching the staged files (absorb.maxStack)\n"
+        ));
+        assert!(report.contains("it ends at the limit, before"));
+        assert!(report.contains("signing: off\n"));
+    }
+
+    #[test]
+    fn test_target_command_picks_targets() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let second = repo.head().unwrap().peel_to_commit().unwrap().id();
+        // both hunks commute with the newest commit
+        repo.config()
+            .unwrap()
+            .set_str(
+                config::TARGET_COMMAND_CONFIG_NAME,
+                &format!("cat > .git/request.json; echo {}", &second.to_string()[..7]),
+            )
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! second\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+        // the last request was for the hunk of the newest commit
+        let request: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(repo.path().join("request.json")).unwrap())
+                .unwrap();
+        assert_eq!(request["path"], "file");
+        assert_eq!(request["added"], serde_json::json!(["b2\n"]));
+        assert_eq!(request["target"], second.to_string());
+        assert_eq!(request["candidates"][0]["summary"], "second");
+        assert_eq!(request["candidates"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_target_command_skips_and_rejects_hunks() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        repo.config()
+            .unwrap()
+            .set_str(config::TARGET_COMMAND_CONFIG_NAME, "echo skip")
+            .unwrap();
+        assert_eq!(
+            run_with_repo(&default_config(&logger), &repo).unwrap(),
+            Outcome::NothingAbsorbed
+        );
+        assert_eq!(log(&repo), vec!["second", "first"]);
+
+        // the first commit doesn't commute with the hunk of the second
+        let first = repo.revparse_single("HEAD~").unwrap().id();
+        repo.config()
+            .unwrap()
+            .set_str(
+                config::TARGET_COMMAND_CONFIG_NAME,
+                &format!("echo {}", first),
+            )
+            .unwrap();
+        assert!(run_with_repo(&default_config(&logger), &repo).is_err());
+        assert_eq!(log(&repo), vec!["second", "first"]);
+    }
+
+    #[test]
+    fn test_from_redistributes_commit() {
+        let (_dir, repo) = prepare_two_line_repo();
+        commit_index(&repo, "fixes");
+        let logger = empty_slog();
+        let config = Config {
+            from: Some("HEAD"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_from_keeps_unabsorbed_changes() {
+        let (_dir, repo) = prepare_two_line_repo();
+        stage_file(&repo, "other", "new file\n");
+        commit_index(&repo, "fixes");
+        let fixes_tree = repo.head().unwrap().peel_to_tree().unwrap().id();
+        let logger = empty_slog();
+        let config = Config {
+            from: Some("HEAD"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec![
+                "fixes",
+                "fixup! second\n",
+                "fixup! first\n",
+                "second",
+                "first"
+            ]
+        );
+        assert_eq!(
+            repo.head().unwrap().peel_to_tree().unwrap().id(),
+            fixes_tree
+        );
+    }
+
+    fn stash(repo: &mut git2::Repository, message: &str) -> git2::Oid {
+        let signature = repo.signature().unwrap();
+        repo.stash_save(&signature, message, None).unwrap()
+    }
+
+    #[test]
+    fn test_stash_absorbs_and_restashes_remainder() {
+        let (_dir, mut repo) = prepare_two_line_repo();
+        stage_file(&repo, "other", "new file\n");
+        stash(&mut repo, "fixes");
+        let logger = empty_slog();
+        let config = Config {
+            stash: Some("stash@{0}"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        // the fixups are checked out
+        assert!(nothing_left_in_index(&repo));
+        assert_eq!(
+            std::fs::read(repo.workdir().unwrap().join("file")).unwrap(),
+            b"a2\n\n\n\nb2\n"
+        );
+        // and the new file is stashed on top of them
+        let reflog = repo.reflog("refs/stash").unwrap();
+        assert_eq!(reflog.len(), 1);
+        assert_eq!(reflog.get(0).unwrap().message(), Some("On master: fixes"));
+        let remainder = repo.find_commit(reflog.get(0).unwrap().id_new()).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(remainder.parent_id(0).unwrap(), head.id());
+        let diff = repo
+            .diff_tree_to_tree(
+                Some(&head.tree().unwrap()),
+                Some(&remainder.tree().unwrap()),
+                None,
+            )
+            .unwrap();
+        assert_eq!(diff.deltas().len(), 1);
+        assert_eq!(
+            diff.get_delta(0).unwrap().new_file().path(),
+            Some(Path::new("other"))
+        );
+    }
+
+    #[test]
+    fn test_stash_absorbed_entirely_is_dropped() {
+        let (_dir, mut repo) = prepare_two_line_repo();
+        stash(&mut repo, "older");
+        stage_file(&repo, "file", "a\n\n\n\nb2\n");
+        stash(&mut repo, "fixes");
+        let logger = empty_slog();
+        let config = Config {
+            stash: Some("0"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        let reflog = repo.reflog("refs/stash").unwrap();
+        assert_eq!(reflog.len(), 1);
+        assert_eq!(reflog.get(0).unwrap().message(), Some("On master: older"));
+        // the older stash was made on top of the old HEAD
+        let config = Config {
+            stash: Some("0"),
+            ..default_config(&logger)
+        };
+        assert!(run_with_repo(&config, &repo).is_err());
+    }
+
+    #[test]
+    fn test_stash_requires_clean_working_tree() {
+        let (_dir, mut repo) = prepare_two_line_repo();
+        stash(&mut repo, "fixes");
+        std::fs::write(repo.workdir().unwrap().join("file"), "a\n\n\n\nb3\n").unwrap();
+        let logger = empty_slog();
+        let config = Config {
+            stash: Some("stash@{0}"),
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        let config = Config {
+            stash: Some("stash@{0}"),
+            ..default_config(&logger)
+        };
+        assert!(run_with_repo(&config, &repo).is_err());
+        assert_eq!(log(&repo), vec!["second", "first"]);
+    }
+
+    const TWO_LINE_PATCH: &str = "\
+diff --git a/file b/file
+--- a/file
++++ b/file
+@@ -1,5 +1,5 @@
+-a
++a2
+ \n \n \n-b
++b2
+";
+
+    /// Undoes the changes of `prepare_two_line_repo()` to the index and
+    /// the working tree, and writes `patch` to a file.
+    fn prepare_patch_file(repo: &git2::Repository, patch: &str) -> String {
+        repo.checkout_head(Some(git2::build::CheckoutBuilder::new().force()))
+            .unwrap();
+        let path = repo.path().join("absorb.patch");
+        std::fs::write(&path, patch).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_patch_file_absorbs_hunks() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let patch = prepare_patch_file(&repo, TWO_LINE_PATCH);
+        let logger = empty_slog();
+        let config = Config {
+            patch_file: Some(&patch),
+            ..default_config(&logger)
+        };
+        assert_eq!(run_with_repo(&config, &repo).unwrap(), Outcome::Absorbed);
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        // the fixups are applied to the index and the working tree
+        assert!(nothing_left_in_index(&repo));
+        let workdir = repo.workdir().unwrap();
+        assert_eq!(
+            std::fs::read(workdir.join("file")).unwrap(),
+            b"a2\n\n\n\nb2\n"
+        );
+        assert!(!workdir.join("file.rej").exists());
+    }
+
+    #[test]
+    fn test_patch_file_rejects_hunks() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let patch = prepare_patch_file(
+            &repo,
+            &format!(
+                "{}{}",
+                TWO_LINE_PATCH,
+                "\
+diff --git a/missing b/missing
+--- a/missing
++++ b/missing
+@@ -1 +1 @@
+-x
++y
+diff --git a/other b/other
+new file mode 100644
+--- /dev/null
++++ b/other
+@@ -0,0 +1 @@
++new file
+"
+            ),
+        );
+        let logger = empty_slog();
+        let config = Config {
+            patch_file: Some(&patch),
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        assert_eq!(
+            run_with_repo(&config, &repo).unwrap(),
+            Outcome::PartiallyAbsorbed
+        );
+        let workdir = repo.workdir().unwrap();
+        assert!(!workdir.join("missing.rej").exists());
+
+        let config = Config {
+            patch_file: Some(&patch),
+            ..default_config(&logger)
+        };
+        assert_eq!(
+            run_with_repo(&config, &repo).unwrap(),
+            Outcome::PartiallyAbsorbed
+        );
+        assert_eq!(log(&repo).len(), 4);
+        // the hunk that doesn't apply, and the one that has no target
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("missing.rej")).unwrap(),
+            "diff a/missing b/missing\t(rejected hunks)\n@@ -1 +1 @@\n-x\n+y\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("other.rej")).unwrap(),
+            "diff a/other b/other\t(rejected hunks)\n@@ -0,0 +1 @@\n+new file\n"
+        );
+        assert!(!workdir.join("other").exists());
+    }
+
+    #[test]
+    fn test_reflog_describes_the_run() {
+        let reflog = |repo: &git2::Repository| -> Vec<String> {
+            let reflog = repo.reflog("HEAD").unwrap();
+            reflog
+                .iter()
+                .map(|entry| entry.message().unwrap_or_default().to_owned())
+                .collect()
+        };
+        let logger = empty_slog();
+
+        let (_dir, repo) = prepare_two_line_repo();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            reflog(&repo)[..2],
+            [
+                "absorb: created 2 fixups for 2 targets",
+                "absorb: commit fixup! first",
+            ]
+        );
+
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str(config::REFLOG_PREFIX_CONFIG_NAME, "tool")
+            .unwrap();
+        let config = Config {
+            and_rebase: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            reflog(&repo)[..2],
+            [
+                "tool: autosquash rebase",
+                "tool: created 2 fixups for 2 targets"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_journal_records_each_run() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        let logger = empty_slog();
+        let dry_run = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&dry_run, &repo).unwrap();
+        assert!(journal::read(&repo).unwrap().is_empty());
+
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        let fixups = repo.head().unwrap().peel_to_commit().unwrap().id();
+        let config = Config {
+            action: Action::Undo,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        let entries = journal::read(&repo).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].action, "absorb");
+        assert_eq!(entries[0].head, head.to_string());
+        assert_eq!(entries[0].fixups.len(), 2);
+        assert_eq!(entries[0].fixups[1].commit, fixups.to_string());
+        assert!(entries[0].skipped.is_empty());
+        let targets: Vec<_> = entries[0]
+            .fixups
+            .iter()
+            .map(|fixup| {
+                repo.find_commit(git2::Oid::from_str(&fixup.target).unwrap())
+                    .unwrap()
+            })
+            .map(|commit| commit.summary().unwrap().to_owned())
+            .collect();
+        assert_eq!(targets, ["first", "second"]);
+        assert_eq!(entries[1].action, "undo");
+        assert_eq!(entries[1].head, fixups.to_string());
+        assert_eq!(entries[1].restored, Some(head.to_string()));
+
+        let mut out = Vec::new();
+        journal::show(&repo, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].ends_with(&format!(
+            "undo: {} -> {}",
+            &fixups.to_string()[..7],
+            &head.to_string()[..7]
+        )));
+        assert!(lines[1].ends_with(&format!("absorb: 2 fixups on {}", &head.to_string()[..7])));
+        assert!(lines[2].ends_with(&format!(
+            "file -1,1 +1,1 -> {} \"first\"",
+            &entries[0].fixups[0].target[..7]
+        )));
+    }
+
+    #[test]
+    fn test_undo_restores_head_and_index() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        let index = repo.index().unwrap().write_tree().unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo).len(), 4);
+
+        let config = Config {
+            action: Action::Undo,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), head);
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), index);
+        assert!(repo.head().unwrap().is_branch());
+
+        // the undo state is consumed
+        assert!(run_with_repo(&config, &repo).is_err());
+    }
+
+    #[test]
+    fn test_and_rebase_stashes_unstaged_changes() {
+        let (_dir, repo) = prepare_two_line_repo();
+        // git rebase would otherwise wait for the todo list to be edited
+        repo.config()
+            .unwrap()
+            .set_str("sequence.editor", "true")
+            .unwrap();
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        stage_file(&repo, "other", "x\n");
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("other")).unwrap();
+        index.write().unwrap();
+        std::fs::write(workdir.join("file"), "a2\n\n\n\nb2\nc\n").unwrap();
+        let logger = empty_slog();
+        // options for git rebase make it run instead of the in-memory
+        // autosquash
+        let config = Config {
+            and_rebase: true,
+            rebase_options: vec!["--no-verify"],
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        assert!(nothing_left_in_index(&repo));
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("file")).unwrap(),
+            "a2\n\n\n\nb2\nc\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("other")).unwrap(),
+            "x\n"
+        );
+
+        // git rebase refuses to start with unstaged changes without it,
+        // which leaves the fixups and the changes as they were
+        repo.config()
+            .unwrap()
+            .set_bool("rebase.autoStash", false)
+            .unwrap();
+        stage_file(&repo, "file", "a3\n\n\n\nb2\n");
+        std::fs::write(workdir.join("file"), "a3\n\n\n\nb2\nc\n").unwrap();
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! first\n", "second", "first"]);
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("file")).unwrap(),
+            "a3\n\n\n\nb2\nc\n"
+        );
+    }
+
+    #[test]
+    fn test_undo_restores_the_working_tree() {
+        let (_dir, repo) = prepare_two_line_repo();
+        // git rebase would otherwise wait for the todo list to be edited
+        repo.config()
+            .unwrap()
+            .set_str("sequence.editor", "true")
+            .unwrap();
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        std::fs::write(workdir.join("file"), "a2\n\n\n\nb2\nc\n").unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        let index = repo.index().unwrap().write_tree().unwrap();
+        let logger = empty_slog();
+        // options for git rebase make it run instead of the in-memory
+        // autosquash
+        let config = Config {
+            and_rebase: true,
+            rebase_options: vec!["--no-verify"],
+            ..default_config(&logger)
+        };
+        let undo = Config {
+            action: Action::Undo,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        run_with_repo(&undo, &repo).unwrap();
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), head);
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), index);
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("file")).unwrap(),
+            "a2\n\n\n\nb2\nc\n"
+        );
+
+        // the files that were checked out since come back, but the ones
+        // that were changed since are left alone
+        let mut index = repo.index().unwrap();
+        index
+            .read_tree(&repo.head().unwrap().peel_to_tree().unwrap())
+            .unwrap();
+        index.write().unwrap();
+        stage_file(&repo, "other", "x\n");
+        commit_index(&repo, "other");
+        stage_file(&repo, "file", "a2\n\n\n\nb2\n");
+        std::fs::write(workdir.join("file"), "a2\n\n\n\nb2\nc\n").unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        repo.checkout_head(Some(checkout.force())).unwrap();
+        std::fs::write(workdir.join("other"), "y\n").unwrap();
+        run_with_repo(&undo, &repo).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("file")).unwrap(),
+            "a2\n\n\n\nb2\nc\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(workdir.join("other")).unwrap(),
+            "y\n"
+        );
+    }
+
+    #[test]
+    fn test_paranoid_undoes_the_run_if_a_check_fails() {
+        let (_dir, repo) = prepare_two_line_repo();
+        // only the first change is absorbed
+        stage_file(&repo, "file", "a2\n\n\n\nb\n");
+        stage_file(&repo, "other", "x\n");
+        let index = repo.index().unwrap().write_tree().unwrap();
+        let logger = empty_slog();
+        let config = Config {
+            paranoid: true,
+            and_rebase: true,
+            ..default_config(&logger)
+        };
+        assert_eq!(
+            run_with_repo(&config, &repo).unwrap(),
+            Outcome::PartiallyAbsorbed
+        );
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), index);
+
+        let (_dir, repo) = prepare_two_line_repo();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let index = repo.index().unwrap().write_tree().unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        // the fixups changed the file, which isn't in the plan
+        let problem = check_invariants(
+            &repo,
+            &plan::Plan::default(),
+            head.tree_id(),
+            index,
+            index,
+            false,
+        )
+        .unwrap();
+        assert!(problem.unwrap().contains("none of whose hunks"));
+        let problem = check_invariants(
+            &repo,
+            &plan::Plan::default(),
+            head.tree_id(),
+            index,
+            head.tree_id(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            problem.as_deref(),
+            Some("committing the fixups changed the index")
+        );
+
+        let settings = Layered::open(&repo).unwrap();
+        let e = undo_after_check(&repo, &settings, &backend::Libgit2, "broken", &logger);
+        assert_eq!(e.to_string(), "broken, the run was undone");
+        assert_eq!(
+            repo.head().unwrap().peel_to_commit().unwrap().id(),
+            head.id()
+        );
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), index);
+    }
+
+    #[test]
+    fn test_all_stages_tracked_changes() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let head = repo.head().unwrap().peel_to_commit().unwrap().id();
+        // only the first change is staged
+        stage_file(&repo, "file", "a2\n\n\n\nb\n");
+        std::fs::write(repo.workdir().unwrap().join("file"), "a2\n\n\n\nb2\n").unwrap();
+        let index = repo.index().unwrap().write_tree().unwrap();
+        let logger = empty_slog();
+
+        let config = Config {
+            all: true,
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        assert_eq!(run_with_repo(&config, &repo).unwrap(), Outcome::Absorbed);
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), index);
+
+        let config = Config {
+            all: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+
+        // only the change that was staged before is staged again
+        let config = Config {
+            action: Action::Undo,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(repo.head().unwrap().peel_to_commit().unwrap().id(), head);
+        assert_eq!(repo.index().unwrap().write_tree().unwrap(), index);
+    }
+
+    #[test]
+    fn test_all_unstages_when_nothing_is_absorbed() {
+        let (_dir, repo) = prepare_two_line_repo();
+        // nothing is staged, and the first line isn't in the stack
+        let mut index = repo.index().unwrap();
+        index
+            .read_tree(&repo.head().unwrap().peel_to_tree().unwrap())
+            .unwrap();
+        index.write().unwrap();
+        std::fs::write(repo.workdir().unwrap().join("file"), "a2\n\n\n\nb\n").unwrap();
+
+        let logger = empty_slog();
+        let config = Config {
+            all: true,
+            base: vec!["HEAD~"],
+            ..default_config(&logger)
+        };
+        assert_eq!(
+            run_with_repo(&config, &repo).unwrap(),
+            Outcome::NothingAbsorbed
+        );
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    /// Adds the file to the working tree, and to the index like `git add
+    /// --intent-to-add` does.
+    fn add_intent_to_add(repo: &git2::Repository, path: &str, content: &str) {
+        std::fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+        repo.blob(&[]).unwrap();
+        let mut index = repo.index().unwrap();
+        index
+            .add(&intent_to_add_entry(
+                path.as_bytes().to_vec(),
+                git2::FileMode::Blob.into(),
+            ))
+            .unwrap();
+        index.write().unwrap();
+    }
+
+    fn is_intent_to_add_in_index(repo: &git2::Repository, path: &str) -> bool {
+        let mut index = repo.index().unwrap();
+        index.read(true).unwrap();
+        index
+            .get_path(Path::new(path), 0)
+            .is_some_and(|entry| is_intent_to_add(&entry))
+    }
+
+    fn file_in_head(repo: &git2::Repository, path: &str) -> Option<Vec<u8>> {
+        let tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let entry = tree.get_path(Path::new(path)).ok()?;
+        Some(repo.find_blob(entry.id()).unwrap().content().to_vec())
+    }
+
+    #[test]
+    fn test_ignores_unstaged_changes_of_partially_staged_files() {
+        for backend in &["libgit2", "cli"] {
+            let (_dir, repo) = prepare_two_line_repo();
+            repo.config()
+                .unwrap()
+                .set_str(config::BACKEND_CONFIG_NAME, backend)
+                .unwrap();
+            stage_file(&repo, "other", "staged\n");
+            let workdir = repo.workdir().unwrap();
+            std::fs::write(workdir.join("file"), "a3\n\n\n\nb3\n").unwrap();
+            std::fs::write(workdir.join("other"), "unstaged\n").unwrap();
+            let logger = empty_slog();
+            run_with_repo(&default_config(&logger), &repo).unwrap();
+
+            assert_eq!(
+                log(&repo),
+                vec!["fixup! second\n", "fixup! first\n", "second", "first"],
+                "{}",
+                backend
+            );
+            assert_eq!(
+                file_in_head(&repo, "file").unwrap(),
+                b"a2\n\n\n\nb2\n",
+                "{}",
+                backend
+            );
+            // the new file is still staged as it was, and the working
+            // tree is left alone
+            let mut index = repo.index().unwrap();
+            index.read(true).unwrap();
+            let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+            let diff = repo
+                .diff_tree_to_index(Some(&head_tree), Some(&index), None)
+                .unwrap();
+            assert_eq!(diff.deltas().len(), 1, "{}", backend);
+            let other = index.get_path(Path::new("other"), 0).unwrap();
+            assert_eq!(
+                repo.find_blob(other.id).unwrap().content(),
+                b"staged\n",
+                "{}",
+                backend
+            );
+            assert_eq!(
+                std::fs::read(workdir.join("file")).unwrap(),
+                b"a3\n\n\n\nb3\n"
+            );
+            assert_eq!(std::fs::read(workdir.join("other")).unwrap(), b"unstaged\n");
+        }
+    }
+
+    #[test]
+    fn test_intent_to_add_files_are_not_staged() {
+        for backend in &["libgit2", "cli"] {
+            let (_dir, repo) = prepare_two_line_repo();
+            repo.config()
+                .unwrap()
+                .set_str(config::BACKEND_CONFIG_NAME, backend)
+                .unwrap();
+            add_intent_to_add(&repo, "new", "new file\n");
+            let logger = empty_slog();
+            let config = Config {
+                new_files: true,
+                leftovers: Leftovers::Commit,
+                ..default_config(&logger)
+            };
+            run_with_repo(&config, &repo).unwrap();
+
+            // nothing was left to commit as leftovers either
+            assert_eq!(
+                log(&repo),
+                vec!["fixup! second\n", "fixup! first\n", "second", "first"],
+                "{}",
+                backend
+            );
+            assert_eq!(file_in_head(&repo, "new"), None, "{}", backend);
+            assert!(is_intent_to_add_in_index(&repo, "new"), "{}", backend);
+            assert_eq!(
+                run_with_repo(&default_config(&logger), &repo).unwrap(),
+                Outcome::NothingStaged,
+                "{}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn test_undo_restores_intent_to_add_files() {
+        for backend in &["libgit2", "cli"] {
+            let (_dir, repo) = prepare_two_line_repo();
+            repo.config()
+                .unwrap()
+                .set_str(config::BACKEND_CONFIG_NAME, backend)
+                .unwrap();
+            add_intent_to_add(&repo, "new", "new file\n");
+            let index = backend::Libgit2.write_index_tree(&repo).unwrap();
+            let logger = empty_slog();
+            // --all stages the file, like `git commit -a` does
+            let config = Config {
+                all: true,
+                ..default_config(&logger)
+            };
+            run_with_repo(&config, &repo).unwrap();
+            assert!(!is_intent_to_add_in_index(&repo, "new"), "{}", backend);
+
+            let config = Config {
+                action: Action::Undo,
+                ..default_config(&logger)
+            };
+            run_with_repo(&config, &repo).unwrap();
+            assert_eq!(log(&repo), vec!["second", "first"], "{}", backend);
+            assert!(is_intent_to_add_in_index(&repo, "new"), "{}", backend);
+            assert_eq!(
+                backend::Libgit2.write_index_tree(&repo).unwrap(),
+                index,
+                "{}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn test_pathspec_limits_absorbed_files() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file1", "a\n");
+        stage_file(&repo, "file2", "b\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file1", "a2\n");
+        stage_file(&repo, "file2", "b2\n");
+
+        let logger = empty_slog();
+        let config = Config {
+            pathspecs: vec!["file2"],
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["fixup! first\n", "first"]);
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let diff = repo
+            .diff_tree_to_index(Some(&head_tree), None, None)
+            .unwrap();
+        let left: Vec<_> = diff
+            .deltas()
+            .map(|delta| delta.new_file().path().unwrap().to_owned())
+            .collect();
+        assert_eq!(left, vec![Path::new("file1")]);
+    }
+
+    #[test]
+    fn test_target_overrides_commutation() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            target: Some("HEAD"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! second\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_target_must_be_in_stack() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            base: vec!["HEAD~"],
+            target: Some("HEAD~"),
+            ..default_config(&logger)
+        };
+        assert!(run_with_repo(&config, &repo).is_err());
+        assert_eq!(log(&repo), vec!["second", "first"]);
+    }
+
+    #[test]
+    fn test_whole_file_uses_newest_commit_touching_file() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            whole_file: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        // both hunks go into the newest commit, even though the first
+        // one would otherwise be absorbed into the older commit
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! second\n", "second", "first"]
+        );
+    }
+
+    #[test]
+    fn test_rejects_unavailable_backend() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str(config::BACKEND_CONFIG_NAME, "gix")
+            .unwrap();
+        let logger = empty_slog();
+        let err = run_with_repo(&default_config(&logger), &repo).unwrap_err();
+        assert!(err.to_string().contains("not available"));
+        assert_eq!(log(&repo), vec!["second", "first"]);
+    }
+
+    #[test]
+    fn test_skips_line_ending_changes() {
+        let prepare = || {
+            let (dir, repo) = init_repo();
+            stage_file(&repo, "file", "a\r\nb\r\nc\r\n");
+            commit_index(&repo, "first");
+            stage_file(&repo, "file", "a\r\nb\r\nc2\r\n");
+            commit_index(&repo, "second");
+            stage_file(&repo, "file", "a\nb\r\nc3\r\n");
+            (dir, repo)
+        };
+        let logger = empty_slog();
+
+        let (_dir, repo) = prepare();
+        repo.config()
+            .unwrap()
+            .set_str("core.autocrlf", "true")
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        assert!(!nothing_left_in_index(&repo));
+
+        // without normalization, the new line ending is a change like
+        // any other
+        let (_dir, repo) = prepare();
+        repo.config()
+            .unwrap()
+            .set_str("core.autocrlf", "false")
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_textconv_files_are_absorbed_whole() {
+        let (_dir, repo) = prepare_two_line_repo();
+        std::fs::create_dir_all(repo.path().join("info")).unwrap();
+        std::fs::write(
+            repo.path().join("info").join("attributes"),
+            "file diff=secret\n",
+        )
+        .unwrap();
+        repo.config()
+            .unwrap()
+            .set_str("diff.secret.textconv", "cat")
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        // both hunks go into the newest commit, as a single fixup
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_absorbs_non_utf8_paths_and_contents() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let (_dir, repo) = init_repo();
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+        let stage = |content: &[u8]| {
+            std::fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(path).unwrap();
+            index.write().unwrap();
+        };
+        stage(b"caf\xe9\n\n\n\n");
+        commit_index(&repo, "first");
+        stage(b"caf\xe9\n\n\n\nna\xefve\n");
+        commit_index(&repo, "second");
+        stage(b"caf\xe9!\n\n\n\nna\xefve!\n");
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_nearby_edits_are_not_welded_together() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "1\n2\n3\n4\n5\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file", "1\n2\n3b\n4\n5\n");
+        commit_index(&repo, "second");
+        // `git diff` shows these as one hunk, but the first edit is one
+        // unchanged line away from the second commit's, so it commutes
+        // with it, while the other edit is right next to it, so it
+        // doesn't
+        stage_file(&repo, "file", "1c\n2\n3b\n4c\n5\n");
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_unified_groups_nearby_changes() {
+        let prepare = || {
+            let (dir, repo) = init_repo();
+            stage_file(&repo, "file", "1\n2\n3\n4\n5\n");
+            commit_index(&repo, "first");
+            stage_file(&repo, "file", "1b\n2\n3\n4\n5\n");
+            commit_index(&repo, "second");
+            stage_file(&repo, "file", "1c\n2\n3c\n4\n5\n");
+            (dir, repo)
+        };
+        let logger = empty_slog();
+
+        let (_dir, repo) = prepare();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+
+        // with one line of context, both changes are in the same hunk,
+        // which can't move past the second commit
+        let (_dir, repo) = prepare();
+        let config = Config {
+            unified: Some(1),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        assert!(nothing_left_in_index(&repo));
+
+        let (_dir, repo) = prepare();
+        repo.config()
+            .unwrap()
+            .set_i64(config::DIFF_CONTEXT_CONFIG_NAME, 1)
+            .unwrap();
+        repo.config()
+            .unwrap()
+            .set_str(config::BACKEND_CONFIG_NAME, "cli")
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+    }
+
+    #[test]
+    fn test_cli_backend() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str(config::BACKEND_CONFIG_NAME, "cli")
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+        assert_eq!(
+            run_with_repo(&default_config(&logger), &repo).unwrap(),
+            Outcome::NothingStaged
+        );
+    }
+
+    #[test]
+    fn test_uses_the_index_file_of_the_repository() {
+        // like a repository opened with GIT_INDEX_FILE
+        let (dir, repo) = prepare_two_line_repo();
+        let index_path = dir.path().join("other-index");
+        std::fs::copy(repo.path().join("index"), &index_path).unwrap();
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let mut index = repo.index().unwrap();
+        index.read_tree(&head_tree).unwrap();
+        index.write().unwrap();
+        repo.set_index(&mut git2::Index::open(&index_path).unwrap())
+            .unwrap();
+        repo.config()
+            .unwrap()
+            .set_str(config::BACKEND_CONFIG_NAME, "cli")
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_keeps_sparse_index_sparse() {
+        let (_dir, repo) = init_repo();
+        let workdir = repo.workdir().unwrap();
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .args(args)
+                .current_dir(workdir)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+        std::fs::create_dir_all(workdir.join("in")).unwrap();
+        std::fs::create_dir_all(workdir.join("out")).unwrap();
+        stage_file(&repo, "in/file", "a\n\n\n\n");
+        stage_file(&repo, "out/file", "a\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "in/file", "a\n\n\n\nb\n");
+        commit_index(&repo, "second");
+        git(&["sparse-checkout", "set", "--cone", "--sparse-index", "in"]);
+        std::fs::write(workdir.join("in/file"), "a2\n\n\n\nb2\n").unwrap();
+        git(&["add", "in/file"]);
+
+        // libgit2 can't read the index anymore
+        let repo = git2::Repository::open(workdir).unwrap();
+        assert!(repo.index().is_err());
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert_eq!(git(&["status", "--porcelain"]), "");
+        assert_eq!(
+            git(&["ls-files", "--sparse"]),
+            "in/file\nout/\n",
+            "the index was expanded"
+        );
+    }
+
+    /// Makes a partial clone without blobs of a repository with two
+    /// commits on top of a base commit, and stages changes to the lines
+    /// that each of them added, which it only has the current version
+    /// of.
+    fn prepare_partial_clone() -> (tempfile::TempDir, git2::Repository) {
+        let (dir, origin) = init_repo();
+        let mut config = origin.config().unwrap();
+        config.set_bool("uploadpack.allowFilter", true).unwrap();
+        config
+            .set_bool("uploadpack.allowAnySHA1InWant", true)
+            .unwrap();
+        stage_file(&origin, "other", "a\n");
+        commit_index(&origin, "base");
+        stage_file(&origin, "file", "a\n\n\n\n");
+        commit_index(&origin, "first");
+        stage_file(&origin, "file", "a\n\n\n\nb\n");
+        commit_index(&origin, "second");
+
+        let clone = dir.path().join("clone");
+        let status = std::process::Command::new("git")
+            .args(["clone", "--quiet", "--filter=blob:none", "--no-local"])
+            .arg(format!("file://{}", origin.workdir().unwrap().display()))
+            .arg(&clone)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let repo = git2::Repository::open(&clone).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "nobody").unwrap();
+        config.set_str("user.email", "nobody@example.com").unwrap();
+        stage_file(&repo, "file", "a2\n\n\n\nb2\n");
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_fetches_missing_blobs_of_partial_clone() {
+        let (_dir, repo) = prepare_partial_clone();
+        let first = repo
+            .revparse_single("HEAD~")
+            .unwrap()
+            .peel_to_tree()
+            .unwrap()
+            .get_path(Path::new("file"))
+            .unwrap()
+            .id();
+        assert!(!repo.odb().unwrap().exists(first));
+        let logger = empty_slog();
+        let config = Config {
+            base: vec!["HEAD~2"],
+            force_pushed: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert!(repo.odb().unwrap().exists(first));
+        assert_eq!(
+            log(&repo),
+            vec![
+                "fixup! second\n",
+                "fixup! first\n",
+                "second",
+                "first",
+                "base"
+            ]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_skips_files_with_missing_blobs() {
+        let (dir, repo) = prepare_partial_clone();
+        repo.remote_set_url(
+            "origin",
+            &format!("file://{}", dir.path().join("gone").display()),
+        )
+        .unwrap();
+        let logger = empty_slog();
+        let config = Config {
+            base: vec!["HEAD~2"],
+            force_pushed: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["second", "first", "base"]);
+        assert!(!nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_large_files_are_not_commuted() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_FILE_SIZE_CONFIG_NAME, 1)
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        // like with --whole-file
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! second\n", "second", "first"]
+        );
+
+        let (_dir, repo) = prepare_two_line_repo();
+        let mut git_config = repo.config().unwrap();
+        git_config
+            .set_i64(config::MAX_LINE_LENGTH_CONFIG_NAME, 1)
+            .unwrap();
+        git_config
+            .set_str(config::LARGE_FILES_CONFIG_NAME, "skip")
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["second", "first"]);
+    }
+
+    #[test]
+    fn test_excluded_paths_are_left_staged() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str(config::EXCLUDE_PATHS_CONFIG_NAME, "*.lock, fi*")
+            .unwrap();
+        let logger = empty_slog();
+        let outcome = run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(outcome, Outcome::NothingStaged);
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        assert!(!nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_generated_files_are_left_staged() {
+        let (dir, repo) = prepare_two_line_repo();
+        std::fs::write(
+            dir.path().join(".gitattributes"),
+            "file linguist-generated\n",
+        )
+        .unwrap();
+        let logger = empty_slog();
+        let outcome = run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(outcome, Outcome::NothingStaged);
+        assert_eq!(log(&repo), vec!["second", "first"]);
+
+        repo.config()
+            .unwrap()
+            .set_bool(config::SKIP_GENERATED_CONFIG_NAME, false)
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_max_stack_only_counts_commits_touching_staged_files() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n");
+        commit_index(&repo, "first");
+        for i in 0..3 {
+            stage_file(&repo, "other", &format!("{}\n", i));
+            commit_index(&repo, "unrelated");
+        }
+        stage_file(&repo, "file", "a2\n");
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 1)
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo)[0], "fixup! first\n");
+
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 1)
+            .unwrap();
+        let recorder = Recorder::default();
+        let logger = slog::Logger::root(recorder.clone(), o!());
+        let config = Config {
+            verbose: true,
+            ..default_config(&logger)
+        };
+        let outcome = run_with_repo(&config, &repo).unwrap();
+        assert_eq!(outcome, Outcome::PartiallyAbsorbed);
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+        // the hunk of the first commit is reported
+        let messages = recorder.0.lock().unwrap();
+        assert!(messages
+            .iter()
+            .any(|message| message.starts_with("1 staged file has hunks")));
+        assert!(messages.contains(&"hunk has a target past the end of the stack".to_owned()));
+    }
+
+    /// A message that was logged, with its values by key.
+    type Logged = (String, HashMap<String, String>);
+
+    /// Records the messages that are logged to it, with their values.
+    #[derive(Clone, Default)]
+    struct ValueRecorder(std::sync::Arc<std::sync::Mutex<Vec<Logged>>>);
+
+    #[derive(Default)]
+    struct Values(HashMap<String, String>);
+
+    impl slog::Serializer for Values {
+        fn emit_arguments(&mut self, key: slog::Key, value: &std::fmt::Arguments) -> slog::Result {
+            self.0.insert(key.to_string(), value.to_string());
+            Ok(())
+        }
+    }
+
+    impl slog::Drain for ValueRecorder {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+            let mut values = Values::default();
+            let _ = slog::KV::serialize(&record.kv(), record, &mut values);
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.msg().to_string(), values.0));
+            Ok(())
+        }
+    }
+
+    /// Commits "second" on top of whatever ends the stack, and stages a
+    /// change to its line and to the line of "first", which is past the
+    /// end of the stack.
+    fn stack_past_boundary(repo: &git2::Repository) {
+        stage_file(repo, "file", "a\n\n\n\nb\n");
+        commit_index(repo, "second");
+        stage_file(repo, "file", "a2\n\n\n\nb2\n");
+    }
+
+    /// Returns the commits and the hints that the hunks left in the index
+    /// were reported with, as having a target past the end of the stack.
+    fn reported_past_the_stack(repo: &git2::Repository, config: Config) -> Vec<(String, String)> {
+        let recorder = ValueRecorder::default();
+        let logger = slog::Logger::root(recorder.clone(), o!());
+        run_with_repo(
+            &Config {
+                logger: &logger,
+                ..config
+            },
+            repo,
+        )
+        .unwrap();
+        let records = recorder.0.lock().unwrap();
+        records
+            .iter()
+            .filter(|(message, _)| message == "hunk has a target past the end of the stack")
+            .map(|(_, values)| (values["commit"].clone(), values["hint"].clone()))
+            .collect()
+    }
+
+    /// Checks that only a dry run, --explain or -v report that the hunk
+    /// of `first` has it as its target past the end of the stack, with
+    /// the hint.
+    fn assert_reported_past_the_stack(repo: &git2::Repository, first: git2::Oid, hint: &str) {
+        let logger = empty_slog();
+        let expected = vec![(first.to_string(), hint.to_owned())];
+        let dry_run = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        assert_eq!(reported_past_the_stack(repo, dry_run), expected);
+        let explain = Config {
+            dry_run: true,
+            explain: true,
+            ..default_config(&logger)
+        };
+        assert_eq!(reported_past_the_stack(repo, explain), expected);
+        // a real run doesn't look past the stack unless asked
+        let verbose = Config {
+            verbose: true,
+            ..default_config(&logger)
+        };
+        assert_eq!(reported_past_the_stack(repo, verbose), expected);
+        assert!(reported_past_the_stack(repo, default_config(&logger)).is_empty());
+    }
+
+    #[test]
+    fn test_reports_target_past_a_merge() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        let first = commit_index(&repo, "first");
+        let tree = first.tree().unwrap();
+        let sig = repo.signature().unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "merge", &tree, &[&first, &first])
+            .unwrap();
+        stack_past_boundary(&repo);
+        assert_reported_past_the_stack(
+            &repo,
+            first.id(),
+            "set absorb.traverseMerges = first-parent to go past merges",
+        );
+    }
+
+    #[test]
+    fn test_reports_target_past_pushed_commits() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        let first = commit_index(&repo, "first");
+        repo.reference("refs/remotes/origin/master", first.id(), true, "push")
+            .unwrap();
+        stack_past_boundary(&repo);
+        assert_reported_past_the_stack(
+            &repo,
+            first.id(),
+            "use --force-pushed to go past pushed commits",
+        );
+    }
+
+    #[test]
+    fn test_reports_target_past_commits_by_other_authors() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        let other = git2::Signature::now("other", "other@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let first = repo
+            .commit(Some("HEAD"), &other, &other, "first", &tree, &[])
+            .unwrap();
+        stack_past_boundary(&repo);
+        assert_reported_past_the_stack(
+            &repo,
+            first,
+            "use --include-author or absorb.authorAllowlist to go past commits by other authors",
+        );
+    }
+
+    #[test]
+    fn test_reports_target_past_the_stack_limit() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        let first = commit_index(&repo, "first");
+        repo.config()
+            .unwrap()
+            .set_i64(config::MAX_STACK_CONFIG_NAME, 1)
+            .unwrap();
+        stack_past_boundary(&repo);
+        assert_reported_past_the_stack(&repo, first.id(), "use --base, or raise absorb.maxStack");
+    }
+
+    #[test]
+    fn test_moved_block_follows_its_removed_lines() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\nb\nc\nd\ne\n");
+        commit_index(&repo, "base");
+        stage_file(&repo, "file", "fn f() {}\na\nb\nc\nd\ne\n");
+        commit_index(&repo, "add f");
+        stage_file(&repo, "file", "a\nb\nc\nd\ne\nfn f() {}\n");
+        let logger = empty_slog();
+        let config = Config {
+            detect_moves: true,
+            ..default_config(&logger)
+        };
+        let outcome = run_with_repo(&config, &repo).unwrap();
+        assert_eq!(outcome, Outcome::Absorbed);
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add f\n", "fixup! add f\n", "add f", "base"]
+        );
+
+        // by default, the added lines go into the commit that added the
+        // file
+        let prepare = || {
+            let (dir, repo) = init_repo();
+            stage_file(&repo, "file", "a\nb\nc\nd\ne\n");
+            commit_index(&repo, "base");
+            stage_file(&repo, "file", "fn f() {}\na\nb\nc\nd\ne\n");
+            commit_index(&repo, "add f");
+            stage_file(&repo, "file", "a\nb\nc\nd\ne\nfn f() {}\n");
+            (dir, repo)
+        };
+        let (_dir, repo) = prepare();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add f\n", "fixup! base\n", "add f", "base"]
+        );
+
+        // and absorb.detectMoves follows moves, like --detect-moves
+        let (_dir, repo) = prepare();
+        repo.config()
+            .unwrap()
+            .set_bool(config::DETECT_MOVES_CONFIG_NAME, true)
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add f\n", "fixup! add f\n", "add f", "base"]
+        );
+    }
+
+    #[test]
+    fn test_lines_moved_from_another_file_are_reported() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "a", "fn f() {\n    one\n}\n\nfn g() {}\n");
+        stage_file(&repo, "b", "fn h() {}\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "a", "fn g() {}\n");
+        stage_file(&repo, "b", "fn h() {}\n\nfn f() {\n    one\n}\n");
+        commit_index(&repo, "move f");
+        stage_file(&repo, "b", "fn h() {}\n\nfn f() {\n    two\n}\n");
+        let recorder = Recorder::default();
+        let logger = slog::Logger::root(recorder.clone(), o!());
+        let config = Config {
+            detect_moves: true,
+            ..default_config(&logger)
+        };
+        let outcome = run_with_repo(&config, &repo).unwrap();
+        // the hunk still goes into the commit that moved the lines
+        assert_eq!(outcome, Outcome::Absorbed);
+        assert_eq!(log(&repo), vec!["fixup! move f\n", "move f", "first"]);
+        let messages = recorder.0.lock().unwrap();
+        assert!(messages
+            .contains(&"hunk edits lines that its target moved from another file".to_owned()));
+    }
+
+    #[test]
+    fn test_semantic_hunks_are_cut_where_functions_start() {
+        let prepare = || {
+            let (dir, repo) = init_repo();
+            stage_file(
+                &repo,
+                "file.py",
+                "def f():\n    return 1\ndef g(x):\n    return 2\n",
+            );
+            commit_index(&repo, "first");
+            stage_file(
+                &repo,
+                "file.py",
+                "def f():\n    return 10\ndef g(x):\n    return 2\n",
+            );
+            commit_index(&repo, "change f");
+            stage_file(
+                &repo,
+                "file.py",
+                "def f():\n    return 10\ndef g(x):\n    return 20\n",
+            );
+            commit_index(&repo, "change g");
+            stage_file(
+                &repo,
+                "file.py",
+                "def f():\n    x = 11\n    return x\ndef g(x, y):\n    return 20\n",
+            );
+            (dir, repo)
+        };
+        let logger = empty_slog();
+
+        let (_dir, repo) = prepare();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo)[..2], ["fixup! change g\n", "change g"]);
+
+        let (_dir, repo) = prepare();
+        repo.config()
+            .unwrap()
+            .set_bool(config::SEMANTIC_HUNKS_CONFIG_NAME, true)
+            .unwrap();
+        let outcome = run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(outcome, Outcome::Absorbed);
+        assert_eq!(
+            log(&repo)[..3],
+            ["fixup! change g\n", "fixup! change f\n", "change g"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_dry_run_lists_other_candidates() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "other", "b\n");
+        commit_index(&repo, "second");
+        stage_file(&repo, "file", "a2\n");
+        let recorder = Recorder::default();
+        let logger = slog::Logger::root(recorder.clone(), o!());
+        let config = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        let messages = recorder.0.lock().unwrap();
+        assert!(messages.contains(&"hunk could also be absorbed into 1 other commit".to_owned()));
+        assert!(messages.contains(&"  candidate".to_owned()));
+    }
+
+    #[test]
+    fn test_bulk_commits_are_not_targeted() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file", "a\n\n\n\nb\n");
+        stage_file(&repo, "other", "x\n");
+        commit_index(&repo, "reformat everything");
+        stage_file(&repo, "file", "a2\n\n\n\nb2\n");
+        repo.config()
+            .unwrap()
+            .set_i64(config::SKIP_BULK_COMMITS_CONFIG_NAME, 1)
+            .unwrap();
+        let logger = empty_slog();
+        let outcome = run_with_repo(&default_config(&logger), &repo).unwrap();
+        // the first hunk commutes past the bulk commit
+        assert_eq!(outcome, Outcome::PartiallyAbsorbed);
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! first\n", "reformat everything", "first"]
+        );
+
+        let config = Config {
+            skip_bulk: Some(0),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_oldest_prefers_oldest_candidate() {
+        // hunks that can't commute past a commit are still absorbed
+        // into it
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            oldest: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+
+        let (_dir, repo) = prepare_two_line_repo();
+        let config = Config {
+            whole_file: true,
+            oldest: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! first\n", "fixup! first\n", "second", "first"]
+        );
+    }
+
+    #[test]
+    fn test_splits_hunk_spanning_several_commits() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "1\n2\n3\n4\n5\n6\n");
+        let base = commit_index(&repo, "base");
+        stage_file(&repo, "file", "1\nx2\n3\n4\n5\n6\n");
+        commit_index(&repo, "edit 2");
+        stage_file(&repo, "file", "1\nx2\n3\nx4\n5\n6\n");
+        commit_index(&repo, "edit 4");
+        // a single hunk replacing lines 2-4
+        stage_file(&repo, "file", "1\ny2\ny3\ny4\n5\n6\n");
+        let index_tree = repo.index().unwrap().write_tree().unwrap();
+
+        let logger = empty_slog();
+        let base = base.id().to_string();
+        let config = Config {
+            base: vec![&base],
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(
+            log(&repo),
+            vec![
+                "fixup! edit 4\n",
+                "fixup! edit 2\n",
+                "edit 4",
+                "edit 2",
+                "base"
+            ]
+        );
+        assert_eq!(
+            repo.head().unwrap().peel_to_tree().unwrap().id(),
+            index_tree
+        );
+    }
+
+    #[test]
+    fn test_intra_line_commutes_disjoint_edits() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "call(a, b)\n");
+        commit_index(&repo, "add call");
+        stage_file(&repo, "file", "call(aaa, b)\n");
+        commit_index(&repo, "edit first argument");
+        stage_file(&repo, "file", "call(aaa, bb)\n");
+
+        let logger = empty_slog();
+        let config = Config {
+            intra_line: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add call\n", "edit first argument", "add call"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_intra_line_warns_and_refuses_to_rebase() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "call(a, b)\n");
+        commit_index(&repo, "add call");
+        stage_file(&repo, "file", "call(aaa, b)\n");
+        commit_index(&repo, "edit first argument");
+        stage_file(&repo, "file", "call(aaa, bb)\n");
+
+        let recorder = Recorder::default();
+        let messages = recorder.0.clone();
+        let logger = slog::Logger::root(recorder, o!());
+        let config = Config {
+            intra_line: true,
+            and_rebase: true,
+            ..default_config(&logger)
+        };
+        assert!(run_with_repo(&config, &repo).is_err());
+        assert_eq!(log(&repo), vec!["edit first argument", "add call"]);
+
+        let config = Config {
+            intra_line: true,
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert!(messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message.starts_with("hunk only commutes within lines")));
+
+        // hunks that commute anyway aren't warned about
+        messages.lock().unwrap().clear();
+        stage_file(&repo, "file", "call(aaa2, b)\n");
+        run_with_repo(&config, &repo).unwrap();
+        assert!(!messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|message| message.starts_with("hunk only commutes within lines")));
+    }
+
+    #[test]
+    fn test_follows_renames_in_stack() {
+        let (_dir, repo) = init_repo();
+        let content = "one\ntwo\nthree\nfour\nfive\nsix\n";
+        stage_file(&repo, "old", content);
+        commit_index(&repo, "add old");
+
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("old")).unwrap();
+        index.write().unwrap();
+        stage_file(&repo, "new", &content.replace("six", "seven"));
+        commit_index(&repo, "rename old to new");
+
+        stage_file(
+            &repo,
+            "new",
+            &content.replace("six", "seven").replace("two", "2"),
+        );
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add old\n", "rename old to new", "add old"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_cached_diffs_follow_renames() {
+        let (_dir, repo) = init_repo();
+        let content = "one\ntwo\nthree\nfour\nfive\nsix\n";
+        stage_file(&repo, "old", content);
+        commit_index(&repo, "add old");
+        let logger = empty_slog();
+
+        // a first run only caches the diff of the stack for "new"
+        stage_file(&repo, "new", "new\n");
+        let config = Config {
+            dry_run: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("old")).unwrap();
+        index.write().unwrap();
+        stage_file(&repo, "new", content);
+        commit_index(&repo, "rename old to new");
+        stage_file(&repo, "new", &content.replace("two", "2"));
+
+        // and the rename widens it to "old" for the commits before it,
+        // which can't reuse that diff
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add old\n", "rename old to new", "add old"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_absorbs_deletion_into_creator() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "base", "base\n");
+        commit_index(&repo, "base");
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "add file");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "add other");
+
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("file")).unwrap();
+        index.write().unwrap();
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add file\n", "add other", "add file", "base"]
+        );
+        assert!(nothing_left_in_index(&repo));
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert!(head_tree.get_name("file").is_none());
+    }
+
+    #[test]
+    fn test_new_files_are_left_in_index_by_default() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "base", "base\n");
+        commit_index(&repo, "base");
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "add file");
+        stage_file(&repo, "sibling", "line\n");
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["add file", "base"]);
+        assert!(!nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_commits_leftovers() {
+        let (_dir, repo) = prepare_two_line_repo();
+        stage_file(&repo, "new", "line\n");
+
+        let logger = empty_slog();
+        let config = Config {
+            leftovers: Leftovers::Commit,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec![
+                "WIP: unabsorbed changes\n",
+                "fixup! second\n",
+                "fixup! first\n",
+                "second",
+                "first"
+            ]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_new_files_absorb_into_sibling_creator() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "base", "base\n");
+        commit_index(&repo, "base");
+        std::fs::create_dir(repo.workdir().unwrap().join("dir")).unwrap();
+        stage_file(&repo, "dir/file", "line\n");
+        commit_index(&repo, "add dir/file");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "add other");
+        stage_file(&repo, "dir/sibling", "line\n");
+
+        let logger = empty_slog();
+        let config = Config {
+            new_files: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add dir/file\n", "add other", "add dir/file", "base"]
+        );
+        assert!(nothing_left_in_index(&repo));
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let entry = head_tree.get_path(Path::new("dir/sibling")).unwrap();
+        assert_eq!(entry.filemode(), i32::from(git2::FileMode::Blob));
+        assert_eq!(repo.find_blob(entry.id()).unwrap().content(), b"line\n");
+    }
+
+    #[test]
+    fn test_absorbs_mode_change() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "add file");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "add other");
+
+        let mut index = repo.index().unwrap();
+        let mut entry = index.get_path(Path::new("file"), 0).unwrap();
+        entry.mode = u32::from(git2::FileMode::BlobExecutable);
+        index.add(&entry).unwrap();
+        index.write().unwrap();
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add file\n", "add other", "add file"]
+        );
+        assert!(nothing_left_in_index(&repo));
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert_eq!(
+            head_tree.get_name("file").unwrap().filemode(),
+            i32::from(git2::FileMode::BlobExecutable)
+        );
+    }
+
+    /// Stages an entry at `path` with the given mode, pointing to `id`
+    /// (which, for submodules, doesn't have to exist).
+    fn stage_entry(repo: &git2::Repository, path: &str, mode: git2::FileMode, id: git2::Oid) {
+        let mut index = repo.index().unwrap();
+        index
+            .add(&git2::IndexEntry {
+                ctime: git2::IndexTime::new(0, 0),
+                mtime: git2::IndexTime::new(0, 0),
+                dev: 0,
+                ino: 0,
+                mode: u32::from(mode),
+                uid: 0,
+                gid: 0,
+                file_size: 0,
+                id,
+                flags: 0,
+                flags_extended: 0,
+                path: path.as_bytes().to_vec(),
+            })
+            .unwrap();
+        index.write().unwrap();
+    }
+
+    #[test]
+    fn test_absorbs_submodule_update() {
+        let (_dir, repo) = init_repo();
+        let submodule_commit = |n: u8| git2::Oid::from_bytes(&[n; 20]).unwrap();
+        stage_entry(&repo, "sub", git2::FileMode::Commit, submodule_commit(1));
+        commit_index(&repo, "add submodule");
+        stage_entry(&repo, "sub", git2::FileMode::Commit, submodule_commit(2));
+        commit_index(&repo, "update submodule");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "add other");
+        stage_entry(&repo, "sub", git2::FileMode::Commit, submodule_commit(3));
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec![
+                "fixup! update submodule\n",
+                "add other",
+                "update submodule",
+                "add submodule"
+            ]
+        );
+        assert!(nothing_left_in_index(&repo));
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        assert_eq!(head_tree.get_name("sub").unwrap().id(), submodule_commit(3));
+    }
+
+    #[test]
+    fn test_absorbs_typechange() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "line\n");
+        commit_index(&repo, "add file");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "add other");
+        let link_target = repo.blob(b"other").unwrap();
+        stage_entry(&repo, "file", git2::FileMode::Link, link_target);
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add file\n", "add other", "add file"]
+        );
+        assert!(nothing_left_in_index(&repo));
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let entry = head_tree.get_name("file").unwrap();
+        assert_eq!(entry.filemode(), i32::from(git2::FileMode::Link));
+        assert_eq!(entry.id(), link_target);
+    }
+
+    #[test]
+    fn test_binary_files_are_skipped_by_default() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "\0binary\n");
+        commit_index(&repo, "add file");
+        stage_file(&repo, "file", "\0changed\n");
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["add file"]);
+        assert!(!nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_absorbs_binary_files_when_enabled() {
+        let (_dir, repo) = init_repo();
+        repo.config()
+            .unwrap()
+            .set_bool(config::ABSORB_BINARY_CONFIG_NAME, true)
+            .unwrap();
+        stage_file(&repo, "file", "\0binary\n");
+        commit_index(&repo, "add file");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "add other");
+        stage_file(&repo, "file", "\0changed\n");
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! add file\n", "add other", "add file"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_amends_existing_fixups() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        stage_file(&repo, "file", "a2\n\n\n\nb\n");
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! first\n", "second", "first"]);
+
+        // the change to the first line again is folded into the existing
+        // fixup, while the change to the second line gets its own
+        stage_file(&repo, "file", "a3\n\n\n\nb2\n");
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+
+        let amended = repo
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap()
+            .parent(0)
+            .unwrap();
+        let blob = amended
+            .tree()
+            .unwrap()
+            .get_name("file")
+            .unwrap()
+            .to_object(&repo)
+            .unwrap()
+            .peel_to_blob()
+            .unwrap();
+        assert_eq!(blob.content(), b"a3\n\n\n\nb\n");
+    }
+
+    #[test]
+    fn test_retargets_hunks_from_fixups_to_their_target() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n\n\n\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file", "a2\n\n\n\n");
+        commit_index(&repo, "squash! first");
+        stage_file(&repo, "other", "other\n");
+        commit_index(&repo, "other");
+        stage_file(&repo, "file", "a3\n\n\n\n");
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! first\n", "other", "squash! first", "first"]
+        );
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_squash_and_amend_commits() {
+        let logger = empty_slog();
+
+        let (_dir, repo) = prepare_two_line_repo();
+        let config = Config {
+            fixup_kind: Some(FixupKind::Squash),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["squash! second\n", "squash! first\n", "second", "first"]
+        );
+
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str(config::FIXUP_KIND_CONFIG_NAME, "amend")
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec![
+                "amend! second\n\nsecond\n",
+                "amend! first\n\nfirst\n",
+                "second",
+                "first"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fixup_message_format() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str(
+                config::FIXUP_MESSAGE_FORMAT_CONFIG_NAME,
+                "{prefix} {sha} ({subject} in {path}) {unknown}",
+            )
+            .unwrap();
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+
+        let ids = log_ids(&repo);
+        assert_eq!(
+            log(&repo)[..2],
+            [
+                format!("fixup! {} (second in file) {{unknown}}\n", ids[2]),
+                format!("fixup! {} (first in file) {{unknown}}\n", ids[3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fixup_by_sha() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            fixup_by_sha: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        let ids = log_ids(&repo);
+        assert_eq!(
+            log(&repo)[..2],
+            [
+                format!("fixup! {}\n", ids[2]),
+                format!("fixup! {}\n", ids[3]),
+            ]
+        );
+
+        // hunks for the commits are folded into those fixups on the next
+        // run, since they are recognized by id
+        stage_file(&repo, "file", "a3\n\n\n\nb3\n");
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo).len(), 4);
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_add_trailers() {
+        let trailers = vec!["Signed-off-by: A <a@example.com>".to_owned()];
+        assert_eq!(
+            add_trailers("fixup! x\n".to_owned(), &trailers),
+            "fixup! x\n\nSigned-off-by: A <a@example.com>\n"
+        );
+        assert_eq!(
+            add_trailers("squash! x\n\nbody\n".to_owned(), &trailers),
+            "squash! x\n\nbody\n\nSigned-off-by: A <a@example.com>\n"
+        );
+        assert_eq!(
+            add_trailers(
+                "amend! x\n\nbody\n\nChange-Id: I123\n".to_owned(),
+                &trailers
+            ),
+            "amend! x\n\nbody\n\nChange-Id: I123\nSigned-off-by: A <a@example.com>\n"
+        );
+        assert_eq!(
+            add_trailers(
+                "amend! x\n\nSigned-off-by: A <a@example.com>\n".to_owned(),
+                &trailers
+            ),
+            "amend! x\n\nSigned-off-by: A <a@example.com>\n"
+        );
+    }
+
+    #[test]
+    fn test_signoff() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            signoff: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo)[..2],
+            [
+                "fixup! second\n\nSigned-off-by: nobody <nobody@example.com>\n",
+                "fixup! first\n\nSigned-off-by: nobody <nobody@example.com>\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_signs_fixups() {
+        let (dir, repo) = prepare_two_line_repo();
+        let program = dir.path().join(".git").join("fake-gpg");
+        std::fs::write(
+            &program,
+            "#!/bin/sh\ncat >/dev/null\necho '-----BEGIN PGP SIGNATURE-----'\n\
+             echo \"$3\"\necho '-----END PGP SIGNATURE-----'\n",
+        )
+        .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        repo.config()
+            .unwrap()
+            .set_str("gpg.program", program.to_str().unwrap())
+            .unwrap();
+
+        let logger = empty_slog();
+        let config = Config {
+            gpg_sign: true,
+            signing_key: Some("some-key"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo),
+            vec!["fixup! second\n", "fixup! first\n", "second", "first"]
+        );
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let (signature, _) = repo.extract_signature(&head.id(), None).unwrap();
+        assert!(std::str::from_utf8(&signature)
+            .unwrap()
+            .contains("some-key"));
+    }
+
+    #[test]
+    fn test_copies_trailers_from_target() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n");
+        commit_index(
+            &repo,
+            "first\n\nbody\n\nChange-Id: I1234\nReviewed-by: someone\nticket: ABC-1\n",
+        );
+        stage_file(&repo, "file", "a2\n");
+        repo.config()
+            .unwrap()
+            .set_str(config::COPY_TRAILERS_CONFIG_NAME, "Change-Id, Ticket")
+            .unwrap();
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(
+            log(&repo)[0],
+            "fixup! first\n\nChange-Id: I1234\nticket: ABC-1\n"
+        );
+    }
+
+    #[test]
+    fn test_runs_commit_hooks() {
+        let (dir, repo) = prepare_two_line_repo();
+        let hooks = dir.path().join("hooks");
+        std::fs::create_dir(&hooks).unwrap();
+        let write_hook = |hooks: &std::path::Path, name: &str, script: &str| {
+            let path = hooks.join(name);
+            std::fs::write(&path, script).unwrap();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            }
+        };
+        write_hook(
+            &hooks,
+            "commit-msg",
+            "#!/bin/sh\nprintf '\\nHooked: yes\\n' >> \"$1\"\n",
+        );
+        repo.config()
+            .unwrap()
+            .set_str("core.hooksPath", "hooks")
+            .unwrap();
+
+        let logger = empty_slog();
+        let config = Config {
+            run_hooks: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(
+            log(&repo)[..2],
+            [
+                "fixup! second\n\nHooked: yes\n",
+                "fixup! first\n\nHooked: yes\n"
+            ]
+        );
+
+        // a failing pre-commit hook stops the run
+        let (dir, repo) = prepare_two_line_repo();
+        let hooks = dir.path().join("hooks");
+        std::fs::create_dir(&hooks).unwrap();
+        write_hook(&hooks, "pre-commit", "#!/bin/sh\nexit 1\n");
+        repo.config()
+            .unwrap()
+            .set_str("core.hooksPath", "hooks")
+            .unwrap();
+        assert!(run_with_repo(&config, &repo).is_err());
+        assert_eq!(
+            repo.head().unwrap().peel_to_commit().unwrap().summary(),
+            Some("second")
+        );
+    }
+
+    #[test]
+    fn test_author_and_date_strategies() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "somebody").unwrap();
+        config.set_str("absorb.authorStrategy", "target").unwrap();
+        config.set_str("absorb.committerDate", "target").unwrap();
+
+        let logger = empty_slog();
+        let config = Config {
+            // the stack is otherwise limited to commits by the user
+            force: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        // HEAD is the fixup of the second commit
+        let target = repo
+            .revparse_single("HEAD~2")
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(head.author().name(), Some("nobody"));
+        assert_eq!(head.author().when(), target.author().when());
+        assert_eq!(head.committer().name(), Some("somebody"));
+        assert_eq!(head.committer().when(), target.committer().when());
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("1700000000 +0130").unwrap(),
+            git2::Time::new(1700000000, 90)
+        );
+        assert_eq!(parse_date("@42 -0800").unwrap(), git2::Time::new(42, -480));
+        assert_eq!(parse_date("42").unwrap(), git2::Time::new(42, 0));
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("42 +1").is_err());
+    }
+
+    #[test]
+    fn test_fixed_commit_date() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            date: Some("1700000000 +0100"),
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.author().when(), git2::Time::new(1700000000, 60));
+        assert_eq!(head.committer().when(), git2::Time::new(1700000000, 60));
+    }
+
+    #[test]
+    fn test_and_rebase_squashes_fixups_in_memory() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let logger = empty_slog();
+        let config = Config {
+            and_rebase: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        let file_at = |rev: &str| {
+            let tree = repo.revparse_single(rev).unwrap().peel_to_tree().unwrap();
+            let blob = tree
+                .get_path(Path::new("file"))
+                .unwrap()
+                .to_object(&repo)
+                .unwrap();
+            String::from_utf8(blob.as_blob().unwrap().content().to_vec()).unwrap()
+        };
+        assert_eq!(file_at("HEAD~"), "a2\n\n\n\n");
+        assert_eq!(file_at("HEAD"), "a2\n\n\n\nb2\n");
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_fixups_of_older_commits_are_committed_first() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "x\n1\n2\n");
+        commit_index(&repo, "first");
+        stage_file(&repo, "file", "1\n2\n");
+        commit_index(&repo, "second");
+        // the first line is next to the line that the second commit
+        // removed, the other one is one line away from it, so the
+        // hunk is cut between them
+        stage_file(&repo, "file", "1c\n2c\n");
+
+        let logger = empty_slog();
+        let config = Config {
+            and_rebase: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        // squashing the fixup of the first commit would conflict with
+        // the other fixup if it had been committed on top of it
+        assert_eq!(log(&repo), vec!["second", "first"]);
+        let file_at = |rev: &str| {
+            let tree = repo.revparse_single(rev).unwrap().peel_to_tree().unwrap();
+            let blob = tree
+                .get_path(Path::new("file"))
+                .unwrap()
+                .to_object(&repo)
+                .unwrap();
+            String::from_utf8(blob.as_blob().unwrap().content().to_vec()).unwrap()
+        };
+        assert_eq!(file_at("HEAD~"), "x\n1\n2c\n");
+        assert_eq!(file_at("HEAD"), "1c\n2c\n");
+        assert!(nothing_left_in_index(&repo));
+    }
+
+    #[test]
+    fn test_and_rebase_updates_stacked_branches() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "\n");
+        let base = commit_index(&repo, "base");
+        stage_file(&repo, "file", "\na\n\n\n\n");
+        let lower = commit_index(&repo, "lower");
+        repo.branch("lower", &lower, false).unwrap();
+        stage_file(&repo, "file", "\na\n\n\n\nb\n");
+        commit_index(&repo, "upper");
+        stage_file(&repo, "file", "\na2\n\n\n\nb2\n");
+
+        let logger = empty_slog();
+        let base_id = base.id().to_string();
+        let config = Config {
+            and_rebase: true,
+            base: vec![&base_id],
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["upper", "lower", "base"]);
+        let lower_branch = repo
+            .find_branch("lower", git2::BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(
+            lower_branch.id(),
+            repo.revparse_single("HEAD~").unwrap().id()
+        );
+        assert_ne!(lower_branch.id(), lower.id());
+    }
+
+    #[test]
+    fn test_git_rebase_updates_stacked_branches() {
+        let (_dir, repo) = init_repo();
+        // git rebase would otherwise wait for the todo list to be edited
+        repo.config()
+            .unwrap()
+            .set_str("sequence.editor", "true")
+            .unwrap();
+        stage_file(&repo, "file", "\n");
+        let base = commit_index(&repo, "base");
+        stage_file(&repo, "file", "\na\n\n\n\n");
+        let lower = commit_index(&repo, "lower");
+        repo.branch("lower", &lower, false).unwrap();
+        stage_file(&repo, "file", "\na\n\n\n\nb\n");
+        commit_index(&repo, "upper");
+        stage_file(&repo, "file", "\na2\n\n\n\nb2\n");
+
+        let logger = empty_slog();
+        let base_id = base.id().to_string();
+        // options for git rebase make it run instead of the in-memory
+        // autosquash
+        let config = Config {
+            and_rebase: true,
+            base: vec![&base_id],
+            rebase_options: vec!["--no-verify"],
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+
+        assert_eq!(log(&repo), vec!["upper", "lower", "base"]);
+        let lower_branch = repo
+            .find_branch("lower", git2::BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap();
+        if git_cli::version().is_some_and(|version| version >= (2, 38)) {
+            assert_eq!(
+                lower_branch.id(),
+                repo.revparse_single("HEAD~").unwrap().id()
+            );
+        } else {
+            assert_eq!(lower_branch.id(), lower.id());
+        }
+
+        assert!(rebase_update_refs(None, Some((2, 38))));
+        assert!(!rebase_update_refs(None, Some((2, 37))));
+        assert!(!rebase_update_refs(None, None));
+        assert!(rebase_update_refs(Some(true), Some((2, 30))));
+        assert!(!rebase_update_refs(Some(false), Some((2, 45))));
+    }
+
+    #[test]
+    fn test_refuses_to_run_during_merge() {
+        let (_dir, repo) = prepare_two_line_repo();
+        let head = repo.head().unwrap().target().unwrap();
+        std::fs::write(repo.path().join("MERGE_HEAD"), format!("{}\n", head)).unwrap();
+
+        let logger = empty_slog();
+        let err = run_with_repo(&default_config(&logger), &repo).unwrap_err();
+        assert!(err.to_string().contains("merge is in progress"));
+        assert_eq!(
+            err.downcast_ref::<Error>(),
+            Some(&Error::InProgress(git2::RepositoryState::Merge))
+        );
+        assert_eq!(exit_code(&err), 4);
+        assert_eq!(log(&repo).len(), 2);
+
+        let config = Config {
+            force_state: true,
+            ..default_config(&logger)
+        };
+        run_with_repo(&config, &repo).unwrap();
+        assert_eq!(log(&repo).len(), 4);
+    }
+
+    #[test]
+    fn test_merge_commits_are_not_fixed_up() {
+        let (_dir, repo) = init_repo();
+        stage_file(&repo, "file", "a\n");
+        let first = commit_index(&repo, "first");
+        stage_file(&repo, "file", "a\n\n\n\nb\n");
+        let second = commit_index(&repo, "second");
+        // a side branch that adds another file, merged into HEAD
+        let sig = repo.signature().unwrap();
+        stage_file(&repo, "other", "x\n");
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "merge", &tree, &[&second, &first])
+            .unwrap();
+        commit_index(&repo, "after");
+        stage_file(&repo, "file", "a\n\n\n\nb2\n");
+        stage_file(&repo, "other", "x2\n");
+        repo.config()
+            .unwrap()
+            .set_str("absorb.traverseMerges", "first-parent")
+            .unwrap();
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo)[..3], ["fixup! second\n", "after", "merge"]);
+        // the change to the file added by the merge is left in the index
+        let head_tree = repo.head().unwrap().peel_to_tree().unwrap();
+        let diff = repo
+            .diff_tree_to_index(Some(&head_tree), None, None)
+            .unwrap();
+        assert_eq!(diff.deltas().len(), 1);
+        assert_eq!(
+            diff.deltas().next().unwrap().new_file().path(),
+            Some(Path::new("other"))
+        );
+    }
+
+    #[test]
+    fn test_message_patterns_exclude_targets() {
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str("absorb.skipMessagePattern", "^sec")
+            .unwrap();
+
+        let logger = empty_slog();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! first\n", "second", "first"]);
+
+        let (_dir, repo) = prepare_two_line_repo();
+        repo.config()
+            .unwrap()
+            .set_str("absorb.onlyMessagePattern", "^sec")
+            .unwrap();
+        run_with_repo(&default_config(&logger), &repo).unwrap();
+        assert_eq!(log(&repo), vec!["fixup! second\n", "second", "first"]);
+
+        repo.config()
+            .unwrap()
+            .set_str("absorb.onlyMessagePattern", "(")
+            .unwrap();
+        assert!(run_with_repo(&default_config(&logger), &repo).is_err());
+    }
 }
@@ -0,0 +1,57 @@
+use anyhow::Result;
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+const LOCK_FILE: &str = "absorb.lock";
+
+/// An advisory lock in the git directory, which keeps two runs from
+/// rewriting the index and HEAD at the same time. It is released when
+/// dropped.
+#[derive(Debug)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    pub fn acquire(repo: &git2::Repository) -> Result<Self> {
+        let path = repo.path().join(LOCK_FILE);
+        let mut file = match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                return Err(crate::Error::Locked(path).into());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Lock { path })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = git2::Repository::init(&dir).unwrap();
+
+        let lock = Lock::acquire(&repo).unwrap();
+        let err = Lock::acquire(&repo).unwrap_err();
+        assert_eq!(crate::exit_code(&err), 4);
+        drop(lock);
+        assert!(Lock::acquire(&repo).is_ok());
+    }
+}
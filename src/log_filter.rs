@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Result};
+
+use crate::i18n::trf;
+
+/// Which records are logged, as given by `ABSORB_LOG`: a comma-separated
+/// list of levels (eg: `debug`), each of which may be restricted to a
+/// module and its submodules (eg: `git_absorb_core::stack=trace`). The
+/// directive with the longest module that a record comes from wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    directives: Vec<(String, slog::FilterLevel)>,
+}
+
+impl LogFilter {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut directives = Vec::new();
+        for directive in spec.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+            let (module, level) = match directive.rsplit_once('=') {
+                Some((module, level)) => (module.trim(), level.trim()),
+                None => ("", directive),
+            };
+            let level = level.parse().map_err(|()| {
+                anyhow!(
+                    "{}",
+                    trf("invalid level {} in ABSORB_LOG", &[&format!("{:?}", level)])
+                )
+            })?;
+            directives.push((module.to_owned(), level));
+        }
+        // the longest module goes first, so that it's found first
+        directives.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(LogFilter { directives })
+    }
+
+    /// The level logged from the module, if any directive covers it.
+    pub fn level(&self, module: &str) -> Option<slog::FilterLevel> {
+        self.directives
+            .iter()
+            .find(|(prefix, _)| {
+                prefix.is_empty()
+                    || module == prefix
+                    || module
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map(|&(_, level)| level)
+    }
+
+    /// Filters the records logged to the drain, falling back to the
+    /// level for the modules that no directive covers.
+    pub fn filter<D: slog::Drain>(
+        self,
+        drain: D,
+        fallback: slog::Level,
+    ) -> slog::Filter<D, impl Fn(&slog::Record) -> bool> {
+        slog::Filter::new(drain, move |record: &slog::Record| {
+            match self.level(record.module()) {
+                Some(level) => level.accepts(record.level()),
+                None => record.level().is_at_least(fallback),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_most_specific_directive_wins() {
+        let filter =
+            LogFilter::parse("info, git_absorb_core=debug,git_absorb_core::stack=trace").unwrap();
+        assert_eq!(filter.level("git_absorb"), Some(slog::FilterLevel::Info));
+        assert_eq!(
+            filter.level("git_absorb_core::commute"),
+            Some(slog::FilterLevel::Debug)
+        );
+        assert_eq!(
+            filter.level("git_absorb_core::stack"),
+            Some(slog::FilterLevel::Trace)
+        );
+        // a module name is not a prefix of another that merely starts
+        // with it
+        assert_eq!(
+            LogFilter::parse("git_absorb=off")
+                .unwrap()
+                .level("git_absorb_core"),
+            None
+        );
+        assert!(LogFilter::parse("git_absorb=loud").is_err());
+    }
+}
@@ -5,46 +5,411 @@ extern crate clap;
 extern crate slog;
 
 use clap::Shell;
+use git_absorb::{tr, trf};
 use slog::Drain;
 use std::io;
 
+/// The flags that can be given with a `GIT_ABSORB_*` variable, set to a
+/// boolean. The ones that stand for a setting are left out, since the
+/// setting's variable is read with the rest of the configuration.
+const ENV_FLAGS: &[&str] = &[
+    "dry-run",
+    "force",
+    "force-pushed",
+    "force-state",
+    "strict-exit",
+    "quiet",
+    "verbose",
+    "profile",
+    "explain",
+    "and-rebase",
+    "paranoid",
+    "interactive",
+    "patch",
+    "all",
+    "edit",
+    "porcelain",
+    "whole-file",
+    "oldest",
+];
+
+/// The options that can be given with a `GIT_ABSORB_*` variable, set to
+/// their value. The ones whose value is optional can also be set to a
+/// boolean.
+const ENV_OPTIONS: &[&str] = &[
+    "base",
+    "target",
+    "include-author",
+    "log-file",
+    "rebase-options",
+    "gpg-sign",
+    "date",
+    "leftovers",
+    "output-format",
+    "path-format",
+];
+const ENV_OPTIONAL_VALUES: &[&str] = &["gpg-sign"];
+
+/// The arguments that can't be given together, so that a variable doesn't
+/// get in the way of the command line.
+const ENV_CONFLICTS: &[&[&str]] = &[
+    &["quiet", "verbose"],
+    &["porcelain", "output-format"],
+    &["all", "from", "stash", "patch-file"],
+];
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "" | "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// The arguments that the `GIT_ABSORB_*` variables stand for, leaving out
+/// the ones that were given on the command line.
+fn env_args(
+    args: &clap::ArgMatches,
+    env: &git_absorb::Layered,
+) -> Result<Vec<String>, clap::Error> {
+    let given = |name: &str| args.occurrences_of(name) > 0;
+    let mut env_args = Vec::new();
+    for &name in ENV_FLAGS.iter().chain(ENV_OPTIONS) {
+        if given(name)
+            || ENV_CONFLICTS
+                .iter()
+                .any(|group| group.contains(&name) && group.iter().any(|&other| given(other)))
+        {
+            continue;
+        }
+        let (var, value) = match env.env_flag(name) {
+            Some(var) => var,
+            None => continue,
+        };
+        if ENV_FLAGS.contains(&name) || ENV_OPTIONAL_VALUES.contains(&name) {
+            match parse_bool(value) {
+                Some(true) => env_args.push(format!("--{}", name)),
+                Some(false) => {}
+                None if ENV_FLAGS.contains(&name) => {
+                    return Err(clap::Error::with_description(
+                        &format!("{} is set to {:?}, which is not a boolean", var, value),
+                        clap::ErrorKind::InvalidValue,
+                    ))
+                }
+                None => env_args.push(format!("--{}={}", name, value)),
+            }
+            continue;
+        }
+        // the rebase options only make sense when rebasing
+        if name == "rebase-options"
+            && !given("and-rebase")
+            && !env_args.contains(&"--and-rebase".to_owned())
+        {
+            continue;
+        }
+        env_args.push(format!("--{}={}", name, value));
+    }
+    Ok(env_args)
+}
+
 fn main() {
+    let pathspec = clap::Arg::with_name("pathspec")
+        .help("Only absorb staged changes to files matching these pathspecs")
+        .multiple(true)
+        .last(true);
     let args = app_from_crate!()
         .about("Automatically absorb staged changes into your current branch")
         .arg(
             clap::Arg::with_name("base")
-                .help("Use this commit as the base of the absorb stack")
+                .help("Use this commit (or range, or all of these) as the base of the absorb stack")
                 .short("b")
                 .long("base")
-                .takes_value(true),
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("from")
+                .help("Absorb the changes of this commit (which must be HEAD) instead of the index")
+                .long("from")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("patch-file")
+                .help("Absorb the hunks of this patch (or of stdin, with -) instead of the index")
+                .long("patch-file")
+                .takes_value(true)
+                .value_name("file")
+                .conflicts_with_all(&["from", "stash"])
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("stash")
+                .help("Absorb the changes of this stash entry (stash@{0} by default) instead of the index")
+                .long("stash")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("stash")
+                .conflicts_with("from")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("target")
+                .help("Absorb all staged changes into this commit in the stack")
+                .short("t")
+                .long("target")
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             clap::Arg::with_name("dry-run")
                 .help("Don't make any actual changes")
                 .short("n")
                 .long("dry-run")
-                .takes_value(false),
+                .takes_value(false)
+                .global(true),
         )
         .arg(
             clap::Arg::with_name("force")
                 .help("Skip safety checks")
                 .short("f")
                 .long("force")
-                .takes_value(false),
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("force-pushed")
+                .help("Absorb into commits that were already pushed to a remote")
+                .long("force-pushed")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("force-state")
+                .help("Run even if a rebase, merge or similar operation is in progress")
+                .long("force-state")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("strict-exit")
+                .help("Exit with a non-zero code when some staged changes were not absorbed")
+                .long("strict-exit")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("include-author")
+                .help("Also absorb into commits authored with this email")
+                .long("include-author")
+                .takes_value(true)
+                .value_name("email")
+                .multiple(true)
+                .number_of_values(1)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("quiet")
+                .help("Only display errors")
+                .short("q")
+                .long("quiet")
+                .takes_value(false)
+                .conflicts_with("verbose")
+                .global(true),
         )
         .arg(
             clap::Arg::with_name("verbose")
                 .help("Display more output")
                 .short("v")
                 .long("verbose")
-                .takes_value(false),
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("profile")
+                .help("Print how long each phase took when exiting")
+                .long("profile")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("log-file")
+                .help("Also write the debug output to this file (appending to it)")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("path")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("explain")
+                .help("Explain why hunks could not be absorbed")
+                .long("explain")
+                .takes_value(false)
+                .global(true),
         )
         .arg(
             clap::Arg::with_name("and-rebase")
                 .help("Run rebase if successful")
                 .short("r")
                 .long("and-rebase")
-                .takes_value(false),
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("paranoid")
+                .help("Check that the fixups (and the rebase) lost none of the staged changes, and undo the run if they did")
+                .long("paranoid")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("rebase-options")
+                .help("Options to pass to git rebase, separated by spaces")
+                .long("rebase-options")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .use_delimiter(true)
+                .value_delimiter(" ")
+                .requires("and-rebase")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("interactive")
+                .help("Review and edit the fixups before they are committed")
+                .short("i")
+                .long("interactive")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("patch")
+                .help("Choose whether to absorb each hunk, like git add --patch")
+                .short("p")
+                .long("patch")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("all")
+                .help("Stage the changes to all tracked files first, like git commit -a")
+                .short("a")
+                .long("all")
+                .takes_value(false)
+                .conflicts_with_all(&["from", "stash", "patch-file"])
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("squash")
+                .help("Create squash! commits, whose message is added to the target's message")
+                .long("squash")
+                .takes_value(false)
+                .conflicts_with("amend")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("amend")
+                .help("Create amend! commits, whose message replaces the target's message")
+                .long("amend")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("edit")
+                .help("Edit the message of each squash! or amend! commit")
+                .long("edit")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("fixup-by-sha")
+                .help("Refer to the target of each fixup by its id rather than its subject")
+                .long("fixup-by-sha")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("signoff")
+                .help("Add a Signed-off-by trailer to each fixup")
+                .short("s")
+                .long("signoff")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("gpg-sign")
+                .help("Sign the fixups, optionally with the given key")
+                .short("S")
+                .long("gpg-sign")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .value_name("keyid")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("hooks")
+                .help("Run the pre-commit and commit-msg hooks for each fixup")
+                .long("hooks")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("date")
+                .help("Create the fixups at this date, given as \"<seconds> <+hhmm>\"")
+                .long("date")
+                .takes_value(true)
+                .value_name("when")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("leftovers")
+                .help("What to do with the changes that could not be absorbed")
+                .long("leftovers")
+                .takes_value(true)
+                .possible_values(&["stage", "commit"])
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("output-format")
+                .help("Format used to report the fixups on stdout")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(&["text", "json", "porcelain"])
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("path-format")
+                .help("How paths are written on stdout: quoted like git does (per core.quotePath), or literal")
+                .long("path-format")
+                .takes_value(true)
+                .possible_values(&["quoted", "literal"])
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("porcelain")
+                .help("Report the fixups on stdout in a stable format for scripts, like --output-format=porcelain")
+                .long("porcelain")
+                .takes_value(false)
+                .conflicts_with("output-format")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("jobs")
+                .help("Commute the staged files on this many threads (defaults to one per CPU)")
+                .short("j")
+                .long("jobs")
+                .takes_value(true)
+                .value_name("n")
+                .validator(|value| {
+                    value
+                        .parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| "must be a number".to_owned())
+                })
+                .global(true),
         )
         .arg(
             clap::Arg::with_name("gen-completions")
@@ -55,68 +420,484 @@ fn main() {
         )
         .arg(
             clap::Arg::with_name("whole-file")
-                .help("Match the change against the complete file   ")
+                .help("Absorb each hunk into the newest commit that touched its file")
                 .short("w")
                 .long("whole-file")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("oldest")
+                .help("Prefer the oldest of the commits that a hunk could be absorbed into")
+                .long("oldest")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("skip-bulk")
+                .help("Never absorb into commits that touched more than this many files (0 for no limit)")
+                .long("skip-bulk")
+                .takes_value(true)
+                .value_name("n")
+                .validator(|value| {
+                    value
+                        .parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| "must be a number".to_owned())
+                })
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("blame-fallback")
+                .help("Use git blame to find a commit for hunks that commute with the whole stack")
+                .long("blame-fallback")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("intra-line")
+                .help("Let hunks move past commits that changed other parts of the same lines")
+                .long("intra-line")
+                .takes_value(false)
+                .conflicts_with("and-rebase")
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("detect-moves")
+                .help("Absorb lines moved within a file into the commit of the lines they were moved from")
+                .long("detect-moves")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("diff-algorithm")
+                .help("Diff with this algorithm, like git diff --diff-algorithm (defaults to diff.algorithm)")
+                .long("diff-algorithm")
+                .takes_value(true)
+                .possible_values(&["myers", "default", "minimal", "patience", "histogram"])
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("unified")
+                .help("Group changes into hunks like git diff -U<n> does (defaults to diff.context, or 0)")
+                .short("U")
+                .long("unified")
+                .takes_value(true)
+                .value_name("n")
+                .validator(|value| {
+                    value
+                        .parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| "must be a number".to_owned())
+                })
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("new-files")
+                .help("Absorb new files into the newest commit that created a file next to them")
+                .long("new-files")
+                .takes_value(false)
+                .global(true),
+        )
+        .arg(
+            clap::Arg::with_name("undo")
+                .help("Restore HEAD and the index to their state before the last run")
+                .long("undo")
                 .takes_value(false),
+        )
+        .arg(
+            clap::Arg::with_name("continue")
+                .help("Continue the rebase of --and-rebase after resolving a conflict")
+                .long("continue")
+                .takes_value(false)
+                .conflicts_with_all(&["undo", "abort"]),
+        )
+        .arg(
+            clap::Arg::with_name("abort")
+                .help("Abort the rebase of --and-rebase and undo the whole run")
+                .long("abort")
+                .takes_value(false)
+                .conflicts_with("undo"),
+        )
+        .arg(
+            clap::Arg::with_name("check")
+                .help("Fail if the working tree or the index has changes that could be absorbed")
+                .long("check")
+                .takes_value(false)
+                .conflicts_with_all(&["undo", "continue", "abort"]),
+        )
+        .arg(
+            clap::Arg::with_name("watch")
+                .help("Plan the fixups again whenever the index changes, until interrupted")
+                .long("watch")
+                .takes_value(false)
+                .conflicts_with_all(&[
+                    "undo",
+                    "continue",
+                    "abort",
+                    "check",
+                    "stash",
+                    "patch-file",
+                ]),
+        )
+        .arg(
+            clap::Arg::with_name("commit")
+                .help("With --watch, also commit the fixups")
+                .long("commit")
+                .takes_value(false)
+                .requires("watch"),
+        )
+        .arg(pathspec.clone())
+        .subcommand(
+            clap::SubCommand::with_name("absorb")
+                .about("Absorb the staged changes, like running git absorb without a subcommand")
+                .arg(pathspec.clone()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("explain")
+                .about("Explain why staged hunks could not be absorbed, without changing anything")
+                .arg(pathspec),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("doctor")
+                .about("Report everything about the repository that affects absorbing"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("config")
+                .about("Check the settings of git absorb")
+                .arg(
+                    clap::Arg::with_name("validate")
+                        .help("Report settings that are unknown, invalid or in conflict")
+                        .long("validate")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("self-test")
+                .about("Absorb changes in made-up histories, checking that the fixups squash cleanly")
+                .arg(
+                    clap::Arg::with_name("iterations")
+                        .help("How many histories to make up")
+                        .long("iterations")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("100")
+                        .validator(|value| {
+                            value
+                                .parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|_| "must be a number".to_owned())
+                        }),
+                )
+                .arg(
+                    clap::Arg::with_name("seed")
+                        .help("Make up the same histories as an earlier run (defaults to a random seed)")
+                        .long("seed")
+                        .takes_value(true)
+                        .value_name("n")
+                        .validator(|value| {
+                            value
+                                .parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| "must be a number".to_owned())
+                        }),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("undo")
+                .about("Restore HEAD and the index to their state before the last run"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("log")
+                .about("Show the past runs, with the fixups they created, newest first"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("completions")
+                .about("Print the completions of git absorb for a shell")
+                .arg(
+                    clap::Arg::with_name("shell")
+                        .help("The shell to print completions for")
+                        .required(true)
+                        .possible_values(&["bash", "fish", "zsh", "powershell", "elvish"])
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("plan")
+                .about("Write the planned fixups to a file, without committing them")
+                .arg(
+                    clap::Arg::with_name("planfile")
+                        .help("Where to write the plan (defaults to stdout)")
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("apply")
+                .about("Commit the fixups described by a plan file")
+                .arg(
+                    clap::Arg::with_name("planfile")
+                        .help("The plan file to apply")
+                        .required(true)
+                        .index(1),
+                ),
         );
     let mut args_clone = args.clone();
     let args = args.get_matches();
 
-    if let Some(shell) = args.value_of("gen-completions") {
-        let app_name = "git-absorb";
-        match shell {
-            "bash" => {
-                args_clone.gen_completions_to(app_name, Shell::Bash, &mut io::stdout());
-            }
-            "fish" => {
-                args_clone.gen_completions_to(app_name, Shell::Fish, &mut io::stdout());
-            }
-            "zsh" => {
-                args_clone.gen_completions_to(app_name, Shell::Zsh, &mut io::stdout());
-            }
-            "powershell" => {
-                args_clone.gen_completions_to(app_name, Shell::PowerShell, &mut io::stdout());
-            }
-            "elvish" => {
-                args_clone.gen_completions_to(app_name, Shell::Elvish, &mut io::stdout());
-            }
+    let shell = match args.subcommand() {
+        ("completions", Some(sub_args)) => sub_args.value_of("shell"),
+        _ => args.value_of("gen-completions"),
+    };
+    if let Some(shell) = shell {
+        let shell = match shell {
+            "bash" => Shell::Bash,
+            "fish" => Shell::Fish,
+            "zsh" => Shell::Zsh,
+            "powershell" => Shell::PowerShell,
+            "elvish" => Shell::Elvish,
             _ => unreachable!(),
-        }
+        };
+        args_clone.gen_completions_to("git-absorb", shell, &mut io::stdout());
         return;
     }
 
+    // GIT_ABSORB_* variables stand in for the flags and options that
+    // weren't given, so the command line is parsed again with them, to
+    // validate them like the rest
+    let env_args = match env_args(
+        match args.subcommand() {
+            (_, Some(sub_args)) => sub_args,
+            _ => &args,
+        },
+        &git_absorb::Layered::env_only(),
+    ) {
+        Ok(env_args) => env_args,
+        Err(e) => e.exit(),
+    };
+    let args = if env_args.is_empty() {
+        args
+    } else {
+        let mut argv: Vec<std::ffi::OsString> = std::env::args_os().collect();
+        argv.splice(1..1, env_args.into_iter().map(Into::into));
+        args_clone.get_matches_from(argv)
+    };
+
+    // global arguments are recorded in the matches of the subcommand,
+    // if there is one
+    let (subcommand, args) = match args.subcommand() {
+        (name, Some(sub_args)) => (name, sub_args.clone()),
+        _ => ("", args),
+    };
+
+    // ABSORB_LOG overrides the level of -v and -q, for the modules it
+    // names
+    let (log_filter, log_filter_err) = match std::env::var("ABSORB_LOG") {
+        Ok(spec) => match git_absorb::LogFilter::parse(&spec) {
+            Ok(filter) => (filter, None),
+            Err(e) => (git_absorb::LogFilter::default(), Some(e)),
+        },
+        Err(_) => (git_absorb::LogFilter::default(), None),
+    };
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = log_filter
+        .clone()
+        .filter(
+            drain,
+            if args.is_present("verbose") {
+                slog::Level::Debug
+            } else if args.is_present("quiet") {
+                slog::Level::Error
+            } else {
+                slog::Level::Info
+            },
+        )
+        .fuse();
+    // the log file gets the debug output whatever the terminal gets,
+    // since it's meant to be attached to bug reports
+    let log_file: Box<dyn Drain<Ok = (), Err = slog::Never> + Send> =
+        match args.value_of("log-file").map(|path| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow::anyhow!("{}", trf("could not open {}: {}", &[&path, &e])))
+        }) {
+            Some(Ok(file)) => {
+                let decorator = slog_term::PlainSyncDecorator::new(file);
+                let drain = slog_term::FullFormat::new(decorator).build().fuse();
+                Box::new(log_filter.filter(drain, slog::Level::Debug).fuse())
+            }
+            Some(Err(e)) => {
+                let logger = slog::Logger::root(slog_async::Async::new(drain).build().fuse(), o!());
+                crit!(logger, "{}", tr("absorb failed"); "err" => e.to_string());
+                drop(logger);
+                ::std::process::exit(1);
+            }
+            None => Box::new(slog::Discard),
+        };
+    let drain = slog::Duplicate::new(drain, log_file).fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
-    let drain = slog::LevelFilter::new(
-        drain,
-        if args.is_present("verbose") {
-            slog::Level::Debug
-        } else {
-            slog::Level::Info
-        },
-    )
-    .fuse();
-    let mut logger = slog::Logger::root(drain, o!());
-    if args.is_present("verbose") {
+    let mut logger = slog::Logger::root(git_absorb::Translate::new(drain), o!());
+    if let Some(e) = log_filter_err {
+        crit!(logger, "{}", tr("absorb failed"); "err" => e.to_string());
+        drop(logger);
+        ::std::process::exit(1);
+    }
+    if args.is_present("verbose") || args.is_present("log-file") {
         logger = logger.new(o!(
             "module" => slog::FnValue(|record| record.module()),
             "line" => slog::FnValue(|record| record.line()),
         ));
     }
 
-    if let Err(e) = git_absorb::run(&git_absorb::Config {
-        dry_run: args.is_present("dry-run"),
+    let profile = if args.is_present("profile") {
+        Some(git_absorb::Profile::default())
+    } else {
+        None
+    };
+
+    // explain is a dry run that says why each hunk goes where it goes
+    let explain = subcommand == "explain";
+    let dry_run = explain || args.is_present("dry-run");
+    let strict_exit = args.is_present("strict-exit");
+    let result = git_absorb::run(&git_absorb::Config {
+        dry_run,
         force: args.is_present("force"),
-        base: args.value_of("base"),
+        force_pushed: args.is_present("force-pushed"),
+        force_state: args.is_present("force-state"),
+        include_authors: args
+            .values_of("include-author")
+            .map(|values| values.collect())
+            .unwrap_or_default(),
+        base: args
+            .values_of("base")
+            .map(|values| values.collect())
+            .unwrap_or_default(),
+        from: args.value_of("from"),
+        patch_file: args.value_of("patch-file"),
+        stash: if args.is_present("stash") {
+            Some(args.value_of("stash").unwrap_or("stash@{0}"))
+        } else {
+            None
+        },
+        target: args.value_of("target"),
         and_rebase: args.is_present("and-rebase"),
+        paranoid: args.is_present("paranoid"),
         whole_file: args.is_present("whole-file"),
+        oldest: args.is_present("oldest"),
+        // validated above
+        skip_bulk: args
+            .value_of("skip-bulk")
+            .map(|files| files.parse().unwrap()),
+        blame_fallback: args.is_present("blame-fallback"),
+        intra_line: args.is_present("intra-line"),
+        detect_moves: args.is_present("detect-moves"),
+        new_files: args.is_present("new-files"),
+        diff_algorithm: args
+            .value_of("diff-algorithm")
+            .map(|algorithm| match algorithm {
+                "minimal" => git_absorb::DiffAlgorithm::Minimal,
+                "patience" => git_absorb::DiffAlgorithm::Patience,
+                "histogram" => git_absorb::DiffAlgorithm::Histogram,
+                _ => git_absorb::DiffAlgorithm::Myers,
+            }),
+        unified: args
+            .value_of("unified")
+            .map(|context| context.parse().unwrap()),
+        interactive: args.is_present("interactive"),
+        patch: args.is_present("patch"),
+        all: args.is_present("all"),
+        explain: explain || args.is_present("explain"),
+        verbose: args.is_present("verbose"),
+        output_format: match args.value_of("output-format") {
+            Some("json") => git_absorb::OutputFormat::Json,
+            Some("porcelain") => git_absorb::OutputFormat::Porcelain,
+            _ if args.is_present("porcelain") => git_absorb::OutputFormat::Porcelain,
+            _ => git_absorb::OutputFormat::Text,
+        },
+        path_format: match args.value_of("path-format") {
+            Some("literal") => git_absorb::PathFormat::Literal,
+            _ => git_absorb::PathFormat::Quoted,
+        },
+        fixup_kind: if args.is_present("squash") {
+            Some(git_absorb::FixupKind::Squash)
+        } else if args.is_present("amend") {
+            Some(git_absorb::FixupKind::Amend)
+        } else {
+            None
+        },
+        edit_message: args.is_present("edit"),
+        fixup_by_sha: args.is_present("fixup-by-sha"),
+        signoff: args.is_present("signoff"),
+        gpg_sign: args.is_present("gpg-sign"),
+        signing_key: args.value_of("gpg-sign"),
+        run_hooks: args.is_present("hooks"),
+        date: args.value_of("date"),
+        rebase_options: args
+            .values_of("rebase-options")
+            .map(|values| values.filter(|value| !value.is_empty()).collect())
+            .unwrap_or_default(),
+        leftovers: match args.value_of("leftovers") {
+            Some("commit") => git_absorb::Leftovers::Commit,
+            _ => git_absorb::Leftovers::Stage,
+        },
+        // validated above
+        jobs: args.value_of("jobs").map(|jobs| jobs.parse().unwrap()),
+        action: match subcommand {
+            "plan" => git_absorb::Action::WritePlan(args.value_of("planfile")),
+            "apply" => git_absorb::Action::ApplyPlan(args.value_of("planfile").unwrap()),
+            "undo" => git_absorb::Action::Undo,
+            "log" => git_absorb::Action::Log,
+            "doctor" => git_absorb::Action::Doctor,
+            "config" => git_absorb::Action::ValidateConfig,
+            "self-test" => git_absorb::Action::SelfTest {
+                iterations: args.value_of("iterations").unwrap().parse().unwrap(),
+                seed: match args.value_of("seed") {
+                    Some(seed) => seed.parse().unwrap(),
+                    None => std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map_or(0, |elapsed| elapsed.as_nanos() as u64),
+                },
+            },
+            _ if args.is_present("undo") => git_absorb::Action::Undo,
+            _ if args.is_present("continue") => git_absorb::Action::Continue,
+            _ if args.is_present("abort") => git_absorb::Action::Abort,
+            _ if args.is_present("check") => git_absorb::Action::Check,
+            _ if args.is_present("watch") => git_absorb::Action::Watch {
+                commit: args.is_present("commit"),
+            },
+            _ => git_absorb::Action::Absorb,
+        },
+        pathspecs: args
+            .values_of("pathspec")
+            .map(|values| values.collect())
+            .unwrap_or_default(),
+        profile: profile.as_ref(),
         logger: &logger,
-    }) {
-        crit!(logger, "absorb failed"; "err" => e.to_string());
-        // wait for async logger to finish writing messages
-        drop(logger);
-        ::std::process::exit(1);
+    });
+    // the profile goes to stderr, so that it doesn't mix with the output
+    // of the run, but after the log
+    let report_profile = || {
+        if let Some(profile) = &profile {
+            let _ = profile.report(&mut io::stderr());
+        }
+    };
+    match result {
+        // the exit code tells scripts whether everything staged could be
+        // absorbed, which a dry run is asked for, and a real run only
+        // tells when asked
+        Ok(outcome) => {
+            drop(logger);
+            report_profile();
+            ::std::process::exit(outcome.exit_code(dry_run, strict_exit));
+        }
+        Err(e) => {
+            crit!(logger, "{}", tr("absorb failed"); "err" => git_absorb::tr_error(&e));
+            // wait for async logger to finish writing messages
+            drop(logger);
+            report_profile();
+            ::std::process::exit(git_absorb::exit_code(&e));
+        }
     }
 }
@@ -0,0 +1,410 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use std::io::Write;
+
+use crate::i18n::trf;
+use crate::{config, plan, PathFormat};
+
+#[derive(Serialize)]
+struct JsonPlan {
+    fixups: Vec<JsonFixup>,
+    skipped: Vec<JsonSkipped>,
+}
+
+#[derive(Serialize)]
+struct JsonFixup {
+    path: String,
+    header: String,
+    target: String,
+    target_summary: Option<String>,
+    /// The fixup commit that was created, or `None` for a dry run.
+    commit: Option<String>,
+}
+
+#[derive(Serialize)]
+struct JsonSkipped {
+    path: String,
+    /// `None` if the whole file was skipped.
+    header: Option<String>,
+    reason: &'static str,
+    /// The delta status of a skipped file, eg: "added" or "deleted".
+    status: Option<String>,
+}
+
+/// Writes the plan to stdout as a single JSON document.
+///
+/// `committed` holds the ids of the fixup commits that were created,
+/// in the same order as `plan.hunks_to_apply()`.
+pub fn print_json(plan: &plan::Plan, committed: &[git2::Oid]) -> Result<()> {
+    let mut committed = committed.iter();
+    let mut ret = JsonPlan {
+        fixups: Vec::new(),
+        skipped: Vec::new(),
+    };
+
+    for (assignment, hunk) in plan.hunks_to_apply() {
+        // unwrap() is safe here, as hunks_to_apply() only returns
+        // hunks that have a target
+        let target = assignment.target.as_ref().unwrap();
+        ret.fixups.push(JsonFixup {
+            path: String::from_utf8_lossy(&assignment.path).into_owned(),
+            header: hunk.header(),
+            target: target.id().to_string(),
+            target_summary: target.summary().map(String::from),
+            commit: committed.next().map(|oid| oid.to_string()),
+        });
+    }
+    for assignment in &plan.assignments {
+        if let Some(reason) = assignment.skip_reason {
+            ret.skipped.push(JsonSkipped {
+                path: String::from_utf8_lossy(&assignment.path).into_owned(),
+                header: Some(assignment.hunk.header()),
+                reason: reason.as_str(),
+                status: None,
+            });
+        }
+    }
+    for skipped in &plan.skipped_paths {
+        ret.skipped.push(JsonSkipped {
+            path: String::from_utf8_lossy(&skipped.path).into_owned(),
+            header: None,
+            reason: skipped.reason.as_str(),
+            status: match skipped.reason {
+                plan::SkipReason::NotModified(status) => {
+                    Some(format!("{:?}", status).to_lowercase())
+                }
+                _ => None,
+            },
+        });
+    }
+
+    serde_json::to_writer_pretty(std::io::stdout(), &ret)?;
+    println!();
+    Ok(())
+}
+
+/// The version of the porcelain format, which is only bumped for
+/// changes that could break a parser.
+const PORCELAIN_VERSION: u32 = 1;
+
+/// Returns the plan in the porcelain format, which is meant to be
+/// parsed by other tools and is kept stable across releases:
+///
+/// - `version\t<n>` comes first, where `<n>` is the format version
+/// - `fixup\t<path>\t<header>\t<target>\t<commit>` for each hunk that
+///   is absorbed, where `<commit>` is the created fixup, or `-` for a
+///   dry run
+/// - `skip\t<path>\t<header>\t<reason>` for each hunk left in the
+///   index, where `<header>` is `-` if the whole file was skipped
+///
+/// Headers look like `-1,2 +1,3`, commits are full ids, and paths are
+/// quoted like git does if they contain special characters. Fields may
+/// be added at the end of a line without changing the version.
+pub fn porcelain_lines(
+    plan: &plan::Plan,
+    committed: &[git2::Oid],
+    path_style: PathStyle,
+) -> Vec<String> {
+    let mut committed = committed.iter();
+    let mut ret = vec![format!("version\t{}", PORCELAIN_VERSION)];
+    for (assignment, hunk) in plan.hunks_to_apply() {
+        // unwrap() is safe here, as hunks_to_apply() only returns
+        // hunks that have a target
+        ret.push(format!(
+            "fixup\t{}\t{}\t{}\t{}",
+            path_style.format(&assignment.path),
+            hunk.header(),
+            assignment.target.as_ref().unwrap().id(),
+            committed
+                .next()
+                .map_or_else(|| "-".to_owned(), |oid| oid.to_string())
+        ));
+    }
+    for assignment in &plan.assignments {
+        if let Some(reason) = assignment.skip_reason {
+            ret.push(format!(
+                "skip\t{}\t{}\t{}",
+                path_style.format(&assignment.path),
+                assignment.hunk.header(),
+                reason.as_str()
+            ));
+        }
+    }
+    for skipped in &plan.skipped_paths {
+        ret.push(format!(
+            "skip\t{}\t-\t{}",
+            path_style.format(&skipped.path),
+            skipped.reason.as_str()
+        ));
+    }
+    ret
+}
+
+/// Writes the plan to stdout in the porcelain format.
+pub fn print_porcelain(
+    plan: &plan::Plan,
+    committed: &[git2::Oid],
+    path_style: PathStyle,
+) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for line in porcelain_lines(plan, committed, path_style) {
+        writeln!(stdout, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// How paths are written on stdout, per `--path-format` and
+/// `core.quotePath`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathStyle {
+    quote: bool,
+    quote_non_ascii: bool,
+}
+impl Default for PathStyle {
+    /// Quotes paths like git does by default.
+    fn default() -> Self {
+        PathStyle {
+            quote: true,
+            quote_non_ascii: true,
+        }
+    }
+}
+impl PathStyle {
+    pub fn new(repo: &git2::Repository, format: PathFormat) -> Self {
+        PathStyle {
+            quote: format == PathFormat::Quoted,
+            quote_non_ascii: config::quote_path(repo),
+        }
+    }
+
+    /// Formats the path for stdout. Paths that aren't valid UTF-8 are
+    /// quoted even when literal paths were asked for.
+    pub fn format(&self, path: &[u8]) -> String {
+        if self.quote {
+            quote_path(path, self.quote_non_ascii)
+        } else {
+            match std::str::from_utf8(path) {
+                Ok(path) => path.to_owned(),
+                Err(_) => quote_path(path, true),
+            }
+        }
+    }
+}
+
+/// Quotes a path like git does: paths with control characters, double
+/// quotes or backslashes (or, with `non_ascii`, bytes outside of ASCII,
+/// like with `core.quotePath` enabled) are wrapped in double quotes,
+/// with those characters escaped. Paths that aren't valid UTF-8 always
+/// have their bytes outside of ASCII escaped, so that nothing is lost.
+fn quote_path(path: &[u8], non_ascii: bool) -> String {
+    let non_ascii = non_ascii || std::str::from_utf8(path).is_err();
+    let special = |byte: u8| {
+        byte < 0x20 || byte == 0x7f || byte == b'"' || byte == b'\\' || (non_ascii && byte >= 0x80)
+    };
+    if !path.iter().copied().any(special) {
+        return String::from_utf8_lossy(path).into_owned();
+    }
+    let mut ret = Vec::with_capacity(path.len() + 2);
+    ret.push(b'"');
+    for &byte in path {
+        match byte {
+            b'"' => ret.extend_from_slice(b"\\\""),
+            b'\\' => ret.extend_from_slice(b"\\\\"),
+            b'\t' => ret.extend_from_slice(b"\\t"),
+            b'\n' => ret.extend_from_slice(b"\\n"),
+            _ if special(byte) => ret.extend_from_slice(format!("\\{:03o}", byte).as_bytes()),
+            _ => ret.push(byte),
+        }
+    }
+    ret.push(b'"');
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+/// The widest that the `+` and `-` bar of a file in a diffstat gets,
+/// like `git diff --stat`.
+const DIFFSTAT_WIDTH: usize = 50;
+
+/// Writes a summary of the hunks that have a target to stdout, in the
+/// format of `git diff --stat`.
+pub fn print_diffstat(plan: &plan::Plan, path_style: PathStyle) -> Result<()> {
+    // (path, added lines, removed lines), in the order of the plan
+    let mut files: Vec<(String, usize, usize)> = Vec::new();
+    for (assignment, hunk) in plan.hunks_to_apply() {
+        let path = path_style.format(&assignment.path);
+        let (added, removed) = (hunk.added.lines.len(), hunk.removed.lines.len());
+        match files.last_mut() {
+            Some((last, last_added, last_removed)) if *last == path => {
+                *last_added += added;
+                *last_removed += removed;
+            }
+            _ => files.push((path, added, removed)),
+        }
+    }
+
+    let path_width = files.iter().map(|(path, ..)| path.len()).max().unwrap_or(0);
+    let most_changes = files
+        .iter()
+        .map(|(_, added, removed)| added + removed)
+        .max()
+        .unwrap_or(0);
+    // large changes are scaled down, but never to nothing
+    let scale = |lines: usize| match lines {
+        0 => 0,
+        _ if most_changes <= DIFFSTAT_WIDTH => lines,
+        _ => (lines * DIFFSTAT_WIDTH / most_changes).max(1),
+    };
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    for (path, added, removed) in &files {
+        writeln!(
+            stdout,
+            " {:width$} | {:>4} {}{}",
+            path,
+            added + removed,
+            "+".repeat(scale(*added)),
+            "-".repeat(scale(*removed)),
+            width = path_width
+        )?;
+    }
+    writeln!(
+        stdout,
+        " {} files changed, {} insertions(+), {} deletions(-)",
+        files.len(),
+        files.iter().map(|(_, added, _)| added).sum::<usize>(),
+        files.iter().map(|(_, _, removed)| removed).sum::<usize>()
+    )?;
+    Ok(())
+}
+
+/// Returns a summary of the plan, with a line for each target commit
+/// (in the order they appear in the plan) giving the number of files,
+/// hunks and lines that are absorbed into it, like `git diff --stat`,
+/// and a last line counting what was left in the index.
+pub fn summary_lines(plan: &plan::Plan) -> Vec<String> {
+    struct Row {
+        target: git2::Oid,
+        label: String,
+        files: Vec<Vec<u8>>,
+        hunks: usize,
+        added: usize,
+        removed: usize,
+    }
+
+    let mut rows: Vec<Row> = Vec::new();
+    for (assignment, hunk) in plan.hunks_to_apply() {
+        // unwrap() is safe here, as hunks_to_apply() only returns
+        // hunks that have a target
+        let target = assignment.target.as_ref().unwrap();
+        let idx = match rows.iter().position(|row| row.target == target.id()) {
+            Some(idx) => idx,
+            None => {
+                rows.push(Row {
+                    target: target.id(),
+                    label: format!(
+                        "{} {}",
+                        &target.id().to_string()[..7],
+                        target.summary().unwrap_or_default()
+                    ),
+                    files: Vec::new(),
+                    hunks: 0,
+                    added: 0,
+                    removed: 0,
+                });
+                rows.len() - 1
+            }
+        };
+        let row = &mut rows[idx];
+        if !row.files.contains(&assignment.path) {
+            row.files.push(assignment.path.clone());
+        }
+        row.hunks += 1;
+        row.added += hunk.added.lines.len();
+        row.removed += hunk.removed.lines.len();
+    }
+
+    let plural = |count: usize, one: &'static str, many: &'static str| {
+        trf(if count == 1 { one } else { many }, &[&count])
+    };
+    let label_width = rows.iter().map(|row| row.label.len()).max().unwrap_or(0);
+    let mut ret: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            format!(
+                "{:width$} | {}, {}, +{} -{}",
+                row.label,
+                plural(row.files.len(), "{} file", "{} files"),
+                plural(row.hunks, "{} hunk", "{} hunks"),
+                row.added,
+                row.removed,
+                width = label_width
+            )
+        })
+        .collect();
+
+    let skipped_hunks = plan
+        .assignments
+        .iter()
+        .filter(|assignment| assignment.target.is_none())
+        .count();
+    ret.push(trf(
+        "{} absorbed into {}, {} and {} left in the index",
+        &[
+            &plural(
+                rows.iter().map(|row| row.hunks).sum(),
+                "{} hunk",
+                "{} hunks",
+            ),
+            &plural(rows.len(), "{} commit", "{} commits"),
+            &plural(skipped_hunks, "{} hunk", "{} hunks"),
+            &plural(plan.skipped_paths.len(), "{} file", "{} files"),
+        ],
+    ));
+    let generated = plan
+        .skipped_paths
+        .iter()
+        .filter(|skipped| skipped.reason == plan::SkipReason::Generated)
+        .count();
+    if generated > 0 {
+        ret.push(plural(
+            generated,
+            "{} of them is generated, per its attributes",
+            "{} of them are generated, per their attributes",
+        ));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_path() {
+        assert_eq!(quote_path(b"src/main.rs", true), "src/main.rs");
+        assert_eq!(quote_path("caf\u{e9}".as_bytes(), false), "caf\u{e9}");
+        assert_eq!(
+            quote_path("caf\u{e9}".as_bytes(), true),
+            "\"caf\\303\\251\""
+        );
+        assert_eq!(quote_path(b"\xff", false), "\"\\377\"");
+        assert_eq!(quote_path(b"\xff", true), "\"\\377\"");
+        assert_eq!(
+            quote_path(b"a\tb\"c\\d\x01", false),
+            "\"a\\tb\\\"c\\\\d\\001\""
+        );
+    }
+
+    #[test]
+    fn test_literal_path_format() {
+        let literal = PathStyle {
+            quote: false,
+            quote_non_ascii: true,
+        };
+        assert_eq!(literal.format(b"a\tb"), "a\tb");
+        assert_eq!(literal.format("caf\u{e9}".as_bytes()), "caf\u{e9}");
+        assert_eq!(literal.format(b"caf\xe9"), "\"caf\\351\"");
+    }
+}
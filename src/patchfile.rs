@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::backend::Backend;
+use crate::i18n::{tr, trf};
+
+/// A patch given with `--patch-file`, applied to a tree as far as it
+/// goes.
+pub struct Applied<'repo> {
+    /// The tree with every hunk of the patch that applied.
+    pub tree: git2::Tree<'repo>,
+    /// The hunks that didn't apply, in the format of a `.rej` file,
+    /// for each path.
+    pub rejected: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Reads a patch (as written by `git diff` or `git format-patch`) from
+/// the path, or from stdin if it's `-`.
+pub fn read(path: &str) -> Result<git2::Diff<'static>> {
+    let mut content = Vec::new();
+    if path == "-" {
+        std::io::stdin().read_to_end(&mut content)?;
+    } else {
+        content = std::fs::read(path)
+            .map_err(|e| anyhow!("{}", trf("could not read {}: {}", &[&path, &e])))?;
+    }
+    git2::Diff::from_buffer(&content)
+        .map_err(|e| anyhow!("{}", trf("could not parse {}: {}", &[&path, &e])))
+}
+
+/// Applies the patch to the tree, leaving out the files and the hunks
+/// that don't apply.
+///
+/// libgit2 either applies a whole patch or nothing, so the files and
+/// then their hunks are added one at a time, and kept if the patch still
+/// applies with them.
+pub fn apply<'repo>(
+    repo: &'repo git2::Repository,
+    tree: &git2::Tree,
+    diff: &git2::Diff,
+) -> Result<Applied<'repo>> {
+    let mut hunks = Vec::with_capacity(diff.deltas().len());
+    for idx in 0..diff.deltas().len() {
+        hunks.push(match git2::Patch::from_diff(diff, idx)? {
+            Some(patch) => patch.num_hunks(),
+            None => 0,
+        });
+    }
+    // libgit2 calls back for each file in order, and then for each of
+    // its hunks if the file is kept
+    let try_apply = |deltas: &[bool], accepted: &[Vec<bool>]| {
+        let delta_idx = Cell::new(None);
+        let hunk_idx = Cell::new(0);
+        let mut options = git2::ApplyOptions::new();
+        options.delta_callback(|_| {
+            let delta = delta_idx.get().map_or(0, |idx: usize| idx + 1);
+            delta_idx.set(Some(delta));
+            hunk_idx.set(0);
+            deltas[delta]
+        });
+        options.hunk_callback(|_| {
+            let hunk = hunk_idx.get();
+            hunk_idx.set(hunk + 1);
+            delta_idx
+                .get()
+                .is_some_and(|delta| accepted[delta].get(hunk) == Some(&true))
+        });
+        repo.apply_to_tree(tree, diff, Some(&mut options))
+    };
+
+    let mut deltas = vec![false; hunks.len()];
+    let mut accepted: Vec<Vec<bool>> = hunks.iter().map(|&count| vec![false; count]).collect();
+    for delta in 0..hunks.len() {
+        deltas[delta] = true;
+        if try_apply(&deltas, &accepted).is_err() {
+            deltas[delta] = false;
+            continue;
+        }
+        for hunk in 0..hunks[delta] {
+            accepted[delta][hunk] = true;
+            if try_apply(&deltas, &accepted).is_err() {
+                accepted[delta][hunk] = false;
+            }
+        }
+    }
+    let mut index = try_apply(&deltas, &accepted)?;
+    let tree = repo.find_tree(index.write_tree_to(repo)?)?;
+
+    let mut rejected = BTreeMap::new();
+    for (delta, accepted) in accepted.iter().enumerate() {
+        if deltas[delta] && accepted.iter().all(|&accepted| accepted) {
+            continue;
+        }
+        let mut patch = match git2::Patch::from_diff(diff, delta)? {
+            Some(patch) => patch,
+            None => continue,
+        };
+        let mut text = Vec::new();
+        for (hunk, &accepted) in accepted.iter().enumerate() {
+            if !deltas[delta] || !accepted {
+                push_hunk(&mut text, &mut patch, hunk)?;
+            }
+        }
+        rejected.insert(patch_path(&patch), text);
+    }
+    Ok(Applied { tree, rejected })
+}
+
+/// Collects the hunks of the diff in the format of a `.rej` file, for
+/// each path.
+pub fn rejects(diff: &git2::Diff) -> Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut ret = BTreeMap::new();
+    for delta in 0..diff.deltas().len() {
+        let mut patch = match git2::Patch::from_diff(diff, delta)? {
+            Some(patch) => patch,
+            None => continue,
+        };
+        let mut text = Vec::new();
+        for hunk in 0..patch.num_hunks() {
+            push_hunk(&mut text, &mut patch, hunk)?;
+        }
+        ret.insert(patch_path(&patch), text);
+    }
+    Ok(ret)
+}
+
+/// Writes the rejected hunks of each file next to it, as `<path>.rej`,
+/// like `git apply --reject` does. Returns the paths written.
+pub fn write_rejects(
+    repo: &git2::Repository,
+    rejected: &BTreeMap<Vec<u8>, Vec<u8>>,
+) -> Result<Vec<std::path::PathBuf>> {
+    let workdir = repo
+        .workdir()
+        .ok_or_else(|| anyhow!("{}", tr("cannot write rejected hunks in a bare repository")))?;
+    let mut written = Vec::new();
+    for (path, hunks) in rejected {
+        let name = crate::path_from_bytes(path)
+            .ok_or_else(|| anyhow!("{}", tr("cannot write rejected hunks of a non-utf8 path")))?;
+        let mut rej_name = name.as_os_str().to_owned();
+        rej_name.push(".rej");
+        let rej_path = workdir.join(rej_name);
+        let mut content = b"diff a/".to_vec();
+        content.extend_from_slice(path);
+        content.extend_from_slice(b" b/");
+        content.extend_from_slice(path);
+        content.extend_from_slice(b"\t(rejected hunks)\n");
+        content.extend_from_slice(hunks);
+        if let Some(parent) = rej_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&rej_path, content)?;
+        written.push(rej_path);
+    }
+    Ok(written)
+}
+
+/// Applies the changes between the trees to the working tree and then to
+/// the index. Either may still have other changes to the same files, as
+/// long as they don't overlap.
+pub fn apply_to_workdir(
+    repo: &git2::Repository,
+    backend: &dyn Backend,
+    from: &git2::Tree,
+    to: &git2::Tree,
+) -> Result<()> {
+    let diff = repo.diff_tree_to_tree(Some(from), Some(to), None)?;
+    backend.apply(repo, &diff)
+}
+
+fn patch_path(patch: &git2::Patch) -> Vec<u8> {
+    let delta = patch.delta();
+    delta
+        .new_file()
+        .path_bytes()
+        .or_else(|| delta.old_file().path_bytes())
+        .unwrap_or_default()
+        .to_vec()
+}
+
+fn push_hunk(text: &mut Vec<u8>, patch: &mut git2::Patch, hunk: usize) -> Result<()> {
+    text.extend_from_slice(patch.hunk(hunk)?.0.header());
+    for idx in 0..patch.num_lines_in_hunk(hunk)? {
+        let line = patch.line_in_hunk(hunk, idx)?;
+        // the "\ No newline at end of file" markers come with their own
+        // text
+        if let origin @ ('+' | '-' | ' ') = line.origin() {
+            text.push(origin as u8);
+        }
+        text.extend_from_slice(line.content());
+    }
+    Ok(())
+}
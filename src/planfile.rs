@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+
+use std::fs;
+use std::io::Write;
+
+use crate::backend::Backend;
+use crate::i18n::{tr, trf};
+use crate::plan;
+
+const HELP: &str = "\
+#
+# Commands:
+# fixup <commit> <hunk> <path> = absorb the hunk into <commit>
+# skip <hunk> <path> = leave the hunk in the index
+#
+# The target of each hunk can be changed, and hunks can be switched
+# between fixup and skip, but lines must not be removed, reordered or
+# added. Apply the plan with `git absorb apply <file>`.
+";
+
+/// Writes the plan in a format similar to a rebase todo list, either
+/// to the given path or to stdout.
+pub fn write(
+    repo: &git2::Repository,
+    backend: &dyn Backend,
+    plan: &plan::Plan,
+    stack: &[git2::Commit],
+    path: Option<&str>,
+) -> Result<()> {
+    let mut ret = String::new();
+    ret.push_str(&format!("head {}\n", repo.head()?.peel_to_commit()?.id()));
+    ret.push_str(&format!("index {}\n", backend.write_index_tree(repo)?));
+    ret.push_str("#\n# Stack (newest first):\n");
+    for commit in stack {
+        ret.push_str(&format!(
+            "#   {} {}\n",
+            short_id(commit)?,
+            commit.summary().unwrap_or("")
+        ));
+    }
+    ret.push('\n');
+    for (assignment, header) in plan.assignments.iter().zip(index_headers(plan)) {
+        let path = String::from_utf8_lossy(&assignment.path);
+        match assignment.target {
+            Some(ref target) => ret.push_str(&format!(
+                "fixup {} {} {}\n",
+                short_id(target)?,
+                header,
+                path
+            )),
+            None => ret.push_str(&format!("skip {} {}\n", header, path)),
+        }
+    }
+    ret.push_str(HELP);
+
+    match path {
+        Some(path) => fs::write(path, ret)?,
+        None => std::io::stdout().write_all(ret.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Reads a plan file written by `write()`, and retargets the hunks in
+/// `plan` accordingly.
+///
+/// The plan file must have been written for the current HEAD and
+/// index, and must list exactly the hunks in `plan`.
+pub fn read<'repo>(
+    repo: &'repo git2::Repository,
+    backend: &dyn Backend,
+    plan: &mut plan::Plan<'repo>,
+    path: &str,
+) -> Result<()> {
+    let content = fs::read_to_string(path)?;
+    let mut head = None;
+    let mut index = None;
+    let mut entries = Vec::new();
+
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad_line = || {
+            anyhow!(
+                "{}",
+                trf("invalid line {} in plan file: {}", &[&(lineno + 1), &line])
+            )
+        };
+        let (command, rest) = line.split_once(' ').ok_or_else(bad_line)?;
+        match command {
+            "head" => head = Some(git2::Oid::from_str(rest.trim())?),
+            "index" => index = Some(git2::Oid::from_str(rest.trim())?),
+            "fixup" => {
+                let (target, rest) = rest.split_once(' ').ok_or_else(bad_line)?;
+                let target = repo.revparse_single(target)?.peel_to_commit()?;
+                entries.push((Some(target), parse_hunk(rest).ok_or_else(bad_line)?));
+            }
+            "skip" => entries.push((None, parse_hunk(rest).ok_or_else(bad_line)?)),
+            _ => return Err(bad_line()),
+        }
+    }
+
+    if head != Some(repo.head()?.peel_to_commit()?.id()) {
+        return Err(anyhow!(
+            "{}",
+            tr("HEAD has moved since the plan file was written")
+        ));
+    }
+    if index != Some(backend.write_index_tree(repo)?) {
+        return Err(anyhow!(
+            "{}",
+            tr("the index has changed since the plan file was written")
+        ));
+    }
+    if entries.len() != plan.assignments.len() {
+        return Err(anyhow!(
+            "{}",
+            trf(
+                "plan file lists {} hunks, but {} are staged",
+                &[&entries.len(), &plan.assignments.len()]
+            )
+        ));
+    }
+
+    let headers = index_headers(plan);
+    for ((assignment, header), (target, (entry_header, entry_path))) in
+        plan.assignments.iter_mut().zip(headers).zip(entries)
+    {
+        if header != entry_header || String::from_utf8_lossy(&assignment.path) != entry_path {
+            return Err(anyhow!(
+                "{}",
+                trf(
+                    "plan file lists hunk {} {}, but the staged hunk is {} {}",
+                    &[
+                        &entry_header,
+                        &entry_path,
+                        &header,
+                        &String::from_utf8_lossy(&assignment.path)
+                    ]
+                )
+            ));
+        }
+        assignment.retarget(target, plan::SkipReason::Dropped);
+    }
+    Ok(())
+}
+
+/// Splits "-1,2 +1,3 some/path" into the header and the path.
+fn parse_hunk(rest: &str) -> Option<(String, &str)> {
+    let mut parts = rest.splitn(3, ' ');
+    let removed = parts.next().filter(|part| part.starts_with('-'))?;
+    let added = parts.next().filter(|part| part.starts_with('+'))?;
+    let path = parts.next().filter(|path| !path.is_empty())?;
+    Some((format!("{} {}", removed, added), path))
+}
+
+/// Returns the header of each hunk as it appears in the index (ie: as
+/// shown by `git diff --cached`), rather than as if it were the only
+/// hunk in its file.
+fn index_headers(plan: &plan::Plan) -> Vec<String> {
+    let mut ret = Vec::with_capacity(plan.assignments.len());
+    let mut current_path: Option<&[u8]> = None;
+    let mut preceding_hunks_offset = 0isize;
+    for assignment in &plan.assignments {
+        if current_path != Some(assignment.path.as_slice()) {
+            current_path = Some(assignment.path.as_slice());
+            preceding_hunks_offset = 0;
+        }
+        ret.push(
+            assignment
+                .hunk
+                .clone()
+                .shift_added_block(preceding_hunks_offset)
+                .header(),
+        );
+        preceding_hunks_offset += assignment.hunk.changed_offset();
+    }
+    ret
+}
+
+fn short_id(commit: &git2::Commit) -> Result<String> {
+    Ok(commit
+        .as_object()
+        .short_id()?
+        .as_str()
+        .unwrap_or_default()
+        .to_owned())
+}
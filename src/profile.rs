@@ -0,0 +1,129 @@
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The time spent in each phase of a run, as recorded for `--profile`.
+#[derive(Debug)]
+pub struct Profile {
+    start: Instant,
+    phases: Mutex<Vec<Phase>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Phase {
+    name: &'static str,
+    total: Duration,
+    count: usize,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            start: Instant::now(),
+            phases: Mutex::default(),
+        }
+    }
+}
+
+impl Profile {
+    /// Adds the time to the phase, which is listed in the order that
+    /// its first time was added.
+    pub fn record(&self, name: &'static str, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap();
+        match phases.iter_mut().find(|phase| phase.name == name) {
+            Some(phase) => {
+                phase.total += elapsed;
+                phase.count += 1;
+            }
+            None => phases.push(Phase {
+                name,
+                total: elapsed,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Writes the time spent in each phase, how many times it was
+    /// entered, and its share of the whole run so far.
+    pub fn report(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let total = self.start.elapsed();
+        let phases = self.phases.lock().unwrap();
+        writeln!(
+            out,
+            "{:<16} {:>10} {:>7} {:>6}",
+            "phase", "time", "count", "share"
+        )?;
+        for phase in phases.iter() {
+            writeln!(
+                out,
+                "{:<16} {:>10} {:>7} {:>5.1}%",
+                phase.name,
+                format_duration(phase.total),
+                phase.count,
+                100.0 * phase.total.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON)
+            )?;
+        }
+        writeln!(out, "{:<16} {:>10}", "total", format_duration(total))
+    }
+}
+
+/// Records the time until it's dropped into a phase of the profile, if
+/// there is one.
+pub struct Timer<'a> {
+    profile: Option<&'a Profile>,
+    name: &'static str,
+    start: Instant,
+}
+
+pub fn start<'a>(profile: Option<&'a Profile>, name: &'static str) -> Timer<'a> {
+    Timer {
+        profile,
+        name,
+        start: Instant::now(),
+    }
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        if let Some(profile) = self.profile {
+            profile.record(self.name, self.start.elapsed());
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration >= Duration::from_secs(1) {
+        format!("{:.2}s", duration.as_secs_f64())
+    } else {
+        format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases_add_up() {
+        let profile = Profile::default();
+        profile.record("stack walk", Duration::from_millis(3));
+        profile.record("commit diffs", Duration::from_millis(1));
+        profile.record("commit diffs", Duration::from_millis(2));
+        drop(start(Some(&profile), "commutation"));
+        drop(start(None, "ignored"));
+
+        let phases = profile.phases.lock().unwrap().clone();
+        let names: Vec<_> = phases.iter().map(|phase| phase.name).collect();
+        assert_eq!(names, vec!["stack walk", "commit diffs", "commutation"]);
+        assert_eq!(phases[1].total, Duration::from_millis(3));
+        assert_eq!(phases[1].count, 2);
+
+        let mut report = Vec::new();
+        profile.report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report
+            .lines()
+            .any(|line| line.starts_with("commit diffs") && line.contains("3.00ms")));
+        assert!(report.lines().last().unwrap().starts_with("total"));
+    }
+}
@@ -0,0 +1,214 @@
+use anyhow::Result;
+
+use std::collections::HashMap;
+
+use crate::backend::Backend;
+use crate::config_file::Layered;
+use crate::i18n::tr;
+use crate::stack;
+use crate::{config, sign};
+
+const FIXUP_PREFIX: &str = "fixup! ";
+
+/// Squashes the `fixup!` commits after `base` (or in all of history, if
+/// there is no base) into their targets, like `git rebase --autosquash`
+/// with its todo list left as it is, but without touching the index or
+/// the working tree.
+///
+/// Returns `false` without changing anything if this needs help from
+/// the user, which `git rebase` can ask for: if a commit would conflict,
+/// if there are `squash!` or `amend!` commits (whose messages have to be
+/// edited), or if there are merges.
+pub fn autosquash(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    base: Option<&git2::Commit>,
+    signature: &git2::Signature,
+    signer: Option<&sign::Signer>,
+    logger: &slog::Logger,
+) -> Result<bool> {
+    let head_commit = repo.head()?.peel_to_commit()?;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    revwalk.push(head_commit.id())?;
+    if let Some(base) = base {
+        revwalk.hide(base.id())?;
+    }
+    // oldest first, in the order of the todo list
+    let commits = revwalk
+        .map(|id| repo.find_commit(id?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if commits.iter().any(|commit| commit.parent_count() > 1) {
+        debug!(logger, "cannot autosquash merges in memory");
+        return Ok(false);
+    }
+    let stack: Vec<_> = commits.iter().rev().collect();
+
+    // each commit, followed by the fixups that are squashed into it
+    let mut todo: Vec<(&git2::Commit, Vec<&git2::Commit>)> = Vec::new();
+    let mut positions: HashMap<git2::Oid, usize> = HashMap::new();
+    for commit in &commits {
+        if let Some(target) = stack::squash_target(&stack, commit) {
+            if !commit
+                .summary()
+                .is_some_and(|summary| summary.starts_with(FIXUP_PREFIX))
+            {
+                debug!(logger, "cannot autosquash squash! or amend! commits in memory";
+                       "commit" => commit.id().to_string(),
+                );
+                return Ok(false);
+            }
+            match positions.get(&target.id()) {
+                Some(&position) => {
+                    todo[position].1.push(commit);
+                    continue;
+                }
+                // the target is itself squashed into another commit
+                None => return Ok(false),
+            }
+        }
+        positions.insert(commit.id(), todo.len());
+        todo.push((commit, Vec::new()));
+    }
+    if todo.len() == commits.len() {
+        debug!(logger, "no fixups to autosquash");
+        return Ok(true);
+    }
+
+    let empty_tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+    let mut parent = base.cloned();
+    // the new id of each commit that was rewritten, and of each fixup
+    // (which is the new id of its target)
+    let mut rewritten = HashMap::new();
+    for (commit, fixups) in todo {
+        let parent_unchanged =
+            parent.as_ref().map(|parent| parent.id()) == commit.parent_ids().next();
+        if parent_unchanged && fixups.is_empty() {
+            parent = Some(commit.clone());
+            continue;
+        }
+        let mut tree = if parent_unchanged {
+            commit.tree()?
+        } else {
+            let onto = match &parent {
+                Some(parent) => parent.tree()?,
+                None => empty_tree.clone(),
+            };
+            match cherry_pick(repo, commit, &onto, &empty_tree)? {
+                Some(tree) => tree,
+                None => {
+                    debug!(logger, "commit conflicts when autosquashing";
+                           "commit" => commit.id().to_string(),
+                    );
+                    return Ok(false);
+                }
+            }
+        };
+        for fixup in &fixups {
+            tree = match cherry_pick(repo, fixup, &tree, &empty_tree)? {
+                Some(tree) => tree,
+                None => {
+                    debug!(logger, "fixup conflicts when autosquashing";
+                           "fixup" => fixup.id().to_string(),
+                    );
+                    return Ok(false);
+                }
+            };
+        }
+        let parents: Vec<_> = parent.iter().collect();
+        let new_commit = sign::commit(
+            repo,
+            backend,
+            signer,
+            &commit.author(),
+            signature,
+            commit.message().unwrap_or_default(),
+            &tree,
+            &parents,
+        )?;
+        rewritten.insert(commit.id(), new_commit);
+        for fixup in fixups {
+            rewritten.insert(fixup.id(), new_commit);
+        }
+        parent = Some(repo.find_commit(new_commit)?);
+    }
+
+    // unwrap() is safe here, as there was at least one fixup
+    let new_head = parent.unwrap();
+    // reordering the commits should not change the end result, but if
+    // it does, the index and working tree would no longer match HEAD
+    if new_head.tree_id() != head_commit.tree_id() {
+        debug!(logger, "autosquashing in memory changed the tree of HEAD");
+        return Ok(false);
+    }
+    let message = config::reflog_message(settings, "autosquash rebase");
+    repo.reference("ORIG_HEAD", head_commit.id(), true, &message)?;
+    repo.head()?.set_target(new_head.id(), &message)?;
+    update_branches(repo, settings, &rewritten, logger)?;
+    info!(logger, "{}", tr("autosquashed fixups");
+          "head" => new_head.id().to_string(),
+    );
+    Ok(true)
+}
+
+/// Moves the local branches other than HEAD that point to rewritten
+/// commits (eg: the lower branches of a stack of branches) to the new
+/// commits, like `git rebase --update-refs`.
+fn update_branches(
+    repo: &git2::Repository,
+    settings: &Layered,
+    rewritten: &HashMap<git2::Oid, git2::Oid>,
+    logger: &slog::Logger,
+) -> Result<()> {
+    for branch in repo.branches(Some(git2::BranchType::Local))? {
+        let (branch, _) = branch?;
+        if branch.is_head() {
+            continue;
+        }
+        let mut reference = branch.into_reference();
+        let new_target = match reference.target().and_then(|target| rewritten.get(&target)) {
+            Some(&new_target) => new_target,
+            None => continue,
+        };
+        reference.set_target(
+            new_target,
+            &config::reflog_message(settings, "update ref after autosquash rebase"),
+        )?;
+        info!(logger, "{}", tr("updated branch");
+              "branch" => reference.shorthand(),
+              "commit" => new_target.to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Applies the changes of `commit` to `onto`, or returns `None` if they
+/// conflict.
+fn cherry_pick<'repo>(
+    repo: &'repo git2::Repository,
+    commit: &git2::Commit,
+    onto: &git2::Tree,
+    empty_tree: &git2::Tree,
+) -> Result<Option<git2::Tree<'repo>>> {
+    let ancestor = match commit.parent_count() {
+        0 => empty_tree.clone(),
+        _ => commit.parent(0)?.tree()?,
+    };
+    merge(repo, &ancestor, onto, &commit.tree()?)
+}
+
+/// Merges the changes from `ancestor` to `theirs` into `ours`, or
+/// returns `None` if they conflict.
+pub fn merge<'repo>(
+    repo: &'repo git2::Repository,
+    ancestor: &git2::Tree,
+    ours: &git2::Tree,
+    theirs: &git2::Tree,
+) -> Result<Option<git2::Tree<'repo>>> {
+    let mut index = repo.merge_trees(ancestor, ours, theirs, None)?;
+    if index.has_conflicts() {
+        return Ok(None);
+    }
+    Ok(Some(repo.find_tree(index.write_tree_to(repo)?)?))
+}
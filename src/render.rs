@@ -0,0 +1,110 @@
+use crate::output::PathStyle;
+use crate::owned;
+
+const RESET: &str = "\x1b[m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+
+/// Renders the hunk as the lines of a unified diff hunk.
+pub fn hunk_text(hunk: &owned::Hunk) -> Vec<String> {
+    let mut ret = vec![format!("@@ {} @@", hunk.header())];
+    for (prefix, block) in [('-', &hunk.removed), ('+', &hunk.added)] {
+        for line in &*block.lines {
+            let line = String::from_utf8_lossy(line);
+            ret.push(format!("{}{}", prefix, line.trim_end_matches('\n')));
+        }
+        if !block.lines.is_empty() && !block.trailing_newline {
+            ret.push("\\ No newline at end of file".to_owned());
+        }
+    }
+    ret
+}
+
+/// Renders the hunk as a unified diff of the file at `path`, with the
+/// file header that `git apply` expects (which quotes the path along
+/// with its prefix, if at all).
+pub fn patch_text(path: &[u8], hunk: &owned::Hunk, path_style: PathStyle) -> Vec<String> {
+    let mut ret = vec![
+        format!("--- {}", path_style.format(&[b"a/", path].concat())),
+        format!("+++ {}", path_style.format(&[b"b/", path].concat())),
+    ];
+    ret.extend(hunk_text(hunk));
+    ret
+}
+
+/// Wraps a line of a unified diff in the escape codes for its color,
+/// like `git diff --color`.
+pub fn colorize(line: &str) -> String {
+    let color = if line.starts_with("---") || line.starts_with("+++") {
+        BOLD
+    } else {
+        match line.chars().next() {
+            Some('@') => CYAN,
+            Some('-') => RED,
+            Some('+') => GREEN,
+            _ => return line.to_owned(),
+        }
+    };
+    format!("{}{}{}", color, line, RESET)
+}
+
+/// Returns whether diffs should be colored, according to `NO_COLOR`,
+/// then `color.diff`, then `color.ui`. Like git, the default is to only
+/// color output that goes to a terminal.
+pub fn use_color(repo: &git2::Repository, is_terminal: bool) -> bool {
+    if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        return false;
+    }
+    let setting = repo.config().ok().and_then(|config| {
+        config
+            .get_string("color.diff")
+            .or_else(|_| config.get_string("color.ui"))
+            .ok()
+    });
+    match setting.as_deref().map(str::to_lowercase).as_deref() {
+        Some("always") | Some("true") | Some("yes") | Some("on") | Some("1") => true,
+        Some("never") | Some("false") | Some("no") | Some("off") | Some("0") => false,
+        _ => is_terminal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_patch_text() {
+        let hunk = owned::Hunk {
+            added: owned::Block {
+                start: 2,
+                lines: Arc::new(vec![
+                    owned::Line::from(&b"bar\n"[..]),
+                    owned::Line::from(&b"baz"[..]),
+                ]),
+                trailing_newline: false,
+            },
+            removed: owned::Block {
+                start: 2,
+                lines: Arc::new(vec![owned::Line::from(&b"foo\n"[..])]),
+                trailing_newline: true,
+            },
+        };
+        assert_eq!(
+            patch_text(b"file", &hunk, PathStyle::default()),
+            vec![
+                "--- a/file",
+                "+++ b/file",
+                "@@ -2,1 +2,2 @@",
+                "-foo",
+                "+bar",
+                "+baz",
+                "\\ No newline at end of file",
+            ]
+        );
+        assert_eq!(colorize("-foo"), "\x1b[31m-foo\x1b[m");
+        assert_eq!(colorize(" context"), " context");
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::{anyhow, Result};
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config_file::Layered;
+use crate::i18n::{tr, trf};
+use crate::{backend, git_cli, rebase, Action, Config, Leftovers, Outcome, OutputFormat};
+
+/// A xorshift64* generator, which is plenty for making up histories, and
+/// gives the same ones for the same seed on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must never be zero
+        Rng((seed ^ 0x9e37_79b9_7f4a_7c15).wrapping_mul(0xbf58_476d_1ce4_e5b9) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+const FILES: &[&str] = &["a", "b", "c"];
+
+/// Makes up a line: mostly unique ones, but also lines that repeat, like
+/// braces and blank lines do in code, which are the hard cases.
+fn line(rng: &mut Rng, counter: &mut usize) -> String {
+    match rng.below(6) {
+        0 => "}\n".to_owned(),
+        1 => "\n".to_owned(),
+        _ => {
+            *counter += 1;
+            format!("line {}\n", counter)
+        }
+    }
+}
+
+/// Inserts, removes or replaces a few lines somewhere in the file.
+fn edit(rng: &mut Rng, lines: &mut Vec<String>, counter: &mut usize) {
+    let at = rng.below(lines.len() + 1);
+    let len = 1 + rng.below(3);
+    match rng.below(3) {
+        0 => {
+            for _ in 0..len {
+                lines.insert(at, line(rng, counter));
+            }
+        }
+        1 => {
+            let end = (at + len).min(lines.len());
+            lines.drain(at..end);
+        }
+        _ => {
+            let end = (at + len).min(lines.len());
+            let new: Vec<_> = (0..len).map(|_| line(rng, counter)).collect();
+            lines.splice(at..end, new);
+        }
+    }
+}
+
+/// Writes the files and adds them to the index.
+fn stage(repo: &git2::Repository, files: &[Vec<String>]) -> Result<git2::Oid> {
+    let workdir = repo.workdir().unwrap();
+    let mut index = repo.index()?;
+    for (name, lines) in FILES.iter().zip(files) {
+        std::fs::write(workdir.join(name), lines.concat())?;
+        index.add_path(Path::new(name))?;
+    }
+    index.write()?;
+    Ok(index.write_tree()?)
+}
+
+fn commit(repo: &git2::Repository, tree: git2::Oid, message: &str) -> Result<()> {
+    let signature = repo.signature()?;
+    let tree = repo.find_tree(tree)?;
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(_) => None,
+    };
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        message,
+        &tree,
+        &parents,
+    )?;
+    Ok(())
+}
+
+/// Makes up a history of a few commits in a new repository, and stages
+/// some more changes on top of it.
+fn make_history(dir: &Path, rng: &mut Rng) -> Result<git2::Repository> {
+    let repo = git2::Repository::init(dir)?;
+    {
+        let mut config = repo.config()?;
+        config.set_str("user.name", "git-absorb")?;
+        config.set_str("user.email", "self-test@git-absorb")?;
+    }
+    let mut counter = 0;
+    let mut files: Vec<Vec<String>> = FILES
+        .iter()
+        .map(|_| {
+            (0..5 + rng.below(10))
+                .map(|_| line(rng, &mut counter))
+                .collect()
+        })
+        .collect();
+    let tree = stage(&repo, &files)?;
+    commit(&repo, tree, "initial")?;
+    for idx in 0..1 + rng.below(5) {
+        for _ in 0..1 + rng.below(3) {
+            let file = rng.below(files.len());
+            edit(rng, &mut files[file], &mut counter);
+        }
+        let tree = stage(&repo, &files)?;
+        commit(&repo, tree, &format!("commit {}", idx))?;
+    }
+    for _ in 0..1 + rng.below(4) {
+        let file = rng.below(files.len());
+        edit(rng, &mut files[file], &mut counter);
+    }
+    stage(&repo, &files)?;
+    Ok(repo)
+}
+
+/// Squashes the fixups with `git rebase --autosquash`, and returns
+/// whether it did without conflicts.
+fn git_autosquash(repo: &git2::Repository) -> Result<bool> {
+    let mut command = Command::new("git");
+    git_cli::in_repo(&mut command, repo)
+        .args([
+            "rebase",
+            "--interactive",
+            "--autosquash",
+            "--autostash",
+            "--root",
+        ])
+        .env("GIT_SEQUENCE_EDITOR", "true")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("{}", trf("could not run git rebase: {}", &[&e])))?;
+    if !status.success() {
+        // keep the fixups as they were for inspection
+        let mut command = Command::new("git");
+        git_cli::in_repo(&mut command, repo)
+            .args(["rebase", "--abort"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        command
+            .status()
+            .map_err(|e| anyhow!("{}", trf("could not run git rebase: {}", &[&e])))?;
+    }
+    Ok(status.success())
+}
+
+/// Absorbs the staged changes of a made-up history, squashes the fixups
+/// and checks that nothing was lost or changed on the way: the index is
+/// as it was, the fixups squash without conflicts, and the history ends
+/// up with the same tree as the fixups did.
+fn check(config: &Config, repo: &git2::Repository) -> Result<()> {
+    let expected = repo.index()?.write_tree()?;
+    let outcome = crate::run_with_repo(config, repo)?;
+    if repo.index()?.write_tree()? != expected {
+        return Err(anyhow!("{}", tr("absorbing changed the index")));
+    }
+    let fixups = repo.head()?.peel_to_commit()?;
+    if outcome == Outcome::Absorbed && fixups.tree_id() != expected {
+        return Err(anyhow!(
+            "{}",
+            tr("absorbing everything left HEAD different from the index")
+        ));
+    }
+    // --and-rebase runs git rebase if the fixups can't be squashed in
+    // memory, which merges them more leniently
+    if !rebase::autosquash(
+        repo,
+        &Layered::open(repo)?,
+        &backend::Libgit2,
+        None,
+        &repo.signature()?,
+        None,
+        config.logger,
+    )? && !git_autosquash(repo)?
+    {
+        return Err(anyhow!(
+            "{}",
+            tr("the fixups could not be squashed without conflicts")
+        ));
+    }
+    let head = repo.head()?.peel_to_commit()?;
+    if head.tree_id() != fixups.tree_id() {
+        return Err(anyhow!(
+            "{}",
+            tr("squashing the fixups changed the tree of HEAD")
+        ));
+    }
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(head.id())?;
+    for id in revwalk {
+        let commit = repo.find_commit(id?)?;
+        if commit.summary().is_some_and(|s| s.starts_with("fixup! ")) {
+            return Err(anyhow!("{}", tr("a fixup was left after squashing")));
+        }
+    }
+    Ok(())
+}
+
+/// Absorbs the staged changes of `iterations` made-up histories, with
+/// the settings of `config` that affect how hunks are commuted, and
+/// fails with the first one that went wrong, whose repository is kept
+/// for inspection.
+pub fn run(config: &Config, iterations: usize, seed: u64) -> Result<Outcome> {
+    let logger = config.logger;
+    let discard = slog::Logger::root(slog::Discard, o!());
+    let config = Config {
+        dry_run: false,
+        base: Vec::new(),
+        from: None,
+        stash: None,
+        patch_file: None,
+        target: None,
+        and_rebase: false,
+        paranoid: false,
+        interactive: false,
+        patch: false,
+        all: false,
+        explain: false,
+        verbose: false,
+        output_format: OutputFormat::Text,
+        fixup_kind: None,
+        edit_message: false,
+        run_hooks: false,
+        gpg_sign: false,
+        signing_key: None,
+        date: None,
+        leftovers: Leftovers::Stage,
+        action: Action::Absorb,
+        pathspecs: Vec::new(),
+        profile: None,
+        logger: &discard,
+        ..config.clone()
+    };
+    let mut rng = Rng::new(seed);
+    for iteration in 0..iterations {
+        let dir: PathBuf = std::env::temp_dir().join(format!(
+            "git-absorb-self-test-{}-{}",
+            std::process::id(),
+            iteration
+        ));
+        let repo = make_history(&dir, &mut rng)?;
+        if let Err(e) = check(&config, &repo) {
+            return Err(anyhow!(
+                "{}",
+                trf(
+                    "self-test failed at iteration {} of seed {}: {}, the repository is kept in {}",
+                    &[&iteration, &seed, &e, &dir.display()]
+                )
+            ));
+        }
+        drop(repo);
+        std::fs::remove_dir_all(&dir)?;
+    }
+    info!(logger, "{}", tr("self-test passed");
+          "iterations" => iterations,
+          "seed" => seed,
+    );
+    Ok(Outcome::Absorbed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut first = Rng::new(42);
+        let mut second = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(first.below(10), second.below(10));
+        }
+        assert!((0..100).all(|_| first.below(3) < 3));
+    }
+}
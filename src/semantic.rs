@@ -0,0 +1,198 @@
+use anyhow::Result;
+use regex::bytes::Regex;
+
+use git_absorb_core::owned;
+
+/// The lines that start a function (or a type) in some languages, like
+/// the `xfuncname` patterns that git has built in, by file extension.
+/// They only look at one line at a time, without parsing the file (no
+/// tree-sitter grammars are available), so they also match in strings
+/// and comments.
+const BUILTIN: &[(&[&str], &str)] = &[
+    (
+        &["rs"],
+        r#"^\s*(pub(\([^)]*\))?\s+)?((async|const|unsafe|default|extern\s+"[^"]*")\s+)*(fn|struct|enum|union|trait|impl|mod|macro_rules!)\b"#,
+    ),
+    (&["py", "pyi"], r"^\s*(async\s+)?(def|class)\s"),
+    (&["go"], r"^(func|type)\s"),
+    (
+        &["js", "jsx", "mjs", "cjs", "ts", "tsx"],
+        r"^\s*(export\s+(default\s+)?)?(async\s+)?(function\b|class\s)",
+    ),
+    (&["rb"], r"^\s*(def|class|module)\s"),
+];
+
+/// The patterns that tell whether a line starts a function.
+struct Boundaries {
+    patterns: Vec<(bool, Regex)>,
+}
+
+impl Boundaries {
+    /// Parses patterns like `diff.<driver>.xfuncname`, one per line,
+    /// where lines that match a pattern starting with `!` are not
+    /// boundaries.
+    fn parse(spec: &str) -> Option<Self> {
+        let patterns = spec
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.strip_prefix('!') {
+                Some(line) => Regex::new(line).map(|regex| (true, regex)),
+                None => Regex::new(line).map(|regex| (false, regex)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+        Some(Boundaries { patterns })
+    }
+
+    fn matches(&self, line: &[u8]) -> bool {
+        match self.patterns.iter().find(|(_, regex)| regex.is_match(line)) {
+            Some((negated, _)) => !negated,
+            None => false,
+        }
+    }
+}
+
+/// Returns the boundaries for the file: those of the `xfuncname` of its
+/// diff driver, if it has one, or the built-in ones for its extension.
+fn boundaries(repo: &git2::Repository, path: &[u8]) -> Option<Boundaries> {
+    let fs_path = crate::path_from_bytes(path)?;
+    if let Ok(Some(driver)) = repo.get_attr(fs_path, "diff", git2::AttrCheckFlags::FILE_THEN_INDEX)
+    {
+        let xfuncname = repo
+            .config()
+            .and_then(|config| config.get_string(&format!("diff.{}.xfuncname", driver)));
+        if let Ok(xfuncname) = xfuncname {
+            return Boundaries::parse(&xfuncname);
+        }
+    }
+    let extension = fs_path.extension()?.to_str()?;
+    BUILTIN
+        .iter()
+        .find(|(extensions, _)| extensions.contains(&extension))
+        .and_then(|(_, pattern)| Boundaries::parse(pattern))
+}
+
+/// Returns where the hunk can be cut so that each part changes a single
+/// function: the pairs of lines that start the same function on the
+/// removed and the added side, counted from the start of the hunk.
+fn cuts(hunk: &owned::Hunk, boundaries: &Boundaries) -> Vec<(usize, usize)> {
+    let starts = |block: &owned::Block| -> Vec<usize> {
+        (1..block.lines.len())
+            .filter(|&idx| boundaries.matches(&block.lines[idx]))
+            .collect()
+    };
+    let removed = starts(&hunk.removed);
+    let added = starts(&hunk.added);
+    // the same functions start on both sides, even if their signatures
+    // changed
+    if removed.len() == added.len() {
+        return removed.into_iter().zip(added).collect();
+    }
+    // otherwise some were added or removed, and only the ones whose first
+    // line is the same on both sides are known to be the same
+    let mut ret = Vec::new();
+    let mut added = added.as_slice();
+    for removed_idx in removed {
+        let line = hunk.removed.lines[removed_idx].trim_ascii();
+        if let Some(pos) = added
+            .iter()
+            .position(|&added_idx| hunk.added.lines[added_idx].trim_ascii() == line)
+        {
+            ret.push((removed_idx, added[pos]));
+            added = &added[pos + 1..];
+        }
+    }
+    ret
+}
+
+/// Cuts the hunk before each line that starts a function, so that the
+/// changes to each function are separate hunks.
+fn split_hunk(hunk: &owned::Hunk, boundaries: &Boundaries) -> Vec<owned::Hunk> {
+    let mut ret = Vec::new();
+    let mut rest = hunk.clone();
+    let (mut removed_done, mut added_done) = (0, 0);
+    for (removed_idx, added_idx) in cuts(hunk, boundaries) {
+        let (first, second) = rest.split_at(removed_idx - removed_done, added_idx - added_done);
+        ret.push(first);
+        rest = second;
+        removed_done = removed_idx;
+        added_done = added_idx;
+    }
+    ret.push(rest);
+    ret
+}
+
+/// Splits the hunks of the modified text files whose language (or diff
+/// driver) tells where functions start at those boundaries.
+pub fn split(
+    repo: &git2::Repository,
+    index: owned::Diff,
+    logger: &slog::Logger,
+) -> Result<owned::Diff> {
+    let mut patches = index.into_patches();
+    for patch in &mut patches {
+        if patch.status != git2::Delta::Modified || patch.is_binary {
+            continue;
+        }
+        let boundaries = match boundaries(repo, &patch.new_path) {
+            Some(boundaries) => boundaries,
+            None => continue,
+        };
+        let hunks: Vec<_> = patch
+            .hunks
+            .iter()
+            .flat_map(|hunk| split_hunk(hunk, &boundaries))
+            .collect();
+        if hunks.len() != patch.hunks.len() {
+            debug!(logger, "split hunks at function boundaries";
+                   "path" => String::from_utf8_lossy(&patch.new_path).into_owned(),
+                   "hunks" => hunks.len(),
+            );
+            patch.hunks = hunks;
+        }
+    }
+    owned::Diff::from_patches(patches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_split_hunk() {
+        let block = |start, lines: &[&[u8]]| owned::Block {
+            start,
+            lines: Arc::new(lines.iter().map(|&line| owned::Line::from(line)).collect()),
+            trailing_newline: true,
+        };
+        let hunk = owned::Hunk {
+            removed: block(2, &[b"    a\n", b"}\n", b"fn g() {\n", b"    b\n"]),
+            added: block(2, &[b"    a2\n", b"}\n", b"fn g() {\n", b"    b2\n"]),
+        };
+        let boundaries = Boundaries::parse(BUILTIN[0].1).unwrap();
+        let hunks = split_hunk(&hunk, &boundaries);
+        let headers: Vec<_> = hunks.iter().map(owned::Hunk::header).collect();
+        assert_eq!(headers, vec!["-2,2 +2,2", "-4,2 +4,2"]);
+
+        // the signature of a function can change too
+        let hunk = owned::Hunk {
+            removed: block(2, &[b"    a\n", b"fn g(x) {\n"]),
+            added: block(2, &[b"    a2\n", b"    a3\n", b"fn g(x, y) {\n"]),
+        };
+        let hunks = split_hunk(&hunk, &boundaries);
+        let headers: Vec<_> = hunks.iter().map(owned::Hunk::header).collect();
+        assert_eq!(headers, vec!["-2,1 +2,2", "-3,1 +4,1"]);
+
+        // but a function that is only on one side can't be cut at
+        let hunk = owned::Hunk {
+            removed: block(2, &[b"    a\n", b"fn g() {\n", b"fn h() {\n"]),
+            added: block(2, &[b"    a2\n", b"fn i() {\n"]),
+        };
+        assert_eq!(split_hunk(&hunk, &boundaries).len(), 1);
+
+        let boundaries = Boundaries::parse("!^fn g\n^fn ").unwrap();
+        assert!(!boundaries.matches(b"fn g() {\n"));
+        assert!(boundaries.matches(b"fn h() {\n"));
+    }
+}
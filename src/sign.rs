@@ -0,0 +1,188 @@
+use anyhow::{anyhow, Result};
+
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::backend::Backend;
+use crate::i18n::{tr, trf};
+
+/// The tool that signs commits, as chosen by `gpg.format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    OpenPgp,
+    X509,
+    Ssh,
+}
+
+/// Signs commits the same way git does, by running gpg, gpgsm or
+/// ssh-keygen, since libgit2 can't sign commits by itself.
+pub struct Signer {
+    format: Format,
+    program: String,
+    key: String,
+}
+
+/// Returns the signer to use, or `None` if commits should not be
+/// signed. Commits are signed if `force` is set or if `commit.gpgSign`
+/// is enabled, with `key` or else `user.signingKey`.
+pub fn signer(
+    repo: &git2::Repository,
+    force: bool,
+    key: Option<&str>,
+    committer: &git2::Signature,
+) -> Result<Option<Signer>> {
+    let config = repo.config()?;
+    if !force && !config.get_bool("commit.gpgSign").unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let format = match config.get_string("gpg.format") {
+        Err(_) => Format::OpenPgp,
+        Ok(format) => match format.as_str() {
+            "openpgp" => Format::OpenPgp,
+            "x509" => Format::X509,
+            "ssh" => Format::Ssh,
+            _ => {
+                return Err(anyhow!(
+                    "{}",
+                    trf("unsupported gpg.format {}", &[&format!("{:?}", format)])
+                ))
+            }
+        },
+    };
+    let (format_name, default_program) = match format {
+        Format::OpenPgp => ("openpgp", "gpg"),
+        Format::X509 => ("x509", "gpgsm"),
+        Format::Ssh => ("ssh", "ssh-keygen"),
+    };
+    let program = config
+        .get_string(&format!("gpg.{}.program", format_name))
+        .or_else(|e| match format {
+            Format::OpenPgp => config.get_string("gpg.program"),
+            _ => Err(e),
+        })
+        .unwrap_or_else(|_| default_program.to_owned());
+
+    let key = match key
+        .map(str::to_owned)
+        .or_else(|| config.get_string("user.signingKey").ok())
+    {
+        Some(key) => key,
+        None if format == Format::Ssh => {
+            return Err(anyhow!(
+                "{}",
+                tr("user.signingKey must be set to sign commits with ssh")
+            ))
+        }
+        // gpg looks the key up by the committer's identity
+        None => format!(
+            "{} <{}>",
+            String::from_utf8_lossy(committer.name_bytes()),
+            String::from_utf8_lossy(committer.email_bytes())
+        ),
+    };
+
+    Ok(Some(Signer {
+        format,
+        program,
+        key,
+    }))
+}
+
+impl Signer {
+    /// Describes how commits are signed, eg: "gpg with key ABCD".
+    pub fn describe(&self) -> String {
+        format!("{} with key {}", self.program, self.key)
+    }
+
+    /// Returns the detached signature of the content.
+    fn sign(&self, repo: &git2::Repository, content: &[u8]) -> Result<String> {
+        let mut command = Command::new(&self.program);
+        // a literal ssh key has to be written to a file for ssh-keygen
+        let literal_key_path = repo.path().join("ABSORB_SIGNING_KEY");
+        let literal_key = match self.format {
+            Format::Ssh => self
+                .key
+                .strip_prefix("key::")
+                .or_else(|| Some(self.key.as_str()).filter(|key| key.starts_with("ssh-"))),
+            _ => None,
+        };
+        match self.format {
+            Format::OpenPgp | Format::X509 => {
+                command.args(["--status-fd=2", "-bsau", &self.key]);
+            }
+            Format::Ssh => {
+                let key_path = match literal_key {
+                    Some(literal_key) => {
+                        fs::write(&literal_key_path, literal_key)?;
+                        literal_key_path.to_string_lossy().into_owned()
+                    }
+                    None => self.key.clone(),
+                };
+                command.args(["-Y", "sign", "-n", "git", "-f", &key_path]);
+            }
+        }
+
+        let result = (|| {
+            let mut child = command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .map_err(|e| anyhow!("{}", trf("could not run {}: {}", &[&self.program, &e])))?;
+            child
+                .stdin
+                .take()
+                .ok_or_else(|| anyhow!("{}", trf("could not write to {}", &[&self.program])))?
+                .write_all(content)?;
+            let output = child.wait_with_output()?;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "{}",
+                    trf(
+                        "{} failed to sign the commit: {}",
+                        &[
+                            &self.program,
+                            &String::from_utf8_lossy(&output.stderr).trim()
+                        ]
+                    )
+                ));
+            }
+            String::from_utf8(output.stdout).map_err(|_| {
+                anyhow!(
+                    "{}",
+                    trf("{} returned a non-utf8 signature", &[&self.program])
+                )
+            })
+        })();
+        if literal_key.is_some() {
+            fs::remove_file(&literal_key_path)?;
+        }
+        result
+    }
+}
+
+/// Creates a commit like `Repository::commit()` without updating any
+/// reference, signing it if a signer is given.
+#[allow(clippy::too_many_arguments)]
+pub fn commit(
+    repo: &git2::Repository,
+    backend: &dyn Backend,
+    signer: Option<&Signer>,
+    author: &git2::Signature,
+    committer: &git2::Signature,
+    message: &str,
+    tree: &git2::Tree,
+    parents: &[&git2::Commit],
+) -> Result<git2::Oid> {
+    let signer = match signer {
+        Some(signer) => signer,
+        None => return backend.commit(repo, author, committer, message, tree, parents),
+    };
+    let buffer = repo.commit_create_buffer(author, committer, message, tree, parents)?;
+    let signature = signer.sign(repo, &buffer)?;
+    let buffer = std::str::from_utf8(&buffer)
+        .map_err(|_| anyhow!("{}", tr("cannot sign a commit with a non-utf8 message")))?;
+    Ok(repo.commit_signed(buffer, &signature, None)?)
+}
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, Result};
+
+use std::fs;
+use std::process::Command;
+
+use crate::backend::Backend;
+use crate::config_file::Layered;
+use crate::i18n::{tr, trf};
+use crate::undo;
+
+/// The file in the git directory that records the fixups of a run whose
+/// rebase stopped on a conflict, one id per line.
+const STATE_FILE: &str = "ABSORB_REBASE";
+
+/// Records that the rebase of `fixups` stopped, so that it can be
+/// continued or aborted with `--continue` and `--abort`.
+pub fn record(repo: &git2::Repository, fixups: &[git2::Oid], logger: &slog::Logger) -> Result<()> {
+    let mut content = String::new();
+    for fixup in fixups {
+        content.push_str(&format!("{}\n", fixup));
+    }
+    fs::write(repo.path().join(STATE_FILE), content)?;
+    report(repo, fixups, logger);
+    Ok(())
+}
+
+fn read(repo: &git2::Repository) -> Result<Vec<git2::Oid>> {
+    let content = fs::read_to_string(repo.path().join(STATE_FILE))
+        .map_err(|_| anyhow!("{}", tr("no absorb is in progress")))?;
+    content
+        .lines()
+        .map(|line| Ok(git2::Oid::from_str(line.trim())?))
+        .collect()
+}
+
+fn clear(repo: &git2::Repository) -> Result<()> {
+    let path = repo.path().join(STATE_FILE);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn rebase_in_progress(repo: &git2::Repository) -> bool {
+    matches!(
+        repo.state(),
+        git2::RepositoryState::Rebase
+            | git2::RepositoryState::RebaseInteractive
+            | git2::RepositoryState::RebaseMerge
+    )
+}
+
+/// Splits the fixups into the ones that the rebase has already squashed
+/// and the ones that remain, according to its list of done commands.
+fn progress(repo: &git2::Repository, fixups: &[git2::Oid]) -> (Vec<git2::Oid>, Vec<git2::Oid>) {
+    let done =
+        fs::read_to_string(repo.path().join("rebase-merge").join("done")).unwrap_or_default();
+    // the commands name abbreviated ids, eg: "fixup 1234abc fixup! x"
+    let mut done_ids: Vec<_> = done
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .collect();
+    // the last command is the one that the rebase stopped on
+    done_ids.pop();
+    fixups.iter().partition(|fixup| {
+        let fixup = fixup.to_string();
+        done_ids
+            .iter()
+            .any(|id| id.len() >= 4 && fixup.starts_with(id))
+    })
+}
+
+fn report(repo: &git2::Repository, fixups: &[git2::Oid], logger: &slog::Logger) {
+    let (applied, remaining) = progress(repo, fixups);
+    warn!(logger, "{}", tr("rebase stopped, resolve the conflict and run git absorb --continue, or run git absorb --abort to undo the whole absorb");
+          "applied" => applied.len(),
+          "remaining" => remaining.len(),
+    );
+    for fixup in remaining {
+        let summary = repo
+            .find_commit(fixup)
+            .ok()
+            .and_then(|commit| commit.summary().map(str::to_owned))
+            .unwrap_or_default();
+        info!(logger, "{}", tr("fixup not applied yet");
+              "commit" => fixup.to_string(),
+              "summary" => summary,
+        );
+    }
+}
+
+fn git_rebase(repo: &git2::Repository, settings: &Layered, arg: &str) -> Result<bool> {
+    let mut command = Command::new("git");
+    crate::git_cli::in_repo(&mut command, repo)
+        .args(["rebase", arg])
+        .env(
+            "GIT_REFLOG_ACTION",
+            crate::config::reflog_message(settings, "autosquash rebase"),
+        );
+    let status = command
+        .status()
+        .map_err(|e| anyhow!("{}", trf("could not run git rebase: {}", &[&e])))?;
+    Ok(status.success())
+}
+
+/// Continues the rebase that stopped, and forgets about it once it is
+/// done.
+pub fn resume(repo: &git2::Repository, settings: &Layered, logger: &slog::Logger) -> Result<()> {
+    let fixups = read(repo)?;
+    if rebase_in_progress(repo) {
+        git_rebase(repo, settings, "--continue")?;
+    }
+    if rebase_in_progress(repo) {
+        report(repo, &fixups, logger);
+        return Ok(());
+    }
+    clear(repo)?;
+    info!(logger, "{}", tr("absorb finished"); "fixups" => fixups.len());
+    Ok(())
+}
+
+/// Aborts the rebase that stopped, and then removes the fixups and
+/// restores the index with `--undo`.
+pub fn abort(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    logger: &slog::Logger,
+) -> Result<()> {
+    read(repo)?;
+    if rebase_in_progress(repo) && !git_rebase(repo, settings, "--abort")? {
+        return Err(anyhow!("{}", tr("could not abort the rebase")));
+    }
+    undo::restore(repo, settings, backend, logger)?;
+    clear(repo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let repo = git2::Repository::init(&dir).unwrap();
+        fs::create_dir(repo.path().join("rebase-merge")).unwrap();
+        fs::write(
+            repo.path().join("rebase-merge").join("done"),
+            "pick 1111111 first\nfixup 2222222 fixup! first\nfixup 3333333 fixup! first\n",
+        )
+        .unwrap();
+
+        let applied = git2::Oid::from_str("2222222222222222222222222222222222222222").unwrap();
+        let remaining = git2::Oid::from_str("3333333333333333333333333333333333333333").unwrap();
+        assert_eq!(
+            progress(&repo, &[applied, remaining]),
+            (vec![applied], vec![remaining])
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use crate::i18n::trf;
+use crate::{config, git_cli, owned};
+
+/// What `absorb.targetCommand` is given on stdin for each hunk.
+#[derive(Serialize)]
+struct Request {
+    path: String,
+    header: String,
+    removed: Vec<String>,
+    added: Vec<String>,
+    /// The commit that git-absorb would absorb the hunk into, if any.
+    target: Option<String>,
+    /// The commits that the hunk can be absorbed into, newest first.
+    candidates: Vec<Candidate>,
+}
+
+#[derive(Serialize)]
+struct Candidate {
+    id: String,
+    summary: Option<String>,
+    message: Option<String>,
+    author_name: Option<String>,
+    author_email: Option<String>,
+}
+
+/// What the command picked for a hunk.
+pub enum Choice<'a, 'repo> {
+    /// Nothing was printed, so the hunk keeps the target it had.
+    Keep,
+    /// `skip` was printed, so the hunk is left in the index.
+    Skip,
+    /// The id (or a prefix of it) of one of the candidates was printed.
+    Target(&'a git2::Commit<'repo>),
+}
+
+fn lines(block: &owned::Block) -> Vec<String> {
+    block
+        .lines
+        .iter()
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect()
+}
+
+/// Runs the command through the shell, with a JSON description of the
+/// hunk and of the candidates on stdin, and returns what it picked.
+pub fn pick<'a, 'repo>(
+    repo: &git2::Repository,
+    command: &str,
+    path: &[u8],
+    hunk: &owned::Hunk,
+    target: Option<&git2::Commit>,
+    candidates: &[&'a git2::Commit<'repo>],
+) -> Result<Choice<'a, 'repo>> {
+    let request = Request {
+        path: String::from_utf8_lossy(path).into_owned(),
+        header: hunk.header(),
+        removed: lines(&hunk.removed),
+        added: lines(&hunk.added),
+        target: target.map(|commit| commit.id().to_string()),
+        candidates: candidates
+            .iter()
+            .map(|commit| Candidate {
+                id: commit.id().to_string(),
+                summary: commit.summary().map(str::to_owned),
+                message: commit.message().map(str::to_owned),
+                author_name: commit.author().name().map(str::to_owned),
+                author_email: commit.author().email().map(str::to_owned),
+            })
+            .collect(),
+    };
+    let input = serde_json::to_vec(&request)?;
+
+    // like git, run the command through the shell, so that it can
+    // contain arguments
+    let mut child = Command::new("sh");
+    git_cli::in_repo(&mut child, repo)
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped());
+    let mut child = child.spawn().map_err(|e| {
+        anyhow!(
+            "{}",
+            trf(
+                "could not run {} {}: {}",
+                &[
+                    &config::TARGET_COMMAND_CONFIG_NAME,
+                    &format!("{:?}", command),
+                    &e
+                ]
+            )
+        )
+    })?;
+    // the command doesn't have to read its input
+    if let Err(e) = child.stdin.take().unwrap().write_all(&input) {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(e.into());
+        }
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{}",
+            trf(
+                "{} {} exited with {}",
+                &[
+                    &config::TARGET_COMMAND_CONFIG_NAME,
+                    &format!("{:?}", command),
+                    &output.status
+                ]
+            )
+        ));
+    }
+
+    let picked = String::from_utf8_lossy(&output.stdout);
+    let picked = picked.trim();
+    if picked.is_empty() {
+        return Ok(Choice::Keep);
+    }
+    if picked == "skip" {
+        return Ok(Choice::Skip);
+    }
+    let mut matching = candidates.iter().filter(|commit| {
+        picked.len() >= 4
+            && picked.chars().all(|c| c.is_ascii_hexdigit())
+            && commit
+                .id()
+                .to_string()
+                .starts_with(&picked.to_ascii_lowercase())
+    });
+    match (matching.next(), matching.next()) {
+        (Some(commit), None) => Ok(Choice::Target(commit)),
+        _ => Err(anyhow!(
+            "{}",
+            trf(
+                "{} picked {}, which is not one of the candidates",
+                &[
+                    &config::TARGET_COMMAND_CONFIG_NAME,
+                    &format!("{:?}", picked)
+                ]
+            )
+        )),
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::{anyhow, Result};
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+use crate::config_file::Layered;
+use crate::i18n::{tr, trf};
+use crate::{config, git_cli};
+
+pub const UNDO_REF: &str = "refs/absorb/undo";
+
+const REF_PREFIX: &str = "ref: ";
+
+/// Records the current HEAD and the index (written as `index_tree`,
+/// along with its `intent_to_add` entries) under `UNDO_REF`, so that
+/// `restore()` can bring them back later.
+///
+/// The state is recorded as a commit whose tree is the index and whose
+/// parent is HEAD, similar to how `git stash` records the index. The
+/// reference that HEAD pointed to is kept in the message. The
+/// intent-to-add entries are kept as the empty files of a second
+/// parent, like `git stash` keeps the untracked files in a third one,
+/// and the tracked files of the working tree as a third parent.
+pub fn record(
+    repo: &git2::Repository,
+    settings: &Layered,
+    index_tree: git2::Oid,
+    intent_to_add: &[git2::IndexEntry],
+    signature: &git2::Signature,
+    logger: &slog::Logger,
+) -> Result<()> {
+    let head = repo.head()?;
+    let head_commit = head.peel_to_commit()?;
+    let head_name = if head.is_branch() {
+        head.name()
+            .ok_or_else(|| anyhow!("{}", tr("HEAD points to a non-utf8 branch")))?
+    } else {
+        "HEAD"
+    };
+    let index_tree = repo.find_tree(index_tree)?;
+    let empty_tree = repo.find_tree(repo.treebuilder(None)?.write()?)?;
+    let worktree = match worktree_tree(repo, &index_tree) {
+        Ok(worktree) => worktree,
+        Err(e) => {
+            warn!(logger, "{}", tr("could not record the working tree, --undo will leave it as it is");
+                  "error" => e.to_string(),
+            );
+            None
+        }
+    };
+    let mut parents = vec![head_commit.clone()];
+    if !intent_to_add.is_empty() || worktree.is_some() {
+        let mut builder = git2::build::TreeUpdateBuilder::new();
+        for entry in intent_to_add {
+            let mode = match entry.mode {
+                0o100755 => git2::FileMode::BlobExecutable,
+                0o120000 => git2::FileMode::Link,
+                _ => git2::FileMode::Blob,
+            };
+            builder.upsert(entry.path.as_slice(), entry.id, mode);
+        }
+        let tree = repo.find_tree(builder.create_updated(repo, &empty_tree)?)?;
+        parents.push(repo.find_commit(repo.commit(
+            None,
+            signature,
+            signature,
+            "git-absorb undo state (intent-to-add)\n",
+            &tree,
+            &[],
+        )?)?);
+    }
+    if let Some(worktree) = worktree {
+        parents.push(repo.find_commit(repo.commit(
+            None,
+            signature,
+            signature,
+            "git-absorb undo state (working tree)\n",
+            &repo.find_tree(worktree)?,
+            &[],
+        )?)?);
+    }
+
+    let state = repo.commit(
+        None,
+        signature,
+        signature,
+        &format!("git-absorb undo state\n\n{}{}\n", REF_PREFIX, head_name),
+        &index_tree,
+        &parents.iter().collect::<Vec<_>>(),
+    )?;
+    repo.reference(
+        UNDO_REF,
+        state,
+        true,
+        &config::reflog_message(settings, "record undo state"),
+    )?;
+    debug!(logger, "recorded undo state";
+           "head" => head_commit.id().to_string(),
+           "index" => index_tree.id().to_string(),
+    );
+    Ok(())
+}
+
+/// Returns the tree of the tracked files in the working tree, or `None`
+/// in a bare repository.
+fn worktree_tree(repo: &git2::Repository, index_tree: &git2::Tree) -> Result<Option<git2::Oid>> {
+    if repo.workdir().is_none() {
+        return Ok(None);
+    }
+    // git stash create records nothing if nothing changed since HEAD,
+    // in which case the working tree is the same as the index
+    match git_cli::stash_create(repo)? {
+        Some(stash) => Ok(Some(repo.find_commit(stash)?.tree_id())),
+        None => Ok(Some(index_tree.id())),
+    }
+}
+
+/// Returns the paths of the tracked files whose content in the working
+/// tree is different from the one in `tree`.
+fn changed_in_workdir(repo: &git2::Repository, tree: &git2::Tree) -> Result<HashSet<PathBuf>> {
+    let diff = repo.diff_tree_to_workdir(Some(tree), None)?;
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+        .map(Path::to_path_buf)
+        .collect())
+}
+
+/// Puts the files of the working tree back the way they were in the
+/// recorded `worktree`, unless they changed since the run (ie: they are
+/// different from `head`, the tree that the run left HEAD at), in which
+/// case they are left alone and reported.
+fn restore_worktree(
+    repo: &git2::Repository,
+    head: &git2::Tree,
+    worktree: &git2::Tree,
+    logger: &slog::Logger,
+) -> Result<()> {
+    let changed = changed_in_workdir(repo, head)?;
+    let mut checkout = git2::build::CheckoutBuilder::new();
+    checkout.force().update_index(false);
+    let mut restored = 0;
+    let mut kept = Vec::new();
+    for path in changed_in_workdir(repo, worktree)? {
+        if changed.contains(&path) {
+            kept.push(path);
+        } else if worktree.get_path(&path).is_ok() {
+            checkout.path(&path);
+            restored += 1;
+        } else if let Some(workdir) = repo.workdir() {
+            std::fs::remove_file(workdir.join(&path))?;
+        }
+    }
+    if restored > 0 {
+        repo.checkout_tree(worktree.as_object(), Some(&mut checkout))?;
+    }
+    for path in kept {
+        warn!(logger, "{}", tr("the file changed since the last absorb, and was left as it is");
+              "path" => path.display().to_string(),
+        );
+    }
+    Ok(())
+}
+
+/// Moves the branch back to where it was before the last run, and puts
+/// the index and the working tree back the way they were. Files that
+/// changed in the working tree since the run are left alone.
+pub fn restore(
+    repo: &git2::Repository,
+    settings: &Layered,
+    backend: &dyn Backend,
+    logger: &slog::Logger,
+) -> Result<()> {
+    if repo.state() != git2::RepositoryState::Clean {
+        return Err(anyhow!(
+            "{}",
+            trf(
+                "cannot undo while a {} is in progress, finish or abort it first",
+                &[&tr(crate::describe_state(repo.state()))]
+            )
+        ));
+    }
+    let mut undo_ref = repo
+        .find_reference(UNDO_REF)
+        .map_err(|_| anyhow!("{}", tr("nothing to undo")))?;
+    let state = undo_ref.peel_to_commit()?;
+    let head_name = state
+        .message()
+        .and_then(|message| {
+            message
+                .lines()
+                .find_map(|line| line.strip_prefix(REF_PREFIX))
+        })
+        .ok_or_else(|| anyhow!("{}", trf("{} is not a valid undo state", &[&UNDO_REF])))?
+        .to_owned();
+    let original_head = state.parent(0)?;
+    let head = repo.head()?.peel_to_tree()?;
+
+    if head_name == "HEAD" {
+        repo.set_head_detached(original_head.id())?;
+    } else {
+        repo.reference(
+            &head_name,
+            original_head.id(),
+            true,
+            &config::reflog_message(settings, "undo"),
+        )?;
+    }
+    let mut intent_to_add = Vec::new();
+    if state.parent_count() > 1 {
+        tree_files(repo, &state.parent(1)?.tree()?, b"", &mut intent_to_add)?;
+    }
+    backend.read_index_tree(repo, &state.tree()?, &intent_to_add)?;
+    if state.parent_count() > 2 {
+        restore_worktree(repo, &head, &state.parent(2)?.tree()?, logger)?;
+    }
+    undo_ref.delete()?;
+
+    info!(logger, "{}", tr("restored state from before the last absorb");
+          "ref" => head_name,
+          "commit" => original_head.id().to_string(),
+    );
+    Ok(())
+}
+
+/// Collects the files of the tree (with their paths under `prefix`) as
+/// intent-to-add entries.
+fn tree_files(
+    repo: &git2::Repository,
+    tree: &git2::Tree,
+    prefix: &[u8],
+    entries: &mut Vec<git2::IndexEntry>,
+) -> Result<()> {
+    for entry in tree.iter() {
+        let mut path = prefix.to_vec();
+        path.extend_from_slice(entry.name_bytes());
+        if entry.kind() == Some(git2::ObjectType::Tree) {
+            path.push(b'/');
+            tree_files(repo, &repo.find_tree(entry.id())?, &path, entries)?;
+        } else {
+            entries.push(crate::intent_to_add_entry(path, entry.filemode() as u32));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,374 @@
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Makes a repository in a temporary directory, with an identity.
+fn init_repo() -> (tempfile::TempDir, git2::Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = git2::Repository::init(dir.path()).unwrap();
+    let mut config = repo.config().unwrap();
+    config.set_str("user.name", "nobody").unwrap();
+    config.set_str("user.email", "nobody@example.com").unwrap();
+    (dir, repo)
+}
+
+/// Writes `content` to `path` in the working tree and stages it.
+fn stage_file(repo: &git2::Repository, path: &str, content: &[u8]) {
+    std::fs::write(repo.workdir().unwrap().join(path), content).unwrap();
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    index.write().unwrap();
+}
+
+/// Commits the current index on top of HEAD.
+fn commit_index(repo: &git2::Repository, message: &str) -> git2::Oid {
+    let sig = repo.signature().unwrap();
+    let tree = repo
+        .find_tree(repo.index().unwrap().write_tree().unwrap())
+        .unwrap();
+    let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+    let parents: Vec<_> = parent.iter().collect();
+    repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parents)
+        .unwrap()
+}
+
+/// Returns a command that runs git absorb in the repository, away from
+/// the user's settings.
+fn command(repo: &git2::Repository) -> Command {
+    let home = repo.path().join("home");
+    std::fs::create_dir_all(&home).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_git-absorb"));
+    command
+        .current_dir(repo.workdir().unwrap())
+        .env("HOME", &home)
+        .env("XDG_CONFIG_HOME", &home)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("LANG", "C")
+        .env_remove("LC_ALL")
+        .env_remove("LC_MESSAGES")
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_WORK_TREE")
+        .env_remove("GIT_INDEX_FILE");
+    command
+}
+
+/// Runs git absorb in the repository with the arguments.
+fn git_absorb(repo: &git2::Repository, args: &[&str]) -> Output {
+    command(repo).args(args).output().unwrap()
+}
+
+#[test]
+fn test_json_output() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", b"a\n\n\n\n");
+    let first = commit_index(&repo, "first");
+    stage_file(&repo, "image.png", b"\x00\x01");
+    stage_file(&repo, "file", b"a\n\n\n\nb\n");
+    let second = commit_index(&repo, "second");
+    // the first line was added before the base, and binary files are
+    // skipped as a whole
+    stage_file(&repo, "file", b"a2\n\n\n\nb2\n");
+    stage_file(&repo, "image.png", b"\x00\x02");
+    let base = first.to_string();
+
+    let output = git_absorb(
+        &repo,
+        &["--output-format=json", "--strict-exit", "--base", &base],
+    );
+    // some hunks were absorbed, and the others were left in the index
+    assert_eq!(
+        output.status.code(),
+        Some(7),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    let fixups = json["fixups"].as_array().unwrap();
+    assert_eq!(fixups.len(), 1);
+    assert_eq!(fixups[0]["path"], "file");
+    assert_eq!(fixups[0]["header"], "-5,1 +5,1");
+    assert_eq!(fixups[0]["target"], second.to_string());
+    assert_eq!(fixups[0]["target_summary"], "second");
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(fixups[0]["commit"], head.id().to_string());
+    assert_eq!(head.parent_id(0).unwrap(), second);
+    assert_eq!(head.summary(), Some("fixup! second"));
+
+    let skipped = json["skipped"].as_array().unwrap();
+    assert_eq!(skipped.len(), 2);
+    assert_eq!(skipped[0]["path"], "file");
+    assert_eq!(skipped[0]["header"], "-1,1 +1,1");
+    assert_eq!(skipped[0]["reason"], "no-target");
+    assert_eq!(skipped[0]["status"], serde_json::Value::Null);
+    assert_eq!(skipped[1]["path"], "image.png");
+    assert_eq!(skipped[1]["header"], serde_json::Value::Null);
+    assert_eq!(skipped[1]["reason"], "binary");
+
+    // a dry run has the same plan, without commits
+    let output = git_absorb(&repo, &["--undo"]);
+    assert!(output.status.success());
+    let output = git_absorb(
+        &repo,
+        &["--dry-run", "--output-format=json", "--base", &base],
+    );
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(json["fixups"][0]["target"], second.to_string());
+    assert_eq!(json["fixups"][0]["commit"], serde_json::Value::Null);
+    assert_eq!(json["skipped"].as_array().unwrap().len(), 2);
+    // which tells that some hunks have no target, and only tells that
+    // others do with --strict-exit
+    assert_eq!(output.status.code(), Some(2));
+    let output = git_absorb(&repo, &["--dry-run", "--strict-exit", "--base", &base]);
+    assert_eq!(output.status.code(), Some(7));
+
+    // without --strict-exit, a real run that absorbed nothing succeeds
+    stage_file(
+        &repo,
+        "file",
+        b"a2
+
+
+
+b
+",
+    );
+    let output = git_absorb(&repo, &["--base", &base]);
+    assert_eq!(output.status.code(), Some(0));
+    let output = git_absorb(&repo, &["--strict-exit", "--base", &base]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_explain_traces_unabsorbed_hunks() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", b"a\n\n\n\n");
+    stage_file(&repo, "other", b"x\n");
+    let first = commit_index(&repo, "first");
+    stage_file(&repo, "file", b"a\n\n\n\nb\n");
+    let second = commit_index(&repo, "second");
+    stage_file(&repo, "other", b"y\n");
+    let third = commit_index(&repo, "third");
+    // the first line was added before the base, and the second commit
+    // is excluded from fixups
+    stage_file(&repo, "file", b"a2\n\n\n\nb2\n");
+    repo.config()
+        .unwrap()
+        .set_str("absorb.skipMessagePattern", "^second$")
+        .unwrap();
+    let base = first.to_string();
+
+    let output = git_absorb(&repo, &["--dry-run", "--explain", "--base", &base]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    let find = |needle: &str| {
+        lines
+            .iter()
+            .position(|line| line.contains(needle))
+            .unwrap_or_else(|| panic!("{:?} is missing from:\n{}", needle, stderr))
+    };
+    let short = |id: git2::Oid| id.to_string()[..7].to_owned();
+
+    let header = format!("{} \"third\": does not touch file", short(third));
+    let unabsorbed = find("Could not find a commit to fix up");
+    assert!(lines[unabsorbed + 1].contains(&header), "{}", stderr);
+    assert!(
+        lines[unabsorbed + 1].contains("header: -1,1 +1,1"),
+        "{}",
+        stderr
+    );
+    assert!(lines[unabsorbed + 2].contains(&format!(
+        "{} \"second\": commutes with its hunks -4,0 +5,1, moving the hunk from -1,1 +1,1 to -1,1 +1,1",
+        short(second)
+    )), "{}", stderr);
+    assert!(lines[unabsorbed + 3]
+        .contains("reached the end of the stack after 2 commits, use --base to search further"));
+
+    let excluded = find("hunk belongs to a commit excluded by its message");
+    assert!(lines[excluded + 1].contains(&header), "{}", stderr);
+    assert!(
+        lines[excluded + 1].contains("header: -5,1 +5,1"),
+        "{}",
+        stderr
+    );
+    assert!(
+        lines[excluded + 2].contains(&format!(
+            "{} \"second\": its hunk -4,0 +5,1 overlaps with the hunk at -5,1 +5,1",
+            short(second)
+        )),
+        "{}",
+        stderr
+    );
+
+    // without --explain, there is no trace
+    let output = git_absorb(&repo, &["--dry-run", "--base", &base]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Could not find a commit to fix up"));
+    assert!(!stderr.contains("reached the end of the stack"));
+}
+
+/// Returns the arguments that git rebase was run with, according to the
+/// trace of git on stderr.
+fn rebase_args(stderr: &str) -> Option<Vec<&str>> {
+    stderr
+        .lines()
+        .find_map(|line| line.split("trace: built-in: git rebase ").nth(1))
+        .map(|args| args.split(' ').collect())
+}
+
+#[test]
+fn test_rebase_options_are_passed_to_git_rebase() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", b"a\n\n\n\n");
+    let first = commit_index(&repo, "first");
+    stage_file(&repo, "file", b"a\n\n\n\nb\n");
+    commit_index(&repo, "second");
+    stage_file(&repo, "file", b"a\n\n\n\nb2\n");
+
+    // the fixups could be squashed in memory, but not with the options
+    let output = command(&repo)
+        .args([
+            "--and-rebase",
+            "--base",
+            &first.to_string(),
+            "--rebase-options",
+            "--no-verify  --committer-date-is-author-date",
+        ])
+        .env("GIT_TRACE", "1")
+        .env("GIT_SEQUENCE_EDITOR", "true")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    let args = rebase_args(&stderr).unwrap_or_else(|| panic!("git rebase didn't run:\n{}", stderr));
+    // the options are split on spaces, and come before the upstream
+    let first = first.to_string();
+    assert_eq!(
+        args[args.len() - 3..],
+        [
+            "--no-verify",
+            "--committer-date-is-author-date",
+            first.as_str()
+        ]
+    );
+    assert_eq!(args[..2], ["--interactive", "--autosquash"]);
+
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.summary(), Some("second"));
+    assert_eq!(head.parent_id(0).unwrap().to_string(), first);
+    assert_eq!(
+        head.tree()
+            .unwrap()
+            .get_path(Path::new("file"))
+            .unwrap()
+            .to_object(&repo)
+            .unwrap()
+            .as_blob()
+            .unwrap()
+            .content(),
+        b"a\n\n\n\nb2\n"
+    );
+
+    // without options, the fixups are squashed in memory
+    stage_file(&repo, "file", b"a\n\n\n\nb3\n");
+    let output = command(&repo)
+        .args(["--and-rebase", "--base", &first])
+        .env("GIT_TRACE", "1")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert_eq!(rebase_args(&stderr), None);
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.summary(), Some("second"));
+}
+
+#[test]
+fn test_cli_backend_runs_git() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", b"a\n\n\n\n");
+    commit_index(&repo, "first");
+    stage_file(&repo, "file", b"a\n\n\n\nb\n");
+    commit_index(&repo, "second");
+    stage_file(&repo, "file", b"a\n\n\n\nb2\n");
+    repo.config()
+        .unwrap()
+        .set_str("absorb.backend", "cli")
+        .unwrap();
+
+    // the git commands that git absorb reads the output of only trace
+    // to a file
+    let trace = repo.path().join("trace");
+    let output = command(&repo)
+        .arg("--and-rebase")
+        .env("GIT_TRACE", &trace)
+        .env("GIT_SEQUENCE_EDITOR", "true")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let trace = std::fs::read_to_string(trace).unwrap();
+    // the stack is walked, diffed and committed to by git, which also
+    // squashes the fixups
+    for command in [
+        "git rev-list",
+        "git diff-tree",
+        "git diff --cached",
+        "git commit-tree",
+        "git rebase",
+    ] {
+        assert!(
+            trace.contains(&format!("trace: built-in: {} ", command)),
+            "{} didn't run:\n{}",
+            command,
+            trace
+        );
+    }
+    let head = repo.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.summary(), Some("second"));
+    assert_eq!(head.parent(0).unwrap().summary(), Some("first"));
+}
+
+#[test]
+fn test_flag_and_setting_share_a_variable() {
+    let (_dir, repo) = init_repo();
+    stage_file(&repo, "file", b"a\n\n\n\n");
+    let first = commit_index(&repo, "first");
+    stage_file(&repo, "file", b"a\n\n\n\nb\n");
+    commit_index(&repo, "second");
+    stage_file(&repo, "file", b"a\n\n\n\nb2\n");
+    repo.config()
+        .unwrap()
+        .set_bool("absorb.signOff", true)
+        .unwrap();
+    let base = first.to_string();
+    let signed_off = |args: &[&str], var: &str, value: &str| {
+        let output = command(&repo)
+            .args(["--base", &base])
+            .args(args)
+            .env(var, value)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.summary(), Some("fixup! second"));
+        let signed_off = head.message().unwrap().contains("Signed-off-by:");
+        assert!(git_absorb(&repo, &["--undo"]).status.success());
+        signed_off
+    };
+
+    // the variable of absorb.signOff wins over git's configuration, and
+    // --signoff wins over the variable
+    assert!(!signed_off(&[], "GIT_ABSORB_SIGN_OFF", "0"));
+    assert!(signed_off(&["--signoff"], "GIT_ABSORB_SIGN_OFF", "0"));
+    // and --signoff has no variable of its own
+    repo.config().unwrap().remove("absorb.signOff").unwrap();
+    assert!(signed_off(&[], "GIT_ABSORB_SIGN_OFF", "1"));
+    assert!(!signed_off(&[], "GIT_ABSORB_SIGNOFF", "1"));
+}